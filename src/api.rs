@@ -0,0 +1,633 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Cursor, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{
+    error::MkError,
+    hooks,
+    making::{make, BuildSettings, MakeOptions},
+    metrics::Metrics,
+    mkfile::{Format, MkFile, Target},
+    ndjson::NdjsonReporter,
+    query::{self, TargetFilter},
+    report::{LogReporter, Reporters},
+    store,
+};
+
+/// A build asked for with `POST /builds`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BuildRequest {
+    /// The target to make, the mkfile's default if not given.
+    target: Option<String>,
+    /// Variables that override the mkfile's.
+    #[serde(default)]
+    variables: BTreeMap<String, String>,
+}
+
+/// How far along a build is.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    Queued,
+    Running,
+    Made,
+    UpToDate,
+    Failed,
+}
+
+/// A build that was asked for, as `GET /builds` lists it.
+#[derive(Debug, Clone, Serialize)]
+struct Build {
+    id: u64,
+    target: Option<String>,
+    variables: BTreeMap<String, String>,
+    status: Status,
+    /// Why the build failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Build events as lines of JSON, passed on to everyone following them.
+#[derive(Default)]
+struct Events(Mutex<Vec<mpsc::Sender<String>>>);
+
+impl Events {
+    fn send(&self, line: String) {
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|follower| follower.send(line.clone()).is_ok());
+    }
+
+    /// Returns the events sent from now on.
+    fn follow(&self) -> mpsc::Receiver<String> {
+        let (follower, events) = mpsc::channel();
+        self.0.lock().unwrap().push(follower);
+        events
+    }
+}
+
+/// Sends what is written to it to the events, a line at a time.
+struct EventWriter {
+    events: Arc<Events>,
+    pending: Vec<u8>,
+}
+
+impl Write for EventWriter {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buffer);
+        while let Some(end) = self.pending.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            self.events
+                .send(String::from_utf8_lossy(&line).into_owned());
+        }
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+type Answer = Response<Cursor<Vec<u8>>>;
+
+/// An error answer, with its status code and message.
+type Failure = (u16, String);
+
+/// How many requests are answered at once. Others are turned away until
+/// some are done.
+const MAX_ANSWERING: usize = 64;
+
+fn answer(status: u16, content_type: &str, body: String) -> Answer {
+    Response::from_string(body)
+        .with_status_code(status)
+        .with_header(Header::from_bytes("Content-Type", content_type).unwrap())
+}
+
+fn answer_json(status: u16, value: &impl Serialize) -> Answer {
+    answer(
+        status,
+        "application/json",
+        serde_json::to_string(value).unwrap_or_default(),
+    )
+}
+
+/// Splits a query string into its names and values, decoding `%XX`
+/// escapes and `+`.
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    fn decode(text: &str) -> String {
+        let bytes = text.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut at = 0;
+        while at < bytes.len() {
+            let escaped = (bytes[at] == b'%')
+                .then(|| bytes.get(at + 1..at + 3))
+                .flatten()
+                .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+            match (bytes[at], escaped) {
+                (_, Some(byte)) => {
+                    decoded.push(byte);
+                    at += 3;
+                    continue;
+                }
+                (b'+', None) => decoded.push(b' '),
+                (byte, None) => decoded.push(byte),
+            }
+            at += 1;
+        }
+        String::from_utf8_lossy(&decoded).into_owned()
+    }
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(name), decode(value))
+        })
+        .collect()
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Returns a token that can't be guessed, from the random number
+/// generator of the OS.
+fn new_token() -> Result<String, MkError> {
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|err| MkError::Io(io::Error::other(err.to_string())))?;
+    Ok(bytes.iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+/// Compares in the same time wherever the texts differ, so the token can't
+/// be found a byte at a time.
+fn same_secret(given: &str, secret: &str) -> bool {
+    given.len() == secret.len()
+        && given
+            .bytes()
+            .zip(secret.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+struct Api {
+    /// What `POST /builds` must send as `Authorization: Bearer`.
+    token: String,
+    /// Where pages allowed to ask for builds from a browser are from.
+    origin: String,
+    /// Accept variables that override the mkfile's.
+    allow_overrides: bool,
+    state: PathBuf,
+    settings: BuildSettings,
+    builds: Mutex<Vec<Build>>,
+    queue: mpsc::Sender<(u64, BuildRequest)>,
+    events: Arc<Events>,
+    metrics: Arc<Metrics>,
+}
+
+/// Answers HTTP requests on the address until killed, for dashboards and
+/// bots that drive builds:
+///
+/// - `GET /targets` lists the targets as `mk list --json` does, filtered
+///   by the `pattern` and `tag` parameters.
+/// - `POST /builds` asks for a build of the `target` in the JSON body, or
+///   of the default target, with `variables` that override the mkfile's if
+///   `allow_overrides`. Builds are made one at a time, in the order they are
+///   asked for. As they run commands, the request must send the token
+///   logged on startup as `Authorization: Bearer TOKEN`, be JSON, and not
+///   come from pages elsewhere.
+/// - `GET /builds` and `GET /builds/ID` show how builds went.
+/// - `GET /events` streams the events of every build from then on, as
+///   lines of JSON like `--events-json` writes, with the ID of their build.
+/// - `GET /state` shows what is recorded about every target, or about the
+///   `target` parameter, as `mk state show` does.
+/// - `GET /metrics` shows metrics about the builds for Prometheus to
+///   scrape, see [`Metrics`].
+pub fn serve(
+    address: &str,
+    state: &Path,
+    settings: BuildSettings,
+    allow_overrides: bool,
+) -> Result<(), MkError> {
+    let server =
+        Server::http(address).map_err(|err| MkError::Io(io::Error::other(err.to_string())))?;
+    let (queue, queued) = mpsc::channel();
+    let api = Arc::new(Api {
+        token: new_token()?,
+        origin: format!("http://{address}"),
+        allow_overrides,
+        state: state.to_path_buf(),
+        settings,
+        builds: Mutex::new(Vec::new()),
+        queue,
+        events: Arc::default(),
+        metrics: Arc::default(),
+    });
+    info!("Answering on http://{address}");
+    info!(
+        "Builds need the header 'Authorization: Bearer {}'",
+        api.token
+    );
+
+    let builder = api.clone();
+    thread::spawn(move || {
+        for (id, request) in queued {
+            builder.build(id, request);
+        }
+    });
+    // Every request is answered on a thread of its own, as streams of
+    // events last as long as their clients, but only so many at once
+    let answering = Arc::new(AtomicUsize::new(0));
+    for request in server.incoming_requests() {
+        if answering.fetch_add(1, Ordering::SeqCst) >= MAX_ANSWERING {
+            answering.fetch_sub(1, Ordering::SeqCst);
+            let url = request.url().to_string();
+            let busy = answer_json(503, &json!({ "error": "Too many requests at once" }));
+            if let Err(err) = request.respond(busy) {
+                warn!("Failed to answer '{}': {}", url, err);
+            }
+            continue;
+        }
+        let (api, answering) = (api.clone(), answering.clone());
+        thread::spawn(move || {
+            api.answer(request);
+            answering.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+    Ok(())
+}
+
+impl Api {
+    fn answer(&self, mut request: Request) {
+        let url = request.url().to_string();
+        let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+        let query = parse_query(query);
+        let answered = match (request.method(), path) {
+            (Method::Get, "/events") => return self.follow(request),
+            (Method::Get, "/targets") => self.targets(&query),
+            (Method::Get, "/state") => self.state(&query),
+            (Method::Get, "/builds") => Ok(answer_json(200, &*self.builds.lock().unwrap())),
+            (Method::Get, "/metrics") => Ok(answer(
+                200,
+                "text/plain; version=0.0.4",
+                self.metrics.render(),
+            )),
+            (Method::Post, "/builds") => self.queue(&mut request),
+            (Method::Get, path) if path.starts_with("/builds/") => {
+                let id = path.trim_start_matches("/builds/");
+                self.builds
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .find(|build| build.id.to_string() == id)
+                    .map(|build| answer_json(200, build))
+                    .ok_or((404, format!("No build '{id}'")))
+            }
+            (_, "/events" | "/targets" | "/state" | "/builds" | "/metrics") => {
+                Err((405, format!("{} isn't allowed here", request.method())))
+            }
+            _ => Err((404, format!("Nothing at '{path}'"))),
+        };
+        let response = answered
+            .unwrap_or_else(|(status, message)| answer_json(status, &json!({ "error": message })));
+        if let Err(err) = request.respond(response) {
+            warn!("Failed to answer '{}': {}", url, err);
+        }
+    }
+
+    /// Streams the events of every build as they happen, until the client
+    /// goes away.
+    fn follow(&self, request: Request) {
+        let events = self.events.follow();
+        let mut writer = request.into_writer();
+        let header = "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\n\
+                      Cache-Control: no-cache\r\nConnection: close\r\n\r\n";
+        let mut sent = writer
+            .write_all(header.as_bytes())
+            .and_then(|()| writer.flush());
+        for line in events {
+            if sent.is_err() {
+                break;
+            }
+            sent = writer
+                .write_all(line.as_bytes())
+                .and_then(|()| writer.flush());
+        }
+    }
+
+    fn parse(&self, variables: BTreeMap<String, String>) -> Result<MkFile, MkError> {
+        let path = &self.settings.mkfile;
+        let text = std::fs::read_to_string(path)?;
+        MkFile::parse_as(
+            &text,
+            Format::of(path),
+            &self.settings.parse_options(variables),
+        )
+    }
+
+    fn targets(&self, query: &[(String, String)]) -> Result<Answer, Failure> {
+        let param = |name: &str| -> Vec<String> {
+            query
+                .iter()
+                .filter(|(key, _)| key == name)
+                .map(|(_, value)| value.clone())
+                .collect()
+        };
+        let pattern = param("pattern").pop();
+        let filter = TargetFilter::new(pattern.as_deref(), param("tag"))
+            .map_err(|err| (400, err.to_string()))?;
+        let mkfile = self
+            .parse(BTreeMap::new())
+            .map_err(|err| (500, format!("Failed to parse mkfile: {err}")))?;
+        let mut state = store::open(&self.state)
+            .map_err(|err| (500, format!("Failed to open state: {err}")))?
+            .load();
+        state.assign_ids(&mkfile);
+        let mut listed = Vec::new();
+        query::list(&mkfile, &state, &filter, true, &mut listed)
+            .map_err(|err| (500, err.to_string()))?;
+        Ok(answer(
+            200,
+            "application/x-ndjson",
+            String::from_utf8_lossy(&listed).into_owned(),
+        ))
+    }
+
+    fn state(&self, query: &[(String, String)]) -> Result<Answer, Failure> {
+        let state = store::open(&self.state)
+            .map_err(|err| (500, format!("Failed to open state: {err}")))?
+            .load();
+        let target = query
+            .iter()
+            .find(|(name, _)| name == "target")
+            .map(|(_, target)| Target::parse(target));
+        let entries = state.entries(target.as_ref());
+        if let (Some(target), true) = (&target, entries.is_empty()) {
+            return Err((404, format!("Nothing is recorded about '{target}'")));
+        }
+        let entries: Vec<_> = entries
+            .into_iter()
+            .map(|(target, field, value)| json!({ "target": target, "field": field, "value": value }))
+            .collect();
+        Ok(answer_json(200, &entries))
+    }
+
+    /// Refuses requests for builds that don't have the token, come from
+    /// another site's pages, or could be sent by a plain HTML form.
+    fn check_access(&self, request: &Request) -> Result<(), Failure> {
+        let header = |name: &str| {
+            request
+                .headers()
+                .iter()
+                .find(|header| header.field.as_str().as_str().eq_ignore_ascii_case(name))
+                .map(|header| header.value.as_str())
+        };
+        let token = header("Authorization").and_then(|value| value.strip_prefix("Bearer "));
+        if !token.is_some_and(|token| same_secret(token, &self.token)) {
+            return Err((
+                401,
+                "Builds need the token mk serve logged, as 'Authorization: Bearer TOKEN'"
+                    .to_string(),
+            ));
+        }
+        if let Some(origin) = header("Origin").filter(|origin| *origin != self.origin) {
+            return Err((403, format!("Builds can't be asked for from '{origin}'")));
+        }
+        let content_type = header("Content-Type")
+            .and_then(|value| value.split(';').next())
+            .map(str::trim);
+        if !content_type.is_some_and(|value| value.eq_ignore_ascii_case("application/json")) {
+            return Err((
+                415,
+                "Builds must be asked for as application/json".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn queue(&self, request: &mut Request) -> Result<Answer, Failure> {
+        self.check_access(request)?;
+        let mut body = String::new();
+        request
+            .as_reader()
+            .read_to_string(&mut body)
+            .map_err(|err| (400, err.to_string()))?;
+        let asked: BuildRequest = if body.trim().is_empty() {
+            BuildRequest::default()
+        } else {
+            serde_json::from_str(&body).map_err(|err| (400, err.to_string()))?
+        };
+        if !asked.variables.is_empty() && !self.allow_overrides {
+            return Err((
+                403,
+                "Variables can only be overridden when mk serve is given --allow-overrides"
+                    .to_string(),
+            ));
+        }
+        let build = {
+            let mut builds = self.builds.lock().unwrap();
+            let build = Build {
+                id: builds.len() as u64 + 1,
+                target: asked.target.clone(),
+                variables: asked.variables.clone(),
+                status: Status::Queued,
+                error: None,
+            };
+            builds.push(build.clone());
+            build
+        };
+        self.announce("build_queued", &build);
+        self.queue
+            .send((build.id, asked))
+            .map_err(|err| (500, err.to_string()))?;
+        Ok(answer_json(202, &build))
+    }
+
+    /// Sends an event about a build as a whole.
+    fn announce(&self, event: &str, build: &Build) {
+        let value = json!({
+            "event": event,
+            "build": build.id,
+            "target": build.target,
+            "status": build.status,
+            "error": build.error,
+            "time": now(),
+        });
+        self.events.send(format!("{value}\n"));
+    }
+
+    /// Sets how far along the build is, and tells those following events.
+    fn update(&self, id: u64, event: &str, status: Status, error: Option<String>) {
+        let build = {
+            let mut builds = self.builds.lock().unwrap();
+            let build = &mut builds[id as usize - 1];
+            build.status = status;
+            build.error = error;
+            build.clone()
+        };
+        self.announce(event, &build);
+    }
+
+    fn build(&self, id: u64, request: BuildRequest) {
+        self.update(id, "build_started", Status::Running, None);
+        match self.make(id, request) {
+            Ok(true) => self.update(id, "build_finished", Status::Made, None),
+            Ok(false) => self.update(id, "build_finished", Status::UpToDate, None),
+            Err(err) => self.update(id, "build_finished", Status::Failed, Some(err.to_string())),
+        }
+    }
+
+    fn make(&self, id: u64, request: BuildRequest) -> Result<bool, MkError> {
+        let _lock = store::StateLock::acquire(&self.state, true)?;
+        let store = store::open(&self.state)?;
+        let mut state = store.load();
+        let mut mkfile = self.parse(request.variables)?;
+        let target = mkfile.target_to_make(request.target.as_deref());
+        mkfile.scope(&target)?;
+        state.assign_ids(&mkfile);
+
+        info!("Making target '{}' for build {}", target, id);
+        let events = NdjsonReporter::new(Box::new(EventWriter {
+            events: self.events.clone(),
+            pending: Vec::new(),
+        }))
+        .with_ids(state.ids().clone())
+        .with_field("build", json!(id));
+        let options = MakeOptions {
+            reporter: Box::new(Reporters(vec![
+                Box::new(LogReporter::default()),
+                Box::new(events),
+                Box::new(self.metrics.clone()),
+            ])),
+            ..self.settings.make_options(&mkfile, None, BTreeMap::new())?
+        };
+        let started = Instant::now();
+        hooks::started(mkfile.hooks(), &target, &options);
+        let made = make(&mkfile, &target, &mut state, &options);
+        hooks::finished(mkfile.hooks(), &target, &made, started.elapsed(), &options);
+        self.metrics.build_over(&made, started.elapsed());
+        if let Err(err) = store.save(&state) {
+            error!("Failed to save state: {}", err);
+        }
+        match &made {
+            Ok(true) => info!("Made target '{}'", target),
+            Ok(false) => info!("Target '{}' is up to date", target),
+            Err(err) => error!("Failed to make target '{}': {}", target, err),
+        }
+        made
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tiny_http::TestRequest;
+
+    use super::*;
+    use crate::{freshness::MtimeChecker, mkfile::ParseOptions};
+
+    fn api() -> Api {
+        Api {
+            token: "0123456789abcdef".to_string(),
+            origin: "http://127.0.0.1:7878".to_string(),
+            allow_overrides: false,
+            state: PathBuf::from(".mkstate.sexpr"),
+            settings: BuildSettings {
+                mkfile: PathBuf::from("mkfile"),
+                parse_options: ParseOptions::default(),
+                freshness: &MtimeChecker,
+                follow_symlinks: true,
+                hermetic: false,
+                timeout: None,
+                max_load: None,
+                cache_dir: None,
+                cache_url: None,
+            },
+            builds: Mutex::new(Vec::new()),
+            queue: mpsc::channel().0,
+            events: Arc::default(),
+            metrics: Arc::default(),
+        }
+    }
+
+    /// A `POST /builds` request with the given headers.
+    fn request(headers: &[(&str, &str)]) -> Request {
+        headers
+            .iter()
+            .fold(
+                TestRequest::new()
+                    .with_method(Method::Post)
+                    .with_path("/builds"),
+                |request, (name, value)| {
+                    request.with_header(Header::from_bytes(*name, *value).unwrap())
+                },
+            )
+            .into()
+    }
+
+    #[test]
+    fn test_new_token() {
+        let token = new_token().unwrap();
+        assert_eq!(token.len(), 32);
+        assert!(token.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(token, new_token().unwrap());
+    }
+
+    #[test]
+    fn test_same_secret() {
+        assert!(same_secret("0123456789abcdef", "0123456789abcdef"));
+        assert!(!same_secret("0123456789abcdee", "0123456789abcdef"));
+        assert!(!same_secret("0123456789abcde", "0123456789abcdef"));
+        assert!(!same_secret("", "0123456789abcdef"));
+    }
+
+    #[test]
+    fn test_check_access() {
+        let api = api();
+        let status = |headers: &[(&str, &str)]| {
+            api.check_access(&request(headers))
+                .err()
+                .map(|(status, _)| status)
+        };
+        let bearer = ("Authorization", "Bearer 0123456789abcdef");
+        let json = ("Content-Type", "application/json; charset=utf-8");
+
+        assert_eq!(status(&[bearer, json]), None);
+        assert_eq!(
+            status(&[bearer, json, ("Origin", "http://127.0.0.1:7878")]),
+            None
+        );
+        assert_eq!(status(&[json]), Some(401));
+        assert_eq!(
+            status(&[("Authorization", "Bearer wrong"), json]),
+            Some(401)
+        );
+        assert_eq!(
+            status(&[("Authorization", "0123456789abcdef"), json]),
+            Some(401)
+        );
+        assert_eq!(
+            status(&[bearer, json, ("Origin", "https://evil.example")]),
+            Some(403)
+        );
+        assert_eq!(status(&[bearer]), Some(415));
+        assert_eq!(status(&[bearer, ("Content-Type", "text/plain")]), Some(415));
+    }
+}
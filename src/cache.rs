@@ -0,0 +1,583 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    str::FromStr,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use log::warn;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    making::with_suffix,
+    mkfile::CacheConfig,
+    vfs::{RealFs, Vfs},
+};
+
+/// How long to wait on a remote cache before giving up on it.
+const REMOTE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts every entry in a remote cache, followed by the SHA-256 of the
+/// rest in hex and a line break.
+const SEALED_MAGIC: &[u8] = b"mk-sha256:";
+
+/// Starts every compressed entry, followed by a byte naming how it is
+/// compressed. Entries without it hold the output as it is.
+const COMPRESSED_MAGIC: &[u8] = b"mk-cache\0";
+
+/// zstd level used for outputs, which favors speed over size.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Outputs at least this big are compressed on every core.
+const PARALLEL_THRESHOLD: usize = 8 << 20;
+
+/// Outputs of earlier builds, stored under a key made from everything that
+/// went into making them. Making a target the same way again copies its
+/// output back instead of running its commands, even after the output and
+/// the state file were deleted.
+///
+/// Outputs are kept in a local folder, a remote cache shared with others,
+/// or both. Missing outputs are looked up locally first, and outputs found
+/// remotely are kept locally too. If the remote cache can't be reached, the
+/// build goes on without it.
+#[derive(Default)]
+pub struct Cache {
+    dir: Option<PathBuf>,
+    remote: Option<Box<dyn CacheBackend>>,
+    read_only: bool,
+    offline: AtomicBool,
+    compression: Compression,
+}
+
+/// How outputs are compressed in the cache. zstd makes smaller entries,
+/// while lz4 restores faster. Outputs that are compressed already, like
+/// archives and images, are always stored as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "none" => Ok(Compression::None),
+            "zstd" => Ok(Compression::Zstd),
+            "lz4" => Ok(Compression::Lz4),
+            _ => Err(format!(
+                "Unknown compression '{text}', expected none, zstd or lz4"
+            )),
+        }
+    }
+}
+
+/// What goes into the cache key of a target.
+pub struct KeyInputs<'a> {
+    /// The file the rule makes.
+    pub output: &'a Path,
+    /// The rule's commands, with variables expanded.
+    pub commands: &'a [String],
+    /// Environment variables set for the commands.
+    pub env: &'a BTreeMap<String, String>,
+    /// Each dependency with the hash of its contents, or no hash for
+    /// virtual targets.
+    pub dependencies: Vec<(String, Option<String>)>,
+    /// Fingerprints of the tools the rule uses, by the command probing them.
+    pub tools: &'a BTreeMap<String, String>,
+}
+
+impl Cache {
+    /// Uses the given folder for the cache. It is created when first
+    /// needed.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Cache {
+            dir: Some(dir.into()),
+            ..Cache::default()
+        }
+    }
+
+    /// Also reads from and writes to a cache shared with others.
+    pub fn with_remote(mut self, remote: Box<dyn CacheBackend>) -> Self {
+        self.remote = Some(remote);
+        self
+    }
+
+    /// Only reads from the remote cache, without storing outputs in it.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Compresses outputs stored from now on. Entries are read back however
+    /// they were stored.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Returns the key that an output made from the given inputs is stored
+    /// under.
+    pub fn key(inputs: &KeyInputs) -> String {
+        let mut hasher = Sha256::new();
+        let mut field = |value: &[u8]| {
+            hasher.update((value.len() as u64).to_le_bytes());
+            hasher.update(value);
+        };
+        field(inputs.output.as_os_str().as_encoded_bytes());
+        for command in inputs.commands {
+            field(command.as_bytes());
+        }
+        for (name, value) in inputs.env {
+            field(name.as_bytes());
+            field(value.as_bytes());
+        }
+        for (dependency, hash) in &inputs.dependencies {
+            field(dependency.as_bytes());
+            field(hash.as_deref().unwrap_or_default().as_bytes());
+        }
+        for (probe, fingerprint) in inputs.tools {
+            field(probe.as_bytes());
+            field(fingerprint.as_bytes());
+        }
+        format!("{:x}", hasher.finalize())
+    }
+
+    fn entry(dir: &Path, key: &str) -> PathBuf {
+        dir.join(&key[..2]).join(key)
+    }
+
+    /// Runs an operation on the remote cache, unless it couldn't be reached
+    /// before. Failures are warned about once, after which the remote cache
+    /// is left alone.
+    fn remote<T>(&self, operation: impl FnOnce(&dyn CacheBackend) -> io::Result<T>) -> Option<T> {
+        let remote = self.remote.as_deref()?;
+        if self.offline.load(Ordering::SeqCst) {
+            return None;
+        }
+        match operation(remote) {
+            Ok(value) => Some(value),
+            Err(err) => {
+                if !self.offline.swap(true, Ordering::SeqCst) {
+                    warn!("Remote cache is unavailable, carrying on without it: {err}");
+                }
+                None
+            }
+        }
+    }
+
+    /// Copies the output stored under the key to `path`. Returns false if
+    /// there is nothing stored under it.
+    pub fn restore(&self, vfs: &dyn Vfs, key: &str, path: &Path) -> io::Result<bool> {
+        let local = self
+            .dir
+            .as_ref()
+            .map(|dir| Self::entry(dir, key))
+            .filter(|entry| vfs.exists(entry));
+        let entry = match local {
+            Some(entry) => vfs.read(&entry)?,
+            None => match self
+                .remote(|remote| remote.get(key))
+                .flatten()
+                .and_then(|sealed| unseal(key, sealed))
+            {
+                Some(entry) => {
+                    self.store_locally(vfs, key, &entry)?;
+                    entry
+                }
+                None => return Ok(false),
+            },
+        };
+        let contents = decompress(entry)?;
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            vfs.create_dir_all(parent)?;
+        }
+        vfs.write(path, &contents)?;
+        Ok(true)
+    }
+
+    /// Stores the file at `path` under the key, compressed the way the
+    /// cache was set up to unless `compression` overrides it.
+    pub fn store(
+        &self,
+        vfs: &dyn Vfs,
+        key: &str,
+        path: &Path,
+        compression: Option<Compression>,
+    ) -> io::Result<()> {
+        let entry = compress(vfs.read(path)?, compression.unwrap_or(self.compression))?;
+        self.store_locally(vfs, key, &entry)?;
+        if !self.read_only {
+            self.remote(|remote| remote.put(key, &seal(&entry)));
+        }
+        Ok(())
+    }
+
+    fn store_locally(&self, vfs: &dyn Vfs, key: &str, contents: &[u8]) -> io::Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        write_entry(vfs, &Self::entry(dir, key), contents)
+    }
+}
+
+/// Writes a cache entry next to where it goes and then moves it there, so
+/// that a build killed halfway through doesn't leave a truncated entry that
+/// would later be restored as a whole output.
+fn write_entry(vfs: &dyn Vfs, entry: &Path, contents: &[u8]) -> io::Result<()> {
+    vfs.create_dir_all(entry.parent().unwrap())?;
+    // Other builds may be storing the same entry, such as in a shared folder
+    let temporary = with_suffix(entry, &format!(".{}.tmp", std::process::id()));
+    vfs.write(&temporary, contents)?;
+    vfs.rename(&temporary, entry)
+}
+
+/// Returns the cache entry for an output.
+fn compress(contents: Vec<u8>, compression: Compression) -> io::Result<Vec<u8>> {
+    if is_compressed(&contents) {
+        return Ok(contents);
+    }
+    let (tag, compressed) = match compression {
+        Compression::None => return Ok(contents),
+        Compression::Zstd if contents.len() >= PARALLEL_THRESHOLD => {
+            let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+            let mut encoder = zstd::Encoder::new(Vec::new(), ZSTD_LEVEL)?;
+            encoder.multithread(threads as u32)?;
+            encoder.write_all(&contents)?;
+            (b'z', encoder.finish()?)
+        }
+        Compression::Zstd => (b'z', zstd::bulk::compress(&contents, ZSTD_LEVEL)?),
+        Compression::Lz4 => (b'4', lz4_flex::compress_prepend_size(&contents)),
+    };
+    let mut entry = Vec::with_capacity(COMPRESSED_MAGIC.len() + 1 + compressed.len());
+    entry.extend_from_slice(COMPRESSED_MAGIC);
+    entry.push(tag);
+    entry.extend(compressed);
+    Ok(entry)
+}
+
+/// Returns the output held in a cache entry.
+fn decompress(entry: Vec<u8>) -> io::Result<Vec<u8>> {
+    let Some(rest) = entry.strip_prefix(COMPRESSED_MAGIC) else {
+        return Ok(entry);
+    };
+    match rest.split_first() {
+        Some((b'z', compressed)) => zstd::decode_all(compressed),
+        Some((b'4', compressed)) => {
+            lz4_flex::decompress_size_prepended(compressed).map_err(io::Error::other)
+        }
+        _ => Err(io::Error::other(
+            "cache entry is compressed in an unknown way",
+        )),
+    }
+}
+
+/// Returns the entry as it is stored remotely, after its SHA-256, so that
+/// entries that were cut short or changed on the way can be told apart.
+fn seal(entry: &[u8]) -> Vec<u8> {
+    let mut sealed = Vec::with_capacity(SEALED_MAGIC.len() + 65 + entry.len());
+    sealed.extend_from_slice(SEALED_MAGIC);
+    sealed.extend_from_slice(format!("{:x}\n", Sha256::digest(entry)).as_bytes());
+    sealed.extend_from_slice(entry);
+    sealed
+}
+
+/// Returns the entry held in a remote one, or `None` if its hash doesn't
+/// match, in which case the output is made instead of restored.
+fn unseal(key: &str, sealed: Vec<u8>) -> Option<Vec<u8>> {
+    let digest_end = SEALED_MAGIC.len() + 64;
+    let valid = sealed.starts_with(SEALED_MAGIC)
+        && sealed.get(digest_end) == Some(&b'\n')
+        && sealed[SEALED_MAGIC.len()..digest_end]
+            == *format!("{:x}", Sha256::digest(&sealed[digest_end + 1..])).as_bytes();
+    if !valid {
+        warn!("Remote cache entry '{key}' is corrupt, making the output instead");
+        return None;
+    }
+    Some(sealed[digest_end + 1..].to_vec())
+}
+
+/// Returns true if the contents start like a compressed format, where
+/// compressing again would only cost time.
+fn is_compressed(contents: &[u8]) -> bool {
+    const SIGNATURES: &[&[u8]] = &[
+        b"\x1f\x8b",           // gzip
+        b"\x28\xb5\x2f\xfd",   // zstd
+        b"\xfd7zXZ\0",         // xz
+        b"BZh",                // bzip2
+        b"\x04\x22\x4d\x18",   // lz4
+        b"PK\x03\x04",         // zip, jar, wheels
+        b"7z\xbc\xaf\x27\x1c", // 7-Zip
+        b"\x89PNG",            // PNG
+        b"\xff\xd8\xff",       // JPEG
+        b"GIF8",               // GIF
+        b"wOF2",               // WOFF2
+    ];
+    SIGNATURES
+        .iter()
+        .any(|signature| contents.starts_with(signature))
+}
+
+/// Where a shared cache keeps outputs.
+pub trait CacheBackend: Send + Sync {
+    /// Returns the output stored under the key, if there is one.
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+    /// Stores an output under the key.
+    fn put(&self, key: &str, contents: &[u8]) -> io::Result<()>;
+}
+
+/// Returns the backend for a shared cache URL: `http://` or `https://`
+/// for a server that answers GET and PUT requests for `URL/KEY`,
+/// `s3://BUCKET/PREFIX` for an S3 bucket, or a folder, e.g. on a network
+/// drive.
+pub fn backend(url: &str) -> Result<Box<dyn CacheBackend>, String> {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        Ok(Box::new(HttpBackend {
+            url: url.trim_end_matches('/').to_string(),
+        }))
+    } else if let Some(rest) = url.strip_prefix("s3://") {
+        Ok(Box::new(S3Backend::new(rest)?))
+    } else if url.contains("://") && !url.starts_with("file://") {
+        Err(format!(
+            "Unsupported cache URL '{url}', expected http://, https://, s3:// or a folder"
+        ))
+    } else {
+        Ok(Box::new(DirBackend {
+            dir: PathBuf::from(url.trim_start_matches("file://")),
+        }))
+    }
+}
+
+/// Sets up the cache from the folder and shared cache URL given on the
+/// command line, falling back to the mkfile's `[cache]` section. Returns
+/// `None` if neither names a cache, and fails if the URL is invalid.
+pub fn open(
+    config: Option<&CacheConfig>,
+    dir: Option<PathBuf>,
+    url: Option<String>,
+) -> Result<Option<Cache>, String> {
+    let dir = dir.or_else(|| config.and_then(|config| config.dir.clone()));
+    let url = url.or_else(|| config.and_then(|config| config.url.clone()));
+    let mut cache = match (dir, &url) {
+        (None, None) => return Ok(None),
+        (Some(dir), _) => Cache::new(dir),
+        (None, Some(_)) => Cache::default(),
+    };
+    if let Some(url) = url {
+        cache = cache.with_remote(backend(&url)?);
+    }
+    if let Some(config) = config {
+        cache = cache.with_compression(config.compression);
+        if config.read_only {
+            cache = cache.read_only();
+        }
+    }
+    Ok(Some(cache))
+}
+
+/// A shared cache in a folder, such as a network drive.
+pub struct DirBackend {
+    pub dir: PathBuf,
+}
+
+impl CacheBackend for DirBackend {
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match std::fs::read(Cache::entry(&self.dir, key)) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn put(&self, key: &str, contents: &[u8]) -> io::Result<()> {
+        write_entry(&RealFs, &Cache::entry(&self.dir, key), contents)
+    }
+}
+
+/// A shared cache behind an HTTP or HTTPS server, which answers
+/// `GET URL/KEY` with the output or 404, and stores the body of
+/// `PUT URL/KEY`.
+pub struct HttpBackend {
+    url: String,
+}
+
+impl CacheBackend for HttpBackend {
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        get(&format!("{}/{key}", self.url), None)
+    }
+
+    fn put(&self, key: &str, contents: &[u8]) -> io::Result<()> {
+        put(&format!("{}/{key}", self.url), contents, None)
+    }
+}
+
+/// A shared cache in an S3 bucket, or a service that speaks the same API
+/// such as MinIO, with an object for every entry under the prefix.
+///
+/// Requests are signed with the credentials in `AWS_ACCESS_KEY_ID`,
+/// `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`, if set, for the region
+/// in `AWS_REGION`. The bucket is reached at AWS unless `AWS_ENDPOINT_URL`
+/// names another service. The URL's `?region=` and `?endpoint=` override
+/// them.
+pub struct S3Backend {
+    /// Where the objects are, up to the key.
+    url: String,
+    /// curl options that sign requests, passed on stdin to keep the
+    /// credentials out of the list of processes.
+    signing: Option<String>,
+}
+
+impl S3Backend {
+    /// Creates the backend for the part of an `s3://` URL after the scheme.
+    fn new(rest: &str) -> Result<Self, String> {
+        let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let parameter = |name: &str| {
+            query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+                .map(String::from)
+        };
+        let variable = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        if bucket.is_empty() {
+            return Err(format!("Cache URL 's3://{rest}' has no bucket"));
+        }
+        let region = parameter("region")
+            .or_else(|| variable("AWS_REGION"))
+            .or_else(|| variable("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|| "us-east-1".to_string());
+        let base = match parameter("endpoint").or_else(|| variable("AWS_ENDPOINT_URL")) {
+            Some(endpoint) => format!("{}/{bucket}", endpoint.trim_end_matches('/')),
+            None => format!("https://{bucket}.s3.{region}.amazonaws.com"),
+        };
+        let prefix = prefix.trim_matches('/');
+        let url = match prefix {
+            "" => base,
+            prefix => format!("{base}/{prefix}"),
+        };
+        // Without credentials, only public buckets can be used
+        let signing = match (
+            variable("AWS_ACCESS_KEY_ID"),
+            variable("AWS_SECRET_ACCESS_KEY"),
+        ) {
+            (Some(id), Some(secret)) => {
+                let quote = |text: &str| text.replace('\\', "\\\\").replace('"', "\\\"");
+                let mut signing = format!(
+                    "user = \"{}:{}\"\naws-sigv4 = \"aws:amz:{}:s3\"\n",
+                    quote(&id),
+                    quote(&secret),
+                    quote(&region)
+                );
+                if let Some(token) = variable("AWS_SESSION_TOKEN") {
+                    signing.push_str(&format!(
+                        "header = \"x-amz-security-token: {}\"\n",
+                        quote(&token)
+                    ));
+                }
+                Some(signing)
+            }
+            _ => None,
+        };
+        Ok(S3Backend { url, signing })
+    }
+}
+
+impl CacheBackend for S3Backend {
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        get(&format!("{}/{key}", self.url), self.signing.as_deref())
+    }
+
+    fn put(&self, key: &str, contents: &[u8]) -> io::Result<()> {
+        put(
+            &format!("{}/{key}", self.url),
+            contents,
+            self.signing.as_deref(),
+        )
+    }
+}
+
+/// Gets the object at the URL, or `None` if there is none.
+fn get(url: &str, config: Option<&str>) -> io::Result<Option<Vec<u8>>> {
+    match curl(url, None, config)? {
+        (200, body) => Ok(Some(body)),
+        (404, _) => Ok(None),
+        (status, _) => Err(io::Error::other(format!(
+            "remote cache answered GET with status {status}"
+        ))),
+    }
+}
+
+/// Stores the contents as the object at the URL.
+fn put(url: &str, contents: &[u8], config: Option<&str>) -> io::Result<()> {
+    // The body goes through a file, as stdin may carry the config
+    static UPLOADS: AtomicUsize = AtomicUsize::new(0);
+    let upload = std::env::temp_dir().join(format!(
+        "mk-cache-{}-{}",
+        std::process::id(),
+        UPLOADS.fetch_add(1, Ordering::SeqCst)
+    ));
+    std::fs::write(&upload, contents)?;
+    let response = curl(url, Some(&upload), config);
+    let _ = std::fs::remove_file(&upload);
+    match response? {
+        (200..=299, _) => Ok(()),
+        (status, _) => Err(io::Error::other(format!(
+            "remote cache answered PUT with status {status}"
+        ))),
+    }
+}
+
+/// Makes a request with curl, a PUT of the file if `upload` is given and a
+/// GET otherwise, with extra curl options from `config`. Returns the status
+/// code and body of the response.
+fn curl(url: &str, upload: Option<&Path>, config: Option<&str>) -> io::Result<(u16, Vec<u8>)> {
+    let mut command = Command::new("curl");
+    command
+        .args(["--silent", "--show-error", "--location"])
+        .args(["--connect-timeout", &REMOTE_TIMEOUT.as_secs().to_string()])
+        // Give up on transfers that stall, however big the output
+        .args(["--speed-limit", "1"])
+        .args(["--speed-time", &REMOTE_TIMEOUT.as_secs().to_string()])
+        .args(["--write-out", "\n%{http_code}"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if let Some(upload) = upload {
+        command.arg("--upload-file").arg(upload);
+    }
+    if config.is_some() {
+        command.args(["--config", "-"]).stdin(Stdio::piped());
+    } else {
+        command.stdin(Stdio::null());
+    }
+    command.arg(url);
+    let mut child = command
+        .spawn()
+        .map_err(|err| io::Error::new(err.kind(), format!("can't run curl: {err}")))?;
+    if let Some(config) = config {
+        child.stdin.take().unwrap().write_all(config.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(stderr.trim().to_string()));
+    }
+    let mut body = output.stdout;
+    let split = body
+        .iter()
+        .rposition(|byte| *byte == b'\n')
+        .ok_or_else(|| io::Error::other("invalid response from remote cache"))?;
+    let status = std::str::from_utf8(&body[split + 1..])
+        .ok()
+        .and_then(|status| status.trim().parse().ok())
+        .ok_or_else(|| io::Error::other("invalid response from remote cache"))?;
+    body.truncate(split);
+    Ok((status, body))
+}
@@ -0,0 +1,355 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+
+use crate::{
+    executor::{CommandExecutor, Invocation, Redirect, Status},
+    mkfile::{MkFile, Target},
+    report::{Event, Reporter},
+};
+
+/// The CI systems whose log syntax mk can use, see [`CiReporter`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ci {
+    Github,
+    Gitlab,
+}
+
+impl FromStr for Ci {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "github" => Ok(Ci::Github),
+            "gitlab" => Ok(Ci::Gitlab),
+            _ => Err(format!("Unknown CI '{text}', expected github or gitlab")),
+        }
+    }
+}
+
+impl fmt::Display for Ci {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Ci::Github => "github",
+            Ci::Gitlab => "gitlab",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// The output of a target whose commands ran, held until it is done.
+struct Section {
+    started: Instant,
+    lines: Vec<u8>,
+}
+
+/// Shows a build in a CI system's log. The output of each target's
+/// commands is held until the target is done and then printed in a section
+/// of its own, which the CI system shows collapsed, so that targets made at
+/// the same time don't mix. Failures are printed as annotations that point
+/// at the rule of the target, which GitHub also shows on the mkfile in the
+/// pull request. As an executor, it runs commands with an inner executor,
+/// so that their output goes to their target's section.
+pub struct CiReporter {
+    ci: Ci,
+    /// Where each rule is written.
+    locations: HashMap<Target, (PathBuf, usize)>,
+    sections: Arc<Mutex<HashMap<String, Section>>>,
+    /// How many sections were printed, which names them for GitLab.
+    printed: AtomicUsize,
+    /// How many failures were annotated.
+    annotated: AtomicUsize,
+    explain: bool,
+    inner: Arc<dyn CommandExecutor>,
+}
+
+impl CiReporter {
+    /// Creates a reporter for the CI system, with failures annotated at the
+    /// rules of the mkfile at the given path, which runs commands with the
+    /// inner executor.
+    pub fn new(
+        ci: Ci,
+        mkfile: &MkFile,
+        path: &Path,
+        explain: bool,
+        inner: Arc<dyn CommandExecutor>,
+    ) -> Self {
+        let locations = mkfile
+            .targets()
+            .filter_map(|target| {
+                let (file, line) = mkfile.location(target)?;
+                Some((target.clone(), (file.unwrap_or(path).to_path_buf(), line)))
+            })
+            .collect();
+        CiReporter {
+            ci,
+            locations,
+            sections: Arc::default(),
+            printed: AtomicUsize::new(0),
+            annotated: AtomicUsize::new(0),
+            explain,
+            inner,
+        }
+    }
+
+    /// Adds a line to the target's section, starting it if need be.
+    fn note(&self, target: &Target, line: &str) {
+        let mut sections = self.sections.lock().unwrap();
+        let section = sections
+            .entry(target.to_string())
+            .or_insert_with(|| Section {
+                started: Instant::now(),
+                lines: Vec::new(),
+            });
+        section.lines.extend_from_slice(line.as_bytes());
+        section.lines.push(b'\n');
+    }
+
+    /// Takes out the target's section, if its commands ran, written as the
+    /// CI system shows it.
+    fn take_section(&self, target: &str, title: &str) -> Option<Vec<u8>> {
+        let section = self.sections.lock().unwrap().remove(target)?;
+        let title = format!("{title} ({:.1}s)", section.started.elapsed().as_secs_f64());
+        let mut text = Vec::new();
+        match self.ci {
+            Ci::Github => {
+                let _ = writeln!(text, "::group::{title}");
+                text.extend_from_slice(&section.lines);
+                let _ = writeln!(text, "::endgroup::");
+            }
+            Ci::Gitlab => {
+                let name = format!("mk_{}", self.printed.fetch_add(1, Ordering::SeqCst));
+                let now = || {
+                    SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs()
+                };
+                let _ = writeln!(
+                    text,
+                    "\x1b[0Ksection_start:{}:{name}[collapsed=true]\r\x1b[0K{title}",
+                    now()
+                );
+                text.extend_from_slice(&section.lines);
+                let _ = writeln!(text, "\x1b[0Ksection_end:{}:{name}\r\x1b[0K", now());
+            }
+        }
+        Some(text)
+    }
+
+    /// Prints the target's section, if its commands ran.
+    fn print_section(&self, target: &str, title: &str) {
+        if let Some(text) = self.take_section(target, title) {
+            let _ = io::stdout().lock().write_all(&text);
+        }
+    }
+
+    /// Returns the failure as an annotation at the target's rule.
+    fn annotation(&self, target: &Target, err: &str) -> String {
+        let message = format!("Failed to make '{target}': {err}");
+        let location = self.locations.get(target);
+        match (self.ci, location) {
+            (Ci::Github, Some((file, line))) => format!(
+                "::error file={},line={line},title=mk::{}",
+                escape_property(&file.to_string_lossy()),
+                escape_data(&message)
+            ),
+            (Ci::Github, None) => format!("::error title=mk::{}", escape_data(&message)),
+            (Ci::Gitlab, Some((file, line))) => {
+                format!(
+                    "\x1b[31;1mERROR: {}:{line}: {message}\x1b[0m",
+                    file.display()
+                )
+            }
+            (Ci::Gitlab, None) => format!("\x1b[31;1mERROR: {message}\x1b[0m"),
+        }
+    }
+}
+
+/// Escapes the message of a GitHub workflow command.
+fn escape_data(text: &str) -> String {
+    text.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Escapes a property, such as the file, of a GitHub workflow command.
+fn escape_property(text: &str) -> String {
+    escape_data(text).replace(':', "%3A").replace(',', "%2C")
+}
+
+impl Reporter for CiReporter {
+    fn report(&self, event: &Event) {
+        match event {
+            Event::TargetStarted(_) | Event::CommandFinished(..) => {}
+            Event::Skipped(target, reason) => info!("Skipping target '{}': {}", target, reason),
+            Event::Outdated(target, reason) => {
+                if self.explain {
+                    self.note(target, &format!("Out of date: {reason}"));
+                }
+            }
+            Event::Unchanged(target) => {
+                if self.explain {
+                    self.note(target, "Same contents as before");
+                }
+            }
+            Event::Restored(target) => info!("Restored target '{}' from the cache", target),
+            Event::CommandStarted(target, command) => self.note(target, &format!("$ {command}")),
+            Event::Retrying(target, attempt, err) => {
+                self.note(
+                    target,
+                    &format!("Failed, starting attempt {attempt}: {err}"),
+                );
+            }
+            Event::TargetFinished(target, made) => {
+                // Only targets whose commands ran have a section
+                let title = if *made {
+                    format!("Made '{target}'")
+                } else {
+                    format!("Made '{target}', which didn't change")
+                };
+                self.print_section(&target.to_string(), &title);
+            }
+            Event::TargetFailed(target, err) => {
+                // Targets fail along with their dependencies, which were
+                // annotated already, unless their own commands failed
+                let ran = self
+                    .sections
+                    .lock()
+                    .unwrap()
+                    .contains_key(&target.to_string());
+                self.print_section(&target.to_string(), &format!("Failed to make '{target}'"));
+                if ran || self.annotated.load(Ordering::SeqCst) == 0 {
+                    self.annotated.fetch_add(1, Ordering::SeqCst);
+                    println!("{}", self.annotation(target, err));
+                }
+            }
+        }
+    }
+
+    fn finish(&self) {
+        // Whatever is left was cut short, such as by an interrupt
+        let left: Vec<String> = self.sections.lock().unwrap().keys().cloned().collect();
+        for target in left {
+            warn!("Target '{}' didn't finish", target);
+            self.print_section(&target, &format!("'{target}' didn't finish"));
+        }
+    }
+}
+
+/// Sends command output to the section of its target.
+#[derive(Clone)]
+struct SectionWriter {
+    sections: Arc<Mutex<HashMap<String, Section>>>,
+    target: String,
+}
+
+impl Write for SectionWriter {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        let mut sections = self.sections.lock().unwrap();
+        let section = sections
+            .entry(self.target.clone())
+            .or_insert_with(|| Section {
+                started: Instant::now(),
+                lines: Vec::new(),
+            });
+        section.lines.extend_from_slice(buffer);
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl CiReporter {
+    /// Sends the output of a command run for a target to its section, whose
+    /// name needn't be in front of every line. Other commands are left as
+    /// they are.
+    fn redirect(&self, invocation: &Invocation) -> Invocation {
+        let Some(target) = &invocation.target else {
+            return invocation.clone();
+        };
+        let writer = SectionWriter {
+            sections: self.sections.clone(),
+            target: target.clone(),
+        };
+        Invocation {
+            prefix: None,
+            redirect: Some(Redirect::to(writer.clone(), writer)),
+            ..invocation.clone()
+        }
+    }
+}
+
+impl CommandExecutor for CiReporter {
+    fn run(&self, invocation: &Invocation) -> io::Result<Status> {
+        self.inner.run(&self.redirect(invocation))
+    }
+
+    fn capture(&self, invocation: &Invocation) -> io::Result<(Status, Vec<u8>)> {
+        self.inner.capture(&self.redirect(invocation))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::executor::{MockExecutor, ShellExecutor};
+
+    #[test]
+    fn test_sections_hold_the_output_of_their_target() {
+        let mkfile = MkFile::parse("$all: $a\n\n$a:\n    echo out\n").unwrap();
+        let inner = Arc::new(ShellExecutor);
+        let reporter = CiReporter::new(Ci::Github, &mkfile, Path::new("mkfile"), false, inner);
+        let target = Target::parse("$a");
+        reporter.report(&Event::CommandStarted(&target, "echo out"));
+        let invocation = Invocation {
+            command: "echo out".to_string(),
+            target: Some("$a".to_string()),
+            ..Invocation::default()
+        };
+        assert!(reporter.run(&invocation).unwrap().success());
+
+        let section = reporter.take_section("$a", "Made '$a'").unwrap();
+        let section = String::from_utf8(section).unwrap();
+        assert!(section.starts_with("::group::Made '$a' ("));
+        assert!(section.ends_with("s)\n$ echo out\nout\n::endgroup::\n"));
+        assert!(reporter.take_section("$a", "Made '$a'").is_none());
+        assert!(reporter.take_section("$all", "Made '$all'").is_none());
+    }
+
+    #[test]
+    fn test_annotations_point_at_the_rule() {
+        let mkfile = MkFile::parse("$all: $a\n\n$a:\n    false\n").unwrap();
+        let path = Path::new("ci/mk,file");
+        let reporter =
+            |ci| CiReporter::new(ci, &mkfile, path, false, Arc::new(MockExecutor::default()));
+        let (github, gitlab) = (reporter(Ci::Github), reporter(Ci::Gitlab));
+        let target = Target::parse("$a");
+
+        assert_eq!(
+            github.annotation(&target, "exit code 1\n100%"),
+            "::error file=ci/mk%2Cfile,line=3,title=mk::Failed to make '$a': exit code 1%0A100%25"
+        );
+        assert_eq!(
+            github.annotation(&Target::parse("$b"), "missing"),
+            "::error title=mk::Failed to make '$b': missing"
+        );
+        assert_eq!(
+            gitlab.annotation(&target, "exit code 1"),
+            "\x1b[31;1mERROR: ci/mk,file:3: Failed to make '$a': exit code 1\x1b[0m"
+        );
+    }
+}
@@ -0,0 +1,54 @@
+use std::{collections::HashSet, io};
+
+use crate::{
+    making::UpdateState,
+    mkfile::{ConcreteTarget, MkFile, Target},
+    provenance,
+    vfs::Vfs,
+};
+
+/// Deletes the outputs that rules produced, as recorded in the state, and
+/// forgets them. With an mkfile and a target, only deletes the outputs of
+/// the target's rule and of the rules it depends on. Outputs that are gone
+/// already are only forgotten. What `--provenance` wrote next to outputs
+/// goes with them. Returns the deleted outputs, sorted, or with `dry_run`
+/// the outputs that would be deleted, without touching anything.
+pub fn clean(
+    state: &mut UpdateState,
+    vfs: &dyn Vfs,
+    only: Option<(&MkFile, &Target)>,
+    dry_run: bool,
+) -> io::Result<Vec<ConcreteTarget>> {
+    let rules: Option<HashSet<String>> = only.map(|(mkfile, target)| {
+        mkfile
+            .reachable(target)
+            .into_iter()
+            .map(Target::to_string)
+            .collect()
+    });
+    let mut outputs: Vec<ConcreteTarget> = state
+        .produced()
+        .filter(|(_, rule)| rules.as_ref().is_none_or(|rules| rules.contains(*rule)))
+        .map(|(path, _)| path.clone())
+        .collect();
+    outputs.sort_by(|a, b| a.pathbuf().cmp(b.pathbuf()));
+
+    let mut deleted = Vec::new();
+    for output in outputs {
+        let exists = vfs.exists(output.pathbuf());
+        if !dry_run {
+            if exists {
+                vfs.remove_all(output.pathbuf())?;
+            }
+            let sidecar = provenance::sidecar_path(output.pathbuf());
+            if vfs.exists(&sidecar) {
+                vfs.remove_all(&sidecar)?;
+            }
+            state.forget_output(&output);
+        }
+        if exists {
+            deleted.push(output);
+        }
+    }
+    Ok(deleted)
+}
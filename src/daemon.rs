@@ -0,0 +1,1217 @@
+use std::{
+    collections::BTreeMap,
+    ops::Bound,
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::MkError,
+    freshness::FreshnessChecker,
+    making::{BuildSettings, Jobs, RebuildReason, UpdateState},
+    mkfile::ConcreteTarget,
+    vfs::Vfs,
+};
+
+/// What the daemon is asked, one request per connection, as a line of JSON.
+#[derive(Debug, Serialize, Deserialize)]
+enum Request {
+    /// Every change seen so far.
+    Changes,
+    /// Make a target, answered with a [`Reply`] line for every log line and
+    /// line of command output.
+    Build(Build),
+}
+
+/// A build that `mk` asks the daemon for, with what can change from one
+/// `mk` to the next. Everything else is as the daemon was started with.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Build {
+    /// The folder `mk` runs in, which must be the one the daemon watches.
+    pub dir: PathBuf,
+    /// The mkfile, which must be the one the daemon reads.
+    pub mkfile: PathBuf,
+    /// The target to make, the mkfile's default if not given.
+    pub target: Option<String>,
+    /// Variables that override the mkfile's.
+    pub variables: BTreeMap<String, String>,
+    pub jobs: Option<Jobs>,
+    pub prefix_output: bool,
+    pub explain: bool,
+    pub dry_run: bool,
+    pub wait_for_lock: bool,
+    /// The environment of `mk`, which commands are run with.
+    pub env: BTreeMap<String, String>,
+}
+
+/// How the daemon answers a build, a line of JSON at a time.
+#[derive(Debug, Serialize, Deserialize)]
+enum Reply {
+    Stdout(String),
+    Stderr(String),
+    Log {
+        level: String,
+        target: String,
+        message: String,
+    },
+    /// The daemon doesn't make builds like this one, so `mk` makes it
+    /// itself.
+    Refused(String),
+    /// The build is over, and `mk` exits with this code.
+    Done(i32),
+}
+
+/// What the daemon has seen so far, as it answers [`Request::Changes`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Changes {
+    /// Changes when the daemon starts, and when it misses changes because
+    /// too many happened at once.
+    daemon: String,
+    /// The folder the daemon watches, which the paths are in.
+    root: PathBuf,
+    /// How many changes the daemon has seen, which numbers them.
+    seen: u64,
+    /// The number of the latest change of every file or folder that
+    /// changed, by path in the watched folder. Paths that can't be watched,
+    /// such as links to other places, change all the time.
+    changed: BTreeMap<PathBuf, u64>,
+}
+
+impl Changes {
+    /// Returns true if the path, something in it or a folder it is in may
+    /// have changed after the daemon had seen `seen` changes.
+    pub fn touched(&self, path: &Path, seen: u64) -> bool {
+        let path = match path.strip_prefix(&self.root) {
+            Ok(path) => path,
+            Err(_) if path.is_absolute() => return true,
+            Err(_) => path,
+        };
+        if path.components().any(|c| c == Component::ParentDir) {
+            return true;
+        }
+        let path: PathBuf = path
+            .components()
+            .filter(|component| *component != Component::CurDir)
+            .collect();
+        let after = |number: &u64| *number > seen;
+        path.ancestors()
+            .any(|ancestor| self.changed.get(ancestor).is_some_and(after))
+            || self
+                .changed
+                .range::<Path, _>((Bound::Excluded(path.as_path()), Bound::Unbounded))
+                .take_while(|(changed, _)| changed.starts_with(&path))
+                .any(|(_, number)| after(number))
+    }
+}
+
+/// Checks files with another checker only if the daemon saw them change
+/// since they were last checked, so that unchanged folders aren't walked.
+/// Files checked during the build are remembered with
+/// [`remember`](Self::remember).
+pub struct DaemonChecker {
+    changes: Changes,
+    inner: &'static dyn FreshnessChecker,
+    checked: Mutex<Vec<ConcreteTarget>>,
+}
+
+impl DaemonChecker {
+    pub fn new(changes: Changes, inner: &'static dyn FreshnessChecker) -> Self {
+        DaemonChecker {
+            changes,
+            inner,
+            checked: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Records in the state that the files checked during the build were
+    /// up to date with what the daemon had seen when it started.
+    pub fn remember(&self, state: &mut UpdateState) {
+        for path in self.checked.lock().unwrap().drain(..) {
+            if state.is_recorded(&path) {
+                state.set_watched(&path, &self.changes.daemon, self.changes.seen);
+            }
+        }
+    }
+
+    fn unchanged(&self, state: &UpdateState, path: &ConcreteTarget) -> bool {
+        let Some((daemon, seen)) = state.watched(path) else {
+            return false;
+        };
+        daemon == self.changes.daemon
+            && state.is_recorded(path)
+            && !self.changes.touched(path.pathbuf(), seen)
+    }
+}
+
+impl FreshnessChecker for DaemonChecker {
+    fn check(
+        &self,
+        state: &UpdateState,
+        vfs: &dyn Vfs,
+        path: &ConcreteTarget,
+    ) -> Result<Result<(), RebuildReason>, MkError> {
+        if self.unchanged(state, path) {
+            return Ok(Ok(()));
+        }
+        let checked = self.inner.check(state, vfs, path)?;
+        self.checked.lock().unwrap().push(path.clone());
+        Ok(checked)
+    }
+
+    fn record(
+        &self,
+        state: &mut UpdateState,
+        vfs: &dyn Vfs,
+        path: &ConcreteTarget,
+    ) -> Result<bool, MkError> {
+        self.checked.lock().unwrap().push(path.clone());
+        self.inner.record(state, vfs, path)
+    }
+}
+
+/// The socket of the daemon that keeps track of changes for the state
+/// file, next to it.
+pub fn socket_path(state: &Path) -> PathBuf {
+    let mut path = state.as_os_str().to_owned();
+    path.push(".sock");
+    PathBuf::from(path)
+}
+
+#[cfg(unix)]
+pub use self::unix::{build, changes};
+#[cfg(any(target_os = "linux", all(unix, feature = "watch")))]
+pub use self::watch::run;
+
+/// Asks the daemon for the changes it has seen. Without one, everything is
+/// checked as usual.
+#[cfg(not(unix))]
+pub fn changes(_state: &Path) -> Option<Changes> {
+    None
+}
+
+/// Asks the daemon to make the build. Without one, `mk` makes it itself.
+#[cfg(not(unix))]
+pub fn build(_state: &Path, _build: Build) -> Option<i32> {
+    None
+}
+
+/// Watches the current folder for changes until killed, answering on the
+/// socket for the state file and making the builds it is asked for.
+#[cfg(not(any(target_os = "linux", all(unix, feature = "watch"))))]
+pub fn run(_state: &Path, _settings: BuildSettings) -> Result<(), MkError> {
+    Err(MkError::Io(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "mk daemon needs Linux, or mk built with the watch feature on other Unixes",
+    )))
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        os::unix::net::UnixStream,
+        path::Path,
+        str::FromStr,
+        time::Duration,
+    };
+
+    use log::{debug, error, warn, Level, Record};
+
+    use super::{socket_path, Build, Changes, Reply, Request};
+
+    /// How long to wait for the daemon to answer.
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Asks the daemon for the changes it has seen, if one is running for
+    /// the state file in the current folder.
+    pub fn changes(state: &Path) -> Option<Changes> {
+        let socket = socket_path(state);
+        if !socket.exists() {
+            return None;
+        }
+        let asked = UnixStream::connect(&socket).and_then(|mut stream| {
+            stream.set_read_timeout(Some(TIMEOUT))?;
+            writeln!(stream, "{}", serde_json::to_string(&Request::Changes)?)?;
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line)?;
+            Ok(serde_json::from_str::<Changes>(&line)?)
+        });
+        let changes = match asked {
+            Ok(changes) => changes,
+            Err(err) => {
+                debug!("No daemon at '{}': {}", socket.display(), err);
+                return None;
+            }
+        };
+        match std::env::current_dir().and_then(std::fs::canonicalize) {
+            Ok(dir) if dir == changes.root => Some(changes),
+            _ => {
+                warn!(
+                    "The daemon at '{}' watches '{}', not the current folder",
+                    socket.display(),
+                    changes.root.display()
+                );
+                None
+            }
+        }
+    }
+
+    /// Asks the daemon to make the build, showing its logs and command
+    /// output as they arrive. Returns the code to exit with, or `None` if no
+    /// daemon is running for the state file or it refused, so that `mk`
+    /// makes the build itself.
+    pub fn build(state: &Path, build: Build) -> Option<i32> {
+        let socket = socket_path(state);
+        if !socket.exists() {
+            return None;
+        }
+        let stream = UnixStream::connect(&socket).and_then(|mut stream| {
+            writeln!(stream, "{}", serde_json::to_string(&Request::Build(build))?)?;
+            Ok(stream)
+        });
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                debug!("No daemon at '{}': {}", socket.display(), err);
+                return None;
+            }
+        };
+        for line in BufReader::new(stream).lines() {
+            let reply = line.and_then(|line| Ok(serde_json::from_str::<Reply>(&line)?));
+            match reply {
+                Ok(Reply::Stdout(text)) => print!("{text}"),
+                Ok(Reply::Stderr(text)) => eprint!("{text}"),
+                Ok(Reply::Log {
+                    level,
+                    target,
+                    message,
+                }) => log::logger().log(
+                    &Record::builder()
+                        .level(Level::from_str(&level).unwrap_or(Level::Info))
+                        .target(&target)
+                        .args(format_args!("{message}"))
+                        .build(),
+                ),
+                Ok(Reply::Refused(reason)) => {
+                    debug!("The daemon doesn't make this build: {reason}");
+                    return None;
+                }
+                Ok(Reply::Done(code)) => return Some(code),
+                Err(err) => {
+                    error!("Lost the daemon while it made the build: {err}");
+                    return Some(1);
+                }
+            }
+        }
+        error!("The daemon stopped before the build was over");
+        Some(1)
+    }
+}
+
+#[cfg(any(target_os = "linux", all(unix, feature = "watch")))]
+mod watch {
+    use std::{
+        collections::BTreeMap,
+        io::{self, BufRead, BufReader, Write},
+        os::unix::net::{UnixListener, UnixStream},
+        path::{Path, PathBuf},
+        sync::{Arc, Condvar, Mutex},
+        thread,
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    };
+
+    use log::{error, info, warn};
+
+    use super::{socket_path, Build, BuildSettings, Changes, DaemonChecker, Reply, Request};
+    use crate::{
+        error::MkError,
+        executor::ForwardingExecutor,
+        hooks,
+        making::{make, MakeOptions, UpdateState},
+        mkfile::{self, MkFile, Target},
+        report::LogReporter,
+        store, warnings,
+    };
+
+    #[cfg(target_os = "linux")]
+    use self::inotify as backend;
+    #[cfg(not(target_os = "linux"))]
+    use self::notifier as backend;
+
+    /// Start of the names of the files created to make sure every change
+    /// made before a request was seen.
+    const SYNC_PREFIX: &str = ".mk-daemon-sync-";
+    /// How long to wait for those files to be seen.
+    const SYNC_TIMEOUT: Duration = Duration::from_secs(1);
+
+    struct Watched {
+        changes: Changes,
+        /// The number of the latest change that added or removed each path,
+        /// which changes what folders list.
+        listed: BTreeMap<PathBuf, u64>,
+        /// The number of the last sync file seen.
+        synced: u64,
+    }
+
+    /// Keeps track of the changes in the watched folder, whichever way
+    /// they are watched.
+    #[derive(Clone)]
+    struct Recorder {
+        root: PathBuf,
+        shared: Arc<(Mutex<Watched>, Condvar)>,
+        /// The mkfile the daemon started with. The folders its `.ignore:`
+        /// patterns name aren't watched.
+        mkfile: Arc<MkFile>,
+    }
+
+    fn new_id() -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        format!("{}-{}", std::process::id(), now.as_nanos())
+    }
+
+    /// Watches the current folder for changes until killed, answering on
+    /// the socket for the state file and making the builds it is asked for.
+    pub fn run(state: &Path, settings: BuildSettings) -> Result<(), MkError> {
+        let root = std::fs::canonicalize(".")?;
+        let socket = socket_path(state);
+        if UnixStream::connect(&socket).is_ok() {
+            return Err(MkError::Io(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("A daemon is answering on '{}' already", socket.display()),
+            )));
+        }
+        // Left over from a daemon that was killed
+        let _ = std::fs::remove_file(&socket);
+
+        let shared = Arc::new((
+            Mutex::new(Watched {
+                changes: Changes {
+                    daemon: new_id(),
+                    root: root.clone(),
+                    seen: 0,
+                    changed: Default::default(),
+                },
+                listed: BTreeMap::new(),
+                synced: 0,
+            }),
+            Condvar::new(),
+        ));
+        let mkfile = std::fs::read_to_string(&settings.mkfile)
+            .map_err(MkError::from)
+            .and_then(|text| {
+                let options = settings.parse_options(BTreeMap::new());
+                MkFile::parse_as(&text, mkfile::Format::of(&settings.mkfile), &options)
+            })
+            .unwrap_or_else(|err| {
+                warn!("Watching every folder, as the mkfile can't be parsed: {err}");
+                MkFile::parse("").unwrap()
+            });
+        let recorder = Recorder {
+            root: root.clone(),
+            shared: shared.clone(),
+            mkfile: Arc::new(mkfile),
+        };
+        let _watching = backend::watch(&root, recorder)?;
+        info!(
+            "Watching '{}', answering on '{}'",
+            root.display(),
+            socket.display()
+        );
+        let listener = UnixListener::bind(&socket)?;
+
+        let session = Arc::new(Mutex::new(Session {
+            mkfile: std::fs::canonicalize(&settings.mkfile).unwrap_or(settings.mkfile.clone()),
+            settings,
+            state: state.to_path_buf(),
+            loaded: None,
+            parsed: None,
+        }));
+        for (number, stream) in (1..).zip(listener.incoming()) {
+            let (root, shared, session) = (root.clone(), shared.clone(), session.clone());
+            let answered = stream.map(|stream| {
+                thread::spawn(move || {
+                    if let Err(err) = answer(stream, &root, &shared, &session, number) {
+                        warn!("Failed to answer a request: {err}");
+                    }
+                })
+            });
+            if let Err(err) = answered {
+                warn!("Failed to answer a request: {err}");
+            }
+        }
+        Ok(())
+    }
+
+    fn answer(
+        mut stream: UnixStream,
+        root: &Path,
+        shared: &(Mutex<Watched>, Condvar),
+        session: &Mutex<Session>,
+        number: u64,
+    ) -> io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line)?;
+        match serde_json::from_str(&line)? {
+            Request::Changes => {
+                let (changes, _) = sync(root, shared, number)?;
+                writeln!(stream, "{}", serde_json::to_string(&changes)?)
+            }
+            Request::Build(build) => {
+                // Builds are made one at a time, in the order they are asked
+                // for
+                let mut session = session.lock().unwrap();
+                let (changes, listed) = sync(root, shared, number)?;
+                let client = Client(Arc::new(Mutex::new(stream)));
+                let code = session.build(build, changes, &listed, &client);
+                client.send(&Reply::Done(code));
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the changes, and the paths that were added or removed, once
+    /// everything that changed before now was seen. Changes reach the
+    /// watcher a little after they are made, so a file is created and the
+    /// changes are returned once it is seen.
+    fn sync(
+        root: &Path,
+        shared: &(Mutex<Watched>, Condvar),
+        number: u64,
+    ) -> io::Result<(Changes, BTreeMap<PathBuf, u64>)> {
+        let (watched, seen) = shared;
+        let sync_file = root.join(format!("{SYNC_PREFIX}{number}"));
+        std::fs::write(&sync_file, b"")?;
+        let _ = std::fs::remove_file(&sync_file);
+        let (watched, timeout) = seen
+            .wait_timeout_while(watched.lock().unwrap(), SYNC_TIMEOUT, |watched| {
+                watched.synced < number
+            })
+            .unwrap();
+        if timeout.timed_out() {
+            warn!("Changes are slow to arrive, the latest ones may be missing");
+        }
+        Ok((watched.changes.clone(), watched.listed.clone()))
+    }
+
+    /// The `mk` that asked for a build, which its output and logs are sent
+    /// to.
+    #[derive(Clone)]
+    struct Client(Arc<Mutex<UnixStream>>);
+
+    impl Client {
+        /// Sends the reply, if the client is still there to read it.
+        fn send(&self, reply: &Reply) {
+            if let Ok(line) = serde_json::to_string(reply) {
+                let _ = writeln!(self.0.lock().unwrap(), "{line}");
+            }
+        }
+    }
+
+    /// Sends what commands write to stdout or stderr to the client.
+    #[derive(Clone)]
+    struct Output {
+        client: Client,
+        stderr: bool,
+    }
+
+    impl Write for Output {
+        fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+            let text = String::from_utf8_lossy(buffer).into_owned();
+            self.client.send(&if self.stderr {
+                Reply::Stderr(text)
+            } else {
+                Reply::Stdout(text)
+            });
+            Ok(buffer.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// What the daemon keeps between builds.
+    struct Session {
+        settings: BuildSettings,
+        /// The mkfile, as the path it really is at.
+        mkfile: PathBuf,
+        /// The state file.
+        state: PathBuf,
+        /// The state as it was last loaded or saved, with the size and
+        /// modification time of the file then, to tell when another `mk`
+        /// saved it.
+        loaded: Option<(UpdateState, Option<(u64, SystemTime)>)>,
+        parsed: Option<Parsed>,
+    }
+
+    /// The mkfile as it was last parsed, before scoped variables were
+    /// applied.
+    struct Parsed {
+        mkfile: MkFile,
+        variables: BTreeMap<String, String>,
+        /// The daemon and the number of changes it had seen then.
+        daemon: String,
+        seen: u64,
+        /// The files that were read to parse it.
+        read: Vec<PathBuf>,
+        /// It runs shell commands, which could read anything.
+        runs_commands: bool,
+        /// It lists folders, with `$(wildcard)` or in scripts.
+        lists_folders: bool,
+    }
+
+    impl Parsed {
+        /// Returns true if the mkfile might read differently now than it did
+        /// when it was parsed: if the files it read changed, or paths were
+        /// added or removed that it may have looked for. Rules don't look
+        /// for the files that other rules make, so the state file and the
+        /// outputs of rules come and go without it being read again,
+        /// unless it lists folders.
+        fn stale(
+            &self,
+            variables: &BTreeMap<String, String>,
+            changes: &Changes,
+            listed: &BTreeMap<PathBuf, u64>,
+            state: &Path,
+        ) -> bool {
+            if self.runs_commands || self.daemon != changes.daemon || self.variables != *variables {
+                return true;
+            }
+            if self
+                .read
+                .iter()
+                .any(|path| changes.touched(path, self.seen))
+            {
+                return true;
+            }
+            let state = state.strip_prefix(&changes.root).unwrap_or(state);
+            listed
+                .iter()
+                .filter(|(_, number)| **number > self.seen)
+                .any(|(path, _)| {
+                    let of_state = path
+                        .as_os_str()
+                        .as_encoded_bytes()
+                        .starts_with(state.as_os_str().as_encoded_bytes());
+                    let made = self
+                        .mkfile
+                        .has_target(&Target::parse(&path.to_string_lossy()));
+                    self.lists_folders || !(of_state || made)
+                })
+        }
+    }
+
+    /// The size and modification time of the file, if it exists.
+    fn stamp(path: &Path) -> Option<(u64, SystemTime)> {
+        let metadata = std::fs::metadata(path).ok()?;
+        Some((metadata.len(), metadata.modified().ok()?))
+    }
+
+    /// Returns true if the text calls the function, as in `$(name ...)`.
+    fn calls(text: &str, name: &str) -> bool {
+        text.contains(&format!("$({name} ")) || text.contains(&format!("${{{name} "))
+    }
+
+    impl Session {
+        /// Makes the build, with its logs and command output sent to the
+        /// client, and returns the code `mk` should exit with.
+        fn build(
+            &mut self,
+            build: Build,
+            changes: Changes,
+            listed: &BTreeMap<PathBuf, u64>,
+            client: &Client,
+        ) -> i32 {
+            let refused = if build.dir != changes.root {
+                Some(format!("it watches '{}'", changes.root.display()))
+            } else if build.mkfile != self.mkfile {
+                Some(format!("it reads '{}'", self.mkfile.display()))
+            } else {
+                None
+            };
+            if let Some(reason) = refused {
+                client.send(&Reply::Refused(reason));
+                return 0;
+            }
+            let sink = client.clone();
+            warnings::redirect(Some(Box::new(move |record| {
+                sink.send(&Reply::Log {
+                    level: record.level().to_string(),
+                    target: record.target().to_string(),
+                    message: record.args().to_string(),
+                })
+            })));
+            let code = match self.make(build, changes, listed, client) {
+                Ok(None) => 0,
+                Ok(Some(reason)) => {
+                    client.send(&Reply::Refused(reason));
+                    0
+                }
+                Err(err) => err.exit_code(),
+            };
+            warnings::redirect(None);
+            code
+        }
+
+        /// Makes the build, or returns why the daemon doesn't. Errors are
+        /// logged as they happen.
+        fn make(
+            &mut self,
+            build: Build,
+            changes: Changes,
+            listed: &BTreeMap<PathBuf, u64>,
+            client: &Client,
+        ) -> Result<Option<String>, MkError> {
+            let _lock = if build.dry_run {
+                None
+            } else {
+                Some(
+                    store::StateLock::acquire(&self.state, build.wait_for_lock)
+                        .inspect_err(|err| error!("{}", err))?,
+                )
+            };
+            let store = store::open(&self.state)
+                .inspect_err(|err| error!("Failed to open state: {}", err))?;
+            let (mut state, _) = match self.loaded.take() {
+                Some((state, loaded)) if loaded == stamp(&self.state) => (state, loaded),
+                _ => (store.load(), None),
+            };
+
+            let variables = build.variables;
+            if self
+                .parsed
+                .as_ref()
+                .is_none_or(|parsed| parsed.stale(&variables, &changes, listed, &self.state))
+            {
+                self.parsed = Some(
+                    self.parse(variables, &changes)
+                        .inspect_err(|err| error!("Failed to parse mkfile: {}", err))?,
+                );
+            }
+            let parsed = self.parsed.as_ref().unwrap();
+            let made_includes = parsed.mkfile.includes().iter().any(|(include, _)| {
+                let target = parsed.mkfile.resolve(&include.to_string_lossy());
+                parsed.mkfile.has_target(&target) || parsed.mkfile.producer(&target).is_some()
+            });
+            if made_includes {
+                self.loaded = Some((state, stamp(&self.state)));
+                return Ok(Some("rules make its included mkfiles".to_string()));
+            }
+            let mut mkfile = parsed.mkfile.clone();
+            let target = mkfile.target_to_make(build.target.as_deref());
+            if mkfile.has_target(&target) && mkfile.options(&target).serve.is_some() {
+                self.loaded = Some((state, stamp(&self.state)));
+                return Ok(Some(format!("'{target}' is a service")));
+            }
+            mkfile
+                .scope(&target)
+                .inspect_err(|err| error!("Failed to parse mkfile: {}", err))?;
+            state.assign_ids(&mkfile);
+
+            let options = self
+                .settings
+                .make_options(&mkfile, build.jobs, build.env)
+                .inspect_err(|err| error!("{}", err))?;
+            let checker = Arc::new(DaemonChecker::new(changes, self.settings.freshness));
+            let options = MakeOptions {
+                prefix_output: build.prefix_output || options.prefix_output,
+                dry_run: build.dry_run,
+                reporter: Box::new(LogReporter {
+                    explain: build.explain || build.dry_run,
+                }),
+                executor: Box::new(ForwardingExecutor {
+                    stdout: Output {
+                        client: client.clone(),
+                        stderr: false,
+                    },
+                    stderr: Output {
+                        client: client.clone(),
+                        stderr: true,
+                    },
+                }),
+                freshness: Box::new(checker.clone()),
+                ..options
+            };
+            let started = Instant::now();
+            if !build.dry_run {
+                hooks::started(mkfile.hooks(), &target, &options);
+            }
+            let made = make(&mkfile, &target, &mut state, &options);
+            if !build.dry_run {
+                hooks::finished(mkfile.hooks(), &target, &made, started.elapsed(), &options);
+            }
+            checker.remember(&mut state);
+            if !build.dry_run {
+                if let Err(err) = store.save(&state) {
+                    error!("Failed to save state: {}", err);
+                }
+            }
+            self.loaded = Some((state, stamp(&self.state)));
+
+            match made {
+                Ok(made) => {
+                    if made && build.dry_run {
+                        info!("Target '{:?}' would be made", target);
+                    } else if made {
+                        info!("Made target '{:?}'", target);
+                    } else {
+                        info!("Target '{:?}' is up to date", target);
+                    }
+                    Ok(None)
+                }
+                Err(err) => {
+                    error!("Failed to make target '{:?}': {}", target, err);
+                    Err(err)
+                }
+            }
+        }
+
+        /// Reads and parses the mkfile.
+        fn parse(
+            &self,
+            variables: BTreeMap<String, String>,
+            changes: &Changes,
+        ) -> Result<Parsed, MkError> {
+            let path = &self.settings.mkfile;
+            let text = std::fs::read_to_string(path)?;
+            let options = self.settings.parse_options(variables.clone());
+            let mkfile = MkFile::parse_as(&text, mkfile::Format::of(path), &options)?;
+            let mut read = vec![path.clone(), PathBuf::from(".gitignore")];
+            read.extend(mkfile.includes().iter().map(|(include, _)| include.clone()));
+            read.extend(mkfile.env_file().map(Path::to_path_buf));
+            let texts: Vec<String> = std::iter::once(text)
+                .chain(
+                    mkfile
+                        .includes()
+                        .iter()
+                        .filter_map(|(include, _)| std::fs::read_to_string(include).ok()),
+                )
+                .collect();
+            Ok(Parsed {
+                runs_commands: texts.iter().any(|text| calls(text, "shell")),
+                lists_folders: texts
+                    .iter()
+                    .any(|text| calls(text, "wildcard") || text.contains("script")),
+                mkfile,
+                variables,
+                daemon: changes.daemon.clone(),
+                seen: changes.seen,
+                read,
+            })
+        }
+    }
+
+    impl Recorder {
+        /// Returns true if the folder isn't watched, as the mkfile ignores
+        /// it.
+        fn ignores(&self, relative: &Path) -> bool {
+            self.mkfile.ignores(relative)
+        }
+
+        /// Goes through the folder and every folder in it, calling `watch`
+        /// with each and going into it if that returns true. Folders that
+        /// are ignored and links, which can't be watched, always count as
+        /// changed.
+        fn walk(&self, path: &Path, relative: &Path, watch: &mut impl FnMut(&Path, &Path) -> bool) {
+            if !watch(path, relative) {
+                return;
+            }
+            let Ok(entries) = std::fs::read_dir(path) else {
+                return;
+            };
+            for entry in entries.flatten() {
+                let Ok(kind) = entry.file_type() else {
+                    continue;
+                };
+                let relative = relative.join(entry.file_name());
+                if kind.is_symlink() || (kind.is_dir() && self.ignores(&relative)) {
+                    self.always_changed(&relative);
+                } else if kind.is_dir() {
+                    self.walk(&entry.path(), &relative, watch);
+                }
+            }
+        }
+
+        fn always_changed(&self, relative: &Path) {
+            let mut watched = self.shared.0.lock().unwrap();
+            watched
+                .changes
+                .changed
+                .insert(relative.to_path_buf(), u64::MAX);
+        }
+
+        /// Forgets the changes seen, as some were missed, so nothing checked
+        /// before is known to be up to date anymore.
+        fn missed(&self) {
+            warn!("Too many changes at once, everything will be checked again");
+            let mut watched = self.shared.0.lock().unwrap();
+            watched.changes.daemon = new_id();
+            watched
+                .changes
+                .changed
+                .retain(|_, number| *number == u64::MAX);
+        }
+
+        /// Records a change of the path in the watched folder, which added
+        /// or removed it if `listing`.
+        fn changed(&self, relative: &Path, listing: bool) {
+            if let Some(number) = relative
+                .to_str()
+                .and_then(|name| name.strip_prefix(SYNC_PREFIX))
+                .and_then(|number| number.parse().ok())
+            {
+                let (watched, seen) = &*self.shared;
+                let mut watched = watched.lock().unwrap();
+                watched.synced = watched.synced.max(number);
+                seen.notify_all();
+                return;
+            }
+            // Watchers that can't leave folders out report changes in them
+            if let Some(folder) = relative
+                .ancestors()
+                .skip(1)
+                .find(|folder| self.ignores(folder))
+            {
+                self.always_changed(folder);
+                return;
+            }
+            let mut watched = self.shared.0.lock().unwrap();
+            watched.changes.seen += 1;
+            let number = watched.changes.seen;
+            if listing {
+                watched.listed.insert(relative.to_path_buf(), number);
+            }
+            let latest = watched
+                .changes
+                .changed
+                .entry(relative.to_path_buf())
+                .or_insert(0);
+            *latest = (*latest).max(number);
+        }
+    }
+
+    /// Watches with inotify, a folder at a time.
+    #[cfg(target_os = "linux")]
+    mod inotify {
+        use std::{
+            collections::HashMap,
+            ffi::{CString, OsStr, OsString},
+            io,
+            os::unix::ffi::OsStrExt,
+            path::{Path, PathBuf},
+            thread,
+        };
+
+        use log::{error, warn};
+
+        use super::Recorder;
+
+        /// The changes that are watched for in every folder.
+        const EVENTS: u32 = libc::IN_MODIFY
+            | libc::IN_ATTRIB
+            | libc::IN_CLOSE_WRITE
+            | libc::IN_CREATE
+            | libc::IN_DELETE
+            | libc::IN_DELETE_SELF
+            | libc::IN_MOVED_FROM
+            | libc::IN_MOVED_TO
+            | libc::IN_MOVE_SELF
+            | libc::IN_ONLYDIR
+            | libc::IN_DONT_FOLLOW;
+        /// The changes that add or remove something from a folder.
+        const LISTING: u32 = libc::IN_CREATE
+            | libc::IN_DELETE
+            | libc::IN_DELETE_SELF
+            | libc::IN_MOVED_FROM
+            | libc::IN_MOVED_TO
+            | libc::IN_MOVE_SELF;
+
+        struct Watcher {
+            fd: i32,
+            /// The folder of every watch, in the watched folder.
+            folders: HashMap<i32, PathBuf>,
+            recorder: Recorder,
+        }
+
+        /// Watches the folder and every folder in it that isn't ignored,
+        /// recording changes on the returned thread.
+        pub fn watch(root: &Path, recorder: Recorder) -> io::Result<thread::JoinHandle<()>> {
+            let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut watcher = Watcher {
+                fd,
+                folders: HashMap::new(),
+                recorder,
+            };
+            watcher.watch_tree(root, Path::new(""));
+            Ok(thread::spawn(move || watcher.read_events()))
+        }
+
+        impl Watcher {
+            /// Watches the folder and every folder in it.
+            fn watch_tree(&mut self, path: &Path, relative: &Path) {
+                let recorder = self.recorder.clone();
+                recorder.walk(path, relative, &mut |path, relative| {
+                    self.add(path, relative)
+                });
+            }
+
+            /// Watches the folder, returning false if it can't be watched and
+            /// so always counts as changed.
+            fn add(&mut self, path: &Path, relative: &Path) -> bool {
+                let Ok(name) = CString::new(path.as_os_str().as_bytes()) else {
+                    return false;
+                };
+                let wd = unsafe { libc::inotify_add_watch(self.fd, name.as_ptr(), EVENTS) };
+                if wd < 0 {
+                    let err = io::Error::last_os_error();
+                    warn!(
+                        "Can't watch '{}', so it is always checked: {err}",
+                        path.display()
+                    );
+                    self.recorder.always_changed(relative);
+                    return false;
+                }
+                self.folders.insert(wd, relative.to_path_buf());
+                true
+            }
+
+            fn read_events(mut self) {
+                let mut buffer = vec![0u8; 64 * 1024];
+                loop {
+                    let read =
+                        unsafe { libc::read(self.fd, buffer.as_mut_ptr().cast(), buffer.len()) };
+                    if read < 0 {
+                        let err = io::Error::last_os_error();
+                        if err.kind() == io::ErrorKind::Interrupted {
+                            continue;
+                        }
+                        error!("Stopped watching for changes: {err}");
+                        std::process::exit(1);
+                    }
+                    for (wd, mask, name) in parse_events(&buffer[..read as usize]) {
+                        self.event(wd, mask, Path::new(&name));
+                    }
+                }
+            }
+
+            fn event(&mut self, wd: i32, mask: u32, name: &Path) {
+                if mask & libc::IN_Q_OVERFLOW != 0 {
+                    self.recorder.missed();
+                    return;
+                }
+                let Some(folder) = self.folders.get(&wd).cloned() else {
+                    return;
+                };
+                if mask & libc::IN_IGNORED != 0 {
+                    self.folders.remove(&wd);
+                    return;
+                }
+                let relative = folder.join(name);
+                if mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 && mask & libc::IN_ISDIR != 0 {
+                    if self.recorder.ignores(&relative) {
+                        self.recorder.always_changed(&relative);
+                    } else {
+                        let path = self.recorder.root.join(&relative);
+                        self.watch_tree(&path, &relative);
+                    }
+                }
+                self.recorder.changed(&relative, mask & LISTING != 0);
+            }
+        }
+
+        /// Splits what was read from inotify into its events: the watch, the
+        /// changes and the name of what changed in the watched folder, empty
+        /// if it is the folder itself.
+        pub(super) fn parse_events(buffer: &[u8]) -> Vec<(i32, u32, OsString)> {
+            let header = std::mem::size_of::<libc::inotify_event>();
+            let mut events = Vec::new();
+            let mut offset = 0;
+            while offset + header <= buffer.len() {
+                let field = |at: usize| {
+                    let start = offset + at;
+                    u32::from_ne_bytes(buffer[start..start + 4].try_into().unwrap())
+                };
+                let (wd, mask, length) = (field(0) as i32, field(4), field(12) as usize);
+                let Some(name) = buffer.get(offset + header..offset + header + length) else {
+                    break;
+                };
+                // Names are padded with zeros
+                let end = name.iter().position(|&byte| byte == 0).unwrap_or(length);
+                events.push((wd, mask, OsStr::from_bytes(&name[..end]).to_os_string()));
+                offset += header + length;
+            }
+            events
+        }
+    }
+
+    /// Watches with the notify crate, which uses FSEvents on macOS and
+    /// kqueue on the BSDs.
+    #[cfg(not(target_os = "linux"))]
+    mod notifier {
+        use std::{io, path::Path};
+
+        use log::warn;
+        use notify::{
+            event::{EventKind, ModifyKind},
+            RecursiveMode, Watcher,
+        };
+
+        use super::Recorder;
+
+        /// Watches the folder and everything in it, recording changes in
+        /// the background until the returned watcher is dropped.
+        pub fn watch(root: &Path, recorder: Recorder) -> io::Result<notify::RecommendedWatcher> {
+            // Finds the links and ignored folders, which always count as
+            // changed
+            recorder.walk(root, Path::new(""), &mut |_, _| true);
+            let handler = move |event: notify::Result<notify::Event>| {
+                let event = match event {
+                    Ok(event) if !event.need_rescan() => event,
+                    Ok(_) => return recorder.missed(),
+                    Err(err) => {
+                        warn!("Failed to watch for changes: {err}");
+                        return recorder.missed();
+                    }
+                };
+                let listing = match event.kind {
+                    EventKind::Access(_) => return,
+                    EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Metadata(_)) => false,
+                    _ => true,
+                };
+                for path in &event.paths {
+                    if let Ok(relative) = path.strip_prefix(&recorder.root) {
+                        recorder.changed(relative, listing);
+                    }
+                }
+            };
+            let mut watcher = notify::recommended_watcher(handler).map_err(io::Error::other)?;
+            watcher
+                .watch(root, RecursiveMode::Recursive)
+                .map_err(io::Error::other)?;
+            Ok(watcher)
+        }
+    }
+
+    #[cfg(all(test, target_os = "linux"))]
+    mod tests {
+        use std::{
+            ffi::OsString,
+            path::Path,
+            sync::{Arc, Condvar, Mutex},
+        };
+
+        use super::{inotify, inotify::parse_events, sync, Changes, MkFile, Recorder, Watched};
+
+        /// Returns a recorder for changes in the folder, ignoring what the
+        /// mkfile does.
+        fn recorder(root: &Path, mkfile: &str) -> Recorder {
+            let changes = Changes {
+                daemon: "test".to_string(),
+                root: root.to_path_buf(),
+                seen: 0,
+                changed: Default::default(),
+            };
+            let watched = Watched {
+                changes,
+                listed: Default::default(),
+                synced: 0,
+            };
+            Recorder {
+                root: root.to_path_buf(),
+                shared: Arc::new((Mutex::new(watched), Condvar::new())),
+                mkfile: Arc::new(MkFile::parse(mkfile).unwrap()),
+            }
+        }
+
+        #[test]
+        fn leaves_ignored_folders_unwatched() {
+            let root = std::env::temp_dir().join(format!("mk-daemon-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&root);
+            std::fs::create_dir_all(root.join("target/debug")).unwrap();
+            std::fs::create_dir_all(root.join("src")).unwrap();
+            let root = root.canonicalize().unwrap();
+            let recorder = recorder(&root, ".ignore: target\n");
+            let _watching = inotify::watch(&root, recorder.clone()).unwrap();
+
+            std::fs::write(root.join("src/main.c"), "").unwrap();
+            std::fs::write(root.join("target/debug/mk"), "").unwrap();
+            let (changes, _) = sync(&root, &recorder.shared, 1).unwrap();
+            assert!(changes.touched(Path::new("src/main.c"), 0));
+            assert_eq!(changes.changed.get(Path::new("target")), Some(&u64::MAX));
+            assert!(!changes.changed.contains_key(Path::new("target/debug/mk")));
+            std::fs::remove_dir_all(&root).unwrap();
+        }
+
+        #[test]
+        fn records_changes_in_ignored_folders_as_the_folder() {
+            let recorder = recorder(Path::new("/project"), ".ignore: target\n");
+            recorder.changed(Path::new("target/debug/mk"), true);
+            recorder.changed(Path::new("src/main.c"), false);
+
+            let watched = recorder.shared.0.lock().unwrap();
+            assert_eq!(watched.changes.seen, 1);
+            assert_eq!(
+                watched.changes.changed.get(Path::new("target")),
+                Some(&u64::MAX)
+            );
+            assert_eq!(
+                watched.changes.changed.get(Path::new("src/main.c")),
+                Some(&1)
+            );
+            assert!(watched.listed.is_empty());
+        }
+
+        /// Writes an event as inotify does, with its name padded.
+        fn event(buffer: &mut Vec<u8>, wd: i32, mask: u32, name: &str) {
+            let length = if name.is_empty() {
+                0
+            } else {
+                (name.len() + 1).next_multiple_of(16)
+            };
+            buffer.extend(wd.to_ne_bytes());
+            buffer.extend(mask.to_ne_bytes());
+            buffer.extend(0u32.to_ne_bytes());
+            buffer.extend((length as u32).to_ne_bytes());
+            let mut padded = name.as_bytes().to_vec();
+            padded.resize(length, 0);
+            buffer.extend(padded);
+        }
+
+        #[test]
+        fn parses_inotify_events() {
+            let mut buffer = Vec::new();
+            event(&mut buffer, 1, libc::IN_MODIFY, "main.c");
+            event(&mut buffer, 2, libc::IN_DELETE_SELF, "");
+            event(
+                &mut buffer,
+                1,
+                libc::IN_CREATE | libc::IN_ISDIR,
+                "a-rather-long-folder-name",
+            );
+            // Cut off in the middle of a name
+            event(&mut buffer, 3, libc::IN_MODIFY, "lost.c");
+            buffer.truncate(buffer.len() - 4);
+
+            assert_eq!(
+                parse_events(&buffer),
+                [
+                    (1, libc::IN_MODIFY, OsString::from("main.c")),
+                    (2, libc::IN_DELETE_SELF, OsString::new()),
+                    (
+                        1,
+                        libc::IN_CREATE | libc::IN_ISDIR,
+                        OsString::from("a-rather-long-folder-name")
+                    ),
+                ]
+            );
+        }
+    }
+}
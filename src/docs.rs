@@ -0,0 +1,144 @@
+use std::fmt::Write;
+
+use crate::mkfile::{MkFile, Target};
+
+fn sorted_targets(mkfile: &MkFile) -> Vec<&Target> {
+    let mut targets: Vec<&Target> = mkfile.targets().collect();
+    targets.sort_by_key(|target| target.to_string());
+    targets
+}
+
+/// Returns a Mermaid node for the target. Ids can't contain most
+/// punctuation, so they are the hex-encoded name.
+fn mermaid_node(target: &Target) -> String {
+    let name = target.to_string();
+    let id: String = name.bytes().map(|b| format!("{b:02x}")).collect();
+    format!("t{id}[\"{}\"]", name.replace('"', "#quot;"))
+}
+
+/// Renders the dependency graph as a Mermaid flowchart.
+fn mermaid(mkfile: &MkFile) -> String {
+    let mut out = String::from("flowchart LR\n");
+    for target in sorted_targets(mkfile) {
+        let dependencies = mkfile.dependencies(target);
+        if dependencies.is_empty() {
+            let _ = writeln!(out, "    {}", mermaid_node(target));
+        }
+        for dependency in dependencies {
+            let _ = writeln!(
+                out,
+                "    {} --> {}",
+                mermaid_node(target),
+                mermaid_node(dependency)
+            );
+        }
+    }
+    out
+}
+
+/// Renders the rules of an mkfile, with their descriptions, tags and
+/// dependency graph, as a Markdown page.
+pub fn markdown(mkfile: &MkFile) -> String {
+    let mut out = String::from("# Build targets\n\n## Dependency graph\n\n");
+    let _ = writeln!(out, "```mermaid\n{}```\n\n## Rules", mermaid(mkfile));
+
+    for target in sorted_targets(mkfile) {
+        let _ = writeln!(out, "\n### `{target}`\n");
+        if let Some(description) = mkfile.description(target) {
+            let _ = writeln!(out, "{description}\n");
+        }
+        let options = mkfile.options(target);
+        if !options.tags.is_empty() {
+            let tags: Vec<_> = options.tags.iter().map(|tag| format!("`{tag}`")).collect();
+            let _ = writeln!(out, "- Tags: {}", tags.join(", "));
+        }
+        if !options.platforms.is_empty() {
+            let _ = writeln!(out, "- Platforms: {}", options.platforms.join(", "));
+        }
+        if let Some(size) = options.size {
+            let _ = writeln!(out, "- Approximate size: {size} bytes");
+        }
+        let dependencies = mkfile.dependencies(target);
+        if !dependencies.is_empty() {
+            let dependencies: Vec<_> = dependencies.iter().map(|d| format!("`{d}`")).collect();
+            let _ = writeln!(out, "- Depends on: {}", dependencies.join(", "));
+        }
+        let commands = mkfile.commands(target);
+        if !commands.is_empty() {
+            let _ = writeln!(out, "\n```sh\n{}\n```", commands.join("\n"));
+        }
+    }
+    out
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Renders the same page as [`markdown`] as a standalone HTML document. The
+/// graph is drawn client-side by Mermaid.
+pub fn html(mkfile: &MkFile) -> String {
+    let mut out = String::from(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>Build targets</title>\n<script type=\"module\">\n\
+         import mermaid from 'https://cdn.jsdelivr.net/npm/mermaid@10/dist/mermaid.esm.min.mjs';\n\
+         </script>\n</head>\n<body>\n<h1>Build targets</h1>\n<h2>Dependency graph</h2>\n",
+    );
+    let _ = writeln!(
+        out,
+        "<pre class=\"mermaid\">\n{}</pre>\n<h2>Rules</h2>",
+        escape_html(&mermaid(mkfile))
+    );
+
+    for target in sorted_targets(mkfile) {
+        let _ = writeln!(
+            out,
+            "<h3><code>{}</code></h3>",
+            escape_html(&target.to_string())
+        );
+        if let Some(description) = mkfile.description(target) {
+            let _ = writeln!(out, "<p>{}</p>", escape_html(description));
+        }
+        let options = mkfile.options(target);
+        out.push_str("<ul>\n");
+        if !options.tags.is_empty() {
+            let _ = writeln!(
+                out,
+                "<li>Tags: {}</li>",
+                escape_html(&options.tags.join(", "))
+            );
+        }
+        if !options.platforms.is_empty() {
+            let _ = writeln!(
+                out,
+                "<li>Platforms: {}</li>",
+                escape_html(&options.platforms.join(", "))
+            );
+        }
+        if let Some(size) = options.size {
+            let _ = writeln!(out, "<li>Approximate size: {size} bytes</li>");
+        }
+        let dependencies: Vec<_> = mkfile
+            .dependencies(target)
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        if !dependencies.is_empty() {
+            let _ = writeln!(
+                out,
+                "<li>Depends on: {}</li>",
+                escape_html(&dependencies.join(", "))
+            );
+        }
+        out.push_str("</ul>\n");
+        let commands = mkfile.commands(target);
+        if !commands.is_empty() {
+            let _ = writeln!(out, "<pre>{}</pre>", escape_html(&commands.join("\n")));
+        }
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
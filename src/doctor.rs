@@ -0,0 +1,201 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use log::{info, warn};
+
+use crate::mkfile::MkFile;
+
+/// Shell builtins and keywords that never live on the PATH.
+const SHELL_BUILTINS: &[&str] = &[
+    ".", ":", "[", "alias", "break", "case", "cd", "continue", "echo", "eval", "exec", "exit",
+    "export", "false", "for", "if", "printf", "pwd", "read", "return", "set", "shift", "source",
+    "test", "trap", "true", "umask", "unset", "until", "wait", "while",
+];
+
+/// A failed check together with a concrete suggestion on how to fix it.
+#[derive(Debug)]
+pub struct Problem {
+    pub message: String,
+    pub fix: String,
+}
+
+impl Problem {
+    fn new(message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Problem {
+            message: message.into(),
+            fix: fix.into(),
+        }
+    }
+}
+
+/// Returns the directory the state file lives in.
+fn state_dir(state: &Path) -> PathBuf {
+    match state.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Checks that `sh` can be spawned, since every command runs through it.
+pub fn check_shell() -> Result<(), Problem> {
+    match std::process::Command::new("sh")
+        .arg("-c")
+        .arg("true")
+        .status()
+    {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(Problem::new(
+            format!("'sh -c true' exited with {status}"),
+            "make sure 'sh' on your PATH is a working POSIX shell",
+        )),
+        Err(err) => Err(Problem::new(
+            format!("could not run 'sh': {err}"),
+            "install a POSIX shell and make sure 'sh' is on your PATH",
+        )),
+    }
+}
+
+/// Checks that the directory holding the state file is writable.
+pub fn check_state_dir(state: &Path) -> Result<(), Problem> {
+    let dir = state_dir(state);
+    let probe = dir.join(".mkdoctor.tmp");
+    let result = std::fs::write(&probe, b"");
+    let _ = std::fs::remove_file(&probe);
+    result.map_err(|err| {
+        Problem::new(
+            format!("state directory '{}' is not writable: {err}", dir.display()),
+            "fix the directory permissions or pass --state with a writable location",
+        )
+    })
+}
+
+/// Checks that the filesystem records modification times with sub-second
+/// precision. Coarse timestamps make quick successive edits look up to date.
+pub fn check_mtime_resolution(state: &Path) -> Result<(), Problem> {
+    let probe = state_dir(state).join(".mkdoctor.tmp");
+    let modified = std::fs::write(&probe, b"").and_then(|_| probe.metadata()?.modified());
+    let _ = std::fs::remove_file(&probe);
+    let modified = modified.map_err(|err| {
+        Problem::new(
+            format!("could not read modification times: {err}"),
+            "make sure the filesystem reports modification times",
+        )
+    })?;
+    let nanos = modified
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    if nanos == 0 {
+        Err(Problem::new(
+            "filesystem modification times have a resolution of one second or worse",
+            "build on a filesystem with sub-second timestamps (ext4, apfs, ntfs), or wait a \
+             second between edits and builds",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns true if the program can be found, either as a path or on the PATH.
+fn find_program(program: &str) -> bool {
+    if program.contains('/') {
+        return Path::new(program).is_file();
+    }
+    std::env::var_os("PATH")
+        .map(|paths| std::env::split_paths(&paths).any(|dir| dir.join(program).is_file()))
+        .unwrap_or(false)
+}
+
+/// Checks that the program invoked by each rule command is available, for
+/// the rules that work on this platform.
+pub fn check_tools(mkfile: &MkFile) -> Vec<Problem> {
+    let mut missing = BTreeMap::new();
+    let supported = mkfile
+        .targets()
+        .filter(|target| mkfile.options(target).supports_current_platform());
+    for target in supported {
+        for command in mkfile.commands(target) {
+            // Skip leading `NAME=value` environment assignments
+            let program = command.split_whitespace().find(|word| !word.contains('='));
+            if let Some(program) = program {
+                if !SHELL_BUILTINS.contains(&program) && !find_program(program) {
+                    missing
+                        .entry(program.to_string())
+                        .or_insert_with(|| target.to_string());
+                }
+            }
+        }
+    }
+    missing
+        .into_iter()
+        .map(|(program, target)| {
+            Problem::new(
+                format!("'{program}' used by target '{target}' was not found"),
+                format!("install '{program}' or add its directory to PATH"),
+            )
+        })
+        .collect()
+}
+
+fn report(name: &str, problems: &[Problem]) {
+    if problems.is_empty() {
+        info!("[ok] {name}");
+    }
+    for problem in problems {
+        warn!("[!!] {name}: {}", problem.message);
+        warn!("     fix: {}", problem.fix);
+    }
+}
+
+/// Runs every check and reports the results. Returns true if all passed.
+pub fn doctor(mkfile: &Path, state: &Path) -> bool {
+    let mut checks = vec![
+        ("shell", check_shell().err().into_iter().collect()),
+        (
+            "state directory",
+            check_state_dir(state).err().into_iter().collect(),
+        ),
+        (
+            "mtime resolution",
+            check_mtime_resolution(state).err().into_iter().collect(),
+        ),
+    ];
+
+    let tools = match std::fs::read_to_string(mkfile) {
+        Ok(text) => match MkFile::parse(&text) {
+            Ok(mkfile) => check_tools(&mkfile),
+            Err(err) => vec![Problem::new(
+                format!("could not parse '{}': {err}", mkfile.display()),
+                "fix the mkfile syntax error",
+            )],
+        },
+        Err(err) => vec![Problem::new(
+            format!("could not read '{}': {err}", mkfile.display()),
+            "create an mkfile or pass --mkfile with its location",
+        )],
+    };
+    checks.push(("tools", tools));
+
+    for (name, problems) in &checks {
+        report(name, problems);
+    }
+    checks.iter().all(|(_, problems)| problems.is_empty())
+}
+
+/// Runs the cheap checks, used the first time mk runs in a directory.
+pub fn first_run_checks(state: &Path) {
+    let problems: Vec<_> = [check_shell(), check_state_dir(state)]
+        .into_iter()
+        .filter_map(Result::err)
+        .collect();
+    for problem in &problems {
+        warn!("{}", problem.message);
+        warn!("fix: {}", problem.fix);
+    }
+    if !problems.is_empty() {
+        warn!("Run 'mk doctor' for a full report");
+    }
+}
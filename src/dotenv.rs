@@ -0,0 +1,67 @@
+use std::{collections::BTreeMap, io, path::Path};
+
+use crate::{error::MkError, vfs::Vfs};
+
+/// Reads the variables of a `.env` file.
+pub fn load(vfs: &dyn Vfs, path: &Path) -> Result<BTreeMap<String, String>, MkError> {
+    let text = vfs.read_to_string(path).map_err(|err| {
+        MkError::Io(io::Error::new(
+            err.kind(),
+            format!("Can't read env file '{}': {err}", path.display()),
+        ))
+    })?;
+    parse(&text).map_err(|err| MkError::Parse(format!("In '{}': {err}", path.display())))
+}
+
+/// Parses the text of a `.env` file: `NAME=value` lines, optionally
+/// starting with `export`, with `#` comments and blank lines in between.
+/// Values in single quotes are taken as they are, values in double quotes
+/// can have `\n`, `\"` and `\\` escapes, and unquoted values end at a ` #`
+/// comment.
+pub fn parse(text: &str) -> Result<BTreeMap<String, String>, MkError> {
+    let mut variables = BTreeMap::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let invalid = || MkError::Parse(format!("Invalid line {}: '{line}'", number + 1));
+        let (name, value) = line.split_once('=').ok_or_else(invalid)?;
+        let name = name.trim();
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(invalid());
+        }
+        let value = value.trim();
+        let value = if let Some(quoted) = value.strip_prefix('\'') {
+            quoted.strip_suffix('\'').ok_or_else(invalid)?.to_string()
+        } else if let Some(quoted) = value.strip_prefix('"') {
+            unescape(quoted.strip_suffix('"').ok_or_else(invalid)?)
+        } else {
+            value
+                .split_once(" #")
+                .map_or(value, |(value, _)| value)
+                .trim_end()
+                .to_string()
+        };
+        variables.insert(name.to_string(), value);
+    }
+    Ok(variables)
+}
+
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => unescaped.push('\n'),
+            ('\\', Some(escaped @ ('"' | '\\'))) => unescaped.push(escaped),
+            _ => {
+                unescaped.push(c);
+                continue;
+            }
+        }
+        chars.next();
+    }
+    unescaped
+}
@@ -0,0 +1,143 @@
+use std::{fmt, io, path::PathBuf, time::Duration};
+
+use crate::{executor::Status, making::format_size, mkfile::Target};
+
+/// Everything that can go wrong while reading an mkfile or making a target.
+#[derive(Debug)]
+pub enum MkError {
+    /// There is no rule to make the target, and it isn't a file either.
+    NoRule(Target),
+    /// A command of the target's rule did not succeed.
+    CommandFailed {
+        target: Target,
+        command: String,
+        status: Status,
+    },
+    /// A command of the target's rule was killed for running longer than
+    /// the rule's timeout.
+    TimedOut {
+        target: Target,
+        command: String,
+        timeout: Duration,
+    },
+    /// The rule ran, but the target's path still does not exist.
+    NotCreated(Target),
+    /// A dependency failed to be made earlier in the same build.
+    DependencyFailed(Target),
+    /// The target's rule doesn't work on this platform.
+    UnsupportedPlatform {
+        target: Target,
+        platforms: Vec<String>,
+    },
+    /// Targets that depend on each other in a loop. The first target is
+    /// repeated at the end.
+    Cycle(Vec<Target>),
+    /// There is not enough free space in the folder to make the target.
+    NoSpace {
+        target: Target,
+        needed: u64,
+        available: u64,
+        dir: PathBuf,
+    },
+    /// Another mk process holds the lock on the state file. `holder` is its
+    /// process ID, if known.
+    Locked { path: PathBuf, holder: Option<u32> },
+    /// Reading or writing a file, or starting a command, failed.
+    Io(io::Error),
+    /// The mkfile is malformed.
+    Parse(String),
+    /// mk received the signal, so the build was stopped.
+    Interrupted(i32),
+}
+
+impl MkError {
+    /// The exit code the `mk` binary uses for the error:
+    ///
+    /// | Code | Error |
+    /// |------|-------|
+    /// | 1 | a command failed or timed out |
+    /// | 2 | no rule, the rule didn't create the target, or it doesn't work on this platform |
+    /// | 3 | dependency cycle |
+    /// | 4 | the mkfile is malformed |
+    /// | 5 | I/O error, not enough disk space, or another mk is running |
+    /// | 128 + signal | interrupted, e.g. 130 for Ctrl-C |
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            MkError::CommandFailed { .. }
+            | MkError::TimedOut { .. }
+            | MkError::DependencyFailed(_) => 1,
+            MkError::NoRule(_) | MkError::NotCreated(_) | MkError::UnsupportedPlatform { .. } => 2,
+            MkError::Cycle(_) => 3,
+            MkError::Parse(_) => 4,
+            MkError::Io(_) | MkError::NoSpace { .. } | MkError::Locked { .. } => 5,
+            MkError::Interrupted(signal) => 128 + signal,
+        }
+    }
+}
+
+impl fmt::Display for MkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MkError::NoRule(target) => write!(f, "No rule to make target '{target}'"),
+            MkError::CommandFailed {
+                command, status, ..
+            } => write!(f, "Failed to execute command '{command}' ({status})"),
+            MkError::TimedOut {
+                command, timeout, ..
+            } => write!(f, "Command '{command}' timed out after {timeout:?}"),
+            MkError::NotCreated(target) => write!(f, "Target '{target}' was not created"),
+            MkError::DependencyFailed(target) => write!(f, "Dependency '{target}' failed"),
+            MkError::UnsupportedPlatform { target, platforms } => write!(
+                f,
+                "Target '{target}' can only be made on {}, not on {}",
+                platforms.join(", "),
+                std::env::consts::OS
+            ),
+            MkError::Cycle(cycle) => {
+                let cycle: Vec<String> = cycle.iter().map(Target::to_string).collect();
+                write!(f, "Dependency cycle: {}", cycle.join(" -> "))
+            }
+            MkError::NoSpace {
+                target,
+                needed,
+                available,
+                dir,
+            } => write!(
+                f,
+                "Not enough disk space to make '{target}': needs about {}, only {} available in '{}'",
+                format_size(*needed),
+                format_size(*available),
+                dir.display()
+            ),
+            MkError::Locked { path, holder } => {
+                write!(f, "Another mk is running here")?;
+                if let Some(pid) = holder {
+                    write!(f, " (process {pid})")?;
+                }
+                write!(
+                    f,
+                    " and holds the lock on '{}'; pass --wait-for-lock to wait for it",
+                    path.display()
+                )
+            }
+            MkError::Io(err) => write!(f, "{err}"),
+            MkError::Parse(message) => write!(f, "{message}"),
+            MkError::Interrupted(signal) => write!(f, "Interrupted by signal {signal}"),
+        }
+    }
+}
+
+impl std::error::Error for MkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MkError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MkError {
+    fn from(err: io::Error) -> Self {
+        MkError::Io(err)
+    }
+}
@@ -0,0 +1,383 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt, io,
+    process::{Child, Command, Stdio},
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{interrupt, limits::Cgroup, output};
+
+/// A command from a rule, with everything needed to run it.
+#[derive(Debug, Clone, Default)]
+pub struct Invocation {
+    /// The command line, run by the shell.
+    pub command: String,
+    /// Environment variables set on top of the inherited ones.
+    pub env: BTreeMap<String, String>,
+    /// Don't inherit any environment variables, only set `env`.
+    pub hermetic: bool,
+    /// Prefix for each line of output, if it should be prefixed.
+    pub prefix: Option<String>,
+    /// The target the command is run for, if it is run for one.
+    pub target: Option<String>,
+    /// Resources the command may use, if they should be enforced.
+    pub limits: Option<Limits>,
+    /// How long the command may run before it is killed.
+    pub timeout: Option<Duration>,
+    /// Where the command's output goes, if not to mk's own stdout and
+    /// stderr.
+    pub redirect: Option<Redirect>,
+    /// What the command reads on stdin, instead of mk's own stdin.
+    pub input: Option<Vec<u8>>,
+}
+
+/// A writer shared by everything that is sent to it.
+type Shared = Arc<Mutex<dyn io::Write + Send>>;
+
+/// Where the output of a command goes instead of mk's own stdout and
+/// stderr, a line at a time. Commands with their output redirected read
+/// nothing from stdin, unless they are given input.
+#[derive(Clone)]
+pub struct Redirect {
+    stdout: Shared,
+    stderr: Shared,
+}
+
+impl Redirect {
+    /// Sends the output to the given writers.
+    pub fn to(
+        stdout: impl io::Write + Send + 'static,
+        stderr: impl io::Write + Send + 'static,
+    ) -> Self {
+        Redirect {
+            stdout: Arc::new(Mutex::new(stdout)),
+            stderr: Arc::new(Mutex::new(stderr)),
+        }
+    }
+}
+
+impl fmt::Debug for Redirect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Redirect").finish_non_exhaustive()
+    }
+}
+
+/// Writes to one of the writers of a [`Redirect`].
+struct SharedWriter(Shared);
+
+impl io::Write for SharedWriter {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buffer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+/// The CPU and memory a command may use, from the options of its rule.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// How many CPUs' worth of time the command may use.
+    pub cpus: Option<u32>,
+    /// Bytes of memory the command may use.
+    pub memory: Option<u64>,
+}
+
+/// How a command ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    /// The exit code, or `None` if the command was killed by a signal.
+    pub code: Option<i32>,
+    /// The command was killed for going over its memory limit.
+    pub out_of_memory: bool,
+    /// The command was killed for running longer than its timeout.
+    pub timed_out: bool,
+}
+
+impl Status {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.code {
+            _ if self.out_of_memory => write!(f, "killed for going over its memory limit"),
+            _ if self.timed_out => write!(f, "killed for running too long"),
+            Some(code) => write!(f, "exit code {code}"),
+            None => write!(f, "killed by a signal"),
+        }
+    }
+}
+
+impl From<std::process::ExitStatus> for Status {
+    fn from(status: std::process::ExitStatus) -> Self {
+        Status {
+            code: status.code(),
+            out_of_memory: false,
+            timed_out: false,
+        }
+    }
+}
+
+/// Runs rule commands, so the engine can be driven without spawning real
+/// processes.
+pub trait CommandExecutor: Send + Sync {
+    /// Runs the command, letting its output through.
+    fn run(&self, invocation: &Invocation) -> io::Result<Status>;
+    /// Runs the command and returns what it wrote to stdout.
+    fn capture(&self, invocation: &Invocation) -> io::Result<(Status, Vec<u8>)>;
+}
+
+impl<T: CommandExecutor + ?Sized> CommandExecutor for Arc<T> {
+    fn run(&self, invocation: &Invocation) -> io::Result<Status> {
+        self.as_ref().run(invocation)
+    }
+
+    fn capture(&self, invocation: &Invocation) -> io::Result<(Status, Vec<u8>)> {
+        self.as_ref().capture(invocation)
+    }
+}
+
+/// Runs commands with `sh -c`, with their output redirected as the
+/// invocation says.
+pub struct ShellExecutor;
+
+impl ShellExecutor {
+    /// Returns the command to run, along with the control group holding it
+    /// to its limits, if any.
+    fn command(invocation: &Invocation) -> io::Result<(Command, Option<Cgroup>)> {
+        let mut command = Command::new("sh");
+        if invocation.hermetic {
+            command.env_clear();
+        }
+        command
+            .arg("-c")
+            .arg(&invocation.command)
+            .envs(&invocation.env);
+        // In a group of its own, so that the whole group can be killed on
+        // timeout
+        #[cfg(unix)]
+        if invocation.timeout.is_some() {
+            use std::os::unix::process::CommandExt;
+            command.process_group(0);
+        }
+        if invocation.input.is_some() {
+            command.stdin(Stdio::piped());
+        } else if invocation.redirect.is_some() {
+            command.stdin(Stdio::null());
+        }
+        let cgroup = invocation.limits.as_ref().map(Cgroup::create).transpose()?;
+        if let Some(cgroup) = &cgroup {
+            cgroup.attach(&mut command)?;
+        }
+        Ok((command, cgroup))
+    }
+
+    /// Waits for the child with `wait`, stopping it if mk is interrupted or
+    /// it runs for longer than its timeout.
+    fn supervise<T>(
+        mut child: Child,
+        invocation: &Invocation,
+        cgroup: Option<Cgroup>,
+        wait: impl FnOnce(Child) -> io::Result<(std::process::ExitStatus, T)>,
+    ) -> io::Result<(Status, T)> {
+        if let Some(cgroup) = &cgroup {
+            cgroup.started(&child)?;
+        }
+        if let (Some(input), Some(mut stdin)) = (&invocation.input, child.stdin.take()) {
+            // Commands that don't read their input are fine too
+            let _ = io::Write::write_all(&mut stdin, input);
+        }
+        let _tracked = interrupt::track(&child);
+        let watchdog = invocation
+            .timeout
+            .map(|timeout| Watchdog::start(child.id(), timeout));
+        let (status, output) = wait(child)?;
+        let status = Status {
+            out_of_memory: cgroup.is_some_and(|cgroup| cgroup.out_of_memory()),
+            timed_out: watchdog.is_some_and(Watchdog::stop),
+            ..status.into()
+        };
+        Ok((status, output))
+    }
+}
+
+impl CommandExecutor for ShellExecutor {
+    fn run(&self, invocation: &Invocation) -> io::Result<Status> {
+        let (mut command, cgroup) = Self::command(invocation)?;
+        if let Some(redirect) = &invocation.redirect {
+            let child = output::spawn_prefixed(&mut command)?;
+            let prefix = invocation.prefix.as_deref().unwrap_or_default();
+            let (status, ()) = Self::supervise(child, invocation, cgroup, |mut child| {
+                let status = output::forward_prefixed_to(
+                    &mut child,
+                    prefix,
+                    SharedWriter(redirect.stdout.clone()),
+                    SharedWriter(redirect.stderr.clone()),
+                )?;
+                Ok((status, ()))
+            })?;
+            return Ok(status);
+        }
+        let (status, ()) = match &invocation.prefix {
+            Some(prefix) => {
+                let child = output::spawn_prefixed(&mut command)?;
+                Self::supervise(child, invocation, cgroup, |mut child| {
+                    Ok((output::forward_prefixed(&mut child, prefix)?, ()))
+                })?
+            }
+            None => Self::supervise(command.spawn()?, invocation, cgroup, |mut child| {
+                Ok((child.wait()?, ()))
+            })?,
+        };
+        Ok(status)
+    }
+
+    fn capture(&self, invocation: &Invocation) -> io::Result<(Status, Vec<u8>)> {
+        let (mut command, cgroup) = Self::command(invocation)?;
+        let Some(redirect) = &invocation.redirect else {
+            let child = command
+                .stdout(Stdio::piped())
+                .stderr(Stdio::inherit())
+                .spawn()?;
+            return Self::supervise(child, invocation, cgroup, |child| {
+                let output = child.wait_with_output()?;
+                Ok((output.status, output.stdout))
+            });
+        };
+        let child = command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        Self::supervise(child, invocation, cgroup, |mut child| {
+            let mut stderr = child.stderr.take().expect("stderr is piped");
+            let mut forwarded = SharedWriter(redirect.stderr.clone());
+            thread::scope(|scope| {
+                scope.spawn(move || io::copy(&mut stderr, &mut forwarded));
+                let output = child.wait_with_output()?;
+                Ok((output.status, output.stdout))
+            })
+        })
+    }
+}
+
+/// Runs commands with [`ShellExecutor`], but sends their output to the
+/// given writers instead of mk's own stdout and stderr.
+pub struct ForwardingExecutor<W> {
+    pub stdout: W,
+    pub stderr: W,
+}
+
+impl<W: io::Write + Clone + Send + Sync + 'static> ForwardingExecutor<W> {
+    fn redirect(&self, invocation: &Invocation) -> Invocation {
+        Invocation {
+            redirect: Some(Redirect::to(self.stdout.clone(), self.stderr.clone())),
+            ..invocation.clone()
+        }
+    }
+}
+
+impl<W: io::Write + Clone + Send + Sync + 'static> CommandExecutor for ForwardingExecutor<W> {
+    fn run(&self, invocation: &Invocation) -> io::Result<Status> {
+        ShellExecutor.run(&self.redirect(invocation))
+    }
+
+    fn capture(&self, invocation: &Invocation) -> io::Result<(Status, Vec<u8>)> {
+        ShellExecutor.capture(&self.redirect(invocation))
+    }
+}
+
+/// Kills a command's process group once it has run for longer than its
+/// timeout. Commands can only be killed on Unix, elsewhere they are only
+/// reported as having timed out when they finish.
+struct Watchdog {
+    cancel: mpsc::Sender<()>,
+    thread: thread::JoinHandle<bool>,
+}
+
+impl Watchdog {
+    fn start(pid: u32, timeout: Duration) -> Self {
+        let (cancel, cancelled) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let expired = cancelled.recv_timeout(timeout) == Err(RecvTimeoutError::Timeout);
+            #[cfg(unix)]
+            if expired {
+                interrupt::signal(pid, libc::SIGKILL);
+            }
+            #[cfg(not(unix))]
+            let _ = pid;
+            expired
+        });
+        Watchdog { cancel, thread }
+    }
+
+    /// Stops watching the command, which has exited. Returns true if it was
+    /// killed for running too long.
+    fn stop(self) -> bool {
+        let _ = self.cancel.send(());
+        self.thread.join().unwrap_or(false)
+    }
+}
+
+/// Called by `MockExecutor` with every command it runs.
+type OnRun = Box<dyn Fn(&str) + Send + Sync>;
+
+/// Records the commands it is asked to run instead of running them. Every
+/// command succeeds with no output, unless it was marked as failing.
+#[derive(Default)]
+pub struct MockExecutor {
+    ran: Mutex<Vec<String>>,
+    failing: HashSet<String>,
+    on_run: Option<OnRun>,
+}
+
+impl MockExecutor {
+    /// Calls the function with every command run, e.g. to write its outputs
+    /// to an in-memory filesystem.
+    pub fn on_run(mut self, on_run: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.on_run = Some(Box::new(on_run));
+        self
+    }
+
+    /// Makes the given command exit with code 1.
+    pub fn fail(mut self, command: &str) -> Self {
+        self.failing.insert(command.to_string());
+        self
+    }
+
+    /// Returns the commands run so far, in order.
+    pub fn ran(&self) -> Vec<String> {
+        self.ran.lock().unwrap().clone()
+    }
+}
+
+impl CommandExecutor for MockExecutor {
+    fn run(&self, invocation: &Invocation) -> io::Result<Status> {
+        self.ran.lock().unwrap().push(invocation.command.clone());
+        if let Some(on_run) = &self.on_run {
+            on_run(&invocation.command);
+        }
+        let code = if self.failing.contains(&invocation.command) {
+            1
+        } else {
+            0
+        };
+        Ok(Status {
+            code: Some(code),
+            out_of_memory: false,
+            timed_out: false,
+        })
+    }
+
+    fn capture(&self, invocation: &Invocation) -> io::Result<(Status, Vec<u8>)> {
+        Ok((self.run(invocation)?, Vec::new()))
+    }
+}
@@ -0,0 +1,392 @@
+use std::{fmt::Write, path::Path};
+
+use log::warn;
+use serde::Serialize;
+
+use crate::mkfile::{ConcreteTarget, MkFile, Target};
+
+/// Returns the targets with rules that making the given ones may make,
+/// sorted by name.
+fn reachable_targets<'a>(mkfile: &'a MkFile, targets: &'a [Target]) -> Vec<&'a Target> {
+    let mut reachable: Vec<&Target> = targets
+        .iter()
+        .flat_map(|target| mkfile.reachable(target))
+        .filter(|target| mkfile.has_target(target))
+        .collect();
+    reachable.sort_by_key(|target| target.to_string());
+    reachable.dedup();
+    reachable
+}
+
+/// Returns the path Ninja knows the target by. Virtual targets go by their
+/// name, which Ninja never finds as a file, so their commands always run.
+fn ninja_path(target: &Target) -> String {
+    let path = match target {
+        Target::Virtual(name) => name.clone(),
+        Target::Concrete(path) => path.pathbuf().display().to_string(),
+    };
+    path.replace('$', "$$")
+        .replace(' ', "$ ")
+        .replace(':', "$:")
+}
+
+/// Lowers the rules that making the targets needs into a Ninja file, with
+/// variables and patterns already expanded. Every rule gets a Ninja rule of
+/// its own that runs its commands in order, with the variables of its
+/// `env:` exported first, and virtual targets without commands become
+/// `phony` edges. What only mk does, such as caching, retries and
+/// freshness strategies, is left out, and folders are tracked by their own
+/// modification time rather than by what is in them.
+pub fn ninja(mkfile: &MkFile, targets: &[Target]) -> String {
+    let mut out = String::from("# Generated by `mk export --ninja`.\n");
+    for (index, made) in reachable_targets(mkfile, targets).into_iter().enumerate() {
+        let options = mkfile.options(made);
+        let inputs: Vec<String> = mkfile.dependencies(made).iter().map(ninja_path).collect();
+        let mut outputs = ninja_path(made);
+        if !options.outputs.is_empty() {
+            let others: Vec<String> = options
+                .outputs
+                .iter()
+                .map(|output| ninja_path(&Target::Concrete(output.clone())))
+                .collect();
+            let _ = write!(outputs, " | {}", others.join(" "));
+        }
+        let commands = mkfile.commands(made);
+        out.push('\n');
+        if commands.is_empty() {
+            let line = format!("build {outputs}: phony {}", inputs.join(" "));
+            let _ = writeln!(out, "{}", line.trim_end());
+            continue;
+        }
+
+        let exports = options
+            .env
+            .iter()
+            .map(|(name, value)| format!("export {name}='{}'", value.replace('\'', "'\\''")));
+        let command = exports
+            .chain(commands.iter().cloned())
+            .collect::<Vec<_>>()
+            .join(" && ");
+        let rule = format!("mk_{index}");
+        let _ = writeln!(out, "rule {rule}");
+        let _ = writeln!(out, "  command = {}", command.replace('$', "$$"));
+        let _ = writeln!(
+            out,
+            "  description = Making {}",
+            made.to_string().replace('$', "$$")
+        );
+        let line = format!("build {outputs}: {rule} {}", inputs.join(" "));
+        let _ = writeln!(out, "{}", line.trim_end());
+    }
+    let defaults: Vec<String> = targets.iter().map(ninja_path).collect();
+    let _ = writeln!(out, "\ndefault {}", defaults.join(" "));
+    out
+}
+
+/// An entry of a `compile_commands.json` file.
+#[derive(Serialize)]
+struct CompileCommand {
+    directory: String,
+    arguments: Vec<String>,
+    file: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<String>,
+}
+
+/// Extensions of the sources that C and C++ compilers are run on.
+const COMPILED_EXTENSIONS: [&str; 7] = ["c", "cc", "cpp", "cxx", "c++", "m", "mm"];
+
+/// Returns true if the program is a C or C++ compiler, including cross
+/// compilers such as `aarch64-linux-gnu-gcc` and versioned ones such as
+/// `clang-17`.
+fn is_compiler(program: &str) -> bool {
+    let name = program.rsplit('/').next().unwrap_or(program);
+    let name = name.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.' || c == '-');
+    let name = name.rsplit('-').next().unwrap_or(name);
+    matches!(name, "cc" | "c++" | "gcc" | "g++" | "clang" | "clang++")
+}
+
+/// Splits a command into words the way the shell would, for the simple
+/// cases: quotes and backslashes are understood, but not substitutions.
+/// `&&`, `||`, `;` and `|` are words of their own.
+fn shell_words(command: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = command.chars().peekable();
+    let mut quote = None;
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"'), '\\') if matches!(chars.peek(), Some('"' | '\\' | '$' | '`')) => {
+                word.get_or_insert_with(String::new).extend(chars.next());
+            }
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, '\\') => word.get_or_insert_with(String::new).extend(chars.next()),
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, ';' | '&' | '|') => {
+                words.extend(word.take());
+                let mut operator = c.to_string();
+                if chars.peek() == Some(&c) && c != ';' {
+                    operator.extend(chars.next());
+                }
+                words.push(operator);
+            }
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    words
+}
+
+/// Writes a `compile_commands.json` file for the rules that making the
+/// targets needs, so that clangd and other tools know how each source is
+/// compiled. Every command that runs a C or C++ compiler on a dependency of
+/// its rule becomes an entry, with `directory` as the folder it runs in.
+pub fn compdb(mkfile: &MkFile, targets: &[Target], directory: &Path) -> String {
+    let mut entries = Vec::new();
+    for made in reachable_targets(mkfile, targets) {
+        let sources: Vec<String> = mkfile
+            .dependencies(made)
+            .iter()
+            .filter_map(|dependency| match dependency {
+                Target::Concrete(ConcreteTarget::Shallow(path)) => Some(path),
+                _ => None,
+            })
+            .filter(|path| {
+                path.extension()
+                    .and_then(|extension| extension.to_str())
+                    .is_some_and(|extension| COMPILED_EXTENSIONS.contains(&extension))
+            })
+            .map(|path| path.display().to_string())
+            .collect();
+        if sources.is_empty() {
+            continue;
+        }
+        for command in mkfile.commands(made) {
+            let words = shell_words(command);
+            let commands = words.split(|word| matches!(word.as_str(), "&&" | "||" | ";" | "|"));
+            for words in commands {
+                // Environment assignments in front of the compiler are left out
+                let start = words
+                    .iter()
+                    .position(|word| !word.contains('=') || word.starts_with('-'));
+                let Some(arguments) = start.map(|start| &words[start..]) else {
+                    continue;
+                };
+                if !arguments
+                    .first()
+                    .is_some_and(|program| is_compiler(program))
+                {
+                    continue;
+                }
+                let Some(file) = sources.iter().find(|source| arguments.contains(source)) else {
+                    continue;
+                };
+                let output = arguments
+                    .iter()
+                    .position(|argument| argument == "-o")
+                    .and_then(|index| arguments.get(index + 1))
+                    .cloned();
+                entries.push(CompileCommand {
+                    directory: directory.display().to_string(),
+                    arguments: arguments.to_vec(),
+                    file: file.clone(),
+                    output,
+                });
+            }
+        }
+    }
+    let mut json = serde_json::to_string_pretty(&entries).unwrap_or_default();
+    json.push('\n');
+    json
+}
+
+/// Returns how a target is named on mk's command line, quoted for the
+/// shell if needed.
+fn command_line_name(target: &Target) -> String {
+    let name = match target {
+        Target::Virtual(name) => name.clone(),
+        Target::Concrete(_) => target.to_string(),
+    };
+    if name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "-_./".contains(c))
+    {
+        name
+    } else {
+        format!("'{}'", name.replace('\'', "'\\''"))
+    }
+}
+
+/// Writes a GitHub Actions workflow that makes the targets on every push
+/// and pull request. The update state and the cache folder, if there is
+/// one, are kept between runs with `actions/cache`, so that CI only makes
+/// what changed. `mkfile` is the path of the mkfile, passed on to mk unless
+/// it is the default one.
+pub fn github_actions(
+    targets: &[Target],
+    mkfile: &str,
+    state: &Path,
+    cache_dir: Option<&Path>,
+) -> String {
+    let mut out = String::from("# Generated by `mk export --github-actions`.\n");
+    for line in [
+        "name: mk",
+        "",
+        "on:",
+        "  push:",
+        "  pull_request:",
+        "",
+        "jobs:",
+        "  build:",
+        "    runs-on: ubuntu-latest",
+        "    steps:",
+        "      - uses: actions/checkout@v4",
+        "      - name: Install mk",
+        "        run: cargo install --locked --git https://github.com/mkualquiera/mk mk",
+        "      - name: Restore the mk state and cache",
+        "        uses: actions/cache@v4",
+        "        with:",
+        "          path: |",
+    ] {
+        let _ = writeln!(out, "{line}");
+    }
+    for path in std::iter::once(state).chain(cache_dir) {
+        let _ = writeln!(out, "            {}", path.display());
+    }
+    let _ = writeln!(
+        out,
+        "          key: mk-${{{{ runner.os }}}}-${{{{ github.sha }}}}"
+    );
+    let _ = writeln!(out, "          restore-keys: mk-${{{{ runner.os }}}}-");
+    let mkfile = match mkfile {
+        "mkfile" => String::new(),
+        path => format!(" --mkfile {path}"),
+    };
+    for target in targets {
+        let name = command_line_name(target);
+        let _ = writeln!(out, "      - name: Make {}", name.trim_matches('\''));
+        let _ = writeln!(out, "        run: mk{mkfile} {name}");
+    }
+    out
+}
+
+/// Quotes text as a Nix string.
+fn nix_string(text: &str) -> String {
+    let escaped = text
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${");
+    format!("\"{escaped}\"")
+}
+
+/// Escapes a command for a Nix indented string.
+fn nix_indented(line: &str) -> String {
+    line.replace("''", "'''").replace("${", "''${")
+}
+
+/// Quotes a word for the shell.
+fn shell_quote(word: &str) -> String {
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+/// Translates the hermetic rules that making the targets needs into Nix
+/// derivations, one per rule, written as a Nix file that goes in the
+/// mkfile's folder. Each derivation copies in its dependencies, from the
+/// derivations of the rules that make them or from the sources next to the
+/// Nix file, runs the rule's commands with its `env:` and copies the target
+/// and its `outputs:` to the same paths under `$out`. The file evaluates to
+/// an attribute set of the derivations, by target path.
+///
+/// Only rules that are `hermetic:`, or all of them with `hermetic`, are
+/// translated, since Nix builds see nothing but what they declare. Other
+/// rules are left out, and what they make is taken from the sources as if
+/// it were one. `env_inputs:` are left out too, as Nix builds can't read
+/// the environment.
+pub fn nix(mkfile: &MkFile, targets: &[Target], hermetic: bool) -> String {
+    let translated: Vec<&Target> = reachable_targets(mkfile, targets)
+        .into_iter()
+        .filter(|made| {
+            if !matches!(made, Target::Concrete(_)) || mkfile.commands(made).is_empty() {
+                return false;
+            }
+            let translate = hermetic || mkfile.options(made).hermetic;
+            if !translate {
+                warn!("The rule for '{made}' isn't hermetic, leaving it out");
+            }
+            translate
+        })
+        .collect();
+    let derivation = |made: &Target| {
+        translated
+            .iter()
+            .position(|translated| *translated == made)
+            .map(|index| format!("mk_{index}"))
+    };
+
+    let mut out = String::from("# Generated by `mk export --nix`.\n");
+    out.push_str("{ pkgs ? import <nixpkgs> { } }:\n\nlet\n  src = ./.;\n");
+    for (index, made) in translated.iter().enumerate() {
+        let Target::Concrete(target) = made else {
+            continue;
+        };
+        let options = mkfile.options(made);
+        let name = target.pathbuf().display().to_string().replace(
+            |c: char| !c.is_ascii_alphanumeric() && !"+-._?=".contains(c),
+            "-",
+        );
+        let attributes: Vec<String> = options
+            .env
+            .iter()
+            .map(|(name, value)| format!("{} = {};", nix_string(name), nix_string(value)))
+            .collect();
+
+        let mut script = Vec::new();
+        for dependency in mkfile.dependencies(made) {
+            let Target::Concrete(path) = dependency else {
+                continue;
+            };
+            let path = path.pathbuf().display().to_string();
+            let from = match derivation(dependency) {
+                Some(derivation) => format!("${{{derivation}}}/{path}"),
+                None => format!("${{src + {}}}", nix_string(&format!("/{path}"))),
+            };
+            script.push(format!("mkdir -p \"$(dirname {})\"", shell_quote(&path)));
+            script.push(format!("cp -r \"{from}\" {}", shell_quote(&path)));
+        }
+        script.push("chmod -R u+w .".to_string());
+        let commands = mkfile.commands(made).iter();
+        script.extend(
+            commands
+                .flat_map(|command| command.lines())
+                .map(nix_indented),
+        );
+        for output in std::iter::once(target).chain(&options.outputs) {
+            let path = shell_quote(&output.pathbuf().display().to_string());
+            script.push(format!("mkdir -p \"$(dirname \"$out\"/{path})\""));
+            script.push(format!("cp -r {path} \"$out\"/{path}"));
+        }
+
+        let _ = writeln!(out, "\n  # {made}");
+        let _ = writeln!(
+            out,
+            "  mk_{index} = pkgs.runCommand {} {{ {} }} ''",
+            nix_string(&name),
+            attributes.join(" ")
+        );
+        for line in &script {
+            let _ = writeln!(out, "    {line}");
+        }
+        let _ = writeln!(out, "  '';");
+    }
+    out.push_str("in\n{\n");
+    for (index, made) in translated.iter().enumerate() {
+        let _ = writeln!(out, "  {} = mk_{index};", nix_string(&made.to_string()));
+    }
+    out.push_str("}\n");
+    out
+}
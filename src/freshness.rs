@@ -0,0 +1,225 @@
+use std::{fmt, str::FromStr, sync::Arc};
+
+use crate::{
+    error::MkError,
+    making::{content_hash, RebuildReason, UpdateState},
+    mkfile::ConcreteTarget,
+    vfs::Vfs,
+};
+
+/// Decides whether a file changed since mk last recorded it.
+pub trait FreshnessChecker: Send + Sync {
+    /// Returns why the file counts as changed since it was last recorded.
+    fn check(
+        &self,
+        state: &UpdateState,
+        vfs: &dyn Vfs,
+        path: &ConcreteTarget,
+    ) -> Result<Result<(), RebuildReason>, MkError>;
+
+    /// Records the file as it is now. Returns true if its contents differ
+    /// from the previous record, which makes its dependents rebuild.
+    fn record(
+        &self,
+        state: &mut UpdateState,
+        vfs: &dyn Vfs,
+        path: &ConcreteTarget,
+    ) -> Result<bool, MkError>;
+}
+
+impl<T: FreshnessChecker + ?Sized> FreshnessChecker for Arc<T> {
+    fn check(
+        &self,
+        state: &UpdateState,
+        vfs: &dyn Vfs,
+        path: &ConcreteTarget,
+    ) -> Result<Result<(), RebuildReason>, MkError> {
+        (**self).check(state, vfs, path)
+    }
+
+    fn record(
+        &self,
+        state: &mut UpdateState,
+        vfs: &dyn Vfs,
+        path: &ConcreteTarget,
+    ) -> Result<bool, MkError> {
+        (**self).record(state, vfs, path)
+    }
+}
+
+impl<T: FreshnessChecker + ?Sized> FreshnessChecker for &T {
+    fn check(
+        &self,
+        state: &UpdateState,
+        vfs: &dyn Vfs,
+        path: &ConcreteTarget,
+    ) -> Result<Result<(), RebuildReason>, MkError> {
+        (**self).check(state, vfs, path)
+    }
+
+    fn record(
+        &self,
+        state: &mut UpdateState,
+        vfs: &dyn Vfs,
+        path: &ConcreteTarget,
+    ) -> Result<bool, MkError> {
+        (**self).record(state, vfs, path)
+    }
+}
+
+/// Compares modification times only. Cheap, but every touch counts as a
+/// change.
+pub struct MtimeChecker;
+
+impl FreshnessChecker for MtimeChecker {
+    fn check(
+        &self,
+        state: &UpdateState,
+        vfs: &dyn Vfs,
+        path: &ConcreteTarget,
+    ) -> Result<Result<(), RebuildReason>, MkError> {
+        state.is_up_to_date(vfs, path)
+    }
+
+    fn record(
+        &self,
+        state: &mut UpdateState,
+        vfs: &dyn Vfs,
+        path: &ConcreteTarget,
+    ) -> Result<bool, MkError> {
+        state.update_state(vfs, path)?;
+        Ok(true)
+    }
+}
+
+/// Compares modification times, then hashes the files that look modified
+/// so that touching a file without changing it doesn't count. The default.
+pub struct MtimeThenHashChecker;
+
+impl FreshnessChecker for MtimeThenHashChecker {
+    fn check(
+        &self,
+        state: &UpdateState,
+        vfs: &dyn Vfs,
+        path: &ConcreteTarget,
+    ) -> Result<Result<(), RebuildReason>, MkError> {
+        state.is_up_to_date(vfs, path)
+    }
+
+    fn record(
+        &self,
+        state: &mut UpdateState,
+        vfs: &dyn Vfs,
+        path: &ConcreteTarget,
+    ) -> Result<bool, MkError> {
+        state.update_state(vfs, path)?;
+        state.update_hash(vfs, path)
+    }
+}
+
+/// Hashes every file, ignoring modification times altogether. Slower, but
+/// not fooled by clocks or tools that restore old timestamps.
+pub struct HashChecker;
+
+impl FreshnessChecker for HashChecker {
+    fn check(
+        &self,
+        state: &UpdateState,
+        vfs: &dyn Vfs,
+        path: &ConcreteTarget,
+    ) -> Result<Result<(), RebuildReason>, MkError> {
+        let Some(recorded) = state.recorded_hash(path) else {
+            return Ok(Err(RebuildReason::NotRecorded));
+        };
+        if content_hash(vfs, path)? == recorded {
+            Ok(Ok(()))
+        } else {
+            Ok(Err(RebuildReason::Modified))
+        }
+    }
+
+    fn record(
+        &self,
+        state: &mut UpdateState,
+        vfs: &dyn Vfs,
+        path: &ConcreteTarget,
+    ) -> Result<bool, MkError> {
+        state.update_state(vfs, path)?;
+        state.update_hash(vfs, path)
+    }
+}
+
+/// Treats every file as changed.
+pub struct AlwaysDirtyChecker;
+
+impl FreshnessChecker for AlwaysDirtyChecker {
+    fn check(
+        &self,
+        _state: &UpdateState,
+        _vfs: &dyn Vfs,
+        _path: &ConcreteTarget,
+    ) -> Result<Result<(), RebuildReason>, MkError> {
+        Ok(Err(RebuildReason::AlwaysDirty))
+    }
+
+    fn record(
+        &self,
+        state: &mut UpdateState,
+        vfs: &dyn Vfs,
+        path: &ConcreteTarget,
+    ) -> Result<bool, MkError> {
+        state.update_state(vfs, path)?;
+        state.update_hash(vfs, path)?;
+        Ok(true)
+    }
+}
+
+/// The built-in strategies, by name, for the command line and the
+/// `freshness:` rule option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Freshness {
+    Mtime,
+    #[default]
+    Auto,
+    Hash,
+    Always,
+}
+
+impl Freshness {
+    pub fn checker(self) -> &'static dyn FreshnessChecker {
+        match self {
+            Freshness::Mtime => &MtimeChecker,
+            Freshness::Auto => &MtimeThenHashChecker,
+            Freshness::Hash => &HashChecker,
+            Freshness::Always => &AlwaysDirtyChecker,
+        }
+    }
+}
+
+impl FromStr for Freshness {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "mtime" => Ok(Freshness::Mtime),
+            "auto" => Ok(Freshness::Auto),
+            "hash" => Ok(Freshness::Hash),
+            "always" => Ok(Freshness::Always),
+            _ => Err(format!(
+                "Unknown freshness '{text}', expected mtime, auto, hash or always"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Freshness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Freshness::Mtime => "mtime",
+            Freshness::Auto => "auto",
+            Freshness::Hash => "hash",
+            Freshness::Always => "always",
+        };
+        write!(f, "{name}")
+    }
+}
@@ -0,0 +1,250 @@
+use std::{path::Path, process::Command};
+
+use log::{info, warn};
+
+use crate::error::MkError;
+
+/// Expands text, with the given variables set on top of the others.
+pub(crate) type Expand<'a> = dyn Fn(&str, &[(&str, &str)]) -> Result<String, MkError> + 'a;
+
+/// Calls the built-in function `name`, as in `$(name args)`, with its
+/// arguments as they are written. They are expanded with `expand`, only
+/// once they are needed. Returns nothing if there is no such function, so
+/// that the text can be left to the shell.
+pub(crate) fn call(name: &str, args: &str, expand: &Expand) -> Option<Result<String, MkError>> {
+    let arity = match name {
+        "strip" | "sort" | "words" | "firstword" | "lastword" | "dir" | "notdir" | "suffix"
+        | "basename" | "wildcard" | "abspath" | "shell" | "error" | "warning" | "info" => 1,
+        "findstring" | "filter" | "filter-out" | "word" | "addsuffix" | "addprefix" => 2,
+        "subst" | "patsubst" | "wordlist" | "if" | "foreach" => 3,
+        "or" | "and" | "call" => usize::MAX,
+        _ => return None,
+    };
+    Some(evaluate(name, &split_args(args, arity), expand))
+}
+
+fn evaluate(name: &str, args: &[&str], expand: &Expand) -> Result<String, MkError> {
+    let expand_arg = |index: usize| {
+        args.get(index)
+            .map_or(Ok(String::new()), |arg| expand(arg, &[]))
+    };
+
+    // Lazy functions expand their arguments themselves
+    match name {
+        "foreach" => {
+            let variable = expand_arg(0)?;
+            let mut generated = Vec::new();
+            for word in expand_arg(1)?.split_whitespace() {
+                let text = args.get(2).copied().unwrap_or_default();
+                generated.push(expand(text, &[(variable.trim(), word)])?);
+            }
+            // Text over several lines, as templates expand to, is kept on
+            // lines of its own
+            let separator = if generated.iter().any(|text| text.contains('\n')) {
+                "\n"
+            } else {
+                " "
+            };
+            return Ok(generated.join(separator));
+        }
+        "call" => {
+            // `$(0)` is the name of the template, `$(1)` its first argument
+            let mut values = vec![expand_arg(0)?.trim().to_string()];
+            for index in 1..args.len() {
+                values.push(expand_arg(index)?);
+            }
+            let names: Vec<String> = (0..values.len()).map(|index| index.to_string()).collect();
+            let bindings: Vec<(&str, &str)> = names
+                .iter()
+                .zip(&values)
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .collect();
+            return expand(&format!("$({})", values[0]), &bindings);
+        }
+        "if" => {
+            return if expand_arg(0)?.trim().is_empty() {
+                expand_arg(2)
+            } else {
+                expand_arg(1)
+            };
+        }
+        "or" => {
+            for arg in args {
+                let value = expand(arg, &[])?;
+                if !value.trim().is_empty() {
+                    return Ok(value);
+                }
+            }
+            return Ok(String::new());
+        }
+        "and" => {
+            let mut value = String::new();
+            for arg in args {
+                value = expand(arg, &[])?;
+                if value.trim().is_empty() {
+                    return Ok(String::new());
+                }
+            }
+            return Ok(value);
+        }
+        _ => {}
+    }
+
+    let args: Vec<String> = args
+        .iter()
+        .map(|arg| expand(arg, &[]))
+        .collect::<Result<_, _>>()?;
+    let arg = |index: usize| args.get(index).map_or("", String::as_str);
+    let words = |index: usize| arg(index).split_whitespace();
+    let each = |map: &dyn Fn(&str) -> String| words(0).map(map).collect::<Vec<_>>().join(" ");
+    let number = |index: usize| {
+        arg(index).trim().parse::<usize>().map_err(|_| {
+            MkError::Parse(format!(
+                "Function '{name}' needs a number, not '{}'",
+                arg(index).trim()
+            ))
+        })
+    };
+    Ok(match name {
+        "subst" => arg(2).replace(arg(0), arg(1)),
+        "patsubst" => words(2)
+            .map(|word| {
+                let (pattern, replacement) = (arg(0).trim(), arg(1).trim());
+                match_pattern(pattern, word)
+                    .map_or(word.to_string(), |stem| replacement.replacen('%', stem, 1))
+            })
+            .collect::<Vec<_>>()
+            .join(" "),
+        "strip" => words(0).collect::<Vec<_>>().join(" "),
+        "findstring" => {
+            if arg(1).contains(arg(0)) {
+                arg(0).to_string()
+            } else {
+                String::new()
+            }
+        }
+        "filter" | "filter-out" => words(1)
+            .filter(|word| {
+                words(0).any(|pattern| match_pattern(pattern, word).is_some()) == (name == "filter")
+            })
+            .collect::<Vec<_>>()
+            .join(" "),
+        "sort" => {
+            let mut sorted: Vec<&str> = words(0).collect();
+            sorted.sort_unstable();
+            sorted.dedup();
+            sorted.join(" ")
+        }
+        "word" => {
+            let index = number(0)?;
+            if index == 0 {
+                return Err(MkError::Parse(
+                    "Function 'word' counts words from 1".to_string(),
+                ));
+            }
+            words(1).nth(index - 1).unwrap_or_default().to_string()
+        }
+        "wordlist" => {
+            let (start, end) = (number(0)?.max(1), number(1)?);
+            words(2)
+                .skip(start - 1)
+                .take((end + 1).saturating_sub(start))
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+        "words" => words(0).count().to_string(),
+        "firstword" => words(0).next().unwrap_or_default().to_string(),
+        "lastword" => words(0).last().unwrap_or_default().to_string(),
+        "dir" => each(&|word| match word.rfind('/') {
+            Some(slash) => word[..=slash].to_string(),
+            None => "./".to_string(),
+        }),
+        "notdir" => each(&|word| word.rsplit('/').next().unwrap_or(word).to_string()),
+        "suffix" => words(0)
+            .filter_map(|word| split_suffix(word).1)
+            .collect::<Vec<_>>()
+            .join(" "),
+        "basename" => each(&|word| split_suffix(word).0.to_string()),
+        "addsuffix" => words(1)
+            .map(|word| format!("{word}{}", arg(0).trim()))
+            .collect::<Vec<_>>()
+            .join(" "),
+        "addprefix" => words(1)
+            .map(|word| format!("{}{word}", arg(0).trim()))
+            .collect::<Vec<_>>()
+            .join(" "),
+        "wildcard" => {
+            let mut paths = Vec::new();
+            for pattern in words(0) {
+                let matches = glob::glob(pattern).map_err(|err| {
+                    MkError::Parse(format!("Invalid wildcard '{pattern}': {err}"))
+                })?;
+                paths.extend(matches.flatten().map(|path| path.display().to_string()));
+            }
+            paths.sort_unstable();
+            paths.join(" ")
+        }
+        "abspath" => {
+            let current = std::env::current_dir()?;
+            each(&|word| current.join(Path::new(word)).display().to_string())
+        }
+        "shell" => {
+            let output = Command::new("sh").arg("-c").arg(arg(0)).output()?;
+            let text = String::from_utf8_lossy(&output.stdout);
+            text.trim_end_matches('\n').replace('\n', " ")
+        }
+        "error" => return Err(MkError::Parse(arg(0).trim().to_string())),
+        "warning" => {
+            warn!("{}", arg(0).trim());
+            String::new()
+        }
+        "info" => {
+            info!("{}", arg(0).trim());
+            String::new()
+        }
+        _ => unreachable!("'{name}' is not a function"),
+    })
+}
+
+/// Splits the arguments of a function at the commas that aren't inside
+/// parentheses, into at most `count` of them.
+fn split_args(text: &str, count: usize) -> Vec<&str> {
+    let mut args = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+    for (index, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 && args.len() + 1 < count => {
+                args.push(&text[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    args.push(&text[start..]);
+    args
+}
+
+/// Matches a word against a pattern in which `%` stands for any text, and
+/// returns the text it stood for. Patterns without `%` only match
+/// themselves.
+fn match_pattern<'a>(pattern: &str, word: &'a str) -> Option<&'a str> {
+    match pattern.split_once('%') {
+        Some((prefix, suffix)) => word
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(suffix)),
+        None => (pattern == word).then_some(""),
+    }
+}
+
+/// Splits a path into what comes before its last `.` and the suffix
+/// starting at it, if the last part of the path has one.
+fn split_suffix(word: &str) -> (&str, Option<&str>) {
+    let name_start = word.rfind('/').map_or(0, |slash| slash + 1);
+    match word[name_start..].rfind('.') {
+        Some(dot) => (&word[..name_start + dot], Some(&word[name_start + dot..])),
+        None => (word, None),
+    }
+}
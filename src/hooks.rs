@@ -0,0 +1,136 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use log::{info, warn};
+use serde_json::{json, Value};
+
+use crate::{
+    executor::Invocation,
+    making::MakeOptions,
+    mkfile::{Hook, HooksConfig, Target},
+    MkError,
+};
+
+/// How long posting to a URL may take.
+const POST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs the `on_start` hooks for a build of the target, with the executor
+/// and environment of the build's options.
+pub fn started(hooks: &HooksConfig, target: &Target, options: &MakeOptions) {
+    let build = json!({
+        "event": "start",
+        "target": target.to_string(),
+    });
+    run(&hooks.on_start, &build, options);
+}
+
+/// Runs the `on_success` or `on_failure` hooks once a build of the target
+/// is over, with the executor and environment of the build's options.
+pub fn finished(
+    hooks: &HooksConfig,
+    target: &Target,
+    made: &Result<bool, MkError>,
+    took: Duration,
+    options: &MakeOptions,
+) {
+    let (hooks, build) = match made {
+        Ok(made) => (
+            &hooks.on_success,
+            json!({
+                "event": "success",
+                "target": target.to_string(),
+                "made": made,
+                "duration": took.as_secs_f64(),
+            }),
+        ),
+        Err(err) => (
+            &hooks.on_failure,
+            json!({
+                "event": "failure",
+                "target": target.to_string(),
+                "error": err.to_string(),
+                "duration": took.as_secs_f64(),
+            }),
+        ),
+    };
+    run(hooks, &build, options);
+}
+
+/// Runs the hooks one after another, passing them the build as JSON. A
+/// command reads it on stdin, with `MK_EVENT` and `MK_TARGET` set, and a
+/// URL gets it as the body of a POST. Hooks that fail are warned about,
+/// but don't fail the build.
+fn run(hooks: &[Hook], build: &Value, options: &MakeOptions) {
+    let body = build.to_string();
+    let event = build["event"].as_str().unwrap_or_default();
+    for hook in hooks {
+        let (ran, name) = match hook {
+            Hook::Command(text) => {
+                info!("Running {} hook '{}'", event, text);
+                let mut env = options.shared_env();
+                env.insert("MK_EVENT".to_string(), event.to_string());
+                let target = build["target"].as_str().unwrap_or_default();
+                env.insert("MK_TARGET".to_string(), target.to_string());
+                let invocation = Invocation {
+                    command: text.clone(),
+                    env,
+                    hermetic: options.hermetic,
+                    input: Some(body.clone().into_bytes()),
+                    ..Invocation::default()
+                };
+                let ran = match options.executor.run(&invocation) {
+                    Ok(status) if status.success() => Ok(()),
+                    Ok(status) => Err(status.to_string()),
+                    Err(err) => Err(err.to_string()),
+                };
+                (ran, text)
+            }
+            Hook::Post(url) => {
+                info!("Posting {} hook to '{}'", event, url);
+                (post(url, "application/json", &body), url)
+            }
+        };
+        if let Err(err) = ran {
+            warn!("Hook '{}' failed: {}", name, err);
+        }
+    }
+}
+
+/// Posts the body to an `http://` or `https://` URL with curl.
+pub(crate) fn post(url: &str, content_type: &str, body: &str) -> Result<(), String> {
+    let mut command = Command::new("curl");
+    command
+        .args(["--fail", "--silent", "--show-error", "--location"])
+        .args(["--max-time", &POST_TIMEOUT.as_secs().to_string()])
+        .args(["--header", &format!("Content-Type: {content_type}")])
+        .args(["--data-binary", "@-"])
+        .arg(url)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    feed(command, body)
+}
+
+/// Runs the command with the body on its stdin. If it fails, the error is
+/// what it wrote to stderr, when that was captured.
+fn feed(mut command: Command, body: &str) -> Result<(), String> {
+    let output = command
+        .stdin(Stdio::piped())
+        .spawn()
+        .and_then(|mut child| {
+            // Commands that don't read the body are fine too
+            let _ = child.stdin.take().unwrap().write_all(body.as_bytes());
+            child.wait_with_output()
+        })
+        .map_err(|err| err.to_string())?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Err(match stderr.trim() {
+        "" => output.status.to_string(),
+        reason => reason.to_string(),
+    })
+}
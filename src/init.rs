@@ -0,0 +1,171 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+/// The kinds of projects that `mk init` writes mkfiles for.
+#[derive(Debug, PartialEq)]
+pub enum Project {
+    Rust,
+    Node {
+        scripts: Vec<String>,
+        lockfile: bool,
+        /// The folder with the sources, if it is one of the usual ones.
+        sources: Option<String>,
+    },
+    C {
+        program: String,
+        cpp: bool,
+    },
+    Other,
+}
+
+#[derive(Deserialize)]
+struct PackageJson {
+    #[serde(default)]
+    scripts: BTreeMap<String, serde_json::Value>,
+}
+
+impl Project {
+    /// Finds out what kind of project is in the folder, by its manifest or,
+    /// for C and C++, by its sources. The folder's name names C programs.
+    pub fn detect(dir: &Path) -> Self {
+        if dir.join("Cargo.toml").exists() {
+            return Project::Rust;
+        }
+        if let Ok(text) = std::fs::read_to_string(dir.join("package.json")) {
+            let scripts = serde_json::from_str::<PackageJson>(&text)
+                .map(|package| package.scripts.into_keys().collect())
+                .unwrap_or_default();
+            return Project::Node {
+                scripts,
+                lockfile: dir.join("package-lock.json").exists(),
+                sources: ["src", "lib"]
+                    .into_iter()
+                    .find(|name| dir.join(name).is_dir())
+                    .map(String::from),
+            };
+        }
+        let extensions: Vec<String> = std::fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| {
+                let path = entry.path();
+                Some(path.extension()?.to_str()?.to_string())
+            })
+            .collect();
+        let has = |extension: &str| extensions.iter().any(|found| found == extension);
+        if has("c") || has("cpp") {
+            let program = std::fs::canonicalize(dir)
+                .unwrap_or_else(|_| PathBuf::from(dir))
+                .file_name()
+                .map(|name| name.to_string_lossy().replace(char::is_whitespace, "-"))
+                .unwrap_or_else(|| "app".to_string());
+            return Project::C {
+                program,
+                cpp: has("cpp"),
+            };
+        }
+        Project::Other
+    }
+
+    /// Writes a starter mkfile with `$build`, `$test` and `$clean` targets,
+    /// of which `$build` is the default. Virtual targets with dependencies
+    /// are only made when one of those changed, so Node packages are built
+    /// and tested again when their sources change, or every time if mk
+    /// doesn't know where they are.
+    pub fn mkfile(&self) -> String {
+        let mut out = String::from(".default: $build\n");
+        match self {
+            Project::Rust => out.push_str(
+                "\n# Builds the crate.\n$build:\n    cargo build\n\
+                 \n# Runs the tests.\n$test:\n    cargo test\n\
+                 \n# Removes build outputs.\n$clean:\n    cargo clean\n",
+            ),
+            Project::Node {
+                scripts,
+                lockfile,
+                sources,
+            } => {
+                let (dependencies, install) = match sources {
+                    Some(sources) => (format!(" node_modules ^{sources}"), ""),
+                    None => (String::new(), "    npm install\n"),
+                };
+                let run = |script: &str, command: &str| {
+                    let command = if scripts.iter().any(|s| s == script) {
+                        command.to_string()
+                    } else {
+                        format!("echo \"package.json has no {script} script\"")
+                    };
+                    format!("${script}:{dependencies}\n{install}    {command}\n")
+                };
+                let manifests = if *lockfile {
+                    "package.json package-lock.json"
+                } else {
+                    "package.json"
+                };
+                if sources.is_some() {
+                    let _ = write!(
+                        out,
+                        "\n# Installs the dependencies.\nnode_modules: {manifests}\n    \
+                         npm install\n    touch node_modules\n"
+                    );
+                }
+                let _ = write!(
+                    out,
+                    "\n# Builds the package.\n{}\
+                     \n# Runs the tests.\n{}\
+                     \n# Removes the dependencies.\n$clean:\n    rm -rf node_modules\n",
+                    run("build", "npm run build"),
+                    run("test", "npm test"),
+                );
+            }
+            Project::C { program, cpp } => {
+                let (extension, flags) = if *cpp {
+                    ("cpp", "CXXFLAGS")
+                } else {
+                    ("c", "CFLAGS")
+                };
+                let _ = write!(
+                    out,
+                    ".builtins: yes\n\
+                     \n{flags} = -Wall -O2\n\
+                     OBJECTS = $(patsubst %.{extension},%.o,$(wildcard *.{extension}))\n\
+                     \n# Builds the program.\n$build: {program}\n\
+                     \n{program}: $(OBJECTS)\n\
+                     \n# Runs the program.\n$test: $build\n    ./{program}\n\
+                     \n# Removes build outputs.\n$clean:\n    rm -f {program} $(OBJECTS)\n"
+                );
+            }
+            Project::Other => out.push_str(
+                "\n# Builds the project.\n$build:\n    echo \"Nothing to build yet\"\n\
+                 \n# Runs the tests.\n$test: $build\n    echo \"No tests yet\"\n\
+                 \n# Removes build outputs.\n$clean:\n    echo \"Nothing to remove yet\"\n",
+            ),
+        }
+        out
+    }
+}
+
+/// Returns the `.gitignore` text with the state file added, or `None` if it
+/// is ignored already.
+pub fn ignore_state(gitignore: &str, state: &str) -> Option<String> {
+    let entry = format!("/{}", state.trim_start_matches("./"));
+    let listed = gitignore
+        .lines()
+        .map(str::trim)
+        .any(|line| line == entry || line == entry.trim_start_matches('/'));
+    if listed {
+        return None;
+    }
+    let mut text = gitignore.to_string();
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    let _ = writeln!(text, "{entry}");
+    Some(text)
+}
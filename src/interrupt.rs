@@ -0,0 +1,145 @@
+use std::{
+    collections::BTreeSet,
+    process::Child,
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Mutex,
+    },
+};
+
+use log::warn;
+
+/// The signal that interrupted mk, or 0 if none did yet.
+static RECEIVED: AtomicI32 = AtomicI32::new(0);
+
+/// Process IDs of the commands running right now.
+static RUNNING: Mutex<BTreeSet<u32>> = Mutex::new(BTreeSet::new());
+
+/// Returns the signal that interrupted mk, if one did. Once interrupted, no
+/// more commands should be started.
+pub fn received() -> Option<i32> {
+    match RECEIVED.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+/// Handles SIGINT and SIGTERM from now on: the first one is passed on to
+/// the running commands so that the build stops, with mk left to clean up
+/// and save the state, and a second one exits right away.
+pub fn install() {
+    #[cfg(unix)]
+    if let Err(err) = unix::install() {
+        warn!("Failed to handle interrupts: {err}");
+    }
+}
+
+/// Keeps track of a running command, so that it is stopped if mk is
+/// interrupted, until the guard is dropped.
+pub fn track(child: &Child) -> Tracked {
+    let pid = child.id();
+    RUNNING.lock().unwrap().insert(pid);
+    // The signal may have been passed on just before the command started
+    if let Some(signal) = received() {
+        forward(pid, signal);
+    }
+    Tracked(pid)
+}
+
+pub struct Tracked(u32);
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        RUNNING.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Called outside of the signal handler when a signal is received, and by
+/// the terminal interface, which gets interrupts as keys.
+#[cfg_attr(not(any(unix, feature = "ui")), allow(dead_code))]
+pub(crate) fn interrupted(signal: i32) {
+    if RECEIVED.swap(signal, Ordering::SeqCst) != 0 {
+        std::process::exit(128 + signal);
+    }
+    warn!("Interrupted, stopping the build; interrupt again to exit right away");
+    for &pid in RUNNING.lock().unwrap().iter() {
+        forward(pid, signal);
+    }
+}
+
+#[cfg(unix)]
+fn forward(pid: u32, signal: i32) {
+    self::signal(pid, signal);
+}
+
+#[cfg(not(unix))]
+fn forward(_pid: u32, _signal: i32) {}
+
+/// Sends the signal to the process group the command leads, or to the
+/// command alone if it doesn't lead one.
+#[cfg(unix)]
+pub(crate) fn signal(pid: u32, signal: i32) {
+    let pid = pid as libc::pid_t;
+    // SAFETY: kill has no memory safety requirements
+    if unsafe { libc::kill(-pid, signal) } != 0 {
+        unsafe { libc::kill(pid, signal) };
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::{
+        fs::File,
+        io::{self, Read},
+        os::fd::FromRawFd,
+        sync::atomic::{AtomicI32, Ordering},
+        thread,
+    };
+
+    /// The end of the pipe the signal handler writes signals to.
+    static WAKE: AtomicI32 = AtomicI32::new(-1);
+
+    pub fn install() -> io::Result<()> {
+        let mut fds = [0; 2];
+        // SAFETY: pipe writes two file descriptors to the array
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        WAKE.store(fds[1], Ordering::SeqCst);
+        // SAFETY: the read end was just opened and nothing else owns it
+        let mut wake = unsafe { File::from_raw_fd(fds[0]) };
+        thread::spawn(move || {
+            let mut signal = [0];
+            while wake.read_exact(&mut signal).is_ok() {
+                super::interrupted(signal[0].into());
+            }
+        });
+        for signal in [libc::SIGINT, libc::SIGTERM] {
+            // SAFETY: the handler only does what is safe in a signal handler
+            if unsafe {
+                libc::signal(
+                    signal,
+                    handle as extern "C" fn(libc::c_int) as libc::sighandler_t,
+                )
+            } == libc::SIG_ERR
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Only writes the signal to the pipe, as little else may be done in
+    /// a signal handler.
+    extern "C" fn handle(signal: libc::c_int) {
+        let signal = signal as u8;
+        // SAFETY: write is async-signal-safe and the byte outlives the call
+        unsafe {
+            libc::write(
+                WAKE.load(Ordering::SeqCst),
+                (&signal as *const u8).cast(),
+                1,
+            )
+        };
+    }
+}
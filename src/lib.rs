@@ -0,0 +1,110 @@
+//! mk is like make, but mk.
+//!
+//! The library exposes everything the `mk` binary uses, so other tools can
+//! drive builds programmatically:
+//!
+//! ```no_run
+//! use mk::{
+//!     making::{make, MakeOptions, UpdateState},
+//!     mkfile::MkFile,
+//!     vfs::RealFs,
+//! };
+//! use std::path::Path;
+//!
+//! let text = std::fs::read_to_string("mkfile")?;
+//! let mkfile = MkFile::parse(&text)?;
+//! let mut state = UpdateState::load(&RealFs, Path::new(".mkstate.sexpr"));
+//!
+//! let target = mkfile.resolve("all");
+//! let made = make(&mkfile, &target, &mut state, &MakeOptions::default())?;
+//! println!("made: {made}");
+//!
+//! state.save(&RealFs, Path::new(".mkstate.sexpr"))?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+/// An HTTP API for making targets and following builds.
+pub mod api;
+/// Restoring outputs made by earlier builds.
+pub mod cache;
+/// Logs for CI systems, with sections and annotations.
+pub mod ci;
+/// Deleting the outputs rules produced.
+pub mod clean;
+/// A daemon that watches the project for changes, so that builds don't
+/// have to look at every file.
+pub mod daemon;
+/// Markdown and HTML documentation for mkfiles.
+pub mod docs;
+/// Checks for common environment problems.
+pub mod doctor;
+pub mod dotenv;
+/// The error type shared by the parser and the engine.
+pub mod error;
+/// How the engine runs rule commands.
+pub mod executor;
+/// Writing the rules of an mkfile for other build tools.
+pub mod export;
+/// Strategies for deciding whether files changed.
+pub mod freshness;
+mod functions;
+/// Commands and webhooks run as builds start and end.
+pub mod hooks;
+/// Starter mkfiles for new projects.
+pub mod init;
+pub mod interrupt;
+mod limits;
+/// Checks for non-portable or dangerous commands in rules.
+pub mod lint;
+/// Writing the output of each target to a file of its own.
+pub mod logs;
+/// The build engine: deciding what is out of date and making it.
+pub mod making;
+/// Prometheus metrics about builds.
+pub mod metrics;
+/// Parsing mkfiles into rules.
+pub mod mkfile;
+/// Newline-delimited JSON event stream.
+pub mod ndjson;
+/// Desktop notifications when builds are over.
+pub mod notify;
+/// Passing on the output of commands.
+pub mod output;
+/// WebAssembly plugins that add target kinds, freshness checkers and
+/// command runners.
+pub mod plugin;
+/// Recording what made each output, to explain it later.
+pub mod provenance;
+/// Listing and filtering targets for the query commands.
+pub mod query;
+/// Recording what release rules made, for release notes.
+pub mod release;
+/// Fetching mkfiles included from other places.
+pub mod remote;
+/// Build events and the reporters that receive them.
+pub mod report;
+/// Bundles for replaying how a build gets planned.
+pub mod repro;
+/// Finding dependencies from `#include`s and imports in sources.
+pub mod scan;
+mod script;
+/// Serving built folders over HTTP with live reload.
+pub mod serve;
+/// Backends that keep the update state between runs.
+pub mod store;
+/// Converting go-task Taskfiles into mkfiles.
+pub mod taskfile;
+/// Per-target timing summaries.
+pub mod timings;
+/// Chrome trace output.
+pub mod trace;
+/// A terminal interface showing targets as they are made.
+#[cfg(feature = "ui")]
+pub mod ui;
+/// Filesystem abstraction used by the engine.
+pub mod vfs;
+mod walk;
+/// Keeping repeated warnings from flooding the console.
+pub mod warnings;
+
+pub use error::MkError;
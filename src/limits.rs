@@ -0,0 +1,570 @@
+use std::{io, process::Command};
+
+use crate::executor::Limits;
+
+/// A control group that a command runs in, so that the kernel holds it to
+/// the limits of its rule. Commands going over their memory limit are
+/// killed, while CPU use is throttled.
+#[cfg(target_os = "linux")]
+pub struct Cgroup {
+    path: std::path::PathBuf,
+}
+
+#[cfg(target_os = "linux")]
+impl Cgroup {
+    /// Creates a group with the given limits, below the group mk runs in.
+    pub fn create(limits: &Limits) -> io::Result<Cgroup> {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            OnceLock,
+        };
+
+        static ROOT: OnceLock<Result<std::path::PathBuf, String>> = OnceLock::new();
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+        let root = ROOT
+            .get_or_init(|| delegate().map_err(|err| err.to_string()))
+            .as_ref()
+            .map_err(|err| io::Error::other(format!("Can't enforce limits: {err}")))?;
+        let path = root.join(format!(
+            "mk-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir(&path)?;
+        let cgroup = Cgroup { path };
+        if let Some(cpus) = limits.cpus {
+            cgroup.write("cpu.max", &format!("{} 100000", cpus as u64 * 100000))?;
+        }
+        if let Some(memory) = limits.memory {
+            cgroup.write("memory.max", &memory.to_string())?;
+            // Swapping would only let the command get slower instead
+            let _ = cgroup.write("memory.swap.max", "0");
+        }
+        Ok(cgroup)
+    }
+
+    fn write(&self, file: &str, value: &str) -> io::Result<()> {
+        std::fs::write(self.path.join(file), value)
+    }
+
+    /// Makes the command join the group as soon as it starts, before it can
+    /// run anything.
+    pub fn attach(&self, command: &mut Command) -> io::Result<()> {
+        use std::{io::Write, os::unix::process::CommandExt};
+
+        let procs = std::fs::OpenOptions::new()
+            .write(true)
+            .open(self.path.join("cgroup.procs"))?;
+        // SAFETY: between fork and exec the closure only makes a single
+        // write to a file opened beforehand, which doesn't allocate or take
+        // locks
+        unsafe {
+            command.pre_exec(move || (&procs).write_all(b"0"));
+        }
+        Ok(())
+    }
+
+    /// Does nothing, as the command joined the group as it started.
+    pub fn started(&self, _child: &std::process::Child) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Returns true if the kernel killed something in the group for going
+    /// over the memory limit.
+    pub fn out_of_memory(&self) -> bool {
+        std::fs::read_to_string(self.path.join("memory.events"))
+            .unwrap_or_default()
+            .lines()
+            .any(|line| matches!(line.split_once(' '), Some(("oom_kill", count)) if count != "0"))
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // Leftover background processes would keep the group alive
+        let _ = self.write("cgroup.kill", "1");
+        let _ = std::fs::remove_dir(&self.path);
+    }
+}
+
+/// Prepares the group mk runs in to have groups of its own. Processes can
+/// only be in groups without children, so mk moves itself into a child
+/// group first, which it leaves again on exit.
+#[cfg(target_os = "linux")]
+fn delegate() -> io::Result<std::path::PathBuf> {
+    let root = std::path::Path::new("/sys/fs/cgroup");
+    if !root.join("cgroup.controllers").exists() {
+        return Err(io::Error::other("cgroups v2 are not available"));
+    }
+    let own = std::fs::read_to_string("/proc/self/cgroup")?
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|path| root.join(path.trim_start_matches('/')))
+        .ok_or_else(|| io::Error::other("mk is not in a cgroups v2 group"))?;
+    let controllers = std::fs::read_to_string(own.join("cgroup.subtree_control"))?;
+    let delegation = Delegation {
+        enabled: !["cpu", "memory"]
+            .iter()
+            .all(|controller| controllers.split_whitespace().any(|on| on == *controller)),
+        own: own.clone(),
+    };
+    let supervisor = delegation.supervisor();
+    if !supervisor.exists() {
+        std::fs::create_dir(&supervisor)?;
+    }
+    let delegated = std::fs::write(supervisor.join("cgroup.procs"), "0").and_then(|()| {
+        std::fs::write(own.join("cgroup.subtree_control"), "+cpu +memory").map_err(|err| {
+            io::Error::other(format!(
+                "the cpu and memory controllers can't be delegated to '{}': {err}",
+                own.display()
+            ))
+        })
+    });
+    if let Err(err) = delegated {
+        delegation.undo();
+        return Err(err);
+    }
+
+    extern "C" fn undo_at_exit() {
+        if let Some(delegation) = DELEGATION.lock().ok().and_then(|mut held| held.take()) {
+            delegation.undo();
+        }
+    }
+    *DELEGATION.lock().unwrap() = Some(delegation);
+    // SAFETY: the function takes nothing and doesn't unwind
+    unsafe {
+        libc::atexit(undo_at_exit);
+    }
+    Ok(own)
+}
+
+/// The group mk moved itself out of to have groups of its own.
+#[cfg(target_os = "linux")]
+static DELEGATION: std::sync::Mutex<Option<Delegation>> = std::sync::Mutex::new(None);
+
+#[cfg(target_os = "linux")]
+struct Delegation {
+    own: std::path::PathBuf,
+    /// Whether mk turned the controllers on for the group's children.
+    enabled: bool,
+}
+
+#[cfg(target_os = "linux")]
+impl Delegation {
+    fn supervisor(&self) -> std::path::PathBuf {
+        self.own.join("mk-supervisor")
+    }
+
+    /// Moves mk back into its own group and removes the one it was in,
+    /// turning the controllers off again first if mk turned them on and no
+    /// other group uses them. Groups still in use are left for whoever is
+    /// in them.
+    fn undo(&self) {
+        let supervisor = self.supervisor();
+        if self.enabled {
+            let others = std::fs::read_dir(&self.own).map_or(true, |entries| {
+                entries
+                    .flatten()
+                    .any(|entry| entry.path().is_dir() && entry.path() != supervisor)
+            });
+            if !others {
+                let _ = std::fs::write(self.own.join("cgroup.subtree_control"), "-cpu -memory");
+            }
+        }
+        if std::fs::write(self.own.join("cgroup.procs"), "0").is_ok() {
+            let _ = std::fs::remove_dir(&supervisor);
+        }
+    }
+}
+
+/// A job object that a command runs in, standing in for a control group.
+/// Processes going over their memory limit fail to allocate more, while CPU
+/// use is capped.
+#[cfg(windows)]
+pub struct Cgroup {
+    job: windows::Handle,
+    memory: Option<u64>,
+}
+
+// SAFETY: job handles can be used from any thread
+#[cfg(windows)]
+unsafe impl Send for Cgroup {}
+
+#[cfg(windows)]
+impl Cgroup {
+    /// Creates a job object with the given limits.
+    pub fn create(limits: &Limits) -> io::Result<Cgroup> {
+        use windows::*;
+
+        // SAFETY: a job without attributes or a name
+        let job = unsafe { CreateJobObjectW(std::ptr::null(), std::ptr::null()) };
+        if job.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let cgroup = Cgroup {
+            job,
+            memory: limits.memory,
+        };
+        // Leftover background processes are killed with the job
+        let mut extended = ExtendedLimitInformation::default();
+        extended.basic.limit_flags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+        if let Some(memory) = limits.memory {
+            extended.basic.limit_flags |= JOB_OBJECT_LIMIT_PROCESS_MEMORY;
+            extended.process_memory_limit = usize::try_from(memory).unwrap_or(usize::MAX);
+        }
+        cgroup.set(JOB_OBJECT_EXTENDED_LIMIT_INFORMATION, &extended)?;
+        if let Some(cpus) = limits.cpus {
+            // In hundredths of a percent of all the machine's cores
+            let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+            let rate = CpuRateControlInformation {
+                control_flags: JOB_OBJECT_CPU_RATE_CONTROL_ENABLE
+                    | JOB_OBJECT_CPU_RATE_CONTROL_HARD_CAP,
+                cpu_rate: (cpus as usize * 10000 / cores).clamp(1, 10000) as u32,
+            };
+            cgroup.set(JOB_OBJECT_CPU_RATE_CONTROL_INFORMATION, &rate)?;
+        }
+        Ok(cgroup)
+    }
+
+    fn set<T>(&self, class: i32, information: &T) -> io::Result<()> {
+        // SAFETY: the information is the struct the class takes, and the
+        // job is open until dropped
+        let set = unsafe {
+            windows::SetInformationJobObject(
+                self.job,
+                class,
+                (information as *const T).cast(),
+                std::mem::size_of::<T>() as u32,
+            )
+        };
+        if set == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Does nothing, as processes can only be put in a job once started.
+    pub fn attach(&self, _command: &mut Command) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Puts the command in the job. Processes it started before then are
+    /// left out.
+    pub fn started(&self, child: &std::process::Child) -> io::Result<()> {
+        use std::os::windows::io::AsRawHandle;
+
+        // SAFETY: both handles are open for as long as the call
+        if unsafe { windows::AssignProcessToJobObject(self.job, child.as_raw_handle()) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Returns true if a process in the job used as much memory as it may,
+    /// which would have made its allocations fail.
+    pub fn out_of_memory(&self) -> bool {
+        let Some(memory) = self.memory else {
+            return false;
+        };
+        let mut extended = windows::ExtendedLimitInformation::default();
+        // SAFETY: the information is the struct the class fills in
+        let queried = unsafe {
+            windows::QueryInformationJobObject(
+                self.job,
+                windows::JOB_OBJECT_EXTENDED_LIMIT_INFORMATION,
+                (&mut extended as *mut windows::ExtendedLimitInformation).cast(),
+                std::mem::size_of::<windows::ExtendedLimitInformation>() as u32,
+                std::ptr::null_mut(),
+            )
+        };
+        // Allocations that failed may have been for more than was left
+        queried != 0 && extended.peak_process_memory_used as u64 >= memory.saturating_sub(1 << 20)
+    }
+}
+
+#[cfg(windows)]
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // SAFETY: the job is open, and isn't used again
+        unsafe {
+            windows::TerminateJobObject(self.job, 1);
+            windows::CloseHandle(self.job);
+        }
+    }
+}
+
+/// The parts of the Windows API for job objects.
+#[cfg(windows)]
+#[allow(non_snake_case)]
+mod windows {
+    use std::ffi::c_void;
+
+    pub type Handle = *mut c_void;
+
+    pub const JOB_OBJECT_EXTENDED_LIMIT_INFORMATION: i32 = 9;
+    pub const JOB_OBJECT_CPU_RATE_CONTROL_INFORMATION: i32 = 15;
+    pub const JOB_OBJECT_LIMIT_PROCESS_MEMORY: u32 = 0x100;
+    pub const JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE: u32 = 0x2000;
+    pub const JOB_OBJECT_CPU_RATE_CONTROL_ENABLE: u32 = 0x1;
+    pub const JOB_OBJECT_CPU_RATE_CONTROL_HARD_CAP: u32 = 0x4;
+
+    /// `JOBOBJECT_BASIC_LIMIT_INFORMATION`
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct BasicLimitInformation {
+        pub per_process_user_time_limit: i64,
+        pub per_job_user_time_limit: i64,
+        pub limit_flags: u32,
+        pub minimum_working_set_size: usize,
+        pub maximum_working_set_size: usize,
+        pub active_process_limit: u32,
+        pub affinity: usize,
+        pub priority_class: u32,
+        pub scheduling_class: u32,
+    }
+
+    /// `JOBOBJECT_EXTENDED_LIMIT_INFORMATION`
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct ExtendedLimitInformation {
+        pub basic: BasicLimitInformation,
+        pub io_counters: [u64; 6],
+        pub process_memory_limit: usize,
+        pub job_memory_limit: usize,
+        pub peak_process_memory_used: usize,
+        pub peak_job_memory_used: usize,
+    }
+
+    /// `JOBOBJECT_CPU_RATE_CONTROL_INFORMATION`, with its rate as a cap.
+    #[repr(C)]
+    pub struct CpuRateControlInformation {
+        pub control_flags: u32,
+        pub cpu_rate: u32,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        pub fn CreateJobObjectW(attributes: *const c_void, name: *const u16) -> Handle;
+        pub fn SetInformationJobObject(
+            job: Handle,
+            class: i32,
+            information: *const c_void,
+            length: u32,
+        ) -> i32;
+        pub fn QueryInformationJobObject(
+            job: Handle,
+            class: i32,
+            information: *mut c_void,
+            length: u32,
+            returned: *mut u32,
+        ) -> i32;
+        pub fn AssignProcessToJobObject(job: Handle, process: Handle) -> i32;
+        pub fn TerminateJobObject(job: Handle, exit_code: u32) -> i32;
+        pub fn CloseHandle(handle: Handle) -> i32;
+    }
+}
+
+/// Stands in for control groups on platforms that don't have them.
+#[cfg(not(any(target_os = "linux", windows)))]
+pub struct Cgroup;
+
+#[cfg(not(any(target_os = "linux", windows)))]
+impl Cgroup {
+    pub fn create(_limits: &Limits) -> io::Result<Cgroup> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Can't enforce limits: only supported on Linux with cgroups v2 and on Windows",
+        ))
+    }
+
+    pub fn attach(&self, _command: &mut Command) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn started(&self, _child: &std::process::Child) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn out_of_memory(&self) -> bool {
+        false
+    }
+}
+
+/// Open files a running job holds in mk, for the pipes its output comes
+/// through and the files it is set up with.
+const FILES_PER_JOB: u64 = 8;
+
+/// Processes a running job counts as, for its shell and the commands it
+/// starts.
+const PROCESSES_PER_JOB: u64 = 4;
+
+/// Open files and processes kept for mk itself, its threads and whatever
+/// else the user is running.
+const RESERVED: u64 = 64;
+
+/// The open-file and process limits of mk, which its jobs count against.
+/// Commands inherit them, so a limit left low also fails big link steps
+/// with "Too many open files".
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResourceLimits {
+    /// How many files mk may have open, or `None` if it is unlimited.
+    pub open_files: Option<u64>,
+    /// How many processes the user may run, or `None` if it is unlimited.
+    pub processes: Option<u64>,
+}
+
+impl ResourceLimits {
+    /// Reads the limits, first raising them as far as allowed when `raise`
+    /// is set.
+    pub fn current(raise: bool) -> ResourceLimits {
+        ResourceLimits {
+            open_files: rlimit::get(rlimit::OPEN_FILES, raise),
+            processes: rlimit::get(rlimit::PROCESSES, raise),
+        }
+    }
+
+    /// Returns how many jobs can run at once without going over the limits.
+    pub fn max_jobs(&self) -> usize {
+        let jobs = |limit: Option<u64>, per_job: u64| {
+            limit.map(|limit| (limit.saturating_sub(RESERVED) / per_job).max(1))
+        };
+        [
+            jobs(self.open_files, FILES_PER_JOB),
+            jobs(self.processes, PROCESSES_PER_JOB),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+        .map_or(usize::MAX, |jobs| {
+            usize::try_from(jobs).unwrap_or(usize::MAX)
+        })
+    }
+
+    /// Returns true if starting `jobs` more jobs would leave mk too close to
+    /// its open-file limit. Files can be held by more than the jobs, like
+    /// cache entries being copied, so this counts what is actually open.
+    pub fn near_open_files_limit(&self, jobs: usize) -> bool {
+        match (self.open_files, open_files()) {
+            (Some(limit), Some(open)) => open + jobs as u64 * FILES_PER_JOB + RESERVED / 2 > limit,
+            _ => false,
+        }
+    }
+}
+
+/// Returns how many files mk has open, if the platform tells.
+fn open_files() -> Option<u64> {
+    let dir = if cfg!(target_os = "linux") {
+        "/proc/self/fd"
+    } else {
+        "/dev/fd"
+    };
+    std::fs::read_dir(dir)
+        .ok()
+        .map(|entries| entries.count() as u64)
+}
+
+/// Returns how many bytes of memory new processes can use without the
+/// system swapping, if the platform tells.
+pub fn available_memory() -> Option<u64> {
+    meminfo("MemAvailable:")
+}
+
+/// Returns true if the machine is overloaded: on average, more processes
+/// are ready to run than there are cores, or almost no memory is left.
+/// The load average trails behind by a few seconds.
+pub fn under_pressure(cores: usize) -> bool {
+    let overloaded = load_above(cores as f64);
+    let out_of_memory = match (available_memory(), meminfo("MemTotal:")) {
+        (Some(available), Some(total)) => available < total / 20,
+        _ => false,
+    };
+    overloaded || out_of_memory
+}
+
+/// Returns true if the load average is above `max`. Where there is no
+/// load average, it never is.
+pub fn load_above(max: f64) -> bool {
+    load_average().is_some_and(|load| load > max)
+}
+
+/// Reads a value in bytes from `/proc/meminfo`.
+fn meminfo(field: &str) -> Option<u64> {
+    std::fs::read_to_string("/proc/meminfo")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix(field))
+        .and_then(|value| {
+            value
+                .trim()
+                .trim_end_matches("kB")
+                .trim()
+                .parse::<u64>()
+                .ok()
+        })
+        .map(|kilobytes| kilobytes * 1024)
+}
+
+/// Returns the number of processes ready to run, averaged over the last
+/// minute.
+#[cfg(unix)]
+fn load_average() -> Option<f64> {
+    let mut load = [0.0];
+    // SAFETY: getloadavg writes at most as many samples as asked for
+    (unsafe { libc::getloadavg(load.as_mut_ptr(), 1) } == 1).then_some(load[0])
+}
+
+#[cfg(not(unix))]
+fn load_average() -> Option<f64> {
+    None
+}
+
+#[cfg(unix)]
+mod rlimit {
+    pub use libc::{RLIMIT_NOFILE as OPEN_FILES, RLIMIT_NPROC as PROCESSES};
+
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    type Resource = libc::__rlimit_resource_t;
+    #[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+    type Resource = libc::c_int;
+
+    /// Returns the soft limit on the resource, after raising it to the hard
+    /// limit when `raise` is set.
+    pub fn get(resource: Resource, raise: bool) -> Option<u64> {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: getrlimit only writes to the struct it is given
+        if unsafe { libc::getrlimit(resource, &mut limit) } != 0 {
+            return None;
+        }
+        if raise && limit.rlim_cur < limit.rlim_max {
+            let raised = libc::rlimit {
+                rlim_cur: limit.rlim_max,
+                rlim_max: limit.rlim_max,
+            };
+            // SAFETY: setrlimit only reads the struct it is given. It fails
+            // without changing anything when the hard limit can't be used,
+            // like on macOS when it is unlimited.
+            if unsafe { libc::setrlimit(resource, &raised) } == 0 {
+                limit = raised;
+            }
+        }
+        // rlim_t is narrower than u64 on some platforms
+        #[allow(clippy::unnecessary_cast)]
+        (limit.rlim_cur != libc::RLIM_INFINITY).then_some(limit.rlim_cur as u64)
+    }
+}
+
+/// Stands in for resource limits on platforms that don't have them.
+#[cfg(not(unix))]
+mod rlimit {
+    pub const OPEN_FILES: () = ();
+    pub const PROCESSES: () = ();
+
+    pub fn get(_resource: (), _raise: bool) -> Option<u64> {
+        None
+    }
+}
@@ -0,0 +1,345 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    str::FromStr,
+};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::mkfile::{MkFile, Target};
+
+/// How serious a finding is. Errors make `mk lint` fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Off,
+    Warning,
+    Error,
+}
+
+impl FromStr for Severity {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "off" => Ok(Severity::Off),
+            "warning" => Ok(Severity::Warning),
+            "error" => Ok(Severity::Error),
+            _ => Err(format!(
+                "Unknown level '{text}', expected off, warning or error"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Severity::Off => "off",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// What a lint looks at.
+enum Check {
+    /// Returns what is wrong with a rule command, if anything.
+    Command(fn(&str) -> Option<String>),
+    /// Returns the targets whose rules are wrong in how they fit together,
+    /// with what is wrong.
+    Graph(fn(&MkFile) -> Vec<(Target, String)>),
+}
+
+/// A check run on every rule command, or on the rules as a whole.
+pub struct Lint {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// How serious findings are unless configured otherwise.
+    pub severity: Severity,
+    check: Check,
+}
+
+/// Every lint, by name.
+pub const LINTS: &[Lint] = &[
+    Lint {
+        name: "bashism",
+        description: "bash features that break when sh is another shell, like dash",
+        severity: Severity::Warning,
+        check: Check::Command(check_bashism),
+    },
+    Lint {
+        name: "dangerous-rm",
+        description: "rm -r on / or on paths that become / when a variable is empty",
+        severity: Severity::Error,
+        check: Check::Command(check_dangerous_rm),
+    },
+    Lint {
+        name: "duplicate-rule",
+        description: "targets with more than one rule, merged with --merge-duplicates",
+        severity: Severity::Warning,
+        check: Check::Graph(check_duplicate_rule),
+    },
+    Lint {
+        name: "missing-source",
+        description: "dependencies that no rule makes and that aren't on disk",
+        severity: Severity::Error,
+        check: Check::Graph(check_missing_source),
+    },
+    Lint {
+        name: "undefined-variable",
+        description: "$(NAME) references to variables that aren't set",
+        severity: Severity::Warning,
+        check: Check::Graph(check_undefined_variable),
+    },
+    Lint {
+        name: "unquoted-args",
+        description: "$@ or $* outside double quotes, which splits arguments on spaces",
+        severity: Severity::Warning,
+        check: Check::Command(check_unquoted_args),
+    },
+    Lint {
+        name: "unreachable-virtual",
+        description: "virtual targets that rules depend on but that no rule defines",
+        severity: Severity::Error,
+        check: Check::Graph(check_unreachable_virtual),
+    },
+    Lint {
+        name: "unused-rule",
+        description: "rules for files that no rule depends on and that aren't the default",
+        severity: Severity::Warning,
+        check: Check::Graph(check_unused_rule),
+    },
+];
+
+/// Parses a `NAME=LEVEL` setting from the command line.
+pub fn parse_level(text: &str) -> Result<(String, Severity), String> {
+    let (name, level) = text
+        .split_once('=')
+        .ok_or_else(|| format!("Expected NAME=LEVEL, got '{text}'"))?;
+    if !LINTS.iter().any(|lint| lint.name == name) {
+        return Err(format!("Unknown lint '{name}'"));
+    }
+    Ok((name.to_string(), level.parse()?))
+}
+
+/// Something a lint found in a rule.
+#[derive(Debug)]
+pub struct Finding {
+    pub lint: &'static str,
+    pub severity: Severity,
+    pub target: Target,
+    pub message: String,
+}
+
+/// Checks every rule and its commands, with lint levels changed by
+/// `levels`. Findings are sorted by target.
+pub fn lint(mkfile: &MkFile, levels: &BTreeMap<String, Severity>) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    for lint in LINTS {
+        let severity = levels.get(lint.name).copied().unwrap_or(lint.severity);
+        if severity == Severity::Off {
+            continue;
+        }
+        let found: Vec<(Target, String)> = match lint.check {
+            Check::Command(check) => mkfile
+                .targets()
+                .flat_map(|target| {
+                    let messages = mkfile.commands(target).iter().filter_map(|c| check(c));
+                    messages.map(|message| (target.clone(), message))
+                })
+                .collect(),
+            Check::Graph(check) => check(mkfile),
+        };
+        findings.extend(found.into_iter().map(|(target, message)| Finding {
+            lint: lint.name,
+            severity,
+            target,
+            message,
+        }));
+    }
+    findings.sort_by_key(|finding| finding.target.to_string());
+    findings
+}
+
+/// Returns the targets that rules depend on, with the rules that do.
+fn dependents(mkfile: &MkFile) -> Vec<(&Target, &Target)> {
+    let mut dependents: Vec<(&Target, &Target)> = mkfile
+        .targets()
+        .flat_map(|target| {
+            let dependencies = mkfile.dependencies(target).iter();
+            dependencies.map(move |dependency| (dependency, target))
+        })
+        .collect();
+    dependents.sort_by_key(|(dependency, target)| (dependency.to_string(), target.to_string()));
+    dependents
+}
+
+fn check_duplicate_rule(mkfile: &MkFile) -> Vec<(Target, String)> {
+    mkfile
+        .duplicates()
+        .iter()
+        .map(|target| {
+            let message = "has more than one rule, which were merged".to_string();
+            (target.clone(), message)
+        })
+        .collect()
+}
+
+fn check_missing_source(mkfile: &MkFile) -> Vec<(Target, String)> {
+    dependents(mkfile)
+        .into_iter()
+        .filter(|(dependency, _)| {
+            let Target::Concrete(path) = dependency else {
+                return false;
+            };
+            !mkfile.has_target(dependency)
+                && mkfile.producer(dependency).is_none()
+                && !path.pathbuf().exists()
+        })
+        .map(|(dependency, target)| {
+            let message =
+                format!("depends on '{dependency}', which no rule makes and isn't on disk");
+            (target.clone(), message)
+        })
+        .collect()
+}
+
+fn check_undefined_variable(mkfile: &MkFile) -> Vec<(Target, String)> {
+    lazy_static! {
+        // Variables are written in capitals, unlike the commands that shell
+        // substitutions run
+        static ref REFERENCE_RE: Regex = Regex::new(r"\$\(([A-Z_][A-Z0-9_]*)\)").unwrap();
+    }
+    let mut findings = Vec::new();
+    for target in mkfile.targets() {
+        let dependencies = mkfile.dependencies(target).iter().map(|d| d.to_string());
+        let names: HashSet<String> = dependencies
+            .chain(mkfile.commands(target).iter().cloned())
+            .flat_map(|text| {
+                let names = REFERENCE_RE
+                    .captures_iter(&text)
+                    .map(|cap| cap[1].to_string());
+                names.collect::<Vec<_>>()
+            })
+            .collect();
+        let mut names: Vec<String> = names.into_iter().collect();
+        names.sort_unstable();
+        for name in names {
+            findings.push((target.clone(), format!("uses '$({name})', which isn't set")));
+        }
+    }
+    findings
+}
+
+fn check_unreachable_virtual(mkfile: &MkFile) -> Vec<(Target, String)> {
+    dependents(mkfile)
+        .into_iter()
+        .filter(|(dependency, _)| {
+            matches!(dependency, Target::Virtual(_)) && !mkfile.has_target(dependency)
+        })
+        .map(|(dependency, target)| {
+            let message = format!("depends on '{dependency}', which no rule defines");
+            (target.clone(), message)
+        })
+        .collect()
+}
+
+fn check_unused_rule(mkfile: &MkFile) -> Vec<(Target, String)> {
+    let used: HashSet<&Target> = dependents(mkfile)
+        .into_iter()
+        .map(|(dependency, _)| dependency)
+        .chain(mkfile.default_target())
+        .collect();
+    mkfile
+        .targets()
+        .filter(|target| matches!(target, Target::Concrete(_)) && !used.contains(target))
+        .map(|target| {
+            let message = "isn't a dependency of any rule, nor the default target".to_string();
+            (target.clone(), message)
+        })
+        .collect()
+}
+
+fn check_bashism(command: &str) -> Option<String> {
+    lazy_static! {
+        static ref BASHISMS: Vec<(Regex, &'static str)> = [
+            (r"\[\[", "'[[ ]]' tests (use '[ ]')"),
+            (r"(^|[;&|(]\s*)function\s+\w", "the 'function' keyword"),
+            (r"(^|[;&|(]\s*)source\s", "'source' (use '.')"),
+            (r"&>", "'&>' redirection (use '> file 2>&1')"),
+            (r"<<<", "here-strings"),
+            (r"\$\{[^}]*//", "pattern substitution in '${...}'"),
+            (r"(^|\s)\w+=\(", "arrays"),
+            (r"\[ [^]]* == ", "'==' in '[ ]' tests (use '=')"),
+            (r"(^|[^$\w])\{\w*,[\w,]*\}", "brace expansion"),
+        ]
+        .into_iter()
+        .map(|(pattern, name)| (Regex::new(pattern).unwrap(), name))
+        .collect();
+    }
+    BASHISMS
+        .iter()
+        .find(|(pattern, _)| pattern.is_match(command))
+        .map(|(_, name)| format!("uses {name}, which sh doesn't have everywhere"))
+}
+
+fn check_dangerous_rm(command: &str) -> Option<String> {
+    lazy_static! {
+        // A variable right before a slash, e.g. `$OUT/` or `"${OUT}"/*`
+        static ref VARIABLE_ROOT: Regex =
+            Regex::new(r#"^"?\$(\{\w+\}|\w+)"?/"#).unwrap();
+    }
+    for part in command.split([';', '&', '|']) {
+        let mut words = part.split_whitespace();
+        if words.next() != Some("rm") {
+            continue;
+        }
+        let (flags, paths): (Vec<_>, Vec<_>) = words.partition(|word| word.starts_with('-'));
+        let recursive = flags.iter().any(|flag| {
+            *flag == "--recursive" || (!flag.starts_with("--") && flag.contains(['r', 'R']))
+        });
+        if !recursive {
+            continue;
+        }
+        for path in paths {
+            let unquoted = path.trim_matches(['"', '\'']);
+            if matches!(unquoted, "/" | "/*" | "~" | "~/" | "~/*") {
+                return Some(format!(
+                    "'rm -r {path}' deletes far more than build outputs"
+                ));
+            }
+            if VARIABLE_ROOT.is_match(path) {
+                return Some(format!(
+                    "'rm -r {path}' deletes from / if the variable is empty"
+                ));
+            }
+        }
+    }
+    None
+}
+
+fn check_unquoted_args(command: &str) -> Option<String> {
+    let mut single = false;
+    let mut double = false;
+    let mut chars = command.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if !single => {
+                chars.next();
+            }
+            '\'' if !double => single = !single,
+            '"' if !single => double = !double,
+            '$' if !single && !double => {
+                if let Some(&name @ ('@' | '*')) = chars.peek() {
+                    return Some(format!(
+                        "unquoted '${name}' splits arguments containing spaces (use \"$@\")"
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
@@ -0,0 +1,210 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::error;
+
+use crate::{
+    executor::{CommandExecutor, Invocation, Redirect, Status},
+    report::{Event, Reporter},
+};
+
+/// How many lines at the end of a failed target's log are shown.
+const TAIL_LINES: usize = 10;
+
+struct Logs {
+    dir: PathBuf,
+    /// When the build started, as the files name it.
+    stamp: String,
+    /// The files of the targets that are being made.
+    open: Mutex<HashMap<String, File>>,
+}
+
+impl Logs {
+    fn path(&self, target: &str) -> PathBuf {
+        let name: String = target
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+                _ => '_',
+            })
+            .collect();
+        self.dir.join(format!("{name}-{}.log", self.stamp))
+    }
+
+    /// Appends to the target's file, opening it if need be.
+    fn write(&self, target: &str, buffer: &[u8]) -> io::Result<()> {
+        let mut open = self.open.lock().unwrap();
+        let file = match open.get_mut(target) {
+            Some(file) => file,
+            None => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.path(target))?;
+                open.entry(target.to_string()).or_insert(file)
+            }
+        };
+        file.write_all(buffer)
+    }
+
+    /// Closes the target's file. Returns true if it had one.
+    fn close(&self, target: &str) -> bool {
+        self.open.lock().unwrap().remove(target).is_some()
+    }
+}
+
+/// Writes the output of each target's commands to a file of its own in a
+/// folder, named after the target and when the build started, instead of
+/// to the console, so that the output of targets made at the same time
+/// stays apart and is still there after the build. Targets that fail are
+/// pointed at their file, with its last lines. Commands are run with an
+/// inner executor, with their output sent to the files.
+#[derive(Clone)]
+pub struct LogDir {
+    logs: Arc<Logs>,
+    inner: Arc<dyn CommandExecutor>,
+}
+
+impl LogDir {
+    /// Creates the folder, if it doesn't exist, for a build that starts now
+    /// and runs its commands with the inner executor.
+    pub fn new(dir: &Path, inner: Arc<dyn CommandExecutor>) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(LogDir {
+            logs: Arc::new(Logs {
+                dir: dir.to_path_buf(),
+                stamp: stamp(SystemTime::now()),
+                open: Mutex::default(),
+            }),
+            inner,
+        })
+    }
+
+    /// Returns the file the target's output is written to.
+    pub fn path(&self, target: &str) -> PathBuf {
+        self.logs.path(target)
+    }
+
+    fn note(&self, target: &str, line: &str) {
+        let _ = self.logs.write(target, format!("{line}\n").as_bytes());
+    }
+}
+
+/// Formats the time as `YYYYMMDD-HHMMSS`, in UTC.
+pub fn stamp(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let (days, time) = ((seconds / 86_400) as i64, seconds % 86_400);
+    // Days since 1970 to the civil date, counting in 400 year eras that
+    // start in March, so that leap days come last
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}{month:02}{day:02}-{:02}{:02}{:02}",
+        time / 3_600,
+        time % 3_600 / 60,
+        time % 60
+    )
+}
+
+impl Reporter for LogDir {
+    fn report(&self, event: &Event) {
+        match event {
+            Event::CommandStarted(target, command) => {
+                self.note(&target.to_string(), &format!("$ {command}"));
+            }
+            Event::Retrying(target, attempt, err) => {
+                self.note(
+                    &target.to_string(),
+                    &format!("Failed, starting attempt {attempt}: {err}"),
+                );
+            }
+            Event::TargetFinished(target, _) => {
+                self.logs.close(&target.to_string());
+            }
+            Event::TargetFailed(target, _) => {
+                let target = target.to_string();
+                if !self.logs.close(&target) {
+                    return;
+                }
+                let path = self.path(&target);
+                let text = fs::read_to_string(&path).unwrap_or_default();
+                let lines: Vec<&str> = text.lines().collect();
+                let tail = lines[lines.len().saturating_sub(TAIL_LINES)..].join("\n");
+                error!(
+                    "Output of '{}' is in '{}', ending with:\n{}",
+                    target,
+                    path.display(),
+                    tail
+                );
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Sends command output to the file of its target.
+#[derive(Clone)]
+struct LogWriter {
+    logs: Arc<Logs>,
+    target: String,
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        self.logs.write(&self.target, buffer).map(|()| buffer.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl LogDir {
+    /// Sends the output of a command run for a target to its file, whose
+    /// name needn't be in front of every line. Other commands are left as
+    /// they are.
+    fn redirect(&self, invocation: &Invocation) -> Invocation {
+        let Some(target) = &invocation.target else {
+            return invocation.clone();
+        };
+        let writer = LogWriter {
+            logs: self.logs.clone(),
+            target: target.clone(),
+        };
+        Invocation {
+            prefix: None,
+            redirect: Some(Redirect::to(writer.clone(), writer)),
+            ..invocation.clone()
+        }
+    }
+}
+
+impl CommandExecutor for LogDir {
+    fn run(&self, invocation: &Invocation) -> io::Result<Status> {
+        self.inner.run(&self.redirect(invocation))
+    }
+
+    fn capture(&self, invocation: &Invocation) -> io::Result<(Status, Vec<u8>)> {
+        self.inner.capture(&self.redirect(invocation))
+    }
+}
@@ -1,55 +1,1389 @@
-use clap::Parser;
-use log::{error, info};
-use making::make;
-use simple_logger::SimpleLogger;
+use std::{
+    collections::BTreeMap,
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-mod making;
-mod mkfile;
+use clap::{parser::ValueSource, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use clap_complete::{
+    engine::{ArgValueCandidates, CompletionCandidate},
+    env::Shells,
+    CompleteEnv, Shell,
+};
+use log::{error, info, warn, LevelFilter};
+use mk::{
+    api,
+    cache::{self, Cache},
+    ci, clean, daemon, docs, doctor, executor, export, freshness, hooks, init, interrupt, lint,
+    logs,
+    making::{self, make, Jobs, MakeOptions},
+    metrics, mkfile, ndjson, notify, output, plugin, provenance, query, release, report, repro,
+    serve, store, taskfile, timings, trace, vfs, warnings,
+};
+use simple_logger::SimpleLogger;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about=None)]
 #[command(propagate_version = true)]
 struct Cli {
-    /// Path to the mkfile to use.
+    /// Change into this folder before doing anything else, so that the
+    /// mkfile, the state file, targets and other paths are found from
+    /// there.
+    #[arg(short = 'C', long, value_name = "DIR")]
+    directory: Option<PathBuf>,
+    /// Path to the mkfile to use. Without an `mkfile`, `mk.toml`, `mk.yaml`
+    /// or `mk.yml` is used instead.
     #[arg(short, long, default_value = "mkfile")]
     mkfile: String,
     /// Path to the update state file to use.
     #[arg(short, long, default_value = ".mkstate.sexpr")]
     state: String,
-    /// The target to make
-    #[arg(default_value = "all")]
-    target: String,
+    /// Apply the settings of the named profile from the mkfile.
+    #[arg(long)]
+    profile: Option<String>,
+    /// Load variables from this `.env` file instead of the one the mkfile
+    /// names. They can be used in the mkfile and are set for every command.
+    #[arg(long)]
+    env_file: Option<PathBuf>,
+    /// Don't fetch included mkfiles, only use the copies fetched before.
+    #[arg(long)]
+    offline: bool,
+    /// Merge the dependencies of rules for the same target instead of
+    /// failing, as long as only one of them has commands.
+    #[arg(long)]
+    merge_duplicates: bool,
+    /// How many targets may run their commands at the same time, or `auto`
+    /// to pick from the CPUs and memory available and hold back while the
+    /// machine is overloaded. Overrides the profile.
+    #[arg(short, long)]
+    jobs: Option<Jobs>,
+    /// Don't start new jobs while the load average is above this, unless
+    /// none are running, to keep the machine responsive during big builds.
+    #[arg(short = 'l', long = "load-average", value_name = "LOAD", value_parser = parse_load)]
+    max_load: Option<f64>,
+    /// Run commands with only the variables rules list under `env_inputs:`,
+    /// those the profile sets, and a fixed PATH, so that builds don't
+    /// depend on the machine's environment.
+    #[arg(long)]
+    hermetic: bool,
+    /// How long the commands of a rule may run before they are killed, such
+    /// as `10m`, for rules without a `timeout:` of their own.
+    #[arg(long, value_parser = parse_duration)]
+    timeout: Option<Duration>,
+    /// Prefix each line of command output with the name of its target. This
+    /// is the default when running more than one job.
+    #[arg(long)]
+    prefix_output: bool,
+    /// How the output of commands that run at the same time is kept apart:
+    /// none streams it as it comes, line prefixes each line with the name of
+    /// its target, and target holds it until the target is done and prints
+    /// it in one piece. The default is line when running more than one job.
+    #[arg(long, value_name = "MODE", conflicts_with = "prefix_output")]
+    output_sync: Option<output::OutputSync>,
+    /// Show a progress display instead of log lines when attached to a terminal.
+    #[arg(long)]
+    progress: bool,
+    /// Show a terminal interface with the tree of targets, the output of
+    /// each and the ones that failed, when attached to a terminal.
+    #[arg(long)]
+    ui: bool,
+    /// Write the log for a CI system, github or gitlab, with the output of
+    /// each target in a collapsible section and failures as annotations.
+    #[arg(long, value_name = "CI", conflicts_with = "ui")]
+    ci: Option<ci::Ci>,
+    /// Write the output of each target's commands to a file of its own in
+    /// this folder, named after the target and when the build started,
+    /// instead of to the console.
+    #[arg(long, value_name = "DIR")]
+    log_dir: Option<PathBuf>,
+    /// Show a desktop notification when the build is over, with the target
+    /// and how long it took.
+    #[arg(long)]
+    notify: bool,
+    /// Explain why each target is being made.
+    #[arg(long)]
+    explain: bool,
+    /// Show what would be made, and why, without running any commands.
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+    /// How to tell whether files changed: mtime, auto (mtime, then content
+    /// hash), hash or always.
+    #[arg(long, default_value_t = freshness::Freshness::Auto)]
+    freshness: freshness::Freshness,
+    /// Take symbolic links as they are instead of following them, so that
+    /// a link only changes when it is pointed somewhere else.
+    #[arg(long)]
+    no_follow_symlinks: bool,
+    /// Retry targets that keep switching between failing and succeeding,
+    /// and list them once the build is over.
+    #[arg(long)]
+    quarantine_flaky: bool,
+    /// Hold commands to the cpus and memory their rules declare, using
+    /// cgroups v2 on Linux and job objects on Windows. Commands going over
+    /// their memory are killed, or fail to allocate more on Windows.
+    #[arg(long)]
+    enforce_limits: bool,
+    /// Raise the open file and process limits as far as they go, for
+    /// builds with many jobs or commands that open many files.
+    #[arg(long)]
+    raise_limits: bool,
+    /// If another mk is running here, wait for it to finish instead of
+    /// failing.
+    #[arg(long)]
+    wait_for_lock: bool,
+    /// Store outputs in this folder, and restore them from it instead of
+    /// running commands that were run the same way before.
+    #[arg(long)]
+    cache: Option<PathBuf>,
+    /// Share cached outputs through this cache too: an http:// or https://
+    /// URL, an s3://BUCKET/PREFIX URL or a folder.
+    #[arg(long)]
+    cache_url: Option<String>,
+    /// Write what went into each output next to it, as OUTPUT.mk.json,
+    /// for why-built.
+    #[arg(long)]
+    provenance: bool,
+    /// Where release rules record what they made, one JSON object per
+    /// line.
+    #[arg(long, default_value = "mk-release.jsonl")]
+    release_manifest: PathBuf,
+    /// Print how long each target took once the build is over.
+    #[arg(long)]
+    timings: bool,
+    /// Write a Chrome trace of the build to this file.
+    #[arg(long)]
+    trace_file: Option<PathBuf>,
+    /// Stream build events as newline-delimited JSON to this file, or to
+    /// stdout instead of the usual logs if no file is given.
+    #[arg(long, value_name = "FILE", num_args = 0..=1, default_missing_value = "-")]
+    events_json: Option<String>,
+    /// Push metrics about the build to a Prometheus Pushgateway once it is
+    /// over, at a URL such as `http://gateway:9091/metrics/job/mk`.
+    #[arg(long, value_name = "URL")]
+    metrics_push: Option<String>,
+    /// Given without arguments of its own, a subcommand makes the mkfile's
+    /// rule of the same name instead if it has one.
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// The target to make, the mkfile's default if not given, and variables
+    /// that override the mkfile's, as `NAME=VALUE`.
+    #[arg(value_name = "TARGET|NAME=VALUE", add = ArgValueCandidates::new(target_candidates))]
+    args: Vec<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check the environment for common setup problems and suggest fixes.
+    Doctor,
+    /// Watch the current folder for changes until killed, usually in the
+    /// background. Builds then ask it what changed instead of looking at
+    /// every file, and it makes the builds that only set the target,
+    /// variables, -j, -n, --explain, --prefix-output or --wait-for-lock
+    /// itself, with the parsed mkfile and the state it keeps in memory.
+    /// Other flags are taken from the daemon's own command line. Folders
+    /// the mkfile's `.ignore:` names aren't watched. Works on Linux, and on
+    /// other Unixes when mk is built with the watch feature.
+    Daemon,
+    /// Answer HTTP requests to list targets, make them, follow builds and
+    /// see the state, for dashboards and bots. Builds are made one at a
+    /// time, with the other flags given here. Asking for builds needs the
+    /// token it logs on startup.
+    Serve {
+        /// The address to listen on.
+        #[arg(long, default_value = "127.0.0.1:7878")]
+        listen: String,
+        /// Let builds that are asked for override the mkfile's variables.
+        #[arg(long)]
+        allow_overrides: bool,
+    },
+    /// Write the script that completes mk's arguments in a shell, including
+    /// the targets of the mkfile in the current folder. Load it from the
+    /// shell's startup file, as in `source <(mk completions bash)`.
+    Completions {
+        /// The shell to complete in.
+        shell: Shell,
+    },
+    /// Write a starter mkfile for the project in the current folder, with
+    /// `$build`, `$test` and `$clean` targets, and ignore the state file in
+    /// `.gitignore`.
+    Init {
+        /// Replace the mkfile if there is one.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Delete the outputs that rules produced. Use --dry-run to only list
+    /// them. Without a target, the mkfile's own `$clean` is made instead if
+    /// it has one.
+    Clean {
+        /// Only delete the outputs of this target and what it depends on.
+        #[arg(add = ArgValueCandidates::new(target_candidates))]
+        target: Option<String>,
+    },
+    /// List the targets with rules, as they are found.
+    List {
+        /// Only list targets whose name matches this glob pattern.
+        pattern: Option<String>,
+        /// Only list targets with this tag. Can be given more than once.
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+        /// Write one JSON object per target instead.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check rules for dependencies that can't be made, unused and
+    /// duplicate rules, undefined variables, and commands with shell
+    /// constructs that aren't portable or are dangerous.
+    Lint {
+        /// Change how serious a lint is, as NAME=LEVEL where LEVEL is off,
+        /// warning or error. Can be given more than once.
+        #[arg(long = "level", value_name = "NAME=LEVEL", value_parser = lint::parse_level)]
+        levels: Vec<(String, lint::Severity)>,
+    },
+    /// Write release notes from what release rules recorded in the release
+    /// manifest.
+    ReleaseNotes {
+        /// List every build of each artifact, not only the latest.
+        #[arg(long)]
+        all: bool,
+        /// File to write to, instead of stdout.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Explain which inputs and commands made an output, from what
+    /// --provenance wrote next to it.
+    WhyBuilt {
+        /// The output to explain.
+        output: PathBuf,
+    },
+    /// Write documentation for the rules in the mkfile.
+    Docs {
+        /// Output format.
+        #[arg(long, value_enum, default_value_t = DocsFormat::Markdown)]
+        format: DocsFormat,
+        /// File to write to, instead of stdout.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Write the rules that a target needs for other build tools.
+    #[command(group(clap::ArgGroup::new("formats").required(true).multiple(true)))]
+    Export {
+        /// The targets to export, with everything they depend on. Defaults
+        /// to the mkfile's default target.
+        #[arg(add = ArgValueCandidates::new(target_candidates))]
+        targets: Vec<String>,
+        /// Write a Ninja file that makes the target to this path.
+        #[arg(long, value_name = "FILE", group = "formats")]
+        ninja: Option<PathBuf>,
+        /// Write the commands that compile C and C++ sources, for clangd
+        /// and other tools, to this path.
+        #[arg(
+            long,
+            value_name = "FILE",
+            group = "formats",
+            num_args = 0..=1,
+            default_missing_value = "compile_commands.json"
+        )]
+        compdb: Option<PathBuf>,
+        /// Write a GitHub Actions workflow that makes the targets to this
+        /// path.
+        #[arg(
+            long,
+            value_name = "FILE",
+            group = "formats",
+            num_args = 0..=1,
+            default_missing_value = ".github/workflows/mk.yml"
+        )]
+        github_actions: Option<PathBuf>,
+        /// Write the hermetic rules as Nix derivations to this path, which
+        /// should be in the mkfile's folder.
+        #[arg(long, value_name = "FILE", group = "formats")]
+        nix: Option<PathBuf>,
+    },
+    /// Convert a go-task Taskfile into an mkfile.
+    ImportTaskfile {
+        /// The Taskfile to convert.
+        #[arg(default_value = "Taskfile.yml")]
+        taskfile: PathBuf,
+        /// File to write to, instead of stdout.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Bundle what is needed to replay how a target gets planned, without
+    /// any build outputs, for bug reports.
+    Repro {
+        /// The target to plan.
+        #[arg(default_value = "all", conflicts_with = "replay", add = ArgValueCandidates::new(target_candidates))]
+        target: String,
+        /// File to write the bundle to.
+        #[arg(short, long, default_value = "mk-repro.json")]
+        output: PathBuf,
+        /// Replay the planning recorded in a bundle instead.
+        #[arg(long, value_name = "BUNDLE")]
+        replay: Option<PathBuf>,
+    },
+    /// Manage the update state file.
+    State {
+        #[command(subcommand)]
+        command: StateCommand,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum DocsFormat {
+    Markdown,
+    Html,
+}
+
+#[derive(Subcommand, Debug)]
+enum StateCommand {
+    /// Back up the state file and start over with an empty one.
+    Reset,
+    /// Forget targets and files that no rule in the mkfile refers to
+    /// anymore.
+    Gc,
+    /// Show what is recorded about every target, or about one.
+    Show {
+        /// Target as written in the mkfile.
+        #[arg(add = ArgValueCandidates::new(target_candidates))]
+        target: Option<String>,
+    },
+    /// Forget what is recorded about a target, so that it is made again.
+    Forget {
+        /// Target as written in the mkfile.
+        #[arg(add = ArgValueCandidates::new(target_candidates))]
+        target: String,
+    },
+    /// Forget what is recorded about every target, keeping their stable
+    /// IDs, so that everything is made again.
+    Clear,
+    /// Show what the last run changed in the state file, or what differs
+    /// between two saved state files.
+    Diff {
+        /// Older state file.
+        #[arg(requires = "after")]
+        before: Option<PathBuf>,
+        /// Newer state file.
+        after: Option<PathBuf>,
+    },
+}
+
+/// Parses a duration given on the command line.
+fn parse_duration(text: &str) -> Result<Duration, String> {
+    mkfile::parse_duration(text).map_err(|err| err.to_string())
+}
+
+/// Parses a load average given on the command line.
+fn parse_load(text: &str) -> Result<f64, String> {
+    match text.parse::<f64>() {
+        Ok(load) if load.is_finite() && load >= 0.0 => Ok(load),
+        _ => Err(format!(
+            "Invalid load '{text}', expected a number that isn't negative"
+        )),
+    }
+}
+
+/// Splits the positional arguments into the target to make, if given, and
+/// the `NAME=VALUE` variable assignments.
+fn split_args(args: &[String]) -> Result<(Option<String>, BTreeMap<String, String>), String> {
+    let mut target = None;
+    let mut variables = BTreeMap::new();
+    for arg in args {
+        match arg.split_once('=') {
+            Some((name, value))
+                if !name.is_empty()
+                    && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+            {
+                variables.insert(name.to_string(), value.to_string());
+            }
+            _ if target.is_none() => target = Some(arg.clone()),
+            _ => return Err(format!("Only one target can be made, got '{arg}' too")),
+        }
+    }
+    Ok((target, variables))
+}
+
+/// The flags of builds that the daemon can make. It makes them with the
+/// other flags it was started with, so with any other flag `mk` makes the
+/// build itself.
+const DAEMON_FLAGS: &[&str] = &[
+    "-C",
+    "--directory",
+    "-m",
+    "--mkfile",
+    "-s",
+    "--state",
+    "-j",
+    "--jobs",
+    "--prefix-output",
+    "--explain",
+    "-n",
+    "--dry-run",
+    "--wait-for-lock",
+];
+
+/// Returns true if the command line only has flags in `DAEMON_FLAGS`.
+fn forwardable(args: impl Iterator<Item = String>) -> bool {
+    args.skip(1)
+        .take_while(|arg| arg != "--")
+        .filter(|arg| arg.starts_with('-') && arg.len() > 1)
+        .all(|arg| {
+            let name = if arg.starts_with("--") {
+                arg.split('=').next().unwrap_or_default()
+            } else {
+                arg.get(..2).unwrap_or_default()
+            };
+            DAEMON_FLAGS.contains(&name)
+        })
+}
+
+/// Returns true if the mkfile has a rule for the target. Mkfiles that can't
+/// be read or parsed have none.
+fn has_rule(path: &str, options: &mkfile::ParseOptions, name: &str) -> bool {
+    let Ok(text) = std::fs::read_to_string(path) else {
+        return false;
+    };
+    // Saves parsing, and warning about the mkfile, twice
+    if !text.contains(name) {
+        return false;
+    }
+    let format = mkfile::Format::of(Path::new(path));
+    mkfile::MkFile::parse_as(&text, format, options)
+        .is_ok_and(|mkfile| mkfile.has_target(&mkfile.resolve(name)))
+}
+
+/// Reads and parses the mkfile, exiting on failure.
+fn read_mkfile(path: &str, options: &mkfile::ParseOptions) -> mkfile::MkFile {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) => {
+            let err = mk::MkError::Io(err);
+            error!("Failed to read mkfile '{}': {}", path, err);
+            std::process::exit(err.exit_code());
+        }
+    };
+    let format = mkfile::Format::of(Path::new(path));
+    match mkfile::MkFile::parse_as(&text, format, options) {
+        Ok(mkfile) => mkfile,
+        Err(err) => {
+            error!("Failed to parse mkfile: {}", err);
+            std::process::exit(err.exit_code());
+        }
+    }
+}
+
+/// Makes the included mkfiles that rules make, reading the mkfile again
+/// until none of them change. Exits if one that isn't optional is still
+/// missing then.
+fn remake_includes(
+    mut mkfile: mkfile::MkFile,
+    path: &str,
+    options: &mkfile::ParseOptions,
+    state: &mut making::UpdateState,
+) -> mkfile::MkFile {
+    for _ in 0..16 {
+        let mut changed = false;
+        for (include, _) in mkfile.includes().to_vec() {
+            let target = mkfile.resolve(&include.to_string_lossy());
+            if !mkfile.has_target(&target) && mkfile.producer(&target).is_none() {
+                continue;
+            }
+            let make_options = MakeOptions {
+                env: mkfile.dotenv().clone(),
+                ..MakeOptions::default()
+            };
+            match make(&mkfile, &target, state, &make_options) {
+                Ok(made) => changed |= made,
+                Err(err) => {
+                    error!(
+                        "Failed to make included mkfile '{}': {}",
+                        include.display(),
+                        err
+                    );
+                    std::process::exit(err.exit_code());
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+        mkfile = read_mkfile(path, options);
+    }
+    for (include, optional) in mkfile.includes() {
+        if !optional && !include.exists() {
+            error!(
+                "Included mkfile '{}' doesn't exist and no rule makes it",
+                include.display()
+            );
+            std::process::exit(1);
+        }
+    }
+    mkfile
+}
+
+/// Sets up the cache from the command line, falling back to the mkfile's
+/// `[cache]` section. Exits if the shared cache URL is invalid.
+fn open_cache(mkfile: &mkfile::MkFile, dir: Option<PathBuf>, url: Option<String>) -> Option<Cache> {
+    match cache::open(mkfile.cache(), dir, url) {
+        Ok(cache) => cache,
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Offers the targets of the mkfile in the current folder for completion,
+/// by the names they are made by.
+fn target_candidates() -> Vec<CompletionCandidate> {
+    let Some(path) = ["mkfile", "mk.toml", "mk.yaml", "mk.yml"]
+        .into_iter()
+        .find(|path| Path::new(path).exists())
+    else {
+        return Vec::new();
+    };
+    let parsed = std::fs::read_to_string(path)
+        .map_err(mk::MkError::Io)
+        .and_then(|text| {
+            mkfile::MkFile::parse_as(
+                &text,
+                mkfile::Format::of(Path::new(path)),
+                &Default::default(),
+            )
+        });
+    let Ok(mkfile) = parsed else {
+        return Vec::new();
+    };
+    let mut targets: Vec<&mkfile::Target> = mkfile
+        .targets()
+        .filter(|target| mkfile.part_of(target).is_none())
+        .collect();
+    targets.sort_by_key(|target| target.to_string());
+    let mut candidates = Vec::new();
+    for target in targets {
+        let name = match target {
+            mkfile::Target::Virtual(name) => name.clone(),
+            mkfile::Target::Concrete(_) => target.to_string(),
+        };
+        let help = mkfile
+            .description(target)
+            .map(|description| description.to_string().into());
+        candidates.push(CompletionCandidate::new(name).help(help));
+    }
+    candidates
 }
 
 fn main() {
-    SimpleLogger::new().init().unwrap();
-    let cli = Cli::parse();
+    CompleteEnv::with_factory(Cli::command).complete();
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    // Logs go to stdout, so keep quiet when it carries the event stream
+    let level = if cli.events_json.as_deref() == Some("-") {
+        LevelFilter::Off
+    } else {
+        LevelFilter::Trace
+    };
+    let logger = SimpleLogger::new()
+        .with_level(level)
+        .with_module_level("tiny_http", level.min(LevelFilter::Warn))
+        .with_module_level("mio", level.min(LevelFilter::Warn));
+    log::set_max_level(logger.max_level());
+    let warnings: &'static _ = Box::leak(Box::new(warnings::WarningLayer::new(logger)));
+    log::set_logger(warnings).unwrap();
+
+    if let Some(dir) = &cli.directory {
+        if let Err(err) = std::env::set_current_dir(dir) {
+            error!("Can't change into '{}': {}", dir.display(), err);
+            std::process::exit(2);
+        }
+        info!("Entering '{}'", dir.display());
+    }
+    if cli.mkfile == "mkfile" && !Path::new("mkfile").exists() {
+        if let Some(structured) = ["mk.toml", "mk.yaml", "mk.yml"]
+            .into_iter()
+            .find(|path| Path::new(path).exists())
+        {
+            cli.mkfile = structured.to_string();
+        }
+    }
+
+    let (mut target, variables) = match split_args(&cli.args) {
+        Ok(split) => split,
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(2);
+        }
+    };
+    let parse_options = mkfile::ParseOptions {
+        profile: cli.profile.clone(),
+        env_file: cli.env_file.clone(),
+        variables,
+        offline: cli.offline,
+        merge_duplicates: cli.merge_duplicates,
+        vfs: if cli.no_follow_symlinks {
+            Arc::new(vfs::NoFollowFs)
+        } else {
+            Arc::new(vfs::RealFs)
+        },
+    };
+
+    // A subcommand given on its own makes the mkfile's rule of the same name
+    // instead, if it has one, so mkfiles keep their own `clean` and `lint`
+    if let (Some((name, args)), None) = (matches.subcommand(), &target) {
+        let bare = args
+            .ids()
+            .all(|id| args.value_source(id.as_str()) != Some(ValueSource::CommandLine));
+        if bare && has_rule(&cli.mkfile, &parse_options, name) {
+            cli.command = None;
+            target = Some(name.to_string());
+        }
+    }
+
+    match cli.command {
+        Some(Command::Doctor) => {
+            let healthy = doctor::doctor(Path::new(&cli.mkfile), Path::new(&cli.state));
+            std::process::exit(if healthy { 0 } else { 1 });
+        }
+        Some(Command::Daemon) => {
+            let settings = making::BuildSettings {
+                mkfile: PathBuf::from(&cli.mkfile),
+                parse_options,
+                freshness: cli.freshness.checker(),
+                follow_symlinks: !cli.no_follow_symlinks,
+                hermetic: cli.hermetic,
+                timeout: cli.timeout,
+                max_load: cli.max_load,
+                cache_dir: cli.cache,
+                cache_url: cli.cache_url,
+            };
+            if let Err(err) = daemon::run(Path::new(&cli.state), settings) {
+                error!("Failed to watch for changes: {}", err);
+                std::process::exit(err.exit_code());
+            }
+            return;
+        }
+        Some(Command::Serve {
+            listen,
+            allow_overrides,
+        }) => {
+            let settings = making::BuildSettings {
+                mkfile: PathBuf::from(&cli.mkfile),
+                parse_options,
+                freshness: cli.freshness.checker(),
+                follow_symlinks: !cli.no_follow_symlinks,
+                hermetic: cli.hermetic,
+                timeout: cli.timeout,
+                max_load: cli.max_load,
+                cache_dir: cli.cache,
+                cache_url: cli.cache_url,
+            };
+            if let Err(err) = api::serve(&listen, Path::new(&cli.state), settings, allow_overrides)
+            {
+                error!("Failed to answer on '{}': {}", listen, err);
+                std::process::exit(err.exit_code());
+            }
+            return;
+        }
+        Some(Command::Completions { shell }) => {
+            let name = shell.to_string();
+            let shells = Shells::builtins();
+            let Some(completer) = shells.completer(&name) else {
+                error!("Can't complete in {name}");
+                std::process::exit(1);
+            };
+            let mut stdout = std::io::stdout();
+            if let Err(err) =
+                completer.write_registration("COMPLETE", "mk", "mk", "mk", &mut stdout)
+            {
+                error!("Failed to write completions: {}", err);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Init { force }) => {
+            let path = Path::new(&cli.mkfile);
+            if path.exists() && !force {
+                error!(
+                    "'{}' exists already, use --force to replace it",
+                    path.display()
+                );
+                std::process::exit(1);
+            }
+            let project = init::Project::detect(Path::new("."));
+            if let Err(err) = std::fs::write(path, project.mkfile()) {
+                error!("Failed to write '{}': {}", path.display(), err);
+                std::process::exit(1);
+            }
+            info!("Wrote '{}'", path.display());
+            let gitignore = std::fs::read_to_string(".gitignore").unwrap_or_default();
+            if let Some(text) = init::ignore_state(&gitignore, &cli.state) {
+                if let Err(err) = std::fs::write(".gitignore", text) {
+                    error!("Failed to write '.gitignore': {}", err);
+                    std::process::exit(1);
+                }
+                info!("Added '{}' to '.gitignore'", cli.state);
+            }
+            return;
+        }
+        Some(Command::Clean { target }) => {
+            let mkfile = target
+                .as_ref()
+                .map(|_| read_mkfile(&cli.mkfile, &parse_options));
+            let target = mkfile.as_ref().zip(target).map(|(mkfile, name)| {
+                let target = mkfile.resolve(&name);
+                (mkfile, target)
+            });
+            let result = store::StateLock::acquire(Path::new(&cli.state), cli.wait_for_lock)
+                .and_then(|_lock| {
+                    let store = store::open(Path::new(&cli.state))?;
+                    let mut state = store.load();
+                    let only = target.as_ref().map(|(mkfile, target)| (*mkfile, target));
+                    let deleted = clean::clean(&mut state, &vfs::RealFs, only, cli.dry_run)?;
+                    if !cli.dry_run {
+                        store.save(&state)?;
+                    }
+                    Ok(deleted)
+                });
+            match result {
+                Ok(deleted) => {
+                    for output in &deleted {
+                        let path = output.pathbuf().display();
+                        if cli.dry_run {
+                            info!("Would delete '{}'", path);
+                        } else {
+                            info!("Deleted '{}'", path);
+                        }
+                    }
+                    if deleted.is_empty() {
+                        info!("Nothing to clean");
+                    }
+                }
+                Err(err) => {
+                    error!("Failed to clean: {}", err);
+                    std::process::exit(err.exit_code());
+                }
+            }
+            return;
+        }
+        Some(Command::State {
+            command: StateCommand::Reset,
+        }) => {
+            let result = store::StateLock::acquire(Path::new(&cli.state), cli.wait_for_lock)
+                .and_then(|_lock| store::open(Path::new(&cli.state))?.reset());
+            if let Err(err) = result {
+                error!("Failed to reset state: {}", err);
+                std::process::exit(1);
+            }
+            info!("State reset");
+            return;
+        }
+        Some(Command::State {
+            command: StateCommand::Gc,
+        }) => {
+            let mkfile = read_mkfile(&cli.mkfile, &parse_options);
+            let result = store::StateLock::acquire(Path::new(&cli.state), cli.wait_for_lock)
+                .and_then(|_lock| {
+                    let store = store::open(Path::new(&cli.state))?;
+                    let mut state = store.load();
+                    let removed = state.collect_garbage(&mkfile, &vfs::RealFs);
+                    store.save(&state)?;
+                    Ok(removed)
+                });
+            match result {
+                Ok(removed) => info!("Removed {} entries from the state", removed),
+                Err(err) => {
+                    error!("Failed to clean up state: {}", err);
+                    std::process::exit(err.exit_code());
+                }
+            }
+            return;
+        }
+        Some(Command::State {
+            command: StateCommand::Show { target },
+        }) => {
+            let state = match store::open(Path::new(&cli.state)) {
+                Ok(store) => store.load(),
+                Err(err) => {
+                    error!("{}", err);
+                    std::process::exit(err.exit_code());
+                }
+            };
+            let target = target.as_deref().map(mkfile::Target::parse);
+            let entries = state.entries(target.as_ref());
+            if entries.is_empty() {
+                if let Some(target) = target {
+                    error!("Nothing is recorded about '{}'", target);
+                    std::process::exit(1);
+                }
+            }
+            for (target, field, value) in entries {
+                println!("{} {}: {}", target, field, value);
+            }
+            return;
+        }
+        Some(Command::State {
+            command: command @ (StateCommand::Forget { .. } | StateCommand::Clear),
+        }) => {
+            let result = store::StateLock::acquire(Path::new(&cli.state), cli.wait_for_lock)
+                .and_then(|_lock| {
+                    let store = store::open(Path::new(&cli.state))?;
+                    let mut state = store.load();
+                    let removed = match &command {
+                        StateCommand::Forget { target } => {
+                            state.forget(&mkfile::Target::parse(target))
+                        }
+                        _ => state.clear(),
+                    };
+                    store.save(&state)?;
+                    Ok(removed)
+                });
+            match result {
+                Ok(0) => {
+                    if let StateCommand::Forget { target } = command {
+                        error!("Nothing is recorded about '{}'", target);
+                        std::process::exit(1);
+                    }
+                    info!("The state is already empty");
+                }
+                Ok(removed) => info!("Removed {} entries from the state", removed),
+                Err(err) => {
+                    error!("Failed to update state: {}", err);
+                    std::process::exit(err.exit_code());
+                }
+            }
+            return;
+        }
+        Some(Command::State {
+            command: StateCommand::Diff { before, after },
+        }) => {
+            let open = |path: &Path| {
+                if !path.exists() {
+                    error!("State file '{}' does not exist", path.display());
+                    std::process::exit(1);
+                }
+                store::open(path).unwrap_or_else(|err| {
+                    error!("{}", err);
+                    std::process::exit(err.exit_code());
+                })
+            };
+            let (before, after) = match (before, after) {
+                (Some(before), Some(after)) => (open(&before).load(), open(&after).load()),
+                _ => {
+                    let store = open(Path::new(&cli.state));
+                    let Some(previous) = store.previous() else {
+                        error!(
+                            "There is no previous state of '{}' to compare with, as it wasn't saved twice yet",
+                            cli.state
+                        );
+                        std::process::exit(1);
+                    };
+                    (previous, store.load())
+                }
+            };
+            for change in before.diff(&after) {
+                match (change.before, change.after) {
+                    (None, Some(after)) => {
+                        println!("+ {} {}: {}", change.target, change.field, after)
+                    }
+                    (Some(before), None) => {
+                        println!("- {} {}: {}", change.target, change.field, before)
+                    }
+                    (Some(before), Some(after)) => println!(
+                        "~ {} {}: {} -> {}",
+                        change.target, change.field, before, after
+                    ),
+                    (None, None) => {}
+                }
+            }
+            return;
+        }
+        Some(Command::List {
+            pattern,
+            tags,
+            json,
+        }) => {
+            let mkfile = read_mkfile(&cli.mkfile, &parse_options);
+            let filter = match query::TargetFilter::new(pattern.as_deref(), tags) {
+                Ok(filter) => filter,
+                Err(err) => {
+                    error!("{}", err);
+                    std::process::exit(err.exit_code());
+                }
+            };
+            let mut state = match store::open(Path::new(&cli.state)) {
+                Ok(store) => store.load(),
+                Err(err) => {
+                    error!("Failed to open state: {}", err);
+                    std::process::exit(err.exit_code());
+                }
+            };
+            state.assign_ids(&mkfile);
+            let mut stdout = std::io::stdout().lock();
+            match query::list(&mkfile, &state, &filter, json, &mut stdout) {
+                // Whoever reads the list may stop early, e.g. `head`
+                Err(err) if err.kind() == std::io::ErrorKind::BrokenPipe => {}
+                Err(err) => {
+                    error!("Failed to write list: {}", err);
+                    std::process::exit(1);
+                }
+                Ok(0) => std::process::exit(1),
+                Ok(_) => {}
+            }
+            return;
+        }
+        Some(Command::Lint { levels }) => {
+            let mkfile = read_mkfile(&cli.mkfile, &parse_options);
+            let findings = lint::lint(&mkfile, &levels.into_iter().collect());
+            for finding in &findings {
+                let line = format!("{}: {} [{}]", finding.target, finding.message, finding.lint);
+                match finding.severity {
+                    lint::Severity::Error => error!("{line}"),
+                    _ => warn!("{line}"),
+                }
+            }
+            if findings.is_empty() {
+                info!("No problems found");
+            }
+            let failed = findings
+                .iter()
+                .any(|finding| finding.severity == lint::Severity::Error);
+            std::process::exit(if failed { 1 } else { 0 });
+        }
+        Some(Command::ReleaseNotes { all, output }) => {
+            let entries = match release::load(&vfs::RealFs, &cli.release_manifest) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    error!(
+                        "Failed to read release manifest '{}': {}",
+                        cli.release_manifest.display(),
+                        err
+                    );
+                    std::process::exit(1);
+                }
+            };
+            let page = release::notes(&entries, all);
+            match output {
+                Some(path) => {
+                    if let Err(err) = std::fs::write(&path, page) {
+                        error!("Failed to write '{}': {}", path.display(), err);
+                        std::process::exit(1);
+                    }
+                }
+                None => print!("{page}"),
+            }
+            return;
+        }
+        Some(Command::WhyBuilt { output }) => {
+            match provenance::load(&vfs::RealFs, &output) {
+                Ok(provenance) => print!("{}", provenance::explain(&provenance, &vfs::RealFs)),
+                Err(err) => {
+                    error!(
+                        "Can't read '{}': {} (was it made with --provenance?)",
+                        provenance::sidecar_path(&output).display(),
+                        err
+                    );
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Command::Docs { format, output }) => {
+            let mkfile = read_mkfile(&cli.mkfile, &parse_options);
+            let page = match format {
+                DocsFormat::Markdown => docs::markdown(&mkfile),
+                DocsFormat::Html => docs::html(&mkfile),
+            };
+            match output {
+                Some(path) => {
+                    if let Err(err) = std::fs::write(&path, page) {
+                        error!("Failed to write '{}': {}", path.display(), err);
+                        std::process::exit(1);
+                    }
+                }
+                None => print!("{page}"),
+            }
+            return;
+        }
+        Some(Command::Export {
+            targets,
+            ninja,
+            compdb,
+            github_actions,
+            nix,
+        }) => {
+            let mut mkfile = read_mkfile(&cli.mkfile, &parse_options);
+            let targets: Vec<_> = match (targets.is_empty(), mkfile.default_target()) {
+                (false, _) => targets.iter().map(|name| mkfile.resolve(name)).collect(),
+                (true, Some(default)) => vec![default.clone()],
+                (true, None) => vec![mkfile.resolve("all")],
+            };
+            for target in &targets {
+                if let Err(err) = mkfile.scope(target) {
+                    error!("Failed to parse mkfile: {}", err);
+                    std::process::exit(err.exit_code());
+                }
+            }
+            let write = |path: &Path, text: String| {
+                let folder = path.parent().filter(|folder| !folder.exists());
+                if let Some(folder) = folder.filter(|folder| !folder.as_os_str().is_empty()) {
+                    let _ = std::fs::create_dir_all(folder);
+                }
+                if let Err(err) = std::fs::write(path, text) {
+                    error!("Failed to write '{}': {}", path.display(), err);
+                    std::process::exit(1);
+                }
+                info!("Wrote '{}'", path.display());
+            };
+            if let Some(path) = ninja {
+                write(&path, export::ninja(&mkfile, &targets));
+            }
+            if let Some(path) = compdb {
+                let directory = std::env::current_dir().unwrap_or_default();
+                write(&path, export::compdb(&mkfile, &targets, &directory));
+            }
+            if let Some(path) = github_actions {
+                let cache_dir = cli
+                    .cache
+                    .or_else(|| mkfile.cache().and_then(|cache| cache.dir.clone()));
+                let workflow = export::github_actions(
+                    &targets,
+                    &cli.mkfile,
+                    Path::new(&cli.state),
+                    cache_dir.as_deref(),
+                );
+                write(&path, workflow);
+            }
+            if let Some(path) = nix {
+                write(&path, export::nix(&mkfile, &targets, cli.hermetic));
+            }
+            return;
+        }
+        Some(Command::ImportTaskfile { taskfile, output }) => {
+            let converted = std::fs::read_to_string(&taskfile)
+                .map_err(mk::MkError::from)
+                .and_then(|text| taskfile::convert(&text));
+            let mkfile = match converted {
+                Ok(mkfile) => mkfile,
+                Err(err) => {
+                    error!("Failed to convert '{}': {}", taskfile.display(), err);
+                    std::process::exit(err.exit_code());
+                }
+            };
+            match output {
+                Some(path) => {
+                    if let Err(err) = std::fs::write(&path, mkfile) {
+                        error!("Failed to write '{}': {}", path.display(), err);
+                        std::process::exit(1);
+                    }
+                }
+                None => print!("{mkfile}"),
+            }
+            return;
+        }
+        Some(Command::Repro {
+            target,
+            output,
+            replay,
+        }) => {
+            let result = match replay {
+                Some(bundle) => repro::replay(&bundle),
+                None => repro::create(
+                    Path::new(&cli.mkfile),
+                    Path::new(&cli.state),
+                    &target,
+                    cli.profile.as_deref(),
+                    &output,
+                ),
+            };
+            if let Err(err) = result {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+            return;
+        }
+        None => {}
+    }
 
-    // Parse the mkfile
-    let text = std::fs::read_to_string(cli.mkfile).expect("Failed to read mkfile");
-    let mkfile = mkfile::MkFile::parse(&text);
+    // A daemon makes builds it can with what it keeps in memory
+    if forwardable(std::env::args()) {
+        let build = daemon::Build {
+            dir: std::env::current_dir()
+                .and_then(std::fs::canonicalize)
+                .unwrap_or_default(),
+            mkfile: std::fs::canonicalize(&cli.mkfile)
+                .unwrap_or_else(|_| cli.mkfile.clone().into()),
+            target: target.clone(),
+            variables: parse_options.variables.clone(),
+            jobs: cli.jobs,
+            prefix_output: cli.prefix_output,
+            explain: cli.explain,
+            dry_run: cli.dry_run,
+            wait_for_lock: cli.wait_for_lock,
+            env: std::env::vars_os()
+                .filter_map(|(name, value)| {
+                    Some((name.into_string().ok()?, value.into_string().ok()?))
+                })
+                .collect(),
+        };
+        if let Some(code) = daemon::build(Path::new(&cli.state), build) {
+            std::process::exit(code);
+        }
+    }
+
+    if !Path::new(&cli.state).exists() {
+        doctor::first_run_checks(Path::new(&cli.state));
+    }
 
     // Load the state
-    let mut state = match std::fs::read_to_string(&cli.state) {
-        Ok(text) => serde_sexpr::from_str(&text).expect("Failed to parse state"),
-        Err(_) => making::UpdateState::default(),
+    let vfs: Box<dyn vfs::Vfs> = Box::new(parse_options.vfs.clone());
+    // Runs that save the state hold the lock on it until they exit
+    let _lock = if cli.dry_run {
+        None
+    } else {
+        match store::StateLock::acquire(Path::new(&cli.state), cli.wait_for_lock) {
+            Ok(lock) => Some(lock),
+            Err(err) => {
+                error!("{}", err);
+                std::process::exit(err.exit_code());
+            }
+        }
     };
+    let store = match store::open(Path::new(&cli.state)) {
+        Ok(store) => store,
+        Err(err) => {
+            error!("Failed to open state: {}", err);
+            std::process::exit(err.exit_code());
+        }
+    };
+    let mut state = store.load();
 
-    // Make the target
-    let mut target = mkfile::Target::parse(&cli.target);
-    if !mkfile.has_target(&target) {
-        target = mkfile::Target::Virtual(cli.target);
+    // Included mkfiles that rules make are made before anything else
+    let mut mkfile = read_mkfile(&cli.mkfile, &parse_options);
+    if !cli.dry_run {
+        mkfile = remake_includes(mkfile, &cli.mkfile, &parse_options, &mut state);
     }
+    let target = mkfile.target_to_make(target.as_deref());
+    if let Err(err) = mkfile.scope(&target) {
+        error!("Failed to parse mkfile: {}", err);
+        std::process::exit(err.exit_code());
+    }
+    let profile = mkfile.profile();
+    state.assign_ids(&mkfile);
 
-    let made = make(&mkfile, &target, &mut state);
+    // Make the target
+    // Each way of showing output wraps the executor, and the innermost one
+    // that takes a target's output gets it
+    let mut reporters: Vec<Box<dyn report::Reporter>> = Vec::new();
+    let mut executor: Arc<dyn executor::CommandExecutor> = Arc::new(executor::ShellExecutor);
+    if let Some(dir) = &cli.log_dir {
+        match logs::LogDir::new(dir, executor) {
+            Ok(logs) => {
+                reporters.push(Box::new(logs.clone()));
+                executor = Arc::new(logs);
+            }
+            Err(err) => {
+                error!("Failed to create log folder '{}': {}", dir.display(), err);
+                std::process::exit(1);
+            }
+        }
+    }
+    let mut display: Box<dyn report::Reporter> = if cli.progress && std::io::stderr().is_terminal()
+    {
+        Box::new(report::ProgressReporter::new(
+            mkfile.reachable(&target).len(),
+            cli.explain || cli.dry_run,
+        ))
+    } else {
+        Box::new(report::LogReporter {
+            explain: cli.explain || cli.dry_run,
+        })
+    };
+    #[cfg(feature = "ui")]
+    if cli.ui && std::io::stdout().is_terminal() {
+        match mk::ui::Ui::start(&mkfile, &target, executor.clone()) {
+            Ok(ui) => {
+                let ui = Arc::new(ui);
+                display = Box::new(ui.clone());
+                executor = ui;
+            }
+            Err(err) => warn!("Can't show the terminal interface: {}", err),
+        }
+    }
+    #[cfg(not(feature = "ui"))]
+    if cli.ui {
+        warn!("mk was built without the ui feature, so --ui shows log lines instead");
+    }
+    if let Some(ci) = cli.ci {
+        let reporter = Arc::new(ci::CiReporter::new(
+            ci,
+            &mkfile,
+            Path::new(&cli.mkfile),
+            cli.explain || cli.dry_run,
+            executor,
+        ));
+        display = Box::new(reporter.clone());
+        executor = reporter;
+    }
+    if cli.output_sync == Some(output::OutputSync::Target) {
+        let held = output::HeldOutput::new(executor);
+        reporters.push(Box::new(held.clone()));
+        executor = Arc::new(held);
+    }
+    reporters.insert(0, display);
+    if cli.timings {
+        reporters.push(Box::<timings::TimingsReporter>::default());
+    }
+    let metrics = cli
+        .metrics_push
+        .as_ref()
+        .map(|_| Arc::new(metrics::Metrics::default()));
+    if let Some(metrics) = &metrics {
+        reporters.push(Box::new(metrics.clone()));
+    }
+    if let Some(path) = cli.trace_file {
+        reporters.push(Box::new(trace::TraceReporter::new(path)));
+    }
+    if let Some(path) = cli.events_json {
+        let writer: Box<dyn std::io::Write + Send> = if path == "-" {
+            Box::new(std::io::stdout())
+        } else {
+            match std::fs::File::create(&path) {
+                Ok(file) => Box::new(file),
+                Err(err) => {
+                    error!("Failed to create events file '{}': {}", path, err);
+                    std::process::exit(1);
+                }
+            }
+        };
+        reporters.push(Box::new(
+            ndjson::NdjsonReporter::new(writer).with_ids(state.ids().clone()),
+        ));
+    }
+    let (jobs, auto_jobs) = match cli.jobs.or(profile.and_then(|profile| profile.jobs)) {
+        Some(Jobs::Count(jobs)) => (jobs, false),
+        Some(Jobs::Auto) => (
+            std::thread::available_parallelism().map_or(1, |cores| cores.get()),
+            true,
+        ),
+        None => (1, false),
+    };
+    let plugins = match plugin::Plugins::load(mkfile.plugins()) {
+        Ok(plugins) => plugins,
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(err.exit_code());
+        }
+    };
+    // With a daemon watching, only files it saw change are looked at
+    let watched = daemon::changes(Path::new(&cli.state))
+        .map(|changes| Arc::new(daemon::DaemonChecker::new(changes, cli.freshness.checker())));
+    let freshness: Box<dyn freshness::FreshnessChecker> = match &watched {
+        Some(checker) => Box::new(checker.clone()),
+        None => Box::new(cli.freshness.checker()),
+    };
+    let options = MakeOptions {
+        prefix_output: match cli.output_sync {
+            Some(sync) => sync == output::OutputSync::Line,
+            None => cli.prefix_output || jobs > 1,
+        },
+        dry_run: cli.dry_run,
+        reporter: Box::new(report::Reporters(reporters)),
+        vfs,
+        executor: Box::new(executor),
+        freshness,
+        jobs,
+        auto_jobs,
+        max_load: cli.max_load,
+        env: mkfile
+            .dotenv()
+            .clone()
+            .into_iter()
+            .chain(profile.into_iter().flat_map(|profile| profile.env.clone()))
+            .collect(),
+        quarantine_flaky: cli.quarantine_flaky,
+        enforce_limits: cli.enforce_limits,
+        raise_limits: cli.raise_limits,
+        release_manifest: Some(release::ReleaseManifest::new(&cli.release_manifest)),
+        provenance: cli.provenance,
+        timeout: cli.timeout,
+        hermetic: cli.hermetic,
+        cache: open_cache(&mkfile, cli.cache, cli.cache_url),
+        plugins,
+    };
+    // Interrupts stop the build, after which the state is still saved
+    interrupt::install();
+    let started = Instant::now();
+    if !cli.dry_run {
+        hooks::started(mkfile.hooks(), &target, &options);
+    }
+    let made = make(&mkfile, &target, &mut state, &options);
+    if let Some(checker) = &watched {
+        checker.remember(&mut state);
+    }
 
     // Save the state
-    let text = serde_sexpr::to_string(&state).expect("Failed to serialize state");
-    std::fs::write(&cli.state, text).expect("Failed to write state");
+    if !cli.dry_run {
+        if let Err(err) = store.save(&state) {
+            error!("Failed to save state: {}", err);
+        }
+    }
+
+    // Finish the progress display before logging the result
+    options.reporter.finish();
+    warnings.summarize();
+    if !cli.dry_run {
+        hooks::finished(mkfile.hooks(), &target, &made, started.elapsed(), &options);
+    }
+    if let (Some(metrics), Some(url)) = (&metrics, &cli.metrics_push) {
+        metrics.build_over(&made, started.elapsed());
+        if let Err(err) = metrics.push(url) {
+            warn!("Failed to push metrics to '{}': {}", url, err);
+        }
+    }
+    if cli.notify && !cli.dry_run {
+        notify::build_over(&target, &made, started.elapsed());
+    }
+
+    if cli.quarantine_flaky {
+        for flaky in mkfile
+            .reachable(&target)
+            .into_iter()
+            .filter(|t| state.is_flaky(t))
+        {
+            let streak = state.streak(flaky);
+            if streak < 0 {
+                warn!("Flaky target '{flaky}' (failing for {} runs)", -streak);
+            } else {
+                warn!("Flaky target '{flaky}' (passing for {streak} runs)");
+            }
+        }
+    }
+
+    // Service targets keep running once made
+    let service = mkfile
+        .has_target(&target)
+        .then(|| mkfile.options(&target).serve.clone())
+        .flatten();
+    if let (Some(config), Ok(_), false) = (service, &made, cli.dry_run) {
+        if let Err(err) = serve::serve(
+            &mkfile,
+            &target,
+            &config,
+            &mut state,
+            store.as_ref(),
+            options,
+        ) {
+            error!("Failed to serve '{}': {}", config.dir.display(), err);
+            std::process::exit(err.exit_code());
+        }
+    }
 
     match made {
         Ok(made) => {
-            if made {
+            if made && cli.dry_run {
+                info!("Target '{:?}' would be made", target);
+            } else if made {
                 info!("Made target '{:?}'", target);
             } else {
                 info!("Target '{:?}' is up to date", target);
@@ -57,7 +1391,7 @@ fn main() {
         }
         Err(err) => {
             error!("Failed to make target '{:?}': {}", target, err);
-            std::process::exit(1);
+            std::process::exit(err.exit_code());
         }
     }
 }
@@ -1,131 +1,2092 @@
-use std::{collections::HashMap, error::Error, time::SystemTime};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    hash::Hash,
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Condvar, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use log::info;
-use serde::{Deserialize, Serialize};
+use log::{info, warn};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
 
-use crate::mkfile::{ConcreteTarget, MkFile, Target};
+use crate::{
+    cache::{self, Cache, KeyInputs},
+    error::MkError,
+    executor::{CommandExecutor, Invocation, Limits, ShellExecutor},
+    freshness::{Freshness, FreshnessChecker, MtimeThenHashChecker},
+    interrupt,
+    limits::{self, ResourceLimits},
+    mkfile::{normalize, ConcreteTarget, MkFile, ParseOptions, Target},
+    output,
+    plugin::{Plugin, Plugins},
+    provenance,
+    release::ReleaseManifest,
+    report::{Event, LogReporter, Reporter},
+    scan,
+    vfs::{NoFollowFs, RealFs, Vfs},
+    walk,
+};
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+/// Settings that control how targets are made.
+pub struct MakeOptions {
+    /// Capture command output and prefix each line with the target name.
+    pub prefix_output: bool,
+    /// Only work out what would be made, without running any commands.
+    pub dry_run: bool,
+    /// Receives progress events.
+    pub reporter: Box<dyn Reporter>,
+    /// The filesystem targets live in.
+    pub vfs: Box<dyn Vfs>,
+    /// Runs the commands of rules.
+    pub executor: Box<dyn CommandExecutor>,
+    /// Decides whether files changed, unless their rule picks another way.
+    pub freshness: Box<dyn FreshnessChecker>,
+    /// How many targets may run their commands at the same time.
+    pub jobs: usize,
+    /// Pick how many targets run at once from the machine instead of
+    /// `jobs`: one per core, as long as the rules that declare `memory:`
+    /// fit in the memory available, and fewer while the machine is
+    /// overloaded.
+    pub auto_jobs: bool,
+    /// Hold back new jobs while the load average is above this, unless
+    /// none are running.
+    pub max_load: Option<f64>,
+    /// Environment variables set for every command.
+    pub env: BTreeMap<String, String>,
+    /// Retry the commands of targets known to be flaky when they fail.
+    pub quarantine_flaky: bool,
+    /// Hold commands to the CPU and memory their rules declare, killing
+    /// those that use too much memory.
+    pub enforce_limits: bool,
+    /// Raise the open-file and process limits of mk, which its commands
+    /// inherit, as far as they can go.
+    pub raise_limits: bool,
+    /// Where to store outputs, so that they can be restored instead of made
+    /// again.
+    pub cache: Option<Cache>,
+    /// Where release rules record what they made.
+    pub release_manifest: Option<ReleaseManifest>,
+    /// Write what went into each output next to it, for `mk why-built`.
+    pub provenance: bool,
+    /// How long the commands of rules without a `timeout:` may run.
+    pub timeout: Option<Duration>,
+    /// Run every rule's commands in a scrubbed environment, as if they
+    /// were all `hermetic:`.
+    pub hermetic: bool,
+    /// Plugins that rules use with the `kind:`, `fingerprint:` and
+    /// `runner:` options.
+    pub plugins: Plugins,
+}
+
+impl Default for MakeOptions {
+    fn default() -> Self {
+        MakeOptions {
+            prefix_output: false,
+            dry_run: false,
+            reporter: Box::new(LogReporter::default()),
+            vfs: Box::new(RealFs),
+            executor: Box::new(ShellExecutor),
+            freshness: Box::new(MtimeThenHashChecker),
+            jobs: 1,
+            auto_jobs: false,
+            max_load: None,
+            env: BTreeMap::new(),
+            quarantine_flaky: false,
+            enforce_limits: false,
+            raise_limits: false,
+            cache: None,
+            release_manifest: None,
+            provenance: false,
+            timeout: None,
+            hermetic: false,
+            plugins: Plugins::default(),
+        }
+    }
+}
+
+impl MakeOptions {
+    /// Returns the environment variables to set for commands that aren't
+    /// run for a rule, such as hooks: the ones set for every command, on
+    /// top of a fixed `PATH` if commands are hermetic.
+    pub fn shared_env(&self) -> BTreeMap<String, String> {
+        let mut env = BTreeMap::new();
+        if self.hermetic {
+            env.insert("PATH".to_string(), HERMETIC_PATH.to_string());
+        }
+        env.extend(self.env.clone());
+        env
+    }
+}
+
+/// How targets are made when the daemon or the HTTP API is asked for them
+/// rather than the command line: with the mkfile and the flags mk was
+/// started with.
+pub struct BuildSettings {
+    pub mkfile: PathBuf,
+    pub parse_options: ParseOptions,
+    pub freshness: &'static dyn FreshnessChecker,
+    pub follow_symlinks: bool,
+    pub hermetic: bool,
+    pub timeout: Option<Duration>,
+    pub max_load: Option<f64>,
+    pub cache_dir: Option<PathBuf>,
+    pub cache_url: Option<String>,
+}
+
+impl BuildSettings {
+    /// Returns the options to parse the mkfile with, with the variables
+    /// added to the ones mk was started with.
+    pub fn parse_options(&self, variables: BTreeMap<String, String>) -> ParseOptions {
+        let mut options = self.parse_options.clone();
+        options.variables.extend(variables);
+        options
+    }
+
+    /// Returns the options to make targets of the mkfile with, `jobs` at a
+    /// time or as many as its profile says, with `env` set for commands
+    /// under the variables of the mkfile and its profile. Commands are run
+    /// with `sh -c` and events are logged, unless the caller replaces them.
+    pub fn make_options(
+        &self,
+        mkfile: &MkFile,
+        jobs: Option<Jobs>,
+        env: BTreeMap<String, String>,
+    ) -> Result<MakeOptions, MkError> {
+        let profile = mkfile.profile();
+        let (jobs, auto_jobs) = match jobs.or(profile.and_then(|profile| profile.jobs)) {
+            Some(Jobs::Count(jobs)) => (jobs, false),
+            Some(Jobs::Auto) => (
+                thread::available_parallelism().map_or(1, |cores| cores.get()),
+                true,
+            ),
+            None => (1, false),
+        };
+        let vfs: Box<dyn Vfs> = if self.follow_symlinks {
+            Box::new(RealFs)
+        } else {
+            Box::new(NoFollowFs)
+        };
+        Ok(MakeOptions {
+            prefix_output: jobs > 1,
+            vfs,
+            freshness: Box::new(self.freshness),
+            jobs,
+            auto_jobs,
+            max_load: self.max_load,
+            env: env
+                .into_iter()
+                .chain(mkfile.dotenv().clone())
+                .chain(profile.into_iter().flat_map(|profile| profile.env.clone()))
+                .collect(),
+            release_manifest: Some(ReleaseManifest::new("mk-release.jsonl")),
+            timeout: self.timeout,
+            hermetic: self.hermetic,
+            cache: cache::open(
+                mkfile.cache(),
+                self.cache_dir.clone(),
+                self.cache_url.clone(),
+            )
+            .map_err(MkError::Parse)?,
+            plugins: Plugins::load(mkfile.plugins())?,
+            ..MakeOptions::default()
+        })
+    }
+}
+
+/// How many targets may run their commands at the same time, as given on
+/// the command line or in a profile: a number, or `auto`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Jobs {
+    Count(usize),
+    Auto,
+}
+
+impl FromStr for Jobs {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "auto" => Ok(Jobs::Auto),
+            _ => match text.parse() {
+                Ok(count) if count > 0 => Ok(Jobs::Count(count)),
+                _ => Err(format!(
+                    "Invalid jobs '{text}', expected a positive number or auto"
+                )),
+            },
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Jobs {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Setting {
+            Count(usize),
+            Name(String),
+        }
+        match Setting::deserialize(deserializer)? {
+            Setting::Count(count) => count.to_string().parse(),
+            Setting::Name(name) => name.parse(),
+        }
+        .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for Jobs {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Jobs::Count(count) => serializer.serialize_u64(*count as u64),
+            Jobs::Auto => serializer.serialize_str("auto"),
+        }
+    }
+}
+
+/// Why a target has to be made.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RebuildReason {
+    /// The target's output does not exist.
+    OutputMissing,
+    /// The target has not been seen before.
+    NotRecorded,
+    /// The file changed since it was last recorded.
+    Modified,
+    /// One of the target's dependencies was made.
+    DependencyChanged(Target),
+    /// Virtual targets without dependencies are always made.
+    NoDependencies,
+    /// The freshness strategy treats everything as changed.
+    AlwaysDirty,
+    /// An environment variable the rule reads has a different value than
+    /// when the target was last made.
+    EnvChanged(String),
+    /// The output of a command probing a tool the rule uses changed, as
+    /// when the compiler was upgraded.
+    ToolChanged(String),
+    /// The check of the plugin for the target's kind failed.
+    KindOutdated(String),
+}
+
+impl fmt::Display for RebuildReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RebuildReason::OutputMissing => write!(f, "output is missing"),
+            RebuildReason::NotRecorded => write!(f, "no previous build recorded"),
+            RebuildReason::Modified => write!(f, "modified since last build"),
+            RebuildReason::DependencyChanged(dependency) => {
+                write!(f, "dependency '{dependency}' changed")
+            }
+            RebuildReason::NoDependencies => write!(f, "virtual target with no dependencies"),
+            RebuildReason::AlwaysDirty => write!(f, "always considered changed"),
+            RebuildReason::EnvChanged(name) => {
+                write!(f, "environment variable '{name}' changed")
+            }
+            RebuildReason::ToolChanged(probe) => write!(f, "output of '{probe}' changed"),
+            RebuildReason::KindOutdated(kind) => write!(f, "'{kind}' check failed"),
+        }
+    }
+}
+
+/// What mk remembers between runs about the targets it has seen.
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct UpdateState {
     last_update: HashMap<ConcreteTarget, SystemTime>,
+    /// Size in bytes of each target the last time it was made.
+    #[serde(default)]
+    output_size: HashMap<ConcreteTarget, u64>,
+    /// Hash of the contents of each target the last time it changed.
+    #[serde(default)]
+    content_hash: HashMap<ConcreteTarget, String>,
+    /// Whether the commands of each target succeeded, for its most recent
+    /// runs, oldest first.
+    #[serde(default)]
+    outcomes: HashMap<String, Vec<bool>>,
+    /// Stable ID of every target name seen, including names targets had
+    /// before they were renamed.
+    #[serde(default)]
+    ids: HashMap<String, String>,
+    /// Every file or folder a rule produced, with the target of the rule,
+    /// so that `mk clean` knows what to delete.
+    #[serde(default)]
+    produced: HashMap<ConcreteTarget, String>,
+    /// Values of the environment variables listed under `env_inputs:` when
+    /// each target was last made. Unset variables are left out.
+    #[serde(default)]
+    env_inputs: HashMap<String, BTreeMap<String, String>>,
+    /// Hash of the output of each `tools:` probe when each target was last
+    /// made.
+    #[serde(default)]
+    tools: HashMap<String, BTreeMap<String, String>>,
+    /// The ID of the watch daemon and how many changes it had seen when
+    /// each file was last checked, see [`daemon`](crate::daemon).
+    #[serde(default)]
+    watched: HashMap<ConcreteTarget, (String, u64)>,
 }
 
+/// How many runs of each target are remembered.
+const OUTCOME_HISTORY: usize = 10;
+/// Targets whose remembered runs switch between failing and succeeding at
+/// least this often are flaky.
+const FLAKY_FLIPS: usize = 3;
+/// The `PATH` of hermetic commands.
+#[cfg(not(windows))]
+const HERMETIC_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+#[cfg(windows)]
+const HERMETIC_PATH: &str = r"C:\Windows\System32;C:\Windows";
+/// How many more times the commands of a flaky target are run if they fail.
+const FLAKY_RETRIES: usize = 1;
+
 /// Returns the update time of the target. If it's a folder, it recursively
 /// finds the latest update time of all files in the folder.
-pub fn update_time(path: &ConcreteTarget) -> Result<SystemTime, Box<dyn Error>> {
-    let metadata = path.pathbuf().metadata()?;
-    if metadata.is_dir() {
-        let mut latest = metadata.modified()?;
-        if let ConcreteTarget::Deep(path) = path {
-            for entry in std::fs::read_dir(path)? {
-                let entry = entry?;
-                let internal_target = ConcreteTarget::Deep(entry.path());
-                let entry_time = update_time(&internal_target)?;
-                if entry_time > latest {
-                    latest = entry_time;
+pub fn update_time(vfs: &dyn Vfs, path: &ConcreteTarget) -> io::Result<SystemTime> {
+    let found = walk::walk(vfs, path)?;
+    Ok(found
+        .iter()
+        .map(|found| {
+            // Pointing a link somewhere else changes the link, not what it
+            // points to
+            let link = found.link.as_ref().map_or(UNIX_EPOCH, |link| link.modified);
+            found.metadata.modified.max(link)
+        })
+        .fold(UNIX_EPOCH, SystemTime::max))
+}
+
+/// Returns a hash of the contents of the target. Folders hash the names of
+/// their entries and, for deep targets, everything inside them. The files
+/// of deep targets are hashed on several threads at once.
+pub fn content_hash(vfs: &dyn Vfs, path: &ConcreteTarget) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    if !path.is_deep() {
+        hash_shallow(vfs, path, &mut hasher)?;
+        return Ok(format!("{:x}", hasher.finalize()));
+    }
+    let mut found = walk::walk(vfs, path)?;
+    // In order, every folder is followed by what is in it
+    found.sort_by(|a, b| a.path.cmp(&b.path));
+    let hashes = walk::in_parallel(&found, |share| {
+        share
+            .iter()
+            .map(|found| {
+                if found.metadata.is_dir {
+                    Ok(None)
+                } else {
+                    vfs.file_hash(&found.path).map(Some)
                 }
-            }
+            })
+            .collect::<io::Result<Vec<_>>>()
+    });
+    let hashes = hashes.into_iter().collect::<io::Result<Vec<_>>>()?;
+    for (index, (found, hash)) in found.iter().zip(hashes.iter().flatten()).enumerate() {
+        if index > 0 {
+            hasher.update(found.path.as_os_str().as_encoded_bytes());
+            hasher.update([0]);
+        }
+        if let Some(link) = &found.link {
+            hasher.update(link.target.as_os_str().as_encoded_bytes());
+            hasher.update([0]);
+        }
+        if let Some(hash) = hash {
+            hasher.update(hash);
+        }
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn hash_shallow(vfs: &dyn Vfs, path: &ConcreteTarget, hasher: &mut Sha256) -> io::Result<()> {
+    let metadata = vfs.metadata(path.pathbuf())?;
+    if let Some(link) = vfs.link(path.pathbuf())? {
+        hasher.update(link.target.as_os_str().as_encoded_bytes());
+        hasher.update([0]);
+    }
+    if metadata.is_dir {
+        let mut entries = vfs.read_dir(path.pathbuf())?;
+        entries.sort();
+        for entry in entries {
+            hasher.update(entry.as_os_str().as_encoded_bytes());
+            hasher.update([0]);
         }
-        Ok(latest)
     } else {
-        Ok(metadata.modified()?)
+        hasher.update(vfs.file_hash(path.pathbuf())?);
+    }
+    Ok(())
+}
+
+/// Returns the size in bytes of the path, including everything inside it if
+/// it's a folder.
+pub fn disk_usage(vfs: &dyn Vfs, path: &Path) -> io::Result<u64> {
+    let found = walk::walk(vfs, &ConcreteTarget::Deep(path.to_path_buf()))?;
+    Ok(found
+        .iter()
+        .filter(|found| !found.metadata.is_dir)
+        .map(|found| found.metadata.len)
+        .sum())
+}
+
+/// Formats a size in bytes for humans.
+pub(crate) fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Fails if there is not enough free space to write about `expected` bytes
+/// for the target. Space taken by a previous version of the output counts as
+/// available, since it gets overwritten.
+fn check_disk_space(vfs: &dyn Vfs, target: &Target, expected: u64) -> Result<(), MkError> {
+    let (mut dir, existing) = match target {
+        Target::Concrete(path) => (
+            path.pathbuf()
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_default(),
+            disk_usage(vfs, path.pathbuf()).unwrap_or(0),
+        ),
+        Target::Virtual(_) => (PathBuf::new(), 0),
+    };
+    // The output folder may not exist yet, so check the closest one that does
+    while !dir.as_os_str().is_empty() && !vfs.exists(&dir) {
+        dir.pop();
+    }
+    if dir.as_os_str().is_empty() {
+        dir = PathBuf::from(".");
     }
+
+    let needed = expected.saturating_sub(existing);
+    let available = vfs.available_space(&dir)?;
+    if available < needed {
+        return Err(MkError::NoSpace {
+            target: target.clone(),
+            needed,
+            available,
+            dir,
+        });
+    }
+    Ok(())
 }
 
 impl UpdateState {
-    /// Determines if the given path is up to date.
-    pub fn is_up_to_date(&self, path: &ConcreteTarget) -> Result<bool, Box<dyn Error>> {
+    /// Loads the state from the given path, or starts from scratch if there
+    /// is none. A state file that can't be parsed is backed up and replaced
+    /// with an empty state, which makes everything rebuild.
+    pub fn load(vfs: &dyn Vfs, path: &Path) -> Self {
+        let Ok(bytes) = vfs.read(path) else {
+            return UpdateState::default();
+        };
+        let parsed = std::str::from_utf8(&bytes)
+            .map_err(|err| err.to_string())
+            .and_then(|text| serde_sexpr::from_str(text).map_err(|err| err.to_string()));
+        match parsed {
+            Ok(state) => state,
+            Err(err) => {
+                warn!("State file '{}' is corrupt: {}", path.display(), err);
+                match Self::backup(vfs, path, &bytes) {
+                    Ok(backup) => warn!("Backed it up to '{}'", backup.display()),
+                    Err(err) => warn!("Could not back it up: {}", err),
+                }
+                warn!("Starting from an empty state, so everything will be rebuilt");
+                UpdateState::default()
+            }
+        }
+    }
+
+    /// Copies the contents of a state file next to it, returning the path of
+    /// the copy.
+    fn backup(vfs: &dyn Vfs, path: &Path, bytes: &[u8]) -> std::io::Result<PathBuf> {
+        let backup = with_suffix(path, ".bak");
+        vfs.write(&backup, bytes)?;
+        Ok(backup)
+    }
+
+    /// Returns where the state a path held before it was last saved is kept.
+    pub fn previous_path(path: &Path) -> PathBuf {
+        with_suffix(path, ".prev")
+    }
+
+    /// Backs up the state file, if any, and replaces it with an empty state.
+    pub fn reset(vfs: &dyn Vfs, path: &Path) -> Result<(), MkError> {
+        if let Ok(bytes) = vfs.read(path) {
+            let backup = Self::backup(vfs, path, &bytes)?;
+            info!("Backed up the previous state to '{}'", backup.display());
+        }
+        UpdateState::default().save(vfs, path)
+    }
+
+    /// Writes the state to the given path, keeping what was there before at
+    /// its `previous_path`. The state is written next to the path first and
+    /// then moved over it, so that a run killed halfway through doesn't
+    /// leave a truncated state file behind.
+    pub fn save(&self, vfs: &dyn Vfs, path: &Path) -> Result<(), MkError> {
+        if let Ok(previous) = vfs.read(path) {
+            let previous_path = Self::previous_path(path);
+            let temporary = with_suffix(&previous_path, ".tmp");
+            vfs.write(&temporary, &previous)?;
+            vfs.rename(&temporary, &previous_path)?;
+        }
+        let text = serde_sexpr::to_string(self)
+            .map_err(|err| MkError::Io(io::Error::other(err.to_string())))?;
+        let temporary = with_suffix(path, ".tmp");
+        vfs.write(&temporary, text.as_bytes())?;
+        vfs.rename(&temporary, path)?;
+        Ok(())
+    }
+
+    /// Returns every entry of the state as a row keyed by the kind of entry
+    /// and the JSON of its key, holding the JSON of its value. Stores that
+    /// keep rows use this to only write the entries that changed.
+    pub fn to_rows(&self) -> BTreeMap<(String, String), String> {
+        fn add<K: Serialize, V: Serialize>(
+            rows: &mut BTreeMap<(String, String), String>,
+            kind: &str,
+            entries: &HashMap<K, V>,
+        ) {
+            for (key, value) in entries {
+                rows.insert(
+                    (kind.to_string(), serde_json::to_string(key).unwrap()),
+                    serde_json::to_string(value).unwrap(),
+                );
+            }
+        }
+
+        let mut rows = BTreeMap::new();
+        add(&mut rows, "last_update", &self.last_update);
+        add(&mut rows, "output_size", &self.output_size);
+        add(&mut rows, "content_hash", &self.content_hash);
+        add(&mut rows, "outcomes", &self.outcomes);
+        add(&mut rows, "ids", &self.ids);
+        add(&mut rows, "produced", &self.produced);
+        add(&mut rows, "env_inputs", &self.env_inputs);
+        add(&mut rows, "tools", &self.tools);
+        add(&mut rows, "watched", &self.watched);
+        rows
+    }
+
+    /// Rebuilds the state from the rows returned by `to_rows`. Rows of
+    /// unknown kinds are ignored.
+    pub fn from_rows(
+        rows: impl IntoIterator<Item = ((String, String), String)>,
+    ) -> serde_json::Result<Self> {
+        fn insert<K: DeserializeOwned + Eq + Hash, V: DeserializeOwned>(
+            entries: &mut HashMap<K, V>,
+            key: &str,
+            value: &str,
+        ) -> serde_json::Result<()> {
+            entries.insert(serde_json::from_str(key)?, serde_json::from_str(value)?);
+            Ok(())
+        }
+
+        let mut state = UpdateState::default();
+        for ((kind, key), value) in rows {
+            match kind.as_str() {
+                "last_update" => insert(&mut state.last_update, &key, &value)?,
+                "output_size" => insert(&mut state.output_size, &key, &value)?,
+                "content_hash" => insert(&mut state.content_hash, &key, &value)?,
+                "outcomes" => insert(&mut state.outcomes, &key, &value)?,
+                "ids" => insert(&mut state.ids, &key, &value)?,
+                "produced" => insert(&mut state.produced, &key, &value)?,
+                "env_inputs" => insert(&mut state.env_inputs, &key, &value)?,
+                "tools" => insert(&mut state.tools, &key, &value)?,
+                "watched" => insert(&mut state.watched, &key, &value)?,
+                _ => {}
+            }
+        }
+        Ok(state)
+    }
+
+    /// Determines if the given path is up to date. If it's not, returns the
+    /// reason why.
+    pub fn is_up_to_date(
+        &self,
+        vfs: &dyn Vfs,
+        path: &ConcreteTarget,
+    ) -> Result<Result<(), RebuildReason>, MkError> {
         let last_update = self.last_update.get(path);
         if let Some(last_update) = last_update {
-            let current_update = update_time(path)?;
-            Ok(current_update <= *last_update)
+            let current_update = update_time(vfs, path)?;
+            if current_update <= *last_update {
+                Ok(Ok(()))
+            } else {
+                Ok(Err(RebuildReason::Modified))
+            }
         } else {
-            Ok(false)
+            Ok(Err(RebuildReason::NotRecorded))
         }
     }
 
+    /// Returns true if the update time of the path is recorded.
+    pub fn is_recorded(&self, path: &ConcreteTarget) -> bool {
+        self.last_update.contains_key(path)
+    }
+
+    /// Returns the ID of the watch daemon and how many changes it had seen
+    /// when the path was last checked.
+    pub fn watched(&self, path: &ConcreteTarget) -> Option<(&str, u64)> {
+        let (daemon, seen) = self.watched.get(path)?;
+        Some((daemon, *seen))
+    }
+
+    /// Remembers that the path was checked when the watch daemon had seen
+    /// that many changes.
+    pub fn set_watched(&mut self, path: &ConcreteTarget, daemon: &str, seen: u64) {
+        self.watched
+            .insert(path.clone(), (daemon.to_string(), seen));
+    }
+
     /// Updates the state of the given path.
-    pub fn update_state(&mut self, path: &ConcreteTarget) -> Result<(), Box<dyn Error>> {
-        let current_update = update_time(path)?;
+    pub fn update_state(&mut self, vfs: &dyn Vfs, path: &ConcreteTarget) -> Result<(), MkError> {
+        let current_update = update_time(vfs, path)?;
         self.last_update.insert(path.clone(), current_update);
         Ok(())
     }
+
+    /// Remembers how big the given path is after making it.
+    pub fn record_size(&mut self, vfs: &dyn Vfs, path: &ConcreteTarget) -> Result<(), MkError> {
+        let size = disk_usage(vfs, path.pathbuf())?;
+        self.output_size.insert(path.clone(), size);
+        Ok(())
+    }
+
+    /// Records the content hash of the given path. Returns true if it differs
+    /// from the previously recorded one, or if there was none.
+    pub fn update_hash(&mut self, vfs: &dyn Vfs, path: &ConcreteTarget) -> Result<bool, MkError> {
+        let hash = content_hash(vfs, path)?;
+        Ok(self.record_hash(path, hash))
+    }
+
+    /// Records a hash of the given path computed some other way, as a
+    /// plugin's fingerprint. Returns true if it differs from the previous
+    /// one.
+    pub fn record_hash(&mut self, path: &ConcreteTarget, hash: String) -> bool {
+        let changed = self.content_hash.get(path) != Some(&hash);
+        self.content_hash.insert(path.clone(), hash);
+        changed
+    }
+
+    /// Returns the stable ID of a target. Targets keep the ID they were
+    /// first given, even after being renamed, as long as their rule lists
+    /// the old name under `was:`.
+    pub fn target_id(&self, target: &Target) -> String {
+        let name = target.to_string();
+        self.ids
+            .get(&name)
+            .cloned()
+            .unwrap_or_else(|| new_id(&name))
+    }
+
+    /// Removes the entries of targets and files that no rule of the mkfile
+    /// refers to anymore, whether as a target, a dependency, an include
+    /// found by a scanner, or an old name under `was:`. Returns how many
+    /// entries were removed.
+    pub fn collect_garbage(&mut self, file: &MkFile, vfs: &dyn Vfs) -> usize {
+        let mut live = HashSet::new();
+        for target in file.targets() {
+            live.insert(target.clone());
+            live.extend(file.dependencies(target).iter().cloned());
+            live.extend(implicit_dependencies(file, vfs, target));
+            live.extend(
+                file.options(target)
+                    .was
+                    .iter()
+                    .map(|name| Target::parse(name)),
+            );
+        }
+        let names: HashSet<String> = live.iter().map(Target::to_string).collect();
+        let is_live = |path: &ConcreteTarget| live.contains(&Target::Concrete(path.clone()));
+
+        let before = self.len();
+        self.last_update.retain(|path, _| is_live(path));
+        self.output_size.retain(|path, _| is_live(path));
+        self.content_hash.retain(|path, _| is_live(path));
+        self.outcomes.retain(|name, _| names.contains(name));
+        self.ids.retain(|name, _| names.contains(name));
+        self.env_inputs.retain(|name, _| names.contains(name));
+        self.tools.retain(|name, _| names.contains(name));
+        self.watched.retain(|path, _| is_live(path));
+        // Outputs of removed rules are kept for `mk clean` while they exist
+        self.produced
+            .retain(|path, _| is_live(path) || vfs.exists(path.pathbuf()));
+        before - self.len()
+    }
+
+    /// Lists every recorded value as its target, what it is and the
+    /// value, sorted by target. With a target, only lists its values.
+    pub fn entries(&self, target: Option<&Target>) -> Vec<(String, &'static str, String)> {
+        let mut shown = self.clone();
+        if let Some(target) = target {
+            shown.retain(|name| is_about(target, name));
+        }
+        UpdateState::default()
+            .diff(&shown)
+            .into_iter()
+            .filter_map(|change| Some((change.target, change.field, change.after?)))
+            .collect()
+    }
+
+    /// Forgets what was recorded about the target, except for its stable
+    /// ID and the outputs it produced, so that it is made again. Files are
+    /// matched by path, whether they were recorded as deep or shallow
+    /// targets. Returns how many entries were removed.
+    pub fn forget(&mut self, target: &Target) -> usize {
+        let ids = self.ids.clone();
+        let produced = self.produced.clone();
+        let before = self.len();
+        self.retain(|name| !is_about(target, name));
+        self.ids = ids;
+        self.produced = produced;
+        before - self.len()
+    }
+
+    /// Forgets what was recorded about every target, except for their
+    /// stable IDs and the outputs they produced, so that everything is made
+    /// again. Returns how many entries were removed.
+    pub fn clear(&mut self) -> usize {
+        let ids = std::mem::take(&mut self.ids);
+        let produced = std::mem::take(&mut self.produced);
+        let before = self.len();
+        *self = UpdateState {
+            ids,
+            produced,
+            ..UpdateState::default()
+        };
+        before
+    }
+
+    /// Returns every output a rule produced, with the target of the rule.
+    pub fn produced(&self) -> impl Iterator<Item = (&ConcreteTarget, &str)> {
+        self.produced
+            .iter()
+            .map(|(path, target)| (path, target.as_str()))
+    }
+
+    /// Forgets everything recorded about an output, once it was deleted.
+    pub fn forget_output(&mut self, path: &ConcreteTarget) {
+        self.last_update.remove(path);
+        self.output_size.remove(path);
+        self.content_hash.remove(path);
+        self.produced.remove(path);
+        self.watched.remove(path);
+    }
+
+    /// Keeps only the entries for which `keep` returns true, given the name
+    /// of the target they are recorded for.
+    fn retain(&mut self, keep: impl Fn(&str) -> bool) {
+        let file = |path: &ConcreteTarget| keep(&Target::Concrete(path.clone()).to_string());
+        self.last_update.retain(|path, _| file(path));
+        self.output_size.retain(|path, _| file(path));
+        self.content_hash.retain(|path, _| file(path));
+        self.outcomes.retain(|name, _| keep(name));
+        self.ids.retain(|name, _| keep(name));
+        self.produced.retain(|path, _| file(path));
+        self.env_inputs.retain(|name, _| keep(name));
+        self.tools.retain(|name, _| keep(name));
+        self.watched.retain(|path, _| file(path));
+    }
+
+    /// Returns how many entries the state has.
+    fn len(&self) -> usize {
+        self.last_update.len()
+            + self.output_size.len()
+            + self.content_hash.len()
+            + self.outcomes.len()
+            + self.ids.len()
+            + self.produced.len()
+            + self.env_inputs.len()
+            + self.tools.len()
+            + self.watched.len()
+    }
+
+    /// Returns the stable IDs recorded so far, by target name.
+    pub fn ids(&self) -> &HashMap<String, String> {
+        &self.ids
+    }
+
+    /// Gives every target of the mkfile a stable ID, if it doesn't have one
+    /// yet. Renamed targets get the ID of the name they had before.
+    pub fn assign_ids(&mut self, mkfile: &MkFile) {
+        for target in mkfile.targets() {
+            let name = target.to_string();
+            if self.ids.contains_key(&name) {
+                continue;
+            }
+            let id = mkfile
+                .options(target)
+                .was
+                .iter()
+                .find_map(|old| self.ids.get(old).cloned())
+                .unwrap_or_else(|| new_id(&name));
+            self.ids.insert(name, id);
+        }
+    }
+
+    /// Remembers whether the commands of the target succeeded.
+    pub fn record_outcome(&mut self, target: &Target, succeeded: bool) {
+        let outcomes = self.outcomes.entry(target.to_string()).or_default();
+        outcomes.push(succeeded);
+        if outcomes.len() > OUTCOME_HISTORY {
+            outcomes.remove(0);
+        }
+    }
+
+    /// Returns the number of runs the target has been succeeding, if
+    /// positive, or failing, if negative.
+    pub fn streak(&self, target: &Target) -> i64 {
+        let Some(outcomes) = self.outcomes.get(&target.to_string()) else {
+            return 0;
+        };
+        let Some(&last) = outcomes.last() else {
+            return 0;
+        };
+        let length = outcomes.iter().rev().take_while(|o| **o == last).count() as i64;
+        if last {
+            length
+        } else {
+            -length
+        }
+    }
+
+    /// Returns true if the target keeps switching between failing and
+    /// succeeding over its recent runs.
+    pub fn is_flaky(&self, target: &Target) -> bool {
+        self.outcomes
+            .get(&target.to_string())
+            .is_some_and(|outcomes| {
+                outcomes
+                    .windows(2)
+                    .filter(|pair| pair[0] != pair[1])
+                    .count()
+                    >= FLAKY_FLIPS
+            })
+    }
+
+    /// Returns the first environment variable listed by the target's rule
+    /// whose value is not the one recorded when it was last made.
+    pub fn changed_env_input(
+        &self,
+        target: &Target,
+        values: &BTreeMap<String, String>,
+    ) -> Option<String> {
+        first_change(self.env_inputs.get(&target.to_string()), values)
+    }
+
+    /// Remembers the values of the environment variables the target's rule
+    /// reads, after making it.
+    pub fn record_env_inputs(&mut self, target: &Target, values: BTreeMap<String, String>) {
+        record_values(&mut self.env_inputs, target, values);
+    }
+
+    /// Returns the first `tools:` probe of the target's rule whose output
+    /// hashes differently than when the target was last made.
+    pub fn changed_tool(
+        &self,
+        target: &Target,
+        fingerprints: &BTreeMap<String, String>,
+    ) -> Option<String> {
+        first_change(self.tools.get(&target.to_string()), fingerprints)
+    }
+
+    /// Remembers the fingerprints of the tools the target's rule uses,
+    /// after making it.
+    pub fn record_tools(&mut self, target: &Target, fingerprints: BTreeMap<String, String>) {
+        record_values(&mut self.tools, target, fingerprints);
+    }
+
+    /// Returns the content hash of the given path the last time it changed.
+    pub fn recorded_hash(&self, path: &ConcreteTarget) -> Option<&str> {
+        self.content_hash.get(path).map(String::as_str)
+    }
+
+    /// Returns the size of the given path the last time it was made.
+    pub fn recorded_size(&self, path: &ConcreteTarget) -> Option<u64> {
+        self.output_size.get(path).copied()
+    }
+
+    /// Lists everything that differs from a newer state, sorted by target.
+    pub fn diff(&self, newer: &UpdateState) -> Vec<StateChange> {
+        let path = |path: &ConcreteTarget| Target::Concrete(path.clone()).to_string();
+        let mut changes = Vec::new();
+        diff_entries(
+            &mut changes,
+            "mtime",
+            &self.last_update,
+            &newer.last_update,
+            path,
+            |time| match time.duration_since(UNIX_EPOCH) {
+                Ok(since) => format!("{}.{:09}", since.as_secs(), since.subsec_nanos()),
+                Err(_) => "before 1970".to_string(),
+            },
+        );
+        diff_entries(
+            &mut changes,
+            "size",
+            &self.output_size,
+            &newer.output_size,
+            path,
+            |size| format_size(*size),
+        );
+        diff_entries(
+            &mut changes,
+            "hash",
+            &self.content_hash,
+            &newer.content_hash,
+            path,
+            |hash| hash.chars().take(12).collect(),
+        );
+        diff_entries(
+            &mut changes,
+            "outcomes",
+            &self.outcomes,
+            &newer.outcomes,
+            String::clone,
+            |outcomes| {
+                outcomes
+                    .iter()
+                    .map(|ok| if *ok { "pass" } else { "fail" })
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            },
+        );
+        diff_entries(
+            &mut changes,
+            "id",
+            &self.ids,
+            &newer.ids,
+            String::clone,
+            String::clone,
+        );
+        diff_entries(
+            &mut changes,
+            "produced by",
+            &self.produced,
+            &newer.produced,
+            path,
+            String::clone,
+        );
+        diff_entries(
+            &mut changes,
+            "env",
+            &self.env_inputs,
+            &newer.env_inputs,
+            String::clone,
+            |values| {
+                values
+                    .iter()
+                    .map(|(name, value)| format!("{name}={value}"))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            },
+        );
+        diff_entries(
+            &mut changes,
+            "tools",
+            &self.tools,
+            &newer.tools,
+            String::clone,
+            |fingerprints| {
+                fingerprints
+                    .iter()
+                    .map(|(probe, hash)| format!("'{probe}' {}", &hash[..hash.len().min(12)]))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            },
+        );
+        changes.sort_by(|a, b| (&a.target, a.field).cmp(&(&b.target, b.field)));
+        changes
+    }
+}
+
+/// Returns the first name whose value differs from the recorded one,
+/// including names that only have a recorded value.
+fn first_change(
+    recorded: Option<&BTreeMap<String, String>>,
+    values: &BTreeMap<String, String>,
+) -> Option<String> {
+    let recorded_value = |name| recorded.and_then(|recorded| recorded.get(name));
+    values
+        .keys()
+        .chain(recorded.into_iter().flat_map(BTreeMap::keys))
+        .find(|name| values.get(*name) != recorded_value(*name))
+        .cloned()
+}
+
+/// Records named values for a target, or forgets them if there are none.
+fn record_values(
+    entries: &mut HashMap<String, BTreeMap<String, String>>,
+    target: &Target,
+    values: BTreeMap<String, String>,
+) {
+    if values.is_empty() {
+        entries.remove(&target.to_string());
+    } else {
+        entries.insert(target.to_string(), values);
+    }
+}
+
+/// One recorded value that differs between two states.
+#[derive(Debug, PartialEq)]
+pub struct StateChange {
+    pub target: String,
+    /// Which of the recorded values, e.g. `mtime` or `hash`.
+    pub field: &'static str,
+    /// The value in the older state, if it had one.
+    pub before: Option<String>,
+    /// The value in the newer state, if it has one.
+    pub after: Option<String>,
+}
+
+fn diff_entries<K: Eq + std::hash::Hash, V: PartialEq>(
+    changes: &mut Vec<StateChange>,
+    field: &'static str,
+    before: &HashMap<K, V>,
+    after: &HashMap<K, V>,
+    key: impl Fn(&K) -> String,
+    value: impl Fn(&V) -> String,
+) {
+    for (k, old) in before {
+        if after.get(k) != Some(old) {
+            changes.push(StateChange {
+                target: key(k),
+                field,
+                before: Some(value(old)),
+                after: after.get(k).map(&value),
+            });
+        }
+    }
+    for (k, new) in after {
+        if !before.contains_key(k) {
+            changes.push(StateChange {
+                target: key(k),
+                field,
+                before: None,
+                after: Some(value(new)),
+            });
+        }
+    }
+}
+
+/// Returns true if an entry recorded for the target name is about the
+/// target. Files are matched by path, whether they are deep or shallow.
+fn is_about(target: &Target, name: &str) -> bool {
+    match (target, Target::parse(name)) {
+        (Target::Concrete(target), Target::Concrete(recorded)) => {
+            target.pathbuf() == recorded.pathbuf()
+        }
+        (target, recorded) => *target == recorded,
+    }
+}
+
+/// Returns the ID for a target name that was never seen before.
+fn new_id(name: &str) -> String {
+    let hash = format!("{:x}", Sha256::digest(name.as_bytes()));
+    format!("t{}", &hash[..12])
+}
+
+/// Returns the files the target's dependencies refer to, according to the
+/// scanner its rule picks, if any.
+pub fn implicit_dependencies(file: &MkFile, vfs: &dyn Vfs, target: &Target) -> Vec<Target> {
+    let Some(scanner) = file.options(target).scan.as_deref().and_then(scan::scanner) else {
+        return Vec::new();
+    };
+    let sources: Vec<PathBuf> = file
+        .dependencies(target)
+        .iter()
+        .filter_map(|dependency| match dependency {
+            Target::Concrete(ConcreteTarget::Shallow(path)) => Some(path.clone()),
+            _ => None,
+        })
+        .collect();
+    scan::scan_all(scanner, vfs, &sources)
+        .into_iter()
+        .map(|path| Target::Concrete(ConcreteTarget::Shallow(normalize(&path))))
+        .collect()
+}
+
+/// Returns the path with the suffix added to its file name.
+pub(crate) fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// How far along a target is in the current build.
+enum Progress {
+    /// Waiting for this many targets to be done before its next stage.
+    Waiting(usize, Stage),
+    /// Ready to be picked up by a worker, or being made by one.
+    Running,
+    /// Holds whether the target was made, or `None` if it failed.
+    Done(Option<bool>),
+}
+
+/// The parts of making a target, which are separated by waiting for other
+/// targets.
+enum Stage {
+    /// Find out what the target waits for.
+    Start,
+    /// Its producer is done, and the target is made with it.
+    Produced(Target),
+    /// Its dependencies are done, so its sources can be scanned.
+    Dependencies,
+    /// The dependencies found by scanning are done too.
+    Scanned(Vec<Target>),
+}
+
+/// What a stage of making a target ended with.
+enum Step {
+    /// The target waits for these targets, and then goes on to the stage.
+    Wait(Vec<Target>, Stage),
+    /// Holds whether the target was made.
+    Done(bool),
+}
+
+/// A target of the build.
+struct Node {
+    progress: Progress,
+    /// Targets waiting for this one.
+    dependents: Vec<Target>,
+}
+
+/// The targets of a build and the ones that are ready to be made.
+#[derive(Default)]
+struct Schedule {
+    nodes: HashMap<Target, Node>,
+    /// Stages that can run now. The last is taken first, so that with one
+    /// job targets are made depth first, in the order they are listed.
+    ready: Vec<(Target, Stage)>,
+    /// How many workers are running a stage.
+    busy: usize,
+    /// Why the build failed, once it has. No new targets are started then.
+    error: Option<MkError>,
+}
+
+/// Limits how many targets run their commands at the same time.
+struct JobSlots {
+    total: usize,
+    /// Memory that rules declaring `memory:` may use together, if limited.
+    memory: Option<u64>,
+    free: Mutex<FreeSlots>,
+    released: Condvar,
+    resources: ResourceLimits,
+    /// Hold back new jobs while the machine is overloaded.
+    adaptive: bool,
+    /// Hold back new jobs while the load average is above this.
+    max_load: Option<f64>,
+}
+
+struct FreeSlots {
+    slots: usize,
+    memory: u64,
+}
+
+impl JobSlots {
+    /// Creates slots for as many jobs as asked for, or as the open-file and
+    /// process limits allow if that is fewer. With `auto`, there is a slot
+    /// for every core and the memory available is shared out.
+    fn new(jobs: usize, auto: bool, max_load: Option<f64>, resources: ResourceLimits) -> Self {
+        let (jobs, memory) = if auto {
+            let cores = thread::available_parallelism().map_or(1, |cores| cores.get());
+            (cores, limits::available_memory())
+        } else {
+            (jobs, None)
+        };
+        let max_jobs = resources.max_jobs();
+        if jobs > max_jobs {
+            warn!(
+                "Running at most {max_jobs} jobs at once, as more would go over \
+                 the open file or process limits (see --raise-limits)"
+            );
+        }
+        let total = jobs.clamp(1, max_jobs);
+        JobSlots {
+            total,
+            memory,
+            free: Mutex::new(FreeSlots {
+                slots: total,
+                memory: memory.unwrap_or(0),
+            }),
+            released: Condvar::new(),
+            resources,
+            adaptive: auto,
+            max_load,
+        }
+    }
+
+    /// Waits for `count` free slots and, if memory is shared out, `memory`
+    /// bytes of it, which are given back when the guard is dropped. Asking
+    /// for more than there is waits for all of it. While mk is close to its
+    /// open-file limit, the machine is overloaded when adapting, or the load
+    /// average is above the maximum, waits for running jobs to finish first.
+    fn acquire(&self, count: usize, memory: u64) -> JobSlot<'_> {
+        let count = count.clamp(1, self.total);
+        let memory = self.memory.map_or(0, |total| memory.min(total));
+        let mut free = self.free.lock().unwrap();
+        loop {
+            let fits = |free: &FreeSlots| free.slots >= count && free.memory >= memory;
+            if !fits(&free) {
+                free = self.released.wait(free).unwrap();
+                continue;
+            }
+            if free.slots == self.total {
+                break;
+            }
+            // Checking the machine reads files, so the lock isn't held
+            // meanwhile
+            drop(free);
+            let hold = self.should_hold(count);
+            free = self.free.lock().unwrap();
+            if !hold {
+                if fits(&free) {
+                    break;
+                }
+                continue;
+            }
+            // Files are closed and load goes down without slots being
+            // released
+            free = self
+                .released
+                .wait_timeout(free, Duration::from_millis(100))
+                .unwrap()
+                .0;
+        }
+        free.slots -= count;
+        free.memory -= memory;
+        JobSlot(self, count, memory)
+    }
+
+    /// Returns true if starting more jobs now would make things worse.
+    fn should_hold(&self, count: usize) -> bool {
+        self.resources.near_open_files_limit(count)
+            || (self.adaptive && limits::under_pressure(self.total))
+            || self.max_load.is_some_and(limits::load_above)
+    }
+}
+
+struct JobSlot<'a>(&'a JobSlots, usize, u64);
+
+impl Drop for JobSlot<'_> {
+    fn drop(&mut self) {
+        let mut free = self.0.free.lock().unwrap();
+        free.slots += self.1;
+        free.memory += self.2;
+        self.0.released.notify_all();
+    }
+}
+
+/// A single call to `make`, shared by the workers making targets.
+struct Build<'a> {
+    file: &'a MkFile,
+    options: &'a MakeOptions,
+    state: Mutex<&'a mut UpdateState>,
+    schedule: Mutex<Schedule>,
+    /// Signalled when a stage ends, which may make others ready.
+    changed: Condvar,
+    slots: JobSlots,
+    /// Hash of the output of every `tools:` probe run so far, as each is
+    /// only run once per build.
+    fingerprints: Mutex<HashMap<String, String>>,
 }
 
 /// Returns true if the target was updated. Might be an error if there is no
 /// rule to make the target.
+///
+/// Every target is made at most once, however many targets depend on it.
+/// With more than one job, targets whose dependencies are done are made in
+/// parallel by as many workers.
 pub fn make(
     file: &MkFile,
     target: &Target,
     update_state: &mut UpdateState,
-) -> Result<bool, Box<dyn std::error::Error>> {
-    info!("Making target '{:?}'", target);
-
-    if !file.has_target(target) {
-        match target {
-            Target::Virtual(name) => {
-                return Err(format!("No rule to make virtual target '{name}'").into());
-            }
-            Target::Concrete(path) => {
-                if !update_state.is_up_to_date(path)? {
-                    update_state.update_state(path)?;
-                    return Ok(true);
-                } else {
-                    return Ok(false);
-                }
+    options: &MakeOptions,
+) -> Result<bool, MkError> {
+    if let Some(cycle) = file.find_cycle(target) {
+        return Err(MkError::Cycle(cycle));
+    }
+    if let Some(unsupported) = file.find_unsupported(target) {
+        return Err(MkError::UnsupportedPlatform {
+            target: unsupported.clone(),
+            platforms: file.options(unsupported).platforms.clone(),
+        });
+    }
+    let build = Build {
+        file,
+        options,
+        state: Mutex::new(update_state),
+        schedule: Mutex::default(),
+        changed: Condvar::new(),
+        slots: JobSlots::new(
+            options.jobs,
+            options.auto_jobs,
+            options.max_load,
+            ResourceLimits::current(options.raise_limits),
+        ),
+        fingerprints: Mutex::new(HashMap::new()),
+    };
+    build.make(target)
+}
+
+impl Build<'_> {
+    /// Makes the target and everything it depends on, with a worker for
+    /// every job slot.
+    fn make(&self, target: &Target) -> Result<bool, MkError> {
+        let workers = self.slots.total.min(self.file.reachable(target).len());
+        self.request(&mut self.schedule.lock().unwrap(), target, None);
+        thread::scope(|scope| {
+            for _ in 1..workers {
+                scope.spawn(|| self.work());
             }
+            self.work();
+        });
+
+        let mut schedule = self.schedule.lock().unwrap();
+        match schedule.nodes[target].progress {
+            Progress::Done(Some(made)) => Ok(made),
+            _ => Err(schedule.error.take().unwrap_or_else(|| {
+                // Targets found while making others can still loop
+                let waiting = schedule
+                    .nodes
+                    .iter()
+                    .filter(|(_, node)| matches!(node.progress, Progress::Waiting(..)))
+                    .map(|(target, _)| target.clone())
+                    .collect();
+                MkError::Cycle(waiting)
+            })),
         }
     }
 
-    let dependency_make_results = file
-        .dependencies(target)
-        .iter()
-        .map(|t| make(file, t, update_state))
-        .collect::<Result<Vec<_>, _>>()?;
+    /// Adds the target to the build, unless it is there already, with the
+    /// dependent waiting for it. Returns what became of it if it is done.
+    fn request(
+        &self,
+        schedule: &mut Schedule,
+        target: &Target,
+        dependent: Option<&Target>,
+    ) -> Option<Option<bool>> {
+        match schedule.nodes.get_mut(target) {
+            Some(Node {
+                progress: Progress::Done(made),
+                ..
+            }) => return Some(*made),
+            Some(node) => node.dependents.extend(dependent.cloned()),
+            None => {
+                let node = Node {
+                    progress: Progress::Running,
+                    dependents: dependent.into_iter().cloned().collect(),
+                };
+                schedule.nodes.insert(target.clone(), node);
+                schedule.ready.push((target.clone(), Stage::Start));
+            }
+        }
+        None
+    }
+
+    /// Runs stages that are ready until there are none left and none are
+    /// running, or the build failed.
+    fn work(&self) {
+        let reporter = &self.options.reporter;
+        let mut schedule = self.schedule.lock().unwrap();
+        loop {
+            let job = match schedule.error {
+                None => schedule.ready.pop(),
+                Some(_) => None,
+            };
+            let Some((target, stage)) = job else {
+                if schedule.busy == 0 {
+                    self.changed.notify_all();
+                    return;
+                }
+                schedule = self.changed.wait(schedule).unwrap();
+                continue;
+            };
+            schedule.busy += 1;
+            drop(schedule);
+
+            let step = self.step(&target, stage);
+            match &step {
+                Ok(Step::Done(made)) => reporter.report(&Event::TargetFinished(&target, *made)),
+                Err(err) => reporter.report(&Event::TargetFailed(&target, &err.to_string())),
+                Ok(Step::Wait(..)) => {}
+            }
+
+            schedule = self.schedule.lock().unwrap();
+            schedule.busy -= 1;
+            let failed = match step {
+                Ok(Step::Wait(targets, stage)) => self.wait(&mut schedule, &target, targets, stage),
+                Ok(Step::Done(made)) => self.done(&mut schedule, &target, Some(made)),
+                Err(err) => {
+                    schedule.error.get_or_insert(err);
+                    self.done(&mut schedule, &target, None)
+                }
+            };
+            self.changed.notify_all();
+            if !failed.is_empty() {
+                drop(schedule);
+                for (dependent, dependency) in failed {
+                    let err = MkError::DependencyFailed(dependency);
+                    reporter.report(&Event::TargetFailed(&dependent, &err.to_string()));
+                }
+                schedule = self.schedule.lock().unwrap();
+            }
+        }
+    }
 
-    let mut needs_making = dependency_make_results.iter().any(|b| *b);
+    /// Has the target wait for others before going on to the stage. Returns
+    /// the targets that failed because one of those did, along with it.
+    fn wait(
+        &self,
+        schedule: &mut Schedule,
+        target: &Target,
+        targets: Vec<Target>,
+        stage: Stage,
+    ) -> Vec<(Target, Target)> {
+        let mut waiting = 0;
+        let mut failed = None;
+        // The last ready is made first
+        for dependency in targets.iter().rev() {
+            match self.request(schedule, dependency, Some(target)) {
+                None => waiting += 1,
+                Some(Some(_)) => {}
+                Some(None) => failed = Some(dependency.clone()),
+            }
+        }
+        if let Some(dependency) = failed {
+            let mut failed = vec![(target.clone(), dependency)];
+            failed.extend(self.done(schedule, target, None));
+            return failed;
+        }
+        if waiting == 0 {
+            schedule.ready.push((target.clone(), stage));
+        } else {
+            schedule.nodes.get_mut(target).unwrap().progress = Progress::Waiting(waiting, stage);
+        }
+        Vec::new()
+    }
 
-    // if it's concrete and doesn't exist, it needs making
-    if let Target::Concrete(path) = target {
-        if !path.exists() {
-            needs_making = true;
+    /// Records what became of the target, readying the targets that were
+    /// only waiting for it, or failing them if it failed. Returns the
+    /// targets that failed along with it, and the dependency that failed
+    /// each.
+    fn done(
+        &self,
+        schedule: &mut Schedule,
+        target: &Target,
+        made: Option<bool>,
+    ) -> Vec<(Target, Target)> {
+        let mut failed = Vec::new();
+        let mut pending = vec![target.clone()];
+        while let Some(target) = pending.pop() {
+            let node = schedule.nodes.get_mut(&target).unwrap();
+            node.progress = Progress::Done(made);
+            for dependent in std::mem::take(&mut node.dependents) {
+                let node = schedule.nodes.get_mut(&dependent).unwrap();
+                let Progress::Waiting(count, _) = &mut node.progress else {
+                    // It failed along with another dependency already
+                    continue;
+                };
+                if made.is_none() {
+                    failed.push((dependent.clone(), target.clone()));
+                    pending.push(dependent);
+                    continue;
+                }
+                *count -= 1;
+                if *count == 0 {
+                    if let Progress::Waiting(_, stage) =
+                        std::mem::replace(&mut node.progress, Progress::Running)
+                    {
+                        schedule.ready.push((dependent, stage));
+                    }
+                }
+            }
         }
+        failed
     }
 
-    // If it's virtual and has no dependencies, it always needs making
-    if let Target::Virtual(_) = target {
-        if dependency_make_results.is_empty() {
-            needs_making = true;
+    /// Returns true if the target, which is done, was made.
+    fn made(&self, target: &Target) -> bool {
+        let schedule = self.schedule.lock().unwrap();
+        matches!(schedule.nodes[target].progress, Progress::Done(Some(true)))
+    }
+
+    /// Runs a stage of making the target.
+    fn step(&self, target: &Target, stage: Stage) -> Result<Step, MkError> {
+        match stage {
+            Stage::Start => self.start(target),
+            Stage::Produced(producer) => Ok(Step::Done(self.made(&producer))),
+            Stage::Dependencies => {
+                // Scan after making the listed dependencies, which may be
+                // generated
+                let implicit = implicit_dependencies(self.file, self.options.vfs.as_ref(), target);
+                Ok(Step::Wait(implicit.clone(), Stage::Scanned(implicit)))
+            }
+            Stage::Scanned(implicit) => self.make_target(target, &implicit).map(Step::Done),
         }
     }
 
-    if needs_making {
-        let commands = file.commands(target);
-        for command in commands {
-            info!("Executing command '{}'", command);
-            let status = std::process::Command::new("sh")
-                .arg("-c")
-                .arg(command)
-                .status()?;
+    /// Starts making the target: files without a rule are checked, and
+    /// other targets wait for what they depend on.
+    fn start(&self, target: &Target) -> Result<Step, MkError> {
+        if let Some(signal) = interrupt::received() {
+            return Err(MkError::Interrupted(signal));
+        }
+        let file = self.file;
+        let options = self.options;
+        let vfs = options.vfs.as_ref();
+        options.reporter.report(&Event::TargetStarted(target));
 
-            if !status.success() {
-                return Err(format!("Failed to execute command '{}'", command).into());
+        if !file.has_target(target) {
+            if let Some(producer) = file.producer(target) {
+                return Ok(Step::Wait(
+                    vec![producer.clone()],
+                    Stage::Produced(producer.clone()),
+                ));
+            }
+            match target {
+                Target::Virtual(_) => return Err(MkError::NoRule(target.clone())),
+                Target::Concrete(path) if !vfs.exists(path.pathbuf()) => {
+                    return Err(MkError::NoRule(target.clone()));
+                }
+                Target::Concrete(path) => {
+                    let mut update_state = self.state.lock().unwrap();
+                    let freshness = options.freshness.as_ref();
+                    if let Err(reason) = freshness.check(&update_state, vfs, path)? {
+                        // Depending on the strategy, touched files with the
+                        // same contents don't count as changes
+                        if !freshness.record(&mut update_state, vfs, path)? {
+                            options.reporter.report(&Event::Unchanged(target));
+                            return Ok(Step::Done(false));
+                        }
+                        options.reporter.report(&Event::Outdated(target, &reason));
+                        return Ok(Step::Done(true));
+                    } else {
+                        return Ok(Step::Done(false));
+                    }
+                }
             }
         }
+
+        // Optional rules are skipped where they don't work, any others were
+        // rejected before the build started
+        let rule_options = file.options(target);
+        if !rule_options.supports_current_platform() {
+            let reason = format!("only works on {}", rule_options.platforms.join(", "));
+            options.reporter.report(&Event::Skipped(target, &reason));
+            return Ok(Step::Done(false));
+        }
+
+        Ok(Step::Wait(
+            file.dependencies(target).to_vec(),
+            Stage::Dependencies,
+        ))
+    }
+
+    /// Makes the target once everything it depends on is done, if it is out
+    /// of date.
+    fn make_target(&self, target: &Target, implicit: &[Target]) -> Result<bool, MkError> {
+        let file = self.file;
+        let options = self.options;
+        let vfs = options.vfs.as_ref();
+        let rule_options = file.options(target);
+        let dependencies = file.dependencies(target);
+        let dependency_make_results: Vec<bool> =
+            dependencies.iter().map(|t| self.made(t)).collect();
+        let implicit_make_results: Vec<bool> = implicit.iter().map(|t| self.made(t)).collect();
+
+        let mut reason = dependencies
+            .iter()
+            .chain(implicit)
+            .zip(dependency_make_results.iter().chain(&implicit_make_results))
+            .find(|(_, made)| **made)
+            .map(|(dependency, _)| RebuildReason::DependencyChanged(dependency.clone()));
+
+        // if it's concrete and doesn't exist, it needs making
         if let Target::Concrete(path) = target {
-            // See if the file does exist
-            if path.exists() {
-                update_state.update_state(path)?;
+            if !vfs.exists(path.pathbuf()) {
+                reason = Some(RebuildReason::OutputMissing);
+            } else if reason.is_none() && !self.state.lock().unwrap().last_update.contains_key(path)
+            {
+                // Nothing is recorded about outputs that were forgotten, or
+                // made by something other than mk
+                reason = Some(RebuildReason::NotRecorded);
+            }
+        }
+        // So do the `::` rules of a target that is missing
+        if let Some(Target::Concrete(path)) = file.part_of(target) {
+            if reason.is_none() && !vfs.exists(path.pathbuf()) {
+                reason = Some(RebuildReason::OutputMissing);
+            }
+        }
+        for output in &rule_options.outputs {
+            if reason.is_some() {
+                break;
+            }
+            if !vfs.exists(output.pathbuf()) {
+                reason = Some(RebuildReason::OutputMissing);
+            } else if !self.state.lock().unwrap().last_update.contains_key(output) {
+                reason = Some(RebuildReason::NotRecorded);
+            }
+        }
+
+        // A rule that picks its own freshness strategy has its output
+        // checked too
+        let plugin_checker = rule_options
+            .fingerprint
+            .as_deref()
+            .map(|name| options.plugins.get(name))
+            .transpose()?
+            .map(Plugin::checker);
+        let rule_freshness: Option<&dyn FreshnessChecker> = match &plugin_checker {
+            Some(checker) => Some(checker),
+            None => rule_options.freshness.map(Freshness::checker),
+        };
+        if let (Some(freshness), Target::Concrete(path), None) = (rule_freshness, target, &reason) {
+            if let Err(output_reason) = freshness.check(&self.state.lock().unwrap(), vfs, path)? {
+                reason = Some(output_reason);
+            }
+        }
+
+        // Commands that read environment variables may make something else
+        // when they change
+        if reason.is_none() {
+            let values = self.env_input_values(target);
+            if let Some(name) = self
+                .state
+                .lock()
+                .unwrap()
+                .changed_env_input(target, &values)
+            {
+                reason = Some(RebuildReason::EnvChanged(name));
+            }
+        }
+
+        // So may a tool that was upgraded
+        if reason.is_none() {
+            let fingerprints = self.tool_fingerprints(target)?;
+            if let Some(probe) = self
+                .state
+                .lock()
+                .unwrap()
+                .changed_tool(target, &fingerprints)
+            {
+                reason = Some(RebuildReason::ToolChanged(probe));
+            }
+        }
+
+        // Targets of a plugin's kind are up to date while its check passes
+        if let (None, Some(kind)) = (&reason, &rule_options.kind) {
+            let name = match target {
+                Target::Virtual(name) => name.clone(),
+                Target::Concrete(_) => target.to_string(),
+            };
+            let (env, hermetic) = self.command_env(target);
+            let invocation = Invocation {
+                command: options.plugins.get(kind)?.check_command(&name)?,
+                env,
+                hermetic,
+                target: Some(target.to_string()),
+                ..Invocation::default()
+            };
+            if !options.executor.capture(&invocation)?.0.success() {
+                reason = Some(RebuildReason::KindOutdated(kind.clone()));
+            }
+        }
+
+        // If it's virtual and has no dependencies, it always needs making,
+        // unless a plugin checks it
+        if let (Target::Virtual(_), None) = (target, &rule_options.kind) {
+            if dependency_make_results.is_empty() {
+                reason = Some(RebuildReason::NoDependencies);
+            }
+        }
+
+        let needs_making = reason.is_some();
+        if let Some(reason) = &reason {
+            options.reporter.report(&Event::Outdated(target, reason));
+            if options.dry_run {
+                return Ok(true);
+            }
+
+            let cache_key = self.cache_key(target, dependencies.iter().chain(implicit));
+            let restored = match (&options.cache, &cache_key, target) {
+                (Some(cache), Some(key), Target::Concrete(path)) => {
+                    match cache.restore(vfs, key, path.pathbuf()) {
+                        Ok(restored) => restored,
+                        Err(err) => {
+                            warn!("Failed to restore '{target}' from the cache: {err}");
+                            false
+                        }
+                    }
+                }
+                _ => false,
+            };
+            if restored {
+                options.reporter.report(&Event::Restored(target));
             } else {
-                return Err(format!("Target '{path:?}' was not created").into());
+                self.run(target)?;
+
+                if let (Some(cache), Some(key), Target::Concrete(path)) =
+                    (&options.cache, &cache_key, target)
+                {
+                    if vfs.exists(path.pathbuf()) {
+                        if let Err(err) =
+                            cache.store(vfs, key, path.pathbuf(), rule_options.compression)
+                        {
+                            warn!("Failed to store '{target}' in the cache: {err}");
+                        }
+                    }
+                }
+            }
+
+            if let (Some(manifest), Some(artifact)) =
+                (&options.release_manifest, &rule_options.release)
+            {
+                if !matches!(target, Target::Concrete(path) if !vfs.exists(path.pathbuf())) {
+                    manifest.record(vfs, target, artifact, rule_options.version.as_deref())?;
+                }
+            }
+            if let (true, Target::Concrete(path)) = (options.provenance, target) {
+                if vfs.exists(path.pathbuf()) {
+                    provenance::record(file, vfs, target, path.pathbuf())?;
+                }
+            }
+
+            let fingerprints = self.tool_fingerprints(target)?;
+            let mut update_state = self.state.lock().unwrap();
+            update_state.record_env_inputs(target, self.env_input_values(target));
+            update_state.record_tools(target, fingerprints);
+            drop(update_state);
+
+            // Early cutoff: if making the target produced the same contents
+            // as before, its dependents don't need making
+            let mut changed = match target {
+                Target::Concrete(path) => self.record_output(target, path, rule_freshness)?,
+                Target::Virtual(_) => true,
+            };
+            for output in &rule_options.outputs {
+                changed |= self.record_output(target, output, rule_freshness)?;
+            }
+            if !changed {
+                options.reporter.report(&Event::Unchanged(target));
+                return Ok(false);
+            }
+        } else {
+            // Update the state of what the rule makes
+            let mut update_state = self.state.lock().unwrap();
+            let primary = match target {
+                Target::Concrete(path) => Some(path),
+                Target::Virtual(_) => None,
+            };
+            for output in primary.into_iter().chain(&rule_options.outputs) {
+                update_state
+                    .produced
+                    .insert(output.clone(), target.to_string());
+                update_state.update_state(vfs, output)?;
             }
         }
-    } else {
-        // If it's concrete, update the state
-        if let Target::Concrete(path) = target {
-            update_state.update_state(path)?;
+
+        Ok(needs_making)
+    }
+
+    /// Records an output the target's rule just made. Returns false if its
+    /// contents are the same as before, depending on the freshness strategy.
+    fn record_output(
+        &self,
+        target: &Target,
+        output: &ConcreteTarget,
+        rule_freshness: Option<&dyn FreshnessChecker>,
+    ) -> Result<bool, MkError> {
+        let vfs = self.options.vfs.as_ref();
+        if !vfs.exists(output.pathbuf()) {
+            return Err(MkError::NotCreated(Target::Concrete(output.clone())));
+        }
+        let mut update_state = self.state.lock().unwrap();
+        update_state
+            .produced
+            .insert(output.clone(), target.to_string());
+        update_state.record_size(vfs, output)?;
+        let freshness = rule_freshness.unwrap_or(self.options.freshness.as_ref());
+        freshness.record(&mut update_state, vfs, output)
+    }
+
+    /// Runs the target's commands once there is room on disk and a free
+    /// job slot, retrying them if the target is flaky.
+    fn run(&self, target: &Target) -> Result<(), MkError> {
+        let expected_size = self.file.options(target).size.or_else(|| match target {
+            Target::Concrete(path) => self.state.lock().unwrap().recorded_size(path),
+            Target::Virtual(_) => None,
+        });
+        if let Some(expected_size) = expected_size {
+            check_disk_space(self.options.vfs.as_ref(), target, expected_size)?;
+        }
+
+        let rule_options = self.file.options(target);
+        let retries =
+            if self.options.quarantine_flaky && self.state.lock().unwrap().is_flaky(target) {
+                rule_options.retries.max(FLAKY_RETRIES)
+            } else {
+                rule_options.retries
+            };
+        let outputs: Vec<&ConcreteTarget> = match target {
+            Target::Concrete(path) => Some(path),
+            Target::Virtual(_) => None,
+        }
+        .into_iter()
+        .chain(&rule_options.outputs)
+        .collect();
+        let vfs = self.options.vfs.as_ref();
+        let modified = |output: &ConcreteTarget| {
+            vfs.metadata(output.pathbuf())
+                .ok()
+                .filter(|metadata| !metadata.is_dir)
+                .map(|metadata| metadata.modified)
+        };
+        let before: Vec<_> = outputs.iter().map(|output| modified(output)).collect();
+        if self.file.creates_parent_dirs() {
+            for output in &outputs {
+                if let Some(parent) = output.pathbuf().parent() {
+                    if !parent.as_os_str().is_empty() && !vfs.exists(parent) {
+                        vfs.create_dir_all(parent)?;
+                    }
+                }
+            }
+        }
+        let mut attempt = 0;
+        let mut backoff = rule_options.backoff.unwrap_or_default();
+        let result = loop {
+            let slot = self.slots.acquire(
+                rule_options.cpus.unwrap_or(1) as usize,
+                rule_options.memory.unwrap_or(0),
+            );
+            let result = self.run_commands(target);
+            drop(slot);
+            // Being interrupted says nothing about whether the rule is flaky
+            if !matches!(result, Err(MkError::Interrupted(_))) {
+                self.state
+                    .lock()
+                    .unwrap()
+                    .record_outcome(target, result.is_ok());
+            }
+            match result {
+                Err(MkError::Interrupted(signal)) => break Err(MkError::Interrupted(signal)),
+                Err(err) if attempt < retries => {
+                    attempt += 1;
+                    let err = err.to_string();
+                    self.options
+                        .reporter
+                        .report(&Event::Retrying(target, attempt + 1, &err));
+                    // Wait without holding on to the job slot
+                    let until = Instant::now() + backoff;
+                    while interrupt::received().is_none() && Instant::now() < until {
+                        thread::sleep((until - Instant::now()).min(Duration::from_millis(100)));
+                    }
+                    backoff *= 2;
+                }
+                result => break result,
+            }
+        };
+
+        // A command that failed halfway may have left a corrupt file behind,
+        // which would look made next time
+        if result.is_err() && !rule_options.keep_on_error {
+            for (output, before) in outputs.into_iter().zip(before) {
+                if modified(output).is_some_and(|after| Some(after) != before) {
+                    warn!(
+                        "Deleting '{}' as its rule failed",
+                        output.pathbuf().display()
+                    );
+                    if let Err(err) = vfs.remove_all(output.pathbuf()) {
+                        warn!("Failed to delete '{}': {err}", output.pathbuf().display());
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Returns the key the target's output is cached under, if there is a
+    /// cache and the rule makes a single file.
+    fn cache_key<'t>(
+        &self,
+        target: &Target,
+        dependencies: impl Iterator<Item = &'t Target>,
+    ) -> Option<String> {
+        self.options.cache.as_ref()?;
+        let Target::Concrete(ConcreteTarget::Shallow(output)) = target else {
+            return None;
+        };
+        // Only the target's own output would be restored
+        if !self.file.options(target).outputs.is_empty() {
+            return None;
+        }
+        let vfs = self.options.vfs.as_ref();
+        let dependencies = dependencies
+            .map(|dependency| match dependency {
+                Target::Concrete(path) => {
+                    Some((dependency.to_string(), Some(content_hash(vfs, path).ok()?)))
+                }
+                Target::Virtual(_) => Some((dependency.to_string(), None)),
+            })
+            .collect::<Option<_>>()?;
+        let (mut env, _) = self.command_env(target);
+        env.extend(self.env_input_values(target));
+        Some(Cache::key(&KeyInputs {
+            output,
+            commands: self.file.commands(target),
+            env: &env,
+            dependencies,
+            tools: &self.tool_fingerprints(target).ok()?,
+        }))
+    }
+
+    /// Runs the `tools:` probes of the target's rule, unless they already
+    /// ran in this build, and returns the hash of their output by probe.
+    /// Probes that fail are warned about and hash their exit status too.
+    fn tool_fingerprints(&self, target: &Target) -> Result<BTreeMap<String, String>, MkError> {
+        let mut fingerprints = BTreeMap::new();
+        for probe in &self.file.options(target).tools {
+            let known = self.fingerprints.lock().unwrap().get(probe).cloned();
+            let fingerprint = match known {
+                Some(fingerprint) => fingerprint,
+                None => {
+                    let (env, hermetic) = self.command_env(target);
+                    let invocation = Invocation {
+                        command: probe.clone(),
+                        env,
+                        hermetic,
+                        ..Invocation::default()
+                    };
+                    let (status, output) = self.options.executor.capture(&invocation)?;
+                    let mut hasher = Sha256::new();
+                    hasher.update(&output);
+                    if !status.success() {
+                        warn!("Tool probe '{probe}' failed ({status})");
+                        hasher.update(status.to_string());
+                    }
+                    let fingerprint = format!("{:x}", hasher.finalize());
+                    self.fingerprints
+                        .lock()
+                        .unwrap()
+                        .insert(probe.clone(), fingerprint.clone());
+                    fingerprint
+                }
+            };
+            fingerprints.insert(probe.clone(), fingerprint);
         }
+        Ok(fingerprints)
+    }
+
+    /// Returns the environment variables to set for the target's commands,
+    /// and whether they are all the commands get. The rule's `env:` comes
+    /// on top of the variables set for every command. Hermetic rules only
+    /// get those, their `env_inputs:` and a fixed `PATH`, so that they run
+    /// the same on every machine.
+    fn command_env(&self, target: &Target) -> (BTreeMap<String, String>, bool) {
+        let rule = self.file.options(target);
+        let hermetic = self.options.hermetic || rule.hermetic;
+        let mut env = BTreeMap::new();
+        if hermetic {
+            env.insert("PATH".to_string(), HERMETIC_PATH.to_string());
+            env.extend(self.env_input_values(target));
+        }
+        env.extend(self.options.env.clone());
+        env.extend(rule.env.clone());
+        (env, hermetic)
+    }
+
+    /// Returns the values of the environment variables the target's rule
+    /// reads, as its commands would see them. Unset variables are left out.
+    fn env_input_values(&self, target: &Target) -> BTreeMap<String, String> {
+        self.file
+            .options(target)
+            .env_inputs
+            .iter()
+            .filter_map(|name| {
+                let rule = self.file.options(target);
+                let value = rule.env.get(name).or_else(|| self.options.env.get(name));
+                let value = value.cloned().or_else(|| std::env::var(name).ok())?;
+                Some((name.clone(), value))
+            })
+            .collect()
     }
 
-    Ok(needs_making)
+    /// Runs the commands of the target's rule in order, stopping at the
+    /// first one that fails.
+    fn run_commands(&self, target: &Target) -> Result<(), MkError> {
+        let options = self.options;
+        let rule = self.file.options(target);
+        let limits = Limits {
+            cpus: rule.cpus,
+            memory: rule.memory,
+        };
+        let timeout = rule.timeout.or(options.timeout);
+        let started = Instant::now();
+        let timed_out = |command: &String| MkError::TimedOut {
+            target: target.clone(),
+            command: command.clone(),
+            timeout: timeout.unwrap_or_default(),
+        };
+        let (env, hermetic) = self.command_env(target);
+        let runner = rule
+            .runner
+            .as_deref()
+            .map(|name| options.plugins.get(name))
+            .transpose()?;
+        for command in self.file.commands(target) {
+            // A plugin's runner decides what the shell runs
+            let command = &match runner {
+                Some(plugin) => plugin.command(command)?,
+                None => command.clone(),
+            };
+            // The timeout is for all of the rule's commands together
+            let remaining = timeout.map(|timeout| timeout.saturating_sub(started.elapsed()));
+            if remaining == Some(Duration::ZERO) {
+                return Err(timed_out(command));
+            }
+            options
+                .reporter
+                .report(&Event::CommandStarted(target, command));
+            let invocation = Invocation {
+                command: command.clone(),
+                env: env.clone(),
+                hermetic,
+                prefix: options
+                    .prefix_output
+                    .then(|| output::prefix(&target.to_string())),
+                limits: (options.enforce_limits && limits != Limits::default()).then_some(limits),
+                timeout: remaining,
+                target: Some(target.to_string()),
+                redirect: None,
+                input: None,
+            };
+            if let Some(signal) = interrupt::received() {
+                return Err(MkError::Interrupted(signal));
+            }
+            let status = options.executor.run(&invocation)?;
+
+            options
+                .reporter
+                .report(&Event::CommandFinished(target, command));
+
+            if let (false, Some(signal)) = (status.success(), interrupt::received()) {
+                return Err(MkError::Interrupted(signal));
+            }
+            if status.timed_out {
+                return Err(timed_out(command));
+            }
+            if !status.success() {
+                return Err(MkError::CommandFailed {
+                    target: target.clone(),
+                    command: command.clone(),
+                    status,
+                });
+            }
+        }
+        Ok(())
+    }
 }
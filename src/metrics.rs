@@ -0,0 +1,221 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    hooks,
+    mkfile::Target,
+    report::{Event, Reporter},
+    MkError,
+};
+
+/// What happened to a target over the builds counted.
+#[derive(Default)]
+struct TargetCounts {
+    /// Times it was made, by running its commands or from the cache.
+    made: u64,
+    /// Times it was restored from the cache instead of made.
+    restored: u64,
+    up_to_date: u64,
+    failed: u64,
+    /// How long its commands took, over the times they ran.
+    seconds: f64,
+    timed: u64,
+    /// When the command that is running for it started, and how long the
+    /// earlier ones took in this build.
+    running: Option<Instant>,
+    this_build: Duration,
+}
+
+#[derive(Default)]
+struct Counts {
+    targets: BTreeMap<String, TargetCounts>,
+    /// Builds, by how they ended.
+    builds: BTreeMap<&'static str, u64>,
+    build_seconds: f64,
+}
+
+impl Counts {
+    fn target(&mut self, target: &Target) -> &mut TargetCounts {
+        self.targets.entry(target.to_string()).or_default()
+    }
+}
+
+/// Counts what builds do, to show as Prometheus metrics: how long builds
+/// and the commands of each target take, and how often each target is
+/// made, restored from the cache, up to date or fails.
+#[derive(Default)]
+pub struct Metrics(Mutex<Counts>);
+
+impl Metrics {
+    /// Counts a build that is over.
+    pub fn build_over(&self, made: &Result<bool, MkError>, took: Duration) {
+        let result = match made {
+            Ok(true) => "made",
+            Ok(false) => "up_to_date",
+            Err(_) => "failed",
+        };
+        let mut counts = self.0.lock().unwrap();
+        *counts.builds.entry(result).or_default() += 1;
+        counts.build_seconds += took.as_secs_f64();
+    }
+
+    /// Writes the metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let counts = self.0.lock().unwrap();
+        let mut text = String::new();
+        let mut family = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+            let _ = writeln!(text, "# HELP {name} {help}");
+            let _ = writeln!(text, "# TYPE {name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(text, "{name}{labels} {value}");
+            }
+        };
+        let per_target = |count: fn(&TargetCounts) -> String| {
+            counts
+                .targets
+                .iter()
+                .map(|(target, counts)| {
+                    (format!("{{target=\"{}\"}}", escape(target)), count(counts))
+                })
+                .collect()
+        };
+
+        family(
+            "mk_builds_total",
+            "counter",
+            "Builds, by how they ended.",
+            counts
+                .builds
+                .iter()
+                .map(|(result, count)| (format!("{{result=\"{result}\"}}"), count.to_string()))
+                .collect(),
+        );
+        family(
+            "mk_build_duration_seconds_total",
+            "counter",
+            "Time spent in builds.",
+            vec![(String::new(), counts.build_seconds.to_string())],
+        );
+        family(
+            "mk_target_made_total",
+            "counter",
+            "Times each target was made, by running its commands or from the cache.",
+            per_target(|counts| counts.made.to_string()),
+        );
+        family(
+            "mk_target_restored_total",
+            "counter",
+            "Times each target was restored from the cache instead of made.",
+            per_target(|counts| counts.restored.to_string()),
+        );
+        family(
+            "mk_target_up_to_date_total",
+            "counter",
+            "Times each target was up to date.",
+            per_target(|counts| counts.up_to_date.to_string()),
+        );
+        family(
+            "mk_target_failures_total",
+            "counter",
+            "Times making each target failed.",
+            per_target(|counts| counts.failed.to_string()),
+        );
+        let mut durations: Vec<(String, String)> = Vec::new();
+        for (target, counts) in &counts.targets {
+            let target = escape(target);
+            durations.push((
+                format!("_sum{{target=\"{target}\"}}"),
+                counts.seconds.to_string(),
+            ));
+            durations.push((
+                format!("_count{{target=\"{target}\"}}"),
+                counts.timed.to_string(),
+            ));
+        }
+        family(
+            "mk_target_duration_seconds",
+            "summary",
+            "How long the commands of each target took.",
+            durations,
+        );
+        let (made, restored) = counts
+            .targets
+            .values()
+            .fold((0, 0), |(made, restored), counts| {
+                (made + counts.made, restored + counts.restored)
+            });
+        if made > 0 {
+            family(
+                "mk_cache_hit_ratio",
+                "gauge",
+                "The share of made targets that were restored from the cache.",
+                vec![(String::new(), (restored as f64 / made as f64).to_string())],
+            );
+        }
+        text
+    }
+
+    /// Pushes the metrics to a Prometheus Pushgateway, at a URL such as
+    /// `http://gateway:9091/metrics/job/mk`.
+    pub fn push(&self, url: &str) -> Result<(), String> {
+        hooks::post(url, "text/plain; version=0.0.4", &self.render())
+    }
+}
+
+/// Escapes a label value for the Prometheus text format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Reporter for Metrics {
+    fn report(&self, event: &Event) {
+        let mut counts = self.0.lock().unwrap();
+        match event {
+            Event::Restored(target) => counts.target(target).restored += 1,
+            Event::CommandStarted(target, _) => {
+                counts.target(target).running = Some(Instant::now());
+            }
+            Event::CommandFinished(target, _) => {
+                let counts = counts.target(target);
+                if let Some(started) = counts.running.take() {
+                    counts.this_build += started.elapsed();
+                }
+            }
+            Event::TargetFinished(target, made) => {
+                let counts = counts.target(target);
+                if *made {
+                    counts.made += 1;
+                } else {
+                    counts.up_to_date += 1;
+                }
+                counts.finish();
+            }
+            Event::TargetFailed(target, _) => {
+                let counts = counts.target(target);
+                counts.failed += 1;
+                counts.finish();
+            }
+            _ => {}
+        }
+    }
+}
+
+impl TargetCounts {
+    /// Adds how long the target's commands took in this build, if any ran.
+    fn finish(&mut self) {
+        if let Some(started) = self.running.take() {
+            self.this_build += started.elapsed();
+        }
+        if !self.this_build.is_zero() {
+            self.seconds += std::mem::take(&mut self.this_build).as_secs_f64();
+            self.timed += 1;
+        }
+    }
+}
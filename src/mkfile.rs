@@ -1,90 +1,1974 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
+use indexmap::{IndexMap, IndexSet};
 use lazy_static::lazy_static;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    cache::Compression,
+    dotenv,
+    error::MkError,
+    freshness::Freshness,
+    functions,
+    making::Jobs,
+    remote, scan, script,
+    serve::ServeConfig,
+    vfs::{RealFs, Vfs},
+};
+
+/// A target backed by a path. Deep targets are out of date whenever anything
+/// inside them changes, shallow ones only look at the path itself. Filtered
+/// deep targets only look at files whose names match one of their patterns.
 #[derive(Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Clone)]
 pub enum ConcreteTarget {
-    Deep(PathBuf),
-    Shallow(PathBuf),
+    Deep(#[serde(with = "path_text")] PathBuf),
+    Shallow(#[serde(with = "path_text")] PathBuf),
+    DeepFiltered(#[serde(with = "path_text")] PathBuf, Vec<String>),
 }
 
-impl ConcreteTarget {
-    pub fn exists(&self) -> bool {
-        match self {
-            ConcreteTarget::Deep(path) => path.exists(),
-            ConcreteTarget::Shallow(path) => path.exists(),
+/// Saves paths as their text, so that the state stays readable, or, for
+/// paths that aren't valid UTF-8, as a NUL followed by the hex of their raw
+/// bytes (UTF-16 units on Windows). No path has a NUL in it, so the two
+/// can't be confused.
+mod path_text {
+    use std::{
+        ffi::OsString,
+        path::{Path, PathBuf},
+    };
+
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    #[cfg(unix)]
+    fn units(path: &Path) -> Vec<u32> {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str()
+            .as_bytes()
+            .iter()
+            .map(|&byte| byte.into())
+            .collect()
+    }
+
+    #[cfg(windows)]
+    fn units(path: &Path) -> Vec<u32> {
+        use std::os::windows::ffi::OsStrExt;
+        path.as_os_str().encode_wide().map(u32::from).collect()
+    }
+
+    #[cfg(unix)]
+    fn from_units(units: Vec<u32>) -> Option<OsString> {
+        use std::os::unix::ffi::OsStringExt;
+        let bytes = units
+            .into_iter()
+            .map(u8::try_from)
+            .collect::<Result<_, _>>();
+        Some(OsString::from_vec(bytes.ok()?))
+    }
+
+    #[cfg(windows)]
+    fn from_units(units: Vec<u32>) -> Option<OsString> {
+        use std::os::windows::ffi::OsStringExt;
+        let wide = units
+            .into_iter()
+            .map(u16::try_from)
+            .collect::<Result<Vec<_>, _>>();
+        Some(OsString::from_wide(&wide.ok()?))
+    }
+
+    const WIDTH: usize = if cfg!(windows) { 4 } else { 2 };
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        match path.to_str() {
+            Some(text) => serializer.serialize_str(text),
+            None => {
+                let hex: String = units(path)
+                    .into_iter()
+                    .map(|unit| format!("{unit:0WIDTH$x}"))
+                    .collect();
+                serializer.serialize_str(&format!("\0{hex}"))
+            }
         }
     }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        let Some(hex) = text.strip_prefix('\0') else {
+            return Ok(PathBuf::from(text));
+        };
+        let units = (0..hex.len())
+            .step_by(WIDTH)
+            .map(|start| u32::from_str_radix(hex.get(start..start + WIDTH)?, 16).ok())
+            .collect::<Option<Vec<_>>>();
+        units
+            .and_then(from_units)
+            .map(PathBuf::from)
+            .ok_or_else(|| D::Error::custom(format!("invalid encoded path '{hex}'")))
+    }
+}
+
+impl ConcreteTarget {
     pub fn pathbuf(&self) -> &PathBuf {
         match self {
             ConcreteTarget::Deep(path) => path,
             ConcreteTarget::Shallow(path) => path,
+            ConcreteTarget::DeepFiltered(path, _) => path,
+        }
+    }
+
+    /// Returns true if changes inside the folder count.
+    pub fn is_deep(&self) -> bool {
+        !matches!(self, ConcreteTarget::Shallow(_))
+    }
+
+    /// Returns the target for an entry of this target's folder, with the
+    /// same depth and filters.
+    pub fn entry(&self, path: PathBuf) -> ConcreteTarget {
+        match self {
+            ConcreteTarget::Deep(_) => ConcreteTarget::Deep(path),
+            ConcreteTarget::Shallow(_) => ConcreteTarget::Shallow(path),
+            ConcreteTarget::DeepFiltered(_, patterns) => {
+                ConcreteTarget::DeepFiltered(path, patterns.clone())
+            }
+        }
+    }
+
+    /// Returns true if the name of the file matches the target's filters, or
+    /// if it has none. Filters that start with `!` leave files out instead,
+    /// see [`ignores`](Self::ignores).
+    pub fn includes_file(&self, path: &Path) -> bool {
+        let ConcreteTarget::DeepFiltered(_, patterns) = self else {
+            return true;
+        };
+        if self.ignores(path) {
+            return false;
         }
+        let mut wanted = patterns
+            .iter()
+            .filter(|pattern| !pattern.starts_with('!'))
+            .peekable();
+        wanted.peek().is_none() || wanted.any(|pattern| name_matches(pattern, path))
     }
+
+    /// Returns true if the name of the file or folder matches one of the
+    /// target's `!` filters, as in `^src[!*.swp,!.git]`, so that it and
+    /// everything in it are left out.
+    pub fn ignores(&self, path: &Path) -> bool {
+        let ConcreteTarget::DeepFiltered(_, patterns) = self else {
+            return false;
+        };
+        patterns
+            .iter()
+            .filter_map(|pattern| pattern.strip_prefix('!'))
+            .any(|pattern| name_matches(pattern, path))
+    }
+
+    /// Returns the target with more `!` filters, unless it isn't deep.
+    fn ignoring(self, ignored: &[String]) -> ConcreteTarget {
+        let (path, mut patterns) = match self {
+            ConcreteTarget::Shallow(_) => return self,
+            _ if ignored.is_empty() => return self,
+            ConcreteTarget::Deep(path) => (path, Vec::new()),
+            ConcreteTarget::DeepFiltered(path, patterns) => (path, patterns),
+        };
+        for pattern in ignored {
+            let pattern = format!("!{pattern}");
+            if !patterns.contains(&pattern) {
+                patterns.push(pattern);
+            }
+        }
+        ConcreteTarget::DeepFiltered(path, patterns)
+    }
+}
+
+/// Returns true if the glob pattern matches the name of the file.
+fn name_matches(pattern: &str, path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    glob::Pattern::new(pattern)
+        .map(|pattern| pattern.matches(name))
+        .unwrap_or(false)
 }
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+/// Returns the patterns of a `.gitignore` file that name files or folders
+/// wherever they are, which are all mk can use, without the `/`s around
+/// them. Negated patterns and those for paths inside folders are left out.
+pub fn gitignore_patterns(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with(['#', '!']))
+        .map(|line| {
+            let line = line.strip_prefix("**/").unwrap_or(line);
+            line.trim_start_matches('/').trim_end_matches('/')
+        })
+        .filter(|pattern| !pattern.is_empty() && !pattern.contains('/'))
+        .map(String::from)
+        .collect()
+}
+
+/// Something that can be made: a path, or a virtual (`$`-prefixed) name.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum Target {
     Concrete(ConcreteTarget),
     Virtual(String),
 }
 
+impl fmt::Display for Target {
+    /// Formats the target the way it is written in an mkfile.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Virtual(name) => write!(f, "${name}"),
+            Target::Concrete(ConcreteTarget::Deep(path)) => write!(f, "^{}", path.display()),
+            Target::Concrete(ConcreteTarget::Shallow(path)) => write!(f, "{}", path.display()),
+            Target::Concrete(ConcreteTarget::DeepFiltered(path, patterns)) => {
+                write!(f, "^{}[{}]", path.display(), patterns.join(","))
+            }
+        }
+    }
+}
+
 pub type UpdateCommand = String;
 
-#[derive(Debug, PartialEq)]
+/// Per-rule settings, declared as `key: value` lines in the rule body,
+/// optionally in brackets as in `[platform: linux, macos]`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RuleOptions {
+    /// Approximate size in bytes of what the rule produces.
+    pub size: Option<u64>,
+    /// How many CPUs the rule's commands use. The rule takes up as many job
+    /// slots while it runs.
+    pub cpus: Option<u32>,
+    /// How many bytes of memory the rule's commands use at most.
+    pub memory: Option<u64>,
+    /// Free-form labels used to group rules, e.g. in generated docs.
+    pub tags: Vec<String>,
+    /// How to tell whether the rule's output changed, instead of the
+    /// strategy used for the rest of the build.
+    pub freshness: Option<Freshness>,
+    /// Operating systems (`linux`, `macos`, `windows`, ...) or families
+    /// (`unix`) the rule works on. Empty if it works everywhere.
+    pub platforms: Vec<String>,
+    /// Skip the rule on unsupported platforms instead of failing the build.
+    pub optional: bool,
+    /// Name of the scanner that finds more dependencies in the sources the
+    /// rule depends on, such as `c` for `#include`s.
+    pub scan: Option<String>,
+    /// Makes the rule a service target, which serves a folder over HTTP
+    /// once made, remaking it as its sources change.
+    pub serve: Option<ServeConfig>,
+    /// Names the target had before, so that it keeps its stable ID.
+    pub was: Vec<String>,
+    /// How the rule's output is compressed in the cache, instead of the
+    /// way set up for the cache.
+    pub compression: Option<Compression>,
+    /// Name of the artifact the rule makes for a release. Every time the
+    /// rule is made, an entry is added to the release manifest.
+    pub release: Option<String>,
+    /// Version of the release artifact, usually from a variable.
+    pub version: Option<String>,
+    /// Files or folders the rule's commands make besides its target, which
+    /// are tracked and cleaned like it. Depending on one makes the rule.
+    pub outputs: Vec<ConcreteTarget>,
+    /// Keep files the rule's commands changed when one of them fails,
+    /// instead of deleting them as likely incomplete.
+    pub keep_on_error: bool,
+    /// How long the rule's commands may run together before they are
+    /// killed and the target fails.
+    pub timeout: Option<Duration>,
+    /// How many more times the rule's commands are run if they fail, for
+    /// rules that depend on the network.
+    pub retries: usize,
+    /// How long to wait before the first retry, doubling for each one
+    /// after it.
+    pub backoff: Option<Duration>,
+    /// Environment variables the rule's commands read. The target is made
+    /// again when their values change.
+    pub env_inputs: Vec<String>,
+    /// Commands that print the version of a tool the rule uses, such as
+    /// `cc --version`, one per `tools:` line. The target is made again when
+    /// their output changes.
+    pub tools: Vec<String>,
+    /// Environment variables set for the rule's commands only, declared as
+    /// `env: NAME=VALUE ...`.
+    pub env: BTreeMap<String, String>,
+    /// Run the rule's commands with only the variables listed under
+    /// `env_inputs:` and a fixed `PATH`, as with `--hermetic`.
+    pub hermetic: bool,
+    /// Name of the plugin whose check tells whether the target is up to
+    /// date, for targets such as database migrations.
+    pub kind: Option<String>,
+    /// Name of the plugin whose fingerprints tell whether the rule's output
+    /// changed, instead of the freshness strategy.
+    pub fingerprint: Option<String>,
+    /// Name of the plugin that turns the rule's commands into the shell
+    /// commands that are run.
+    pub runner: Option<String>,
+    /// Names of files and folders, as glob patterns, that are left out of
+    /// the rule's deep dependencies, such as editor backups.
+    pub ignore: Vec<String>,
+}
+
+impl RuleOptions {
+    /// Sets the option named by `key`. Returns false if there is no such
+    /// option, in which case the line is a regular command.
+    fn set(&mut self, key: &str, value: &str) -> Result<bool, MkError> {
+        match key {
+            "size" => self.size = Some(parse_size(value)?),
+            "cpus" => {
+                self.cpus = Some(
+                    value
+                        .parse()
+                        .ok()
+                        .filter(|&cpus| cpus > 0)
+                        .ok_or_else(|| MkError::Parse(format!("Invalid cpus '{value}'")))?,
+                )
+            }
+            "memory" => self.memory = Some(parse_size(value)?),
+            "timeout" => self.timeout = Some(parse_duration(value)?),
+            "retries" => {
+                self.retries = value
+                    .parse()
+                    .map_err(|_| MkError::Parse(format!("Invalid retries '{value}'")))?
+            }
+            "backoff" => self.backoff = Some(parse_duration(value)?),
+            "env_inputs" => self.env_inputs = value.split_whitespace().map(String::from).collect(),
+            "tools" => self.tools.push(value.to_string()),
+            "env" => {
+                for assignment in value.split_whitespace() {
+                    let (name, value) = assignment.split_once('=').ok_or_else(|| {
+                        MkError::Parse(format!("Invalid env '{assignment}', expected NAME=VALUE"))
+                    })?;
+                    self.env.insert(name.to_string(), value.to_string());
+                }
+            }
+            "tags" => self.tags = value.split_whitespace().map(String::from).collect(),
+            "freshness" => self.freshness = Some(value.parse().map_err(MkError::Parse)?),
+            "scan" => {
+                if scan::scanner(value).is_none() {
+                    return Err(MkError::Parse(format!("Unknown scanner '{value}'")));
+                }
+                self.scan = Some(value.to_string())
+            }
+            "serve" => self.serve = Some(value.parse().map_err(MkError::Parse)?),
+            "was" => self.was = value.split_whitespace().map(String::from).collect(),
+            "compression" => self.compression = Some(value.parse().map_err(MkError::Parse)?),
+            "release" => self.release = Some(value.to_string()),
+            "version" => self.version = Some(value.to_string()),
+            "outputs" => {
+                for output in value.split_whitespace() {
+                    match Target::parse(output) {
+                        Target::Concrete(path) => self.outputs.push(path),
+                        Target::Virtual(_) => {
+                            return Err(MkError::Parse(format!(
+                                "Output '{output}' must be a file or folder"
+                            )))
+                        }
+                    }
+                }
+            }
+            "platform" => {
+                self.platforms = value
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|platform| !platform.is_empty())
+                    .map(String::from)
+                    .collect()
+            }
+            "optional" => self.optional = parse_flag(key, value)?,
+            "keep_on_error" => self.keep_on_error = parse_flag(key, value)?,
+            "hermetic" => self.hermetic = parse_flag(key, value)?,
+            "kind" => self.kind = Some(value.to_string()),
+            "fingerprint" => self.fingerprint = Some(value.to_string()),
+            "runner" => self.runner = Some(value.to_string()),
+            "ignore" => self.ignore = value.split_whitespace().map(String::from).collect(),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Returns true if `key` names an option.
+    fn has_option(key: &str) -> bool {
+        // Only names that aren't options are turned down without a value
+        !matches!(Self::default().set(key, ""), Ok(false))
+    }
+
+    /// Returns true if the rule works on the platform mk is running on.
+    pub fn supports_current_platform(&self) -> bool {
+        self.platforms.is_empty()
+            || self.platforms.iter().any(|platform| {
+                platform == std::env::consts::OS || platform == std::env::consts::FAMILY
+            })
+    }
+}
+
+/// Parses a size such as `512`, `10K`, `1.5G` or `2GiB` into bytes.
+pub fn parse_size(text: &str) -> Result<u64, MkError> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| MkError::Parse(format!("Invalid size '{text}'")))?;
+    let multiplier = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1u64,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(MkError::Parse(format!("Invalid size unit in '{text}'"))),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Parses a duration such as `300s`, `5m`, `1h` or `500ms`. A number
+/// without a unit is in seconds.
+pub fn parse_duration(text: &str) -> Result<Duration, MkError> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| MkError::Parse(format!("Invalid duration '{text}'")))?;
+    let seconds = match unit.trim() {
+        "ms" => 0.001,
+        "" | "s" => 1.0,
+        "m" | "min" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(MkError::Parse(format!("Invalid duration unit in '{text}'"))),
+    };
+    Ok(Duration::from_secs_f64(number * seconds))
+}
+
+/// Parses the value of a yes/no option.
+fn parse_flag(key: &str, value: &str) -> Result<bool, MkError> {
+    match value {
+        "true" | "yes" => Ok(true),
+        "false" | "no" => Ok(false),
+        _ => Err(MkError::Parse(format!("Invalid {key} '{value}'"))),
+    }
+}
+
+/// How to make a target.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Rule {
+    /// Text of the `#` comment lines right above the rule.
+    description: Option<String>,
     dependencies: Vec<Target>,
     commands: Vec<UpdateCommand>,
+    options: RuleOptions,
+    /// The lines under the rule as written, before variables are expanded,
+    /// so that they can be expanded again with scoped variables.
+    body: Vec<String>,
+    /// For each of the `::` rules of a target, the target.
+    part_of: Option<Target>,
+}
+
+/// Returns the path the way mk knows it, whichever way it was written:
+/// without `.` components and repeated or trailing separators, so that
+/// `./build//a.o` is `build/a.o`. `..` components are kept, since the
+/// folder before one can be a link to somewhere else.
+pub fn normalize(path: &Path) -> PathBuf {
+    let normalized: PathBuf = path
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .collect();
+    if normalized.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        normalized
+    }
 }
 
 impl Target {
+    /// Parses a target as written in an mkfile: `$name` is virtual, `^path`
+    /// is deep and anything else is a shallow path. Deep paths can be
+    /// followed by file name patterns, as in `^src[*.rs,*.toml]`. Paths are
+    /// normalized, see [`normalize`].
     pub fn parse(text: &str) -> Self {
         if let Some(text) = text.strip_prefix('$') {
             Target::Virtual(text.to_string())
         } else if let Some(text) = text.strip_prefix('^') {
-            Target::Concrete(ConcreteTarget::Deep(PathBuf::from(text)))
+            if let Some((path, patterns)) =
+                text.strip_suffix(']').and_then(|text| text.split_once('['))
+            {
+                let patterns = patterns.split(',').map(String::from).collect();
+                return Target::Concrete(ConcreteTarget::DeepFiltered(
+                    normalize(Path::new(path)),
+                    patterns,
+                ));
+            }
+            Target::Concrete(ConcreteTarget::Deep(normalize(Path::new(text))))
+        } else {
+            Target::Concrete(ConcreteTarget::Shallow(normalize(Path::new(text))))
+        }
+    }
+}
+
+/// Collects the block of `#` comment lines at the end of `text`, which is
+/// everything before a rule.
+fn description_above(text: &str) -> Option<String> {
+    let mut lines: Vec<&str> = text
+        .lines()
+        .rev()
+        .take_while(|line| line.starts_with('#'))
+        .map(|line| line.trim_start_matches('#').trim())
+        .collect();
+    if lines.is_empty() {
+        return None;
+    }
+    lines.reverse();
+    Some(lines.join("\n"))
+}
+
+/// A named set of settings, declared in a `[profile.NAME]` section and
+/// selected with `--profile NAME`.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Variables that override the ones assigned in the mkfile.
+    #[serde(default)]
+    pub vars: BTreeMap<String, String>,
+    /// Environment variables set for every command.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// How many commands may run at the same time, or `auto`.
+    pub jobs: Option<Jobs>,
+}
+
+/// Where outputs are cached, declared in a `[cache]` section. Flags given
+/// on the command line take precedence.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Local folder for cached outputs.
+    pub dir: Option<PathBuf>,
+    /// Cache shared with others, as an `http://`, `https://` or `s3://` URL
+    /// or a folder.
+    pub url: Option<String>,
+    /// Only read from the shared cache, leaving filling it to others.
+    #[serde(default)]
+    pub read_only: bool,
+    /// How outputs are compressed: `none`, `zstd` or `lz4`.
+    #[serde(default)]
+    pub compression: Compression,
+}
+
+/// What to do as builds start and end, declared in a `[hooks]` section.
+/// Each is a hook or a list of them.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HooksConfig {
+    /// Run before anything is made.
+    #[serde(default, deserialize_with = "one_or_more")]
+    pub on_start: Vec<Hook>,
+    /// Run once the target is made or up to date.
+    #[serde(default, deserialize_with = "one_or_more")]
+    pub on_success: Vec<Hook>,
+    /// Run once making the target failed.
+    #[serde(default, deserialize_with = "one_or_more")]
+    pub on_failure: Vec<Hook>,
+}
+
+/// A command, or an `http://` or `https://` URL to post the build to, see
+/// [`hooks`](crate::hooks).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(from = "String")]
+pub enum Hook {
+    Command(String),
+    Post(String),
+}
+
+impl From<String> for Hook {
+    fn from(text: String) -> Self {
+        if text.starts_with("http://") || text.starts_with("https://") {
+            Self::Post(text)
         } else {
-            Target::Concrete(ConcreteTarget::Shallow(PathBuf::from(text)))
+            Self::Command(text)
         }
     }
 }
 
-#[derive(Debug)]
+fn one_or_more<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<Hook>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMore {
+        One(Hook),
+        More(Vec<Hook>),
+    }
+    Ok(match OneOrMore::deserialize(deserializer)? {
+        OneOrMore::One(hook) => vec![hook],
+        OneOrMore::More(hooks) => hooks,
+    })
+}
+
+/// The settings sections of an mkfile, read as TOML. They start with
+/// `[profile.NAME]`, `[cache]` or `[hooks]` and end at a blank line, or at
+/// the first line that isn't TOML, such as a rule.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    #[serde(default)]
+    profile: BTreeMap<String, Profile>,
+    cache: Option<CacheConfig>,
+    #[serde(default)]
+    hooks: HooksConfig,
+}
+
+/// The languages an mkfile can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// The mkfile syntax.
+    Mk,
+    /// TOML, as in `mk.toml`.
+    Toml,
+    /// YAML, as in `mk.yaml`.
+    Yaml,
+}
+
+impl Format {
+    /// Picks the format from the extension of a file's name: `.toml`,
+    /// `.yaml` or `.yml`, and the mkfile syntax for anything else.
+    pub fn of(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Self::Toml,
+            Some("yaml" | "yml") => Self::Yaml,
+            _ => Self::Mk,
+        }
+    }
+}
+
+/// An mkfile written as TOML or YAML, see [`MkFile::parse_as`].
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StructuredMkFile {
+    default: Option<String>,
+    #[serde(default)]
+    variables: BTreeMap<String, StructuredValue>,
+    #[serde(default)]
+    rules: BTreeMap<String, StructuredRule>,
+    #[serde(default)]
+    profile: BTreeMap<String, toml::Table>,
+    cache: Option<toml::Table>,
+    hooks: Option<toml::Table>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StructuredRule {
+    description: Option<String>,
+    #[serde(default)]
+    dependencies: Vec<String>,
+    #[serde(default)]
+    commands: Vec<String>,
+    #[serde(default)]
+    options: BTreeMap<String, StructuredValue>,
+}
+
+/// The value of a variable or rule option in a TOML or YAML mkfile.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StructuredValue {
+    Flag(bool),
+    Number(i64),
+    Text(String),
+    List(Vec<String>),
+    Map(BTreeMap<String, String>),
+}
+
+impl StructuredValue {
+    /// Writes the value as it would be written in an mkfile.
+    fn text(&self) -> String {
+        match self {
+            Self::Flag(true) => "yes".to_string(),
+            Self::Flag(false) => "no".to_string(),
+            Self::Number(number) => number.to_string(),
+            Self::Text(text) => text.clone(),
+            Self::List(words) => words.join(" "),
+            Self::Map(map) => map
+                .iter()
+                .map(|(name, value)| format!("{name}={value}"))
+                .collect::<Vec<_>>()
+                .join(" "),
+        }
+    }
+}
+
+impl StructuredMkFile {
+    /// Writes the mkfile out in the mkfile syntax, so that it is read the
+    /// same way as one written by hand.
+    fn to_mkfile(&self) -> Result<String, MkError> {
+        let mut text = String::new();
+        if let Some(default) = &self.default {
+            text.push_str(&format!(".default: {default}\n"));
+        }
+        for (name, value) in &self.variables {
+            let value = value.text();
+            if value.contains('\n') {
+                text.push_str(&format!("define {name}\n{value}\nendef\n"));
+            } else {
+                text.push_str(&format!("{name} = {value}\n"));
+            }
+        }
+
+        let mut settings = toml::Table::new();
+        if !self.profile.is_empty() {
+            let profiles = self
+                .profile
+                .iter()
+                .map(|(name, profile)| (name.clone(), toml::Value::Table(profile.clone())))
+                .collect();
+            settings.insert("profile".to_string(), toml::Value::Table(profiles));
+        }
+        if let Some(cache) = &self.cache {
+            settings.insert("cache".to_string(), toml::Value::Table(cache.clone()));
+        }
+        if let Some(hooks) = &self.hooks {
+            settings.insert("hooks".to_string(), toml::Value::Table(hooks.clone()));
+        }
+        if !settings.is_empty() {
+            let settings = toml::to_string(&settings)
+                .map_err(|err| MkError::Parse(format!("In settings: {err}")))?;
+            text.push_str(&format!("\n{settings}\n"));
+        }
+
+        for (target, rule) in &self.rules {
+            text.push('\n');
+            for line in rule
+                .description
+                .iter()
+                .flat_map(|description| description.lines())
+            {
+                text.push_str(&format!("# {line}\n"));
+            }
+            text.push_str(&format!("{target}: {}\n", rule.dependencies.join(" ")));
+            for (key, value) in &rule.options {
+                if !RuleOptions::has_option(key) {
+                    return Err(MkError::Parse(format!(
+                        "In rule for '{target}': Unknown option '{key}'"
+                    )));
+                }
+                match value {
+                    // Each tool is checked on a line of its own
+                    StructuredValue::List(tools) if key == "tools" => {
+                        for tool in tools {
+                            text.push_str(&format!("    {key}: {tool}\n"));
+                        }
+                    }
+                    value => text.push_str(&format!("    {key}: {}\n", value.text())),
+                }
+            }
+            for line in rule.commands.iter().flat_map(|command| command.lines()) {
+                if !line.trim().is_empty() {
+                    text.push_str(&format!("    {line}\n"));
+                }
+            }
+        }
+        Ok(text)
+    }
+}
+
+/// Settings that change how an mkfile is read.
+#[derive(Clone)]
+pub struct ParseOptions {
+    /// Name of the profile to apply.
+    pub profile: Option<String>,
+    /// `.env` file to load instead of the one named by the mkfile's
+    /// `.env_file:` directive. Unlike that one, it must exist.
+    pub env_file: Option<PathBuf>,
+    /// Variables that override every other assignment, as given on the
+    /// command line.
+    pub variables: BTreeMap<String, String>,
+    /// Only use copies of remote includes that were fetched before.
+    pub offline: bool,
+    /// Merge the dependencies of rules for the same target instead of
+    /// failing, as long as no more than one of them has commands.
+    pub merge_duplicates: bool,
+    /// Where the files the mkfile reads or looks for are: includes, `.env`
+    /// files, `.gitignore`, `.vpath:` folders and the sources of built-in
+    /// rules.
+    pub vfs: Arc<dyn Vfs>,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            profile: None,
+            env_file: None,
+            variables: BTreeMap::new(),
+            offline: false,
+            merge_duplicates: false,
+            vfs: Arc::new(RealFs),
+        }
+    }
+}
+
+impl fmt::Debug for ParseOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ParseOptions")
+            .field("profile", &self.profile)
+            .field("env_file", &self.env_file)
+            .field("variables", &self.variables)
+            .field("offline", &self.offline)
+            .field("merge_duplicates", &self.merge_duplicates)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The value of a variable, and how it is expanded.
+///
+/// Variables are assigned with `NAME = value` lines and used as `$(NAME)`.
+/// `NAME += value` appends to a variable and `NAME ?= value` only assigns
+/// one that isn't set yet, taking the value from the environment if it is
+/// set there. A `TARGET: NAME=value` line assigns a variable only while
+/// making that target, see [`MkFile::scope`].
+///
+/// Built-in functions, such as `$(patsubst %.c,%.o,$(SOURCES))`, are called
+/// in values, dependencies and directives. In command lines they are left
+/// to the shell as command substitution, so a command that needs one uses a
+/// variable whose value calls it.
+#[derive(Debug, Clone, PartialEq)]
+enum Variable {
+    /// Assigned with `=`: expanded wherever it is used, with the variables
+    /// as they are by then.
+    Recursive(String),
+    /// Assigned with `:=`: expanded once where it is assigned, with the
+    /// variables assigned above it.
+    Simple(String),
+}
+
+impl Variable {
+    /// Evaluates an assignment with `=`, `:=`, `+=` or `?=` to a variable
+    /// whose value is `old`. Returns its new value, or nothing if it keeps
+    /// the old one.
+    fn assign(
+        name: &str,
+        operator: &str,
+        value: &str,
+        old: Option<&Variable>,
+        variables: &BTreeMap<String, Variable>,
+    ) -> Result<Option<Self>, MkError> {
+        let append = |old: &str, value: &str| format!("{old} {value}").trim().to_string();
+        Ok(Some(match (operator, old) {
+            ("?=", Some(_)) => return Ok(None),
+            ("?=", None) => match std::env::var(name) {
+                Ok(value) => Variable::Simple(value),
+                Err(_) => Variable::Recursive(value.to_string()),
+            },
+            ("+=", Some(Variable::Recursive(old))) => Variable::Recursive(append(old, value)),
+            ("+=", Some(Variable::Simple(old))) => {
+                Variable::Simple(append(old, &expand(value, variables)?))
+            }
+            (":=", _) => Variable::Simple(expand(value, variables)?),
+            _ => Variable::Recursive(value.to_string()),
+        }))
+    }
+
+    fn value(&self) -> &str {
+        match self {
+            Variable::Recursive(value) | Variable::Simple(value) => value,
+        }
+    }
+}
+
+/// Replaces every `$(NAME)` with the value of the variable, expanding
+/// variables in the value too, unless it was expanded already, and every
+/// `$(function args)` with what the built-in function returns. Other
+/// references, such as to undefined variables, are left alone, so that
+/// shell command substitution keeps working.
+fn expand(text: &str, variables: &BTreeMap<String, Variable>) -> Result<String, MkError> {
+    expand_nested(text, variables, 0, true)
+}
+
+/// Expands a command line as [`expand`] does, but leaves the functions
+/// written in it to the shell, so that `$(basename FILE)` in a command
+/// runs the `basename` command. Functions in the values of variables are
+/// still called.
+fn expand_command(text: &str, variables: &BTreeMap<String, Variable>) -> Result<String, MkError> {
+    expand_nested(text, variables, 0, false)
+}
+
+/// Expands text at the given depth of variable references, calling the
+/// functions in it only if `functions` is set.
+fn expand_nested(
+    text: &str,
+    variables: &BTreeMap<String, Variable>,
+    depth: usize,
+    functions: bool,
+) -> Result<String, MkError> {
+    let mut expanded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after.chars().next() {
+            Some('(') => {
+                let inner = &after[1..];
+                let Some(end) = closing_paren(inner) else {
+                    rest = &rest[start..];
+                    break;
+                };
+                expanded.push_str(&expand_reference(
+                    &inner[..end],
+                    variables,
+                    depth,
+                    functions,
+                )?);
+                rest = &inner[end + 1..];
+            }
+            // `$@` and `$*` are only set while expanding dependencies a
+            // second time, and go to the shell otherwise
+            Some(name @ ('@' | '*')) if variables.contains_key(&name.to_string()) => {
+                expanded.push_str(variables[&name.to_string()].value());
+                rest = &after[1..];
+            }
+            _ => {
+                expanded.push('$');
+                rest = after;
+            }
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Expands what is between the parentheses of a `$(...)`.
+fn expand_reference(
+    inner: &str,
+    variables: &BTreeMap<String, Variable>,
+    depth: usize,
+    functions: bool,
+) -> Result<String, MkError> {
+    lazy_static! {
+        static ref NAME_RE: Regex = Regex::new(r"^([A-Za-z_][A-Za-z0-9_]*|[0-9]+|[@*])$").unwrap();
+    }
+
+    // Names can be computed, as in `$(FLAGS_$(MODE))`
+    let computed;
+    let inner = if inner.contains('$') && !inner.contains(char::is_whitespace) {
+        computed = expand_nested(inner, variables, depth + 1, functions)?;
+        &computed
+    } else {
+        inner
+    };
+
+    if NAME_RE.is_match(inner) {
+        return Ok(match variables.get(inner) {
+            Some(Variable::Recursive(_)) if depth > 32 => {
+                return Err(MkError::Parse(format!(
+                    "Variable '{inner}' refers to itself"
+                )));
+            }
+            Some(Variable::Recursive(value)) => expand_nested(value, variables, depth + 1, true)?,
+            Some(Variable::Simple(value)) => value.clone(),
+            None => format!("$({inner})"),
+        });
+    }
+    if let Some((name, args)) = inner.split_once(char::is_whitespace).filter(|_| functions) {
+        let expand = |text: &str, bindings: &[(&str, &str)]| {
+            if bindings.is_empty() {
+                return expand_nested(text, variables, depth + 1, true);
+            }
+            let mut variables = variables.clone();
+            for (name, value) in bindings {
+                variables.insert(name.to_string(), Variable::Simple(value.to_string()));
+            }
+            expand_nested(text, &variables, depth + 1, true)
+        };
+        if let Some(result) = functions::call(name, args.trim_start(), &expand) {
+            return result;
+        }
+    }
+    Ok(format!(
+        "$({})",
+        expand_nested(inner, variables, depth, functions)?
+    ))
+}
+
+/// Returns the offset of the `)` that closes a `$(`, in the text after it.
+fn closing_paren(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (index, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Some(index),
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Variables the built-in rules use, with their values unless the mkfile
+/// sets them.
+const BUILTIN_VARIABLES: [(&str, &str); 7] = [
+    ("CC", "cc"),
+    ("CXX", "c++"),
+    ("CFLAGS", ""),
+    ("CXXFLAGS", ""),
+    ("CPPFLAGS", ""),
+    ("LDFLAGS", ""),
+    ("LDLIBS", ""),
+];
+
+/// Extensions of the sources the built-in rules compile, and whether they
+/// are C++.
+const SOURCE_EXTENSIONS: [(&str, bool); 4] =
+    [("c", false), ("cpp", true), ("cc", true), ("cxx", true)];
+
+/// Adds the built-in rules: `.o` files that no rule makes are compiled
+/// from the C or C++ source next to them, and rules without commands get
+/// some if they link `.o` files into a program, or copy a file into a
+/// folder under the same name.
+fn add_builtin_rules(
+    rules: &mut IndexMap<Target, Rule>,
+    variables: &mut BTreeMap<String, Variable>,
+    vfs: &dyn Vfs,
+) -> Result<(), MkError> {
+    for (name, value) in BUILTIN_VARIABLES {
+        variables
+            .entry(name.to_string())
+            .or_insert_with(|| Variable::Recursive(value.to_string()));
+    }
+    let shallow = |target: &Target| match target {
+        Target::Concrete(ConcreteTarget::Shallow(path)) => Some(path.clone()),
+        _ => None,
+    };
+    let source_of = |object: &Path| {
+        SOURCE_EXTENSIONS.iter().find_map(|(extension, cpp)| {
+            let source = object.with_extension(extension);
+            let target = Target::Concrete(ConcreteTarget::Shallow(source.clone()));
+            (vfs.exists(&source) || rules.contains_key(&target)).then_some((source, *cpp))
+        })
+    };
+    let is_object = |path: &Path| path.extension().is_some_and(|extension| extension == "o");
+
+    let mut added: Vec<(Target, Vec<Target>, String)> = Vec::new();
+    for (target, rule) in rules.iter() {
+        for object in rule.dependencies.iter().filter_map(shallow) {
+            let object_target = Target::Concrete(ConcreteTarget::Shallow(object.clone()));
+            if !is_object(&object)
+                || rules.contains_key(&object_target)
+                || added.iter().any(|(target, ..)| *target == object_target)
+            {
+                continue;
+            }
+            if let Some((source, cpp)) = source_of(&object) {
+                let command = if cpp {
+                    "$(CXX) $(CXXFLAGS) $(CPPFLAGS)"
+                } else {
+                    "$(CC) $(CFLAGS) $(CPPFLAGS)"
+                };
+                let body = format!("{command} -c {} -o {}", source.display(), object.display());
+                let source = Target::Concrete(ConcreteTarget::Shallow(source));
+                added.push((object_target, vec![source], body));
+            }
+        }
+
+        let Some(path) = shallow(target) else {
+            continue;
+        };
+        if !rule.commands.is_empty() || rule.dependencies.is_empty() {
+            continue;
+        }
+        let dependencies: Vec<PathBuf> = rule.dependencies.iter().filter_map(shallow).collect();
+        if dependencies.len() != rule.dependencies.len() {
+            continue;
+        }
+        let body = if dependencies.iter().all(|path| is_object(path)) {
+            let cpp = dependencies
+                .iter()
+                .any(|object| source_of(object).is_some_and(|(_, cpp)| cpp));
+            let objects: Vec<String> = dependencies
+                .iter()
+                .map(|object| object.display().to_string())
+                .collect();
+            format!(
+                "{} $(LDFLAGS) -o {} {} $(LDLIBS)",
+                if cpp { "$(CXX)" } else { "$(CC)" },
+                path.display(),
+                objects.join(" ")
+            )
+        } else if let [file] = &dependencies[..] {
+            if path.file_name() != file.file_name() || path.parent() == file.parent() {
+                continue;
+            }
+            format!("cp {} {}", file.display(), path.display())
+        } else {
+            continue;
+        };
+        added.push((target.clone(), rule.dependencies.clone(), body));
+    }
+
+    for (target, dependencies, command) in added {
+        let rule = rules.entry(target.clone()).or_insert_with(|| Rule {
+            description: None,
+            dependencies,
+            commands: Vec::new(),
+            options: RuleOptions::default(),
+            body: Vec::new(),
+            part_of: None,
+        });
+        rule.body.push(command);
+        let (commands, options) = parse_body(&target, &rule.body, variables)?;
+        rule.commands = commands;
+        rule.options = options;
+    }
+    Ok(())
+}
+
+lazy_static! {
+    static ref INCLUDE_RE: Regex = Regex::new(r"^(-?)include\s+(.*?)\s*$").unwrap();
+}
+
+/// Where a line of an mkfile was written: its number, and the included
+/// file it is in, unless it is in the mkfile itself.
+#[derive(Debug, Clone)]
+struct Location {
+    file: Option<PathBuf>,
+    line: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "line {} of '{}'", self.line, file.display()),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+/// Replaces the `include FILE...` lines of an mkfile with the text of the
+/// files they name, as far as they exist, noting every file in `includes`
+/// and where each line of the result was written in `lines`. Files can
+/// also be fetched from elsewhere, see [`remote::fetch`], and ones that
+/// can't be are left out too if the line is written `-include`.
+fn splice_includes(
+    text: &str,
+    file: Option<&Path>,
+    includes: &mut Vec<(PathBuf, bool)>,
+    lines: &mut Vec<Location>,
+    options: &ParseOptions,
+    depth: usize,
+) -> Result<String, MkError> {
+    if depth > 16 {
+        return Err(MkError::Parse(
+            "Included mkfiles include each other".to_string(),
+        ));
+    }
+    let mut spliced = String::new();
+    for (index, line) in text.split_inclusive('\n').enumerate() {
+        let Some(include) = INCLUDE_RE.captures(line.trim_end()) else {
+            spliced.push_str(line);
+            lines.push(Location {
+                file: file.map(Path::to_path_buf),
+                line: index + 1,
+            });
+            continue;
+        };
+        let optional = !include[1].is_empty();
+        for source in include[2].split_whitespace() {
+            let path = if remote::is_remote(source) {
+                match remote::fetch(source, options.offline) {
+                    Ok(path) => path,
+                    Err(_) if optional => continue,
+                    Err(err) => return Err(err),
+                }
+            } else {
+                PathBuf::from(source)
+            };
+            includes.push((path.clone(), optional));
+            if options.vfs.exists(&path) {
+                let text = options.vfs.read_to_string(&path).map_err(|err| {
+                    MkError::Parse(format!("Can't read '{}': {err}", path.display()))
+                })?;
+                let name = PathBuf::from(source);
+                let nested =
+                    splice_includes(&text, Some(&name), includes, lines, options, depth + 1)?;
+                // The newline after the file ends its last line, or is a
+                // blank line of its own
+                if nested.is_empty() || nested.ends_with('\n') {
+                    lines.push(Location {
+                        file: Some(name),
+                        line: text.split_inclusive('\n').count() + 1,
+                    });
+                }
+                spliced.push_str(&nested);
+                spliced.push('\n');
+            }
+        }
+    }
+    Ok(spliced)
+}
+
+/// Parses a target, unless it is one of the names declared virtual.
+fn parse_target(text: &str, virtuals: &BTreeSet<String>) -> Target {
+    if virtuals.contains(text) {
+        Target::Virtual(text.to_string())
+    } else {
+        Target::parse(text)
+    }
+}
+
+/// Expands the dependencies of a rule. References escaped as `$$(...)` are
+/// expanded a second time once the target is known, with `$@` set to the
+/// target and `$*` to the target without its extension.
+fn expand_dependencies(
+    text: &str,
+    target: &Target,
+    variables: &BTreeMap<String, Variable>,
+) -> Result<String, MkError> {
+    if !text.contains("$$") {
+        return expand(text, variables);
+    }
+    let first = expand(&text.replace("$$", "\0"), variables)?.replace('\0', "$");
+    let stem = match target {
+        Target::Concrete(path) => path.pathbuf().with_extension("").display().to_string(),
+        Target::Virtual(name) => name.clone(),
+    };
+    let mut variables = variables.clone();
+    variables.insert("@".to_string(), Variable::Simple(target.to_string()));
+    variables.insert("*".to_string(), Variable::Simple(stem));
+    expand(&first, &variables)
+}
+
+/// Expands the lines under a rule and sorts them into commands and options.
+fn parse_body(
+    target: &Target,
+    body: &[String],
+    variables: &BTreeMap<String, Variable>,
+) -> Result<(Vec<UpdateCommand>, RuleOptions), MkError> {
+    lazy_static! {
+        static ref OPTION_RE: Regex = Regex::new(r"^([a-z_]+):\s*(.*)$").unwrap();
+    }
+
+    let mut commands = Vec::new();
+    let mut options = RuleOptions::default();
+    for line in body {
+        let line = expand_command(line, variables)?;
+        let unbracketed = line
+            .strip_prefix('[')
+            .and_then(|option| option.strip_suffix(']'))
+            .unwrap_or(&line);
+        if let Some(option) = OPTION_RE.captures(unbracketed) {
+            if options
+                .set(&option[1], &option[2])
+                .map_err(|err| MkError::Parse(format!("In rule for '{target}': {err}")))?
+            {
+                continue;
+            }
+        }
+        commands.push(line);
+    }
+    Ok((commands, options))
+}
+
+/// Replaces everything but the line break with spaces, keeping offsets the
+/// same.
+fn blank(line: &str) -> String {
+    line.chars()
+        .map(|c| if c == '\n' { c } else { ' ' })
+        .collect()
+}
+
+/// The rules of a parsed mkfile.
+#[derive(Debug, Clone)]
 pub struct MkFile {
-    rules: HashMap<Target, Rule>,
+    variables: BTreeMap<String, Variable>,
+    profiles: BTreeMap<String, Profile>,
+    /// Name of the profile that was applied.
+    profile: Option<String>,
+    cache: Option<CacheConfig>,
+    hooks: HooksConfig,
+    /// Variables loaded from the `.env` file.
+    dotenv: BTreeMap<String, String>,
+    /// The `.env` file they were loaded from, if any.
+    env_file: Option<PathBuf>,
+    /// Variables assigned to a target with `TARGET: NAME=value` lines.
+    scoped: IndexMap<Target, BTreeMap<String, Variable>>,
+    /// The target to make when none is given.
+    default: Option<Target>,
+    /// Names declared virtual with the `.virtual:` directive.
+    virtuals: BTreeSet<String>,
+    /// Whether the folders of concrete targets are created before their
+    /// commands run.
+    parent_dirs: bool,
+    /// The files named by `include` lines, and whether they are optional.
+    includes: Vec<(PathBuf, bool)>,
+    /// The plugins named by `.plugin:` directives.
+    plugins: Vec<PathBuf>,
+    /// The patterns of `.ignore:` and `.use_gitignore:`.
+    ignored: Vec<String>,
+    /// The rules, in the order they are written.
+    rules: IndexMap<Target, Rule>,
+    /// Targets with more than one `:` rule, which were merged as with
+    /// `merge_duplicates`.
+    duplicates: Vec<Target>,
+    /// Where the rules that were written, rather than generated, are.
+    locations: IndexMap<Target, Location>,
 }
 
 impl MkFile {
-    pub fn parse(text: &str) -> Self {
+    /// Parses the text of an mkfile.
+    pub fn parse(text: &str) -> Result<Self, MkError> {
+        Self::parse_with(text, &ParseOptions::default())
+    }
+
+    /// Parses an mkfile written in the given format. TOML and YAML mkfiles
+    /// hold a `variables` table, a `rules` table with the `description`,
+    /// `dependencies`, `commands` and `options` of each target, the
+    /// `default` target and the `profile`, `cache` and `hooks` settings.
+    /// They are read the same way as the mkfile they would be written as.
+    pub fn parse_as(
+        text: &str,
+        format: Format,
+        parse_options: &ParseOptions,
+    ) -> Result<Self, MkError> {
+        let structured: StructuredMkFile = match format {
+            Format::Mk => return Self::parse_with(text, parse_options),
+            Format::Toml => toml::from_str(text).map_err(|err| MkError::Parse(err.to_string()))?,
+            Format::Yaml => {
+                serde_yaml::from_str(text).map_err(|err| MkError::Parse(err.to_string()))?
+            }
+        };
+        Self::parse_with(&structured.to_mkfile()?, parse_options)
+    }
+
+    /// Parses the text of an mkfile, applying the given options. Besides
+    /// rules, it can hold variables, settings sections, includes,
+    /// templates, directives and scripts.
+    pub fn parse_with(text: &str, parse_options: &ParseOptions) -> Result<Self, MkError> {
         lazy_static! {
+            /// A rule: `TARGET: dependencies` and the indented lines below
+            /// it. A target can have several `TARGET:: dependencies` rules
+            /// instead of one, each with its own commands, which run when
+            /// the rule's own dependencies changed or the target is
+            /// missing.
             static ref RULE_RE: Regex =
                 Regex::new(r"([^\s]+)\s*:([^\n]*)((\n[ \t]+[^\n]+)*)").unwrap();
+            static ref VARIABLE_RE: Regex =
+                Regex::new(r"^([A-Za-z_][A-Za-z0-9_]*)\s*([:+?]?=)\s*(.*?)\s*$").unwrap();
+            static ref SECTION_RE: Regex = Regex::new(r"^\[(profile\.[^\]]+|cache|hooks)\]\s*$").unwrap();
+            // Lines that can be part of a settings section: `key = value`,
+            // tables, the rest of values that span lines and comments
+            static ref SETTING_RE: Regex =
+                Regex::new(r#"^(\s|#|\[|\]|\}|[A-Za-z0-9_\-."']+\s*=)"#).unwrap();
+            /// Directives, such as `.env_file: .env`, which loads the
+            /// variables of a `.env` file, if it exists. They override the
+            /// ones assigned in the mkfile and are set for every command.
+            ///
+            /// - `.default: TARGET` picks the target to make when none is
+            ///   given.
+            /// - `.virtual: NAME...` makes the names virtual targets without
+            ///   a `$` in front, even if there are files with the same names.
+            /// - `.parent_dirs: no` stops mk from creating the folders that
+            ///   targets go in before their commands run.
+            /// - `.vpath: DIR...` looks for dependencies that no rule makes
+            ///   and that don't exist in the given folders, in order.
+            /// - `.ignore: PATTERN...` leaves files and folders whose names
+            ///   match out of deep dependencies that no rule makes, as does
+            ///   a rule's `ignore:` option for its own dependencies.
+            /// - `.use_gitignore: yes` adds the patterns of `.gitignore`
+            ///   that mk can use, see [`gitignore_patterns`].
+            /// - `.builtins: yes` adds rules for small C and C++ projects,
+            ///   see [`add_builtin_rules`].
+            /// - `.plugin: PATH...` names WebAssembly plugins, which rules
+            ///   use with the `kind:`, `fingerprint:` and `runner:` options,
+            ///   see [`Plugin`](crate::plugin::Plugin).
+            static ref DIRECTIVE_RE: Regex = Regex::new(
+                r"^\.(env_file|default|virtual|parent_dirs|vpath|builtins|plugin|ignore|use_gitignore):\s*(.*?)\s*$"
+            )
+            .unwrap();
+            /// Templates are variables defined over several lines, between
+            /// `define NAME` and `endef`. `$(call NAME,a,b)` expands one
+            /// with `$(1)` set to `a` and `$(2)` to `b`, and an
+            /// `$(eval ...)` line parses the rules that it expands to as
+            /// part of the mkfile.
+            static ref DEFINE_RE: Regex =
+                Regex::new(r"^define\s+([A-Za-z_][A-Za-z0-9_]*)\s*=?\s*$").unwrap();
+            static ref EVAL_RE: Regex = Regex::new(r"^\$\(eval\s(.*)\)\s*$").unwrap();
+            /// A rule for `A B C &: dependencies`, which makes all of its
+            /// targets at once, with `B` and `C` as its `outputs:`.
+            static ref GROUPED_RE: Regex = Regex::new(r"^(\S+)((?:[ \t]+\S+)+?)[ \t]*&:").unwrap();
         }
 
-        let mut rules = HashMap::new();
+        let mut includes = Vec::new();
+        let mut lines = Vec::new();
+        let spliced;
+        let text = if text.lines().any(|line| INCLUDE_RE.is_match(line)) {
+            spliced = splice_includes(text, None, &mut includes, &mut lines, parse_options, 0)?;
+            spliced.as_str()
+        } else {
+            text
+        };
+        let locate = |offset: usize| {
+            let index = text[..offset].matches('\n').count();
+            lines.get(index).cloned().unwrap_or(Location {
+                file: None,
+                line: index + 1,
+            })
+        };
+
+        let mut rules = IndexMap::new();
+        let mut assignments = Vec::new();
+        let mut settings_text = String::new();
+        let mut directives: BTreeMap<String, String> = BTreeMap::new();
 
-        for cap in RULE_RE.captures_iter(text) {
-            let target = Target::parse(&cap[1]);
-            let dependencies = cap[2].split_whitespace().map(Target::parse).collect();
-            let commands = cap[3]
-                .split('\n')
-                .map(|s| s.trim().to_string())
-                .filter(|s| !s.is_empty())
+        // Take out comments, variable assignments and settings sections so
+        // they can't be mistaken for rules, while keeping offsets the same so
+        // descriptions can be found in `text`
+        let mut in_section = false;
+        let mut defining: Option<(String, Vec<&str>)> = None;
+        let mut scripting: Option<Vec<&str>> = None;
+        let mut scripts = Vec::new();
+        let mut evals = Vec::new();
+        let mut grouped = HashMap::new();
+        let uncommented: String = text
+            .split_inclusive('\n')
+            .map(|line| {
+                let content = line.trim_end();
+                if let Some((name, lines)) = &mut defining {
+                    if content.trim() == "endef" {
+                        assignments.push((name.clone(), "=".to_string(), lines.join("\n")));
+                        defining = None;
+                    } else {
+                        lines.push(content);
+                    }
+                    return blank(line);
+                }
+                if let Some(lines) = &mut scripting {
+                    if content.trim() == "endscript" {
+                        scripts.push(lines.join("\n"));
+                        scripting = None;
+                    } else {
+                        lines.push(content);
+                    }
+                    return blank(line);
+                }
+                if content == "script" {
+                    scripting = Some(Vec::new());
+                    return blank(line);
+                }
+                if let Some(define) = DEFINE_RE.captures(content) {
+                    defining = Some((define[1].to_string(), Vec::new()));
+                    return blank(line);
+                }
+                if let Some(eval) = EVAL_RE.captures(content) {
+                    evals.push(eval[1].to_string());
+                    return blank(line);
+                }
+                // The other targets of a group become outputs of the rule
+                // for the first one, with the line padded to keep offsets
+                let group = (!in_section && !line.starts_with('#'))
+                    .then(|| GROUPED_RE.captures(line))
+                    .flatten();
+                if let Some(group) = group {
+                    let (first, others) = (&group[1], group[2].trim());
+                    grouped.insert(first.to_string(), others.to_string());
+                    let rest = &line[group.get(0).unwrap().end()..];
+                    let padding = line.len() - first.len() - 1 - rest.len();
+                    return format!("{first}:{}{rest}", " ".repeat(padding));
+                }
+                if SECTION_RE.is_match(content) {
+                    in_section = true;
+                } else if content.trim().is_empty() || !SETTING_RE.is_match(content) {
+                    in_section = false;
+                }
+                if in_section {
+                    settings_text.push_str(line);
+                    if !line.ends_with('\n') {
+                        settings_text.push('\n');
+                    }
+                    blank(line)
+                } else if line.starts_with('#') {
+                    blank(line)
+                } else if let Some(directive) = DIRECTIVE_RE.captures(content) {
+                    let value = directives.entry(directive[1].to_string()).or_default();
+                    if matches!(&directive[1], "virtual" | "vpath" | "plugin" | "ignore")
+                        && !value.is_empty()
+                    {
+                        value.push(' ');
+                    } else {
+                        value.clear();
+                    }
+                    value.push_str(&directive[2]);
+                    blank(line)
+                } else if let Some(assignment) = VARIABLE_RE.captures(content) {
+                    assignments.push((
+                        assignment[1].to_string(),
+                        assignment[2].to_string(),
+                        assignment[3].to_string(),
+                    ));
+                    blank(line)
+                } else {
+                    line.to_string()
+                }
+            })
+            .collect();
+
+        if let Some((name, _)) = defining {
+            return Err(MkError::Parse(format!("Missing 'endef' for '{name}'")));
+        }
+        if scripting.is_some() {
+            return Err(MkError::Parse("Missing 'endscript'".to_string()));
+        }
+
+        let Settings {
+            profile: profiles,
+            cache,
+            hooks,
+        } = toml::from_str(&settings_text)
+            .map_err(|err| MkError::Parse(format!("In settings: {err}")))?;
+        let vfs = parse_options.vfs.as_ref();
+        let env_file = parse_options
+            .env_file
+            .clone()
+            .or_else(|| directives.get("env_file").map(PathBuf::from));
+        let dotenv = match (&parse_options.env_file, &env_file) {
+            (Some(path), _) => dotenv::load(vfs, path)?,
+            (None, Some(path)) if vfs.exists(path) => dotenv::load(vfs, path)?,
+            _ => BTreeMap::new(),
+        };
+        let mut overrides = dotenv.clone();
+        if let Some(name) = &parse_options.profile {
+            let profile = profiles
+                .get(name)
+                .ok_or_else(|| MkError::Parse(format!("No profile named '{name}'")))?;
+            overrides.extend(profile.vars.clone());
+        }
+        overrides.extend(parse_options.variables.clone());
+
+        // Assignments are evaluated in order, so that `:=` sees the
+        // variables assigned above it, and those of the `.env` file, the
+        // profile and the command line, which replace assignments
+        let mut variables: BTreeMap<String, Variable> = overrides
+            .iter()
+            .map(|(name, value)| (name.clone(), Variable::Recursive(value.clone())))
+            .collect();
+        for (name, operator, value) in assignments {
+            if overrides.contains_key(&name) {
+                continue;
+            }
+            let old = variables.get(&name);
+            if let Some(value) = Variable::assign(&name, &operator, &value, old, &variables)? {
+                variables.insert(name, value);
+            }
+        }
+
+        // Scripts run once the variables are assigned. What they set
+        // replaces assignments, but not the variables that override them
+        let mut scripted = Vec::new();
+        for source in scripts {
+            let known = variables.clone();
+            let lookup = move |name: &str| {
+                known
+                    .contains_key(name)
+                    .then(|| expand(&format!("$({name})"), &known))
+                    .transpose()
+            };
+            let output = script::run(&source, Box::new(lookup))?;
+            for (name, value) in output.variables {
+                if !overrides.contains_key(&name) {
+                    variables.insert(name, Variable::Simple(value));
+                }
+            }
+            scripted.push(output.rules);
+        }
+
+        let virtuals: BTreeSet<String> = match directives.get("virtual") {
+            Some(names) => expand(names, &variables)?
+                .split_whitespace()
+                .map(|name| name.trim_start_matches('$').to_string())
+                .collect(),
+            None => BTreeSet::new(),
+        };
+
+        // Rules that `$(eval ...)` lines and scripts generate are parsed
+        // after the written ones, and have no description
+        let generated = evals
+            .iter()
+            .map(|eval| expand(eval, &variables))
+            .chain(scripted.into_iter().map(Ok))
+            .collect::<Result<Vec<_>, _>>()?
+            .join("\n");
+        let mut scoped: IndexMap<Target, BTreeMap<String, Variable>> = IndexMap::new();
+        let mut parted: HashMap<Target, usize> = HashMap::new();
+        let mut duplicates = Vec::new();
+        let mut locations: IndexMap<Target, Option<Location>> = IndexMap::new();
+        let mut first = None;
+        let captures = RULE_RE
+            .captures_iter(&uncommented)
+            .map(|cap| (true, cap))
+            .chain(RULE_RE.captures_iter(&generated).map(|cap| (false, cap)));
+        for (written, cap) in captures {
+            let description = written
+                .then(|| description_above(&text[..cap.get(0).unwrap().start()]))
+                .flatten();
+            let (written_target, double_colon) = match cap[1].strip_suffix(':') {
+                Some(target) => (target, true),
+                None => (&cap[1], false),
+            };
+            let target = parse_target(&expand(written_target, &variables)?, &virtuals);
+            let body: Vec<String> = grouped
+                .get(written_target)
+                .map(|others| format!("outputs: {others}"))
+                .into_iter()
+                .chain(
+                    cap[3]
+                        .split('\n')
+                        .map(str::trim)
+                        .filter(|s| !s.is_empty())
+                        .map(String::from),
+                )
+                .collect();
+            if let Some(assignment) = VARIABLE_RE.captures(cap[2].trim()) {
+                if !body.is_empty() {
+                    return Err(MkError::Parse(format!(
+                        "Scoped variable for '{target}' can't be followed by commands"
+                    )));
+                }
+                let scope = scoped.entry(target).or_default();
+                let name = &assignment[1];
+                let old = scope.get(name).or(variables.get(name));
+                if let Some(value) =
+                    Variable::assign(name, &assignment[2], &assignment[3], old, &variables)?
+                {
+                    scope.insert(name.to_string(), value);
+                }
+                continue;
+            }
+            let dependencies = expand_dependencies(&cap[2], &target, &variables)?
+                .split_whitespace()
+                .map(|dependency| parse_target(dependency, &virtuals))
                 .collect();
+            let (commands, options) = parse_body(&target, &body, &variables)?;
+            if first.is_none() {
+                first = Some(target.clone());
+            }
 
-            let rule = Rule {
+            if double_colon != parted.contains_key(&target) && rules.contains_key(&target) {
+                return Err(MkError::Parse(format!(
+                    "'{target}' has both ':' and '::' rules"
+                )));
+            }
+            let mut rule = Rule {
+                description,
                 dependencies,
                 commands,
+                options,
+                body,
+                part_of: None,
             };
 
+            // Each `::` rule is a part of the target, made on its own, which
+            // then only depends on its parts
+            if double_colon {
+                let count = parted.entry(target.clone()).or_insert(0);
+                *count += 1;
+                let name = target.to_string();
+                let part = Target::Virtual(format!("{}::{count}", name.trim_start_matches('$')));
+                let whole = rules.entry(target.clone()).or_insert_with(|| Rule {
+                    description: rule.description.clone(),
+                    dependencies: Vec::new(),
+                    commands: Vec::new(),
+                    options: RuleOptions::default(),
+                    body: Vec::new(),
+                    part_of: None,
+                });
+                whole.dependencies.push(part.clone());
+                rule.part_of = Some(target);
+                rules.insert(part, rule);
+                continue;
+            }
+
+            let location = written.then(|| locate(cap.get(0).unwrap().start()));
+            if let Some(earlier) = rules.get_mut(&target) {
+                if !parse_options.merge_duplicates
+                    || !(earlier.commands.is_empty() || rule.commands.is_empty())
+                {
+                    let at = |location: Option<&Location>| match location {
+                        Some(location) => format!("at {location}"),
+                        None => "generated by $(eval ...) or a script".to_string(),
+                    };
+                    return Err(MkError::Parse(format!(
+                        "'{target}' has more than one rule, {} and {}",
+                        at(locations[&target].as_ref()),
+                        at(location.as_ref())
+                    )));
+                }
+                // The rule with commands is kept, with the dependencies of
+                // both
+                let mut dependencies = std::mem::take(&mut earlier.dependencies);
+                for dependency in rule.dependencies.drain(..) {
+                    if !dependencies.contains(&dependency) {
+                        dependencies.push(dependency);
+                    }
+                }
+                if !rule.commands.is_empty() {
+                    rule.description = rule.description.or(earlier.description.take());
+                    *earlier = rule;
+                }
+                earlier.dependencies = dependencies;
+                duplicates.push(target);
+                continue;
+            }
+            locations.insert(target.clone(), location);
             rules.insert(target, rule);
         }
 
-        MkFile { rules }
+        // Dependencies that no rule makes and that aren't where they are
+        // written are looked for in the `.vpath:` folders
+        if let Some(dirs) = directives.get("vpath") {
+            let dirs: Vec<PathBuf> = expand(dirs, &variables)?
+                .split_whitespace()
+                .map(PathBuf::from)
+                .collect();
+            let made: HashSet<Target> = rules
+                .iter()
+                .flat_map(|(target, rule)| {
+                    let outputs = rule.options.outputs.iter().cloned();
+                    std::iter::once(target.clone()).chain(outputs.map(Target::Concrete))
+                })
+                .collect();
+            for rule in rules.values_mut() {
+                for dependency in &mut rule.dependencies {
+                    let Target::Concrete(ConcreteTarget::Shallow(path)) = &*dependency else {
+                        continue;
+                    };
+                    if made.contains(dependency) || path.is_absolute() || vfs.exists(path) {
+                        continue;
+                    }
+                    if let Some(found) = dirs
+                        .iter()
+                        .map(|dir| dir.join(path))
+                        .find(|path| vfs.exists(path))
+                    {
+                        *dependency = Target::Concrete(ConcreteTarget::Shallow(found));
+                    }
+                }
+            }
+        }
+
+        // Deep dependencies that no rule makes leave out the files and
+        // folders that `.ignore:`, `.use_gitignore:` and their rule's
+        // `ignore:` name
+        let mut ignored = match directives.get("ignore") {
+            Some(patterns) => expand(patterns, &variables)?
+                .split_whitespace()
+                .map(String::from)
+                .collect(),
+            None => Vec::new(),
+        };
+        if let Some(value) = directives.get("use_gitignore") {
+            if parse_flag("use_gitignore", value)? {
+                if let Ok(text) = vfs.read_to_string(Path::new(".gitignore")) {
+                    ignored.extend(gitignore_patterns(&text));
+                }
+            }
+        }
+        let made: HashSet<Target> = rules.keys().cloned().collect();
+        for rule in rules.values_mut() {
+            let ignored: Vec<String> = ignored
+                .iter()
+                .chain(&rule.options.ignore)
+                .cloned()
+                .collect();
+            for dependency in &mut rule.dependencies {
+                if made.contains(dependency) {
+                    continue;
+                }
+                if let Target::Concrete(path) = dependency {
+                    *path = path.clone().ignoring(&ignored);
+                }
+            }
+        }
+
+        if let Some(value) = directives.get("builtins") {
+            if parse_flag("builtins", value)? {
+                add_builtin_rules(&mut rules, &mut variables, vfs)?;
+            }
+        }
+
+        let parent_dirs = directives
+            .get("parent_dirs")
+            .map_or(Ok(true), |value| parse_flag("parent_dirs", value))?;
+        let default = directives
+            .get("default")
+            .map(|name| expand(name, &variables))
+            .transpose()?;
+        let plugins = match directives.get("plugin") {
+            Some(paths) => expand(paths, &variables)?
+                .split_whitespace()
+                .map(PathBuf::from)
+                .collect(),
+            None => Vec::new(),
+        };
+        let mut mkfile = MkFile {
+            variables,
+            profiles,
+            profile: parse_options.profile.clone(),
+            cache,
+            hooks,
+            dotenv,
+            env_file,
+            scoped,
+            default: None,
+            virtuals,
+            parent_dirs,
+            includes,
+            plugins,
+            ignored,
+            rules,
+            duplicates,
+            locations: locations
+                .into_iter()
+                .filter_map(|(target, location)| Some((target, location?)))
+                .collect(),
+        };
+        mkfile.default = match default {
+            Some(name) => Some(mkfile.resolve(&name)),
+            None => [Target::parse("$all"), Target::parse("all")]
+                .into_iter()
+                .find(|target| mkfile.has_target(target))
+                .or(first),
+        };
+        Ok(mkfile)
+    }
+
+    /// Returns the files named by `include` lines, including those that
+    /// don't exist, and whether they are optional, as with `-include`.
+    /// Those that a rule makes should be made, and the mkfile parsed again.
+    pub fn includes(&self) -> &[(PathBuf, bool)] {
+        &self.includes
+    }
+
+    /// Returns true if the name of the file or folder matches a pattern of
+    /// `.ignore:` or `.use_gitignore:`, which deep dependencies leave out.
+    pub fn ignores(&self, path: &Path) -> bool {
+        self.ignored
+            .iter()
+            .any(|pattern| name_matches(pattern, path))
+    }
+
+    /// Returns the WebAssembly plugins named by `.plugin:` directives, see
+    /// [`Plugins::load`](crate::plugin::Plugins::load).
+    pub fn plugins(&self) -> &[PathBuf] {
+        &self.plugins
+    }
+
+    /// Returns true unless the `.parent_dirs: no` directive says that
+    /// commands create the folders of their targets themselves.
+    pub fn creates_parent_dirs(&self) -> bool {
+        self.parent_dirs
+    }
+
+    /// Returns the target to make when none is given: the one the
+    /// `.default:` directive names, the `all` rule, or else the first rule
+    /// in the mkfile.
+    pub fn default_target(&self) -> Option<&Target> {
+        self.default.as_ref()
+    }
+
+    /// Returns the target to make: the one named, the default target if
+    /// none is, or `all`.
+    pub fn target_to_make(&self, name: Option<&str>) -> Target {
+        match (name, self.default_target()) {
+            (Some(name), _) => self.resolve(name),
+            (None, Some(default)) => default.clone(),
+            (None, None) => self.resolve("all"),
+        }
+    }
+
+    /// Applies the scoped variables for making the given target: each one
+    /// is in effect for the commands and options of the target it is
+    /// assigned to and of everything that target is made from, and
+    /// overrides the other variables there. A target made from two scopes
+    /// gets the variables of the one it is first reached from.
+    pub fn scope(&mut self, target: &Target) -> Result<(), MkError> {
+        if self.scoped.is_empty() {
+            return Ok(());
+        }
+        let mut assigned: HashMap<Target, BTreeMap<String, Variable>> = HashMap::new();
+        let mut pending = vec![(target.clone(), BTreeMap::new())];
+        while let Some((target, mut variables)) = pending.pop() {
+            if assigned.contains_key(&target) {
+                continue;
+            }
+            if let Some(own) = self.scoped.get(&target) {
+                variables.extend(own.clone());
+            }
+            let next: Vec<&Target> = if self.has_target(&target) {
+                self.dependencies(&target).iter().rev().collect()
+            } else {
+                self.producer(&target).into_iter().collect()
+            };
+            pending.extend(
+                next.into_iter()
+                    .map(|next| (next.clone(), variables.clone())),
+            );
+            assigned.insert(target, variables);
+        }
+
+        for (target, scoped) in assigned {
+            if scoped.is_empty() {
+                continue;
+            }
+            let Some(rule) = self.rules.get(&target) else {
+                continue;
+            };
+            let mut variables = self.variables.clone();
+            variables.extend(scoped);
+            let (commands, options) = parse_body(&target, &rule.body, &variables)?;
+            let rule = self.rules.get_mut(&target).unwrap();
+            rule.commands = commands;
+            rule.options = options;
+        }
+        Ok(())
     }
 
+    /// Returns the profile that was applied when parsing, if any.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref().map(|name| &self.profiles[name])
+    }
+
+    /// Returns the cache settings from the `[cache]` section, if there is
+    /// one.
+    pub fn cache(&self) -> Option<&CacheConfig> {
+        self.cache.as_ref()
+    }
+
+    /// Returns the hooks from the `[hooks]` section, which are empty if
+    /// there is none.
+    pub fn hooks(&self) -> &HooksConfig {
+        &self.hooks
+    }
+
+    /// Returns the variables loaded from the `.env` file, which are set for
+    /// every command.
+    pub fn dotenv(&self) -> &BTreeMap<String, String> {
+        &self.dotenv
+    }
+
+    /// Returns the `.env` file named by `--env-file` or the `.env_file:`
+    /// directive, if any, whether it exists or not.
+    pub fn env_file(&self) -> Option<&Path> {
+        self.env_file.as_deref()
+    }
+
+    /// Returns the value of a variable, after applying the profile.
+    pub fn variable(&self, name: &str) -> Option<&str> {
+        self.variables.get(name).map(Variable::value)
+    }
+
+    /// The following accessors panic if there is no rule for the target.
     pub fn dependencies(&self, target: &Target) -> &Vec<Target> {
         &self.rules[target].dependencies
     }
@@ -93,9 +1977,140 @@ impl MkFile {
         &self.rules[target].commands
     }
 
+    pub fn description(&self, target: &Target) -> Option<&str> {
+        self.rules[target].description.as_deref()
+    }
+
+    pub fn options(&self, target: &Target) -> &RuleOptions {
+        &self.rules[target].options
+    }
+
+    /// Returns the target that the rule is one of the `::` rules of, if it
+    /// is one.
+    pub fn part_of(&self, target: &Target) -> Option<&Target> {
+        self.rules[target].part_of.as_ref()
+    }
+
+    /// Returns true if there is a rule for the target.
     pub fn has_target(&self, target: &Target) -> bool {
         self.rules.contains_key(target)
     }
+
+    /// Resolves a target name given on the command line. Names that no
+    /// rule makes are taken to be virtual targets.
+    pub fn resolve(&self, name: &str) -> Target {
+        let target = parse_target(name, &self.virtuals);
+        if self.has_target(&target) || self.producer(&target).is_some() {
+            target
+        } else {
+            Target::Virtual(name.to_string())
+        }
+    }
+
+    /// Returns every target that has a rule, in the order the rules are
+    /// written.
+    pub fn targets(&self) -> impl Iterator<Item = &Target> {
+        self.rules.keys()
+    }
+
+    /// Returns the file the target's rule is written in, if it is in an
+    /// included one rather than the mkfile itself, and the number of the
+    /// line it starts at. Rules that are generated have no location.
+    pub fn location(&self, target: &Target) -> Option<(Option<&Path>, usize)> {
+        self.locations
+            .get(target)
+            .map(|location| (location.file.as_deref(), location.line))
+    }
+
+    /// Returns the targets that have more than one `:` rule, once for each
+    /// rule that was merged into an earlier one.
+    pub fn duplicates(&self) -> &[Target] {
+        &self.duplicates
+    }
+
+    /// Returns the target of the rule that declares the file or folder
+    /// under `outputs:`, if any.
+    pub fn producer(&self, target: &Target) -> Option<&Target> {
+        let Target::Concrete(path) = target else {
+            return None;
+        };
+        self.rules
+            .iter()
+            .find(|(_, rule)| rule.options.outputs.contains(path))
+            .map(|(target, _)| target)
+    }
+
+    /// Returns every target reachable from the given one, including itself,
+    /// in the order they are found. Outputs declared under `outputs:` lead
+    /// to the rule that makes them.
+    pub fn reachable<'a>(&'a self, target: &'a Target) -> IndexSet<&'a Target> {
+        let mut seen = IndexSet::new();
+        let mut pending = vec![target];
+        while let Some(target) = pending.pop() {
+            if seen.insert(target) {
+                if self.has_target(target) {
+                    pending.extend(self.dependencies(target));
+                } else {
+                    pending.extend(self.producer(target));
+                }
+            }
+        }
+        seen
+    }
+
+    /// Returns the first target reachable from the given one whose rule
+    /// doesn't work on this platform and isn't optional. Dependencies of
+    /// optional rules that get skipped are not looked at.
+    pub fn find_unsupported<'a>(&'a self, target: &'a Target) -> Option<&'a Target> {
+        let mut seen = HashSet::new();
+        let mut pending = vec![target];
+        while let Some(target) = pending.pop() {
+            if !seen.insert(target) || !self.has_target(target) {
+                continue;
+            }
+            let options = self.options(target);
+            if !options.supports_current_platform() {
+                if options.optional {
+                    continue;
+                }
+                return Some(target);
+            }
+            pending.extend(self.dependencies(target));
+        }
+        None
+    }
+
+    /// Returns a chain of targets that depend on each other in a loop, if
+    /// any can be reached from the given target. The first target of the
+    /// chain is repeated at its end.
+    pub fn find_cycle(&self, target: &Target) -> Option<Vec<Target>> {
+        fn visit<'a>(
+            file: &'a MkFile,
+            target: &'a Target,
+            chain: &mut Vec<&'a Target>,
+            done: &mut HashSet<&'a Target>,
+        ) -> Option<Vec<Target>> {
+            if let Some(start) = chain.iter().position(|t| *t == target) {
+                let mut cycle: Vec<Target> = chain[start..].iter().map(|t| (*t).clone()).collect();
+                cycle.push(target.clone());
+                return Some(cycle);
+            }
+            if done.contains(target) || !file.has_target(target) {
+                return None;
+            }
+            chain.push(target);
+            for dependency in file.dependencies(target) {
+                if let Some(cycle) = visit(file, dependency, chain, done) {
+                    return Some(cycle);
+                }
+            }
+            chain.pop();
+            done.insert(target);
+            None
+        }
+
+        visit(self, target, &mut Vec::new(), &mut HashSet::new())
+    }
 }
 
 #[cfg(test)]
@@ -107,8 +2122,532 @@ mod test {
     #[test]
     fn test_parse() {
         let test_input = include_str!("test_input.mk");
-        let rules = MkFile::parse(test_input);
+        let rules = MkFile::parse(test_input).unwrap();
 
         assert_debug_snapshot!(rules);
     }
+
+    #[test]
+    fn test_parse_duplicate_rules() {
+        let text = "my_file : my_file.c another_file.c\n\tgcc -o my_file my_file.c\n\n\
+                    $all: my_file\n\n\
+                    my_file :my_file.c another_file.c\n    gcc -o my_file my_file.c\n";
+        let err = MkFile::parse(text).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "'my_file' has more than one rule, at line 1 and at line 6"
+        );
+    }
+
+    #[test]
+    fn test_parse_profile() {
+        let text = "CC = gcc\n\n[profile.release]\nvars = { CC = \"clang\" }\njobs = 4\n\n\
+                    app: app.c\n    $(CC) -o app app.c\n";
+        let options = ParseOptions {
+            profile: Some("release".to_string()),
+            ..ParseOptions::default()
+        };
+        let rules = MkFile::parse_with(text, &options).unwrap();
+
+        let target = Target::parse("app");
+        assert_eq!(rules.commands(&target)[0], "clang -o app app.c");
+        assert_eq!(rules.profile().unwrap().jobs, Some(Jobs::Count(4)));
+    }
+
+    #[test]
+    fn test_profile_sections_end_at_blank_lines() {
+        let text = "[profile.ci]\nvars = { CC = \"clang\" }\n# Runners have 4 cores\njobs = 4\n\n\
+                    OPT = 2\n\n$all:\n    echo $(CC) -O$(OPT)\n";
+        let options = ParseOptions {
+            profile: Some("ci".to_string()),
+            ..ParseOptions::default()
+        };
+        let rules = MkFile::parse_with(text, &options).unwrap();
+
+        assert_eq!(rules.profile().unwrap().jobs, Some(Jobs::Count(4)));
+        assert_eq!(rules.variable("jobs"), None);
+        assert_eq!(rules.variable("OPT"), Some("2"));
+        assert_eq!(rules.commands(&Target::parse("$all")), &["echo clang -O2"]);
+
+        let text = "[profile.ci]\njobs = 4\n\nCC = gcc\n";
+        let rules = MkFile::parse_with(text, &options).unwrap();
+        assert_eq!(rules.variable("CC"), Some("gcc"));
+        assert_eq!(rules.profile().unwrap().jobs, Some(Jobs::Count(4)));
+    }
+
+    #[test]
+    fn test_sections_mixed_with_variables() {
+        let text = "[cache]\ndir=\".c\"\n\nOPT = \"fast\"\n\n[hooks]\non_start = \"echo start\"\n\
+                    \nLEVEL = 3\n[profile.release]\njobs = 2\n$all:\n    echo $(OPT) $(LEVEL)\n";
+        let options = ParseOptions {
+            profile: Some("release".to_string()),
+            ..ParseOptions::default()
+        };
+        let rules = MkFile::parse_with(text, &options).unwrap();
+
+        assert_eq!(rules.variable("OPT"), Some("\"fast\""));
+        assert_eq!(rules.variable("LEVEL"), Some("3"));
+        assert!(rules.cache().is_some());
+        assert_eq!(
+            rules.hooks().on_start,
+            &[Hook::Command("echo start".to_string())]
+        );
+        assert_eq!(rules.profile().unwrap().jobs, Some(Jobs::Count(2)));
+        assert_eq!(rules.commands(&Target::parse("$all")), &["echo \"fast\" 3"]);
+    }
+
+    #[test]
+    fn test_parse_command_line_variables() {
+        let text = "CC = gcc\n\n[profile.release]\nvars = { CC = \"clang\" }\n\n\
+                    app: app.c\n    $(CC) -o app app.c\n";
+        let options = ParseOptions {
+            profile: Some("release".to_string()),
+            variables: BTreeMap::from([("CC".to_string(), "tcc".to_string())]),
+            ..ParseOptions::default()
+        };
+        let rules = MkFile::parse_with(text, &options).unwrap();
+
+        let target = Target::parse("app");
+        assert_eq!(rules.commands(&target)[0], "tcc -o app app.c");
+    }
+
+    #[test]
+    fn test_parse_scoped_variables() {
+        let text = "CFLAGS = -g\n\n$all: app\n\n$all: CFLAGS=-O2\n\n\
+                    app:\n    cc $(CFLAGS) -o app app.c\n";
+        let mut rules = MkFile::parse(text).unwrap();
+
+        rules.scope(&Target::parse("$all")).unwrap();
+        assert_eq!(
+            rules.commands(&Target::parse("app"))[0],
+            "cc -O2 -o app app.c"
+        );
+        assert!(MkFile::parse("app: CFLAGS=-O2\n    cc\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_cache_section() {
+        let text = "[cache]\ndir = \".mk-cache\"\ncompression = \"zstd\"\n\napp:\n    cc\n";
+        let rules = MkFile::parse(text).unwrap();
+
+        let cache = rules.cache().unwrap();
+        assert_eq!(cache.dir, Some(PathBuf::from(".mk-cache")));
+        assert_eq!(cache.compression, Compression::Zstd);
+    }
+
+    #[test]
+    fn test_parse_descriptions() {
+        let text = "# Removes build outputs\n$clean:\n    rm -f app\n\n\
+                    # Compiles the program.\n# Links it too.\napp: app.c\n    cc app.c\n\n\
+                    lib.a:\n    ar rcs lib.a\n";
+        let rules = MkFile::parse(text).unwrap();
+
+        let description = |name| rules.description(&Target::parse(name));
+        assert_eq!(description("$clean"), Some("Removes build outputs"));
+        assert_eq!(
+            description("app"),
+            Some("Compiles the program.\nLinks it too.")
+        );
+        assert_eq!(description("lib.a"), None);
+    }
+
+    /// Parses a rule with the given body and returns its options.
+    fn options_of(body: &str) -> RuleOptions {
+        let text = format!("CC = gcc\n\napp: app.c\n    {body}\n    cc -o app app.c\n");
+        let rules = MkFile::parse(&text).unwrap();
+        let target = Target::parse("app");
+        assert_eq!(rules.commands(&target), &["cc -o app app.c"]);
+        rules.options(&target).clone()
+    }
+
+    #[test]
+    fn test_parse_size_option() {
+        assert_eq!(options_of("size: 10M").size, Some(10 << 20));
+    }
+
+    #[test]
+    fn test_parse_tags_option() {
+        assert_eq!(options_of("tags: build c").tags, ["build", "c"]);
+    }
+
+    #[test]
+    fn test_parse_platform_option() {
+        let options = options_of("[platform: linux, macos]");
+        assert_eq!(options.platforms, ["linux", "macos"]);
+    }
+
+    #[test]
+    fn test_parse_cpus_option() {
+        assert_eq!(options_of("cpus: 2").cpus, Some(2));
+        assert!(MkFile::parse("app:\n    cpus: 0\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_memory_option() {
+        assert_eq!(options_of("memory: 512M").memory, Some(512 << 20));
+    }
+
+    #[test]
+    fn test_parse_compression_option() {
+        let options = options_of("compression: lz4");
+        assert_eq!(options.compression, Some(Compression::Lz4));
+    }
+
+    #[test]
+    fn test_parse_release_option() {
+        let options = options_of("release: my-app");
+        assert_eq!(options.release.as_deref(), Some("my-app"));
+    }
+
+    #[test]
+    fn test_parse_version_option() {
+        let options = options_of("version: $(CC)-1.2.0");
+        assert_eq!(options.version.as_deref(), Some("gcc-1.2.0"));
+    }
+
+    #[test]
+    fn test_parse_outputs_option() {
+        let options = options_of("outputs: app.map ^app.dSYM");
+        let outputs: Vec<Target> = options.outputs.into_iter().map(Target::Concrete).collect();
+        assert_eq!(
+            outputs,
+            [Target::parse("app.map"), Target::parse("^app.dSYM")]
+        );
+        assert!(MkFile::parse("app:\n    outputs: $map\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_keep_on_error_option() {
+        assert!(options_of("keep_on_error: yes").keep_on_error);
+    }
+
+    #[test]
+    fn test_parse_timeout_option() {
+        let options = options_of("timeout: 5m");
+        assert_eq!(options.timeout, Some(Duration::from_secs(300)));
+    }
+
+    #[test]
+    fn test_parse_retries_option() {
+        assert_eq!(options_of("retries: 2").retries, 2);
+    }
+
+    #[test]
+    fn test_parse_backoff_option() {
+        let options = options_of("backoff: 500ms");
+        assert_eq!(options.backoff, Some(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_parse_env_inputs_option() {
+        let options = options_of("env_inputs: CC CFLAGS");
+        assert_eq!(options.env_inputs, ["CC", "CFLAGS"]);
+    }
+
+    #[test]
+    fn test_parse_tools_option() {
+        assert_eq!(
+            options_of("tools: $(CC) --version").tools,
+            ["gcc --version"]
+        );
+    }
+
+    #[test]
+    fn test_parse_hermetic_option() {
+        assert!(options_of("hermetic: yes").hermetic);
+    }
+
+    #[test]
+    fn test_parse_env_option() {
+        let options = options_of("env: RUSTFLAGS=-g CC=$(CC)");
+        assert_eq!(
+            options.env,
+            BTreeMap::from([
+                ("CC".to_string(), "gcc".to_string()),
+                ("RUSTFLAGS".to_string(), "-g".to_string()),
+            ])
+        );
+        assert!(MkFile::parse("app:\n    env: RUSTFLAGS\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_simple_variables() {
+        let text = "NAME = old\nLATER = $(NAME)\nNOW := $(NAME)\nNAME = new\n\n\
+                    $all:\n    echo $(LATER) $(NOW)\n";
+        let rules = MkFile::parse(text).unwrap();
+
+        let target = Target::parse("$all");
+        assert_eq!(rules.commands(&target)[0], "echo new old");
+    }
+
+    #[test]
+    fn test_parse_normalizes_paths() {
+        let text = "app: ./build//a.o ^./src/ ../lib/b.o\n    cc -o app build/a.o\n\n\
+                    build/a.o: a.c\n    cc -c a.c -o build/a.o\n";
+        let rules = MkFile::parse(text).unwrap();
+
+        let dependencies = rules.dependencies(&Target::parse("app"));
+        assert_eq!(dependencies[0].to_string(), "build/a.o");
+        assert_eq!(dependencies[1].to_string(), "^src");
+        assert_eq!(dependencies[2].to_string(), "../lib/b.o");
+        assert!(rules.has_target(&dependencies[0]));
+        assert_eq!(Target::parse("./build/./a.o"), dependencies[0]);
+        assert_eq!(Target::parse("./").to_string(), ".");
+    }
+
+    #[test]
+    fn test_parse_ignores_files_in_deep_dependencies() {
+        let text = ".ignore: .git\n\napp: ^src ^assets[*.png] lib.a\n    ignore: *.swp\n    \
+                    build\n\nother: ^src\n    build\n";
+        let rules = MkFile::parse(text).unwrap();
+
+        let dependencies = rules.dependencies(&Target::parse("app"));
+        assert_eq!(dependencies[0].to_string(), "^src[!.git,!*.swp]");
+        assert_eq!(dependencies[1].to_string(), "^assets[*.png,!.git,!*.swp]");
+        assert_eq!(dependencies[2].to_string(), "lib.a");
+        let other = rules.dependencies(&Target::parse("other"));
+        assert_eq!(other[0].to_string(), "^src[!.git]");
+
+        let Target::Concrete(assets) = &dependencies[1] else {
+            panic!("{} isn't concrete", dependencies[1]);
+        };
+        assert!(assets.includes_file(Path::new("assets/a.png")));
+        assert!(!assets.includes_file(Path::new("assets/a.jpg")));
+        assert!(assets.ignores(Path::new("assets/.a.png.swp")));
+        assert!(assets.ignores(Path::new("assets/.git")));
+
+        assert_eq!(
+            gitignore_patterns("# built\n/target/\n**/*.log\n!keep.log\ndocs/out\n"),
+            ["target", "*.log"]
+        );
+    }
+
+    #[test]
+    fn test_parse_keeps_rule_order() {
+        let text = "zeta: b\n\n$mid:\n    true\n\nalpha: a\n\nb:\n    touch b\n";
+        let rules = MkFile::parse(text).unwrap();
+
+        let targets: Vec<String> = rules.targets().map(Target::to_string).collect();
+        assert_eq!(targets, ["zeta", "$mid", "alpha", "b"]);
+    }
+
+    #[test]
+    fn test_parse_append_and_conditional_variables() {
+        let text = "FLAGS = -g\nFLAGS += -O2\nFLAGS ?= -O0\nMK_TEST_UNSET ?= fallback\n\
+                    PATH ?= nowhere\n\n$all:\n    echo $(FLAGS) $(MK_TEST_UNSET)\n";
+        let rules = MkFile::parse(text).unwrap();
+
+        let target = Target::parse("$all");
+        assert_eq!(rules.commands(&target)[0], "echo -g -O2 fallback");
+        assert_eq!(
+            rules.variable("PATH"),
+            std::env::var("PATH").ok().as_deref()
+        );
+    }
+
+    #[test]
+    fn test_parse_functions() {
+        let text = "SOURCES = src/main.c src/util.c include/util.h\n\
+                    OBJECTS = $(patsubst %.c,%.o,$(filter %.c,$(SOURCES)))\n\
+                    NAMES = $(notdir $(basename $(OBJECTS)))\n\
+                    COUNTS = $(if $(filter %.h,$(SOURCES)),headers,none) $(words $(SOURCES))\n\n\
+                    $all:\n    echo $(OBJECTS)\n    echo $(NAMES)\n    echo $(COUNTS)\n\
+                    \x20   echo $(date +%s) $(UNDEFINED)\n\
+                    \x20   echo $(basename $(OBJECTS)) $(words $(NAMES))\n";
+        let rules = MkFile::parse(text).unwrap();
+
+        let target = Target::parse("$all");
+        assert_eq!(
+            rules.commands(&target),
+            &[
+                "echo src/main.o src/util.o",
+                "echo main util",
+                "echo headers 3",
+                "echo $(date +%s) $(UNDEFINED)",
+                "echo $(basename src/main.o src/util.o) $(words main util)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_foreach() {
+        let text = "ARCHES = x86 arm\n\n\
+                    APPS = $(foreach arch,$(ARCHES),build/$(arch)/app)\n\n\
+                    $all:\n    echo $(APPS)\n";
+        let rules = MkFile::parse(text).unwrap();
+
+        let target = Target::parse("$all");
+        assert_eq!(
+            rules.commands(&target)[0],
+            "echo build/x86/app build/arm/app"
+        );
+    }
+
+    #[test]
+    fn test_parse_templates() {
+        let text = "SERVICES = api web\n\n\
+                    define image\n\
+                    build/$(1).tar: services/$(1)/Dockerfile\n\
+                    \x20   docker build -t $(2)/$(1) services/$(1)\n\
+                    endef\n\n\
+                    $(eval $(foreach service,$(SERVICES),$(call image,$(service),acme)))\n";
+        let rules = MkFile::parse(text).unwrap();
+
+        for service in ["api", "web"] {
+            let target = Target::parse(&format!("build/{service}.tar"));
+            assert_eq!(
+                rules.dependencies(&target),
+                &[Target::parse(&format!("services/{service}/Dockerfile"))]
+            );
+            assert_eq!(
+                rules.commands(&target)[0],
+                format!("docker build -t acme/{service} services/{service}")
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_default_target() {
+        let rules = "b.txt:\n    touch b.txt\n\na.txt:\n    touch a.txt\n";
+        let default = |text: &str| MkFile::parse(text).unwrap().default_target().cloned();
+
+        assert_eq!(default(rules), Some(Target::parse("b.txt")));
+        assert_eq!(
+            default(&format!("{rules}\n$all: a.txt\n")),
+            Some(Target::parse("$all"))
+        );
+        assert_eq!(
+            default(&format!(".default: a.txt\n\n{rules}\n$all: a.txt\n")),
+            Some(Target::parse("a.txt"))
+        );
+    }
+
+    #[test]
+    fn test_parse_virtual_directive() {
+        let text = ".virtual: clean build\n\nbuild: out.txt\n\nclean:\n    rm -f out.txt\n";
+        let rules = MkFile::parse(text).unwrap();
+
+        let clean = Target::Virtual("clean".to_string());
+        assert!(rules.has_target(&clean));
+        assert_eq!(rules.resolve("clean"), clean);
+        assert_eq!(
+            rules.default_target(),
+            Some(&Target::Virtual("build".to_string()))
+        );
+        assert_eq!(
+            rules.dependencies(&rules.resolve("build")),
+            &[Target::parse("out.txt")]
+        );
+    }
+
+    #[test]
+    fn test_parse_second_expansion() {
+        let text = "HEADERS_FOR_main = util.h config.h\nMODE = debug\nFLAGS_debug = -g\n\n\
+                    main.o: main.c $$(HEADERS_FOR_$$*)\n    cc $(FLAGS_$(MODE)) -c main.c -o $@\n";
+        let rules = MkFile::parse(text).unwrap();
+
+        let target = Target::parse("main.o");
+        assert_eq!(
+            rules.dependencies(&target),
+            &["main.c", "util.h", "config.h"].map(Target::parse)
+        );
+        assert_eq!(rules.commands(&target)[0], "cc -g -c main.c -o $@");
+    }
+
+    #[test]
+    fn test_rule_locations() {
+        let text = "# Objects\nCC = cc\n\nmain.o: main.c\n    $(CC) -c main.c\n\n\
+                    $(eval app: main.o)\n";
+        let rules = MkFile::parse(text).unwrap();
+
+        assert_eq!(rules.location(&Target::parse("main.o")), Some((None, 4)));
+        assert_eq!(rules.location(&Target::parse("app")), None);
+    }
+
+    #[test]
+    fn test_parse_structured() {
+        let toml = r#"
+            default = "$all"
+
+            [variables]
+            CC = "gcc"
+
+            [profile.release]
+            vars = { CC = "clang" }
+
+            [rules."$all"]
+            dependencies = ["my_file"]
+
+            [rules.my_file]
+            description = "Compiles the program."
+            dependencies = ["my_file.c"]
+            commands = ["$(CC) -o my_file my_file.c"]
+            options = { tags = ["build", "c"], keep_on_error = true, retries = 2 }
+        "#;
+        let yaml = r#"
+default: $all
+variables:
+  CC: gcc
+profile:
+  release:
+    vars: { CC: clang }
+rules:
+  $all:
+    dependencies: [my_file]
+  my_file:
+    description: Compiles the program.
+    dependencies: [my_file.c]
+    commands: ["$(CC) -o my_file my_file.c"]
+    options: { tags: [build, c], keep_on_error: true, retries: 2 }
+"#;
+        let options = ParseOptions {
+            profile: Some("release".to_string()),
+            ..ParseOptions::default()
+        };
+        for (text, format) in [(toml, Format::Toml), (yaml, Format::Yaml)] {
+            let rules = MkFile::parse_as(text, format, &options).unwrap();
+
+            let target = Target::parse("my_file");
+            assert_eq!(rules.default_target(), Some(&Target::parse("$all")));
+            assert_eq!(rules.dependencies(&target), &[Target::parse("my_file.c")]);
+            assert_eq!(rules.commands(&target), &["clang -o my_file my_file.c"]);
+            assert_eq!(rules.description(&target), Some("Compiles the program."));
+            let rule_options = rules.options(&target);
+            assert_eq!(rule_options.tags, ["build", "c"]);
+            assert!(rule_options.keep_on_error);
+            assert_eq!(rule_options.retries, 2);
+        }
+
+        let unknown = "[rules.a]\noptions = { speed = 2 }\n";
+        assert!(MkFile::parse_as(unknown, Format::Toml, &ParseOptions::default()).is_err());
+    }
+
+    #[cfg(feature = "scripting")]
+    #[test]
+    fn test_parse_script() {
+        let text = "CC = gcc\n\nscript\n\
+                    let sources = [\"main.c\", \"util.c\", \"notes.txt\"];\n\
+                    let objects = sources.filter(|s| s.ends_with(\".c\")).map(|s| s.sub_string(0, s.len() - 2) + \".o\");\n\
+                    for object in objects {\n\
+                    \x20   let source = object.sub_string(0, object.len() - 2) + \".c\";\n\
+                    \x20   rule(object, [source], [get(\"CC\") + \" -c \" + source]);\n\
+                    }\n\
+                    set(\"OBJECTS\", objects);\n\
+                    rule(\"$all\", objects);\n\
+                    endscript\n\n\
+                    app: $(OBJECTS)\n    $(CC) -o app $(OBJECTS)\n";
+        let rules = MkFile::parse(text).unwrap();
+
+        assert_eq!(rules.commands(&Target::parse("util.o")), &["gcc -c util.c"]);
+        assert_eq!(
+            rules.dependencies(&Target::parse("$all")),
+            &["main.o", "util.o"].map(Target::parse)
+        );
+        assert_eq!(
+            rules.commands(&Target::parse("app")),
+            &["gcc -o app main.o util.o"]
+        );
+        assert!(MkFile::parse("script\nrule(\"a\", [\"b\"]);\n").is_err());
+    }
 }
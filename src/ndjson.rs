@@ -0,0 +1,118 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+
+use crate::report::{Event, Reporter};
+
+/// Streams every event as a line of JSON, for editors and CI wrappers.
+pub struct NdjsonReporter {
+    writer: Mutex<Box<dyn Write + Send>>,
+    /// Stable ID of each target, by name.
+    ids: HashMap<String, String>,
+    /// Fields added to every event.
+    fields: Vec<(String, Value)>,
+}
+
+impl NdjsonReporter {
+    pub fn new(writer: Box<dyn Write + Send>) -> Self {
+        NdjsonReporter {
+            writer: Mutex::new(writer),
+            ids: HashMap::new(),
+            fields: Vec::new(),
+        }
+    }
+
+    /// Adds the stable ID of the target to events, from the given IDs by
+    /// target name.
+    pub fn with_ids(mut self, ids: HashMap<String, String>) -> Self {
+        self.ids = ids;
+        self
+    }
+
+    /// Adds the field to every event, such as the build it is part of.
+    pub fn with_field(mut self, name: &str, value: Value) -> Self {
+        self.fields.push((name.to_string(), value));
+        self
+    }
+}
+
+fn to_json(event: &Event) -> Value {
+    match event {
+        Event::TargetStarted(target) => json!({
+            "event": "target_started",
+            "target": target.to_string(),
+        }),
+        Event::Skipped(target, reason) => json!({
+            "event": "target_skipped",
+            "target": target.to_string(),
+            "reason": reason,
+        }),
+        Event::Outdated(target, reason) => json!({
+            "event": "target_outdated",
+            "target": target.to_string(),
+            "reason": reason.to_string(),
+        }),
+        Event::Unchanged(target) => json!({
+            "event": "target_unchanged",
+            "target": target.to_string(),
+        }),
+        Event::Restored(target) => json!({
+            "event": "target_restored",
+            "target": target.to_string(),
+        }),
+        Event::CommandStarted(target, command) => json!({
+            "event": "command_started",
+            "target": target.to_string(),
+            "command": command,
+        }),
+        Event::CommandFinished(target, command) => json!({
+            "event": "command_finished",
+            "target": target.to_string(),
+            "command": command,
+        }),
+        Event::Retrying(target, attempt, err) => json!({
+            "event": "target_retrying",
+            "target": target.to_string(),
+            "attempt": attempt,
+            "message": err,
+        }),
+        Event::TargetFinished(target, true) => json!({
+            "event": "target_made",
+            "target": target.to_string(),
+        }),
+        Event::TargetFinished(target, false) => json!({
+            "event": "target_up_to_date",
+            "target": target.to_string(),
+        }),
+        Event::TargetFailed(target, err) => json!({
+            "event": "error",
+            "target": target.to_string(),
+            "message": err,
+        }),
+    }
+}
+
+impl Reporter for NdjsonReporter {
+    fn report(&self, event: &Event) {
+        let mut value = to_json(event);
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        value["time"] = json!(time.as_secs_f64());
+        if let Some(id) = value["target"].as_str().and_then(|name| self.ids.get(name)) {
+            value["target_id"] = json!(id);
+        }
+        for (name, field) in &self.fields {
+            value[name] = field.clone();
+        }
+
+        let mut writer = self.writer.lock().unwrap();
+        let _ = writeln!(writer, "{value}");
+        let _ = writer.flush();
+    }
+}
@@ -0,0 +1,40 @@
+use std::time::Duration;
+
+use log::warn;
+
+use crate::{mkfile::Target, MkError};
+
+/// Shows a desktop notification that the build of the target is over, with
+/// whether it made the target and how long it took.
+pub fn build_over(target: &Target, made: &Result<bool, MkError>, took: Duration) {
+    let took = format!("{:.1}s", took.as_secs_f64());
+    let (summary, body) = match made {
+        Ok(true) => (format!("Made '{target}'"), format!("Finished in {took}")),
+        Ok(false) => (
+            format!("'{target}' is up to date"),
+            format!("Finished in {took}"),
+        ),
+        Err(err) => (
+            format!("Failed to make '{target}'"),
+            format!("{err}, after {took}"),
+        ),
+    };
+    show(&summary, &body);
+}
+
+#[cfg(feature = "notify")]
+fn show(summary: &str, body: &str) {
+    if let Err(err) = notify_rust::Notification::new()
+        .appname("mk")
+        .summary(summary)
+        .body(body)
+        .show()
+    {
+        warn!("Can't show a notification: {}", err);
+    }
+}
+
+#[cfg(not(feature = "notify"))]
+fn show(_summary: &str, _body: &str) {
+    warn!("mk was built without the notify feature, so --notify does nothing");
+}
@@ -0,0 +1,246 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::{Hash, Hasher},
+    io::{self, BufRead, BufReader, IsTerminal, Read, Write},
+    process::{Child, Command, ExitStatus, Stdio},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    executor::{CommandExecutor, Invocation, Redirect, Status},
+    report::{Event, Reporter},
+};
+
+/// ANSI colors cycled through for target prefixes.
+const COLORS: &[u8] = &[32, 33, 34, 35, 36, 91, 92, 93, 94, 95, 96];
+
+/// Builds the `name | ` prefix for a target, colored when writing to a
+/// terminal. The color is derived from the name so it is stable across runs.
+pub fn prefix(name: &str) -> String {
+    if io::stdout().is_terminal() {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        name.hash(&mut hasher);
+        let color = COLORS[(hasher.finish() % COLORS.len() as u64) as usize];
+        format!("\x1b[{color}m{name}\x1b[0m | ")
+    } else {
+        format!("{name} | ")
+    }
+}
+
+/// Copies every line from the reader to the writer, prepending the prefix.
+fn copy_prefixed(reader: impl Read, mut writer: impl Write, prefix: &str) {
+    for line in BufReader::new(reader).split(b'\n') {
+        let Ok(line) = line else { break };
+        let mut buffer = Vec::with_capacity(prefix.len() + line.len() + 1);
+        buffer.extend_from_slice(prefix.as_bytes());
+        buffer.extend_from_slice(&line);
+        buffer.push(b'\n');
+        // Write whole lines at once so concurrent writers don't interleave
+        let _ = writer.write_all(&buffer);
+    }
+}
+
+/// Starts the command with its stdout and stderr captured, to be forwarded
+/// by `forward_prefixed`.
+pub fn spawn_prefixed(command: &mut Command) -> io::Result<Child> {
+    command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+}
+
+/// Forwards the stdout and stderr of a command started by `spawn_prefixed`
+/// line by line with the given prefix, until it exits.
+pub fn forward_prefixed(child: &mut Child, prefix: &str) -> io::Result<ExitStatus> {
+    forward_prefixed_to(child, prefix, io::stdout(), io::stderr())
+}
+
+/// Like `forward_prefixed`, but to the given writers instead of mk's own
+/// stdout and stderr.
+pub fn forward_prefixed_to(
+    child: &mut Child,
+    prefix: &str,
+    out: impl Write + Send,
+    err: impl Write + Send,
+) -> io::Result<ExitStatus> {
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+
+    std::thread::scope(|scope| {
+        scope.spawn(|| copy_prefixed(stdout, out, prefix));
+        scope.spawn(|| copy_prefixed(stderr, err, prefix));
+        child.wait()
+    })
+}
+
+/// How the output of commands that run at the same time is kept apart.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputSync {
+    /// Let it through as it comes.
+    None,
+    /// Let it through a line at a time, with the target's name in front.
+    Line,
+    /// Hold it until the target is done and print it in one piece, see
+    /// [`HeldOutput`].
+    Target,
+}
+
+impl FromStr for OutputSync {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "none" => Ok(OutputSync::None),
+            "line" => Ok(OutputSync::Line),
+            "target" => Ok(OutputSync::Target),
+            _ => Err(format!(
+                "Unknown output sync '{text}', expected none, line or target"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OutputSync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OutputSync::None => "none",
+            OutputSync::Line => "line",
+            OutputSync::Target => "target",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// What a target's commands wrote to stdout and stderr.
+type Held = Arc<Mutex<HashMap<String, (Vec<u8>, Vec<u8>)>>>;
+
+/// Holds the output of each target's commands until the target is done,
+/// and then prints it in one piece, so that the output of targets made at
+/// the same time doesn't mix. It runs commands with an inner executor,
+/// with their output sent to it, and learns that targets are done as a
+/// reporter.
+#[derive(Clone)]
+pub struct HeldOutput {
+    held: Held,
+    inner: Arc<dyn CommandExecutor>,
+}
+
+impl HeldOutput {
+    /// Holds the output of the commands that the inner executor runs.
+    pub fn new(inner: Arc<dyn CommandExecutor>) -> Self {
+        HeldOutput {
+            held: Held::default(),
+            inner,
+        }
+    }
+
+    /// Prints what the target's commands wrote, if anything.
+    fn release(&self, target: &str) {
+        let Some((out, err)) = self.held.lock().unwrap().remove(target) else {
+            return;
+        };
+        let (mut stdout, mut stderr) = (io::stdout().lock(), io::stderr().lock());
+        let _ = stdout.write_all(&out);
+        let _ = stdout.flush();
+        let _ = stderr.write_all(&err);
+    }
+}
+
+impl Reporter for HeldOutput {
+    fn report(&self, event: &Event) {
+        if let Event::TargetFinished(target, _) | Event::TargetFailed(target, _) = event {
+            self.release(&target.to_string());
+        }
+    }
+
+    fn finish(&self) {
+        let left: Vec<String> = self.held.lock().unwrap().keys().cloned().collect();
+        for target in left {
+            self.release(&target);
+        }
+    }
+}
+
+/// Sends command output to what is held for its target.
+#[derive(Clone)]
+struct HeldWriter {
+    held: Held,
+    target: String,
+    stderr: bool,
+}
+
+impl Write for HeldWriter {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        let mut held = self.held.lock().unwrap();
+        let (out, err) = held.entry(self.target.clone()).or_default();
+        match self.stderr {
+            true => err.extend_from_slice(buffer),
+            false => out.extend_from_slice(buffer),
+        }
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl HeldOutput {
+    /// Sends the output of a command run for a target to what is held for
+    /// it. Other commands are left as they are.
+    fn redirect(&self, invocation: &Invocation) -> Invocation {
+        let Some(target) = &invocation.target else {
+            return invocation.clone();
+        };
+        let writer = |stderr| HeldWriter {
+            held: self.held.clone(),
+            target: target.clone(),
+            stderr,
+        };
+        Invocation {
+            prefix: None,
+            redirect: Some(Redirect::to(writer(false), writer(true))),
+            ..invocation.clone()
+        }
+    }
+}
+
+impl CommandExecutor for HeldOutput {
+    fn run(&self, invocation: &Invocation) -> io::Result<Status> {
+        self.inner.run(&self.redirect(invocation))
+    }
+
+    fn capture(&self, invocation: &Invocation) -> io::Result<(Status, Vec<u8>)> {
+        self.inner.capture(&self.redirect(invocation))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{executor::ShellExecutor, mkfile::Target};
+
+    #[test]
+    fn test_holds_output_until_the_target_is_done() {
+        let output = HeldOutput::new(Arc::new(ShellExecutor));
+        let invocation = |target: Option<&str>| Invocation {
+            command: "echo out; echo err >&2".to_string(),
+            prefix: Some("a | ".to_string()),
+            target: target.map(String::from),
+            ..Invocation::default()
+        };
+        assert!(output.run(&invocation(Some("a"))).unwrap().success());
+        let (status, captured) = output.capture(&invocation(Some("a"))).unwrap();
+        assert!(status.success());
+        assert_eq!(captured, b"out\n");
+        assert!(output.run(&invocation(None)).unwrap().success());
+
+        let held = |target| output.held.lock().unwrap().get(target).cloned();
+        assert_eq!(held("a"), Some((b"out\n".to_vec(), b"err\nerr\n".to_vec())));
+        assert_eq!(output.held.lock().unwrap().len(), 1);
+        output.report(&Event::TargetFinished(&Target::parse("a"), true));
+        assert_eq!(held("a"), None);
+    }
+}
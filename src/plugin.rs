@@ -0,0 +1,218 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::MkError,
+    freshness::FreshnessChecker,
+    making::{RebuildReason, UpdateState},
+    mkfile::ConcreteTarget,
+    vfs::Vfs,
+};
+
+/// A loaded plugin: a WebAssembly module that an mkfile loads with a
+/// `.plugin: PATH` directive, named after its file without the extension.
+/// A plugin exports its `memory`, an `mk_alloc(len: i32) -> i32` function
+/// that returns where the host may write `len` bytes, and any of these
+/// hooks:
+///
+/// - `mk_check` adds a target kind, picked with `kind: NAME`. It gets the
+///   name of the target and returns a shell command that succeeds if the
+///   target is up to date, as in a migration that was applied already.
+/// - `mk_fingerprint` adds a freshness checker, picked with
+///   `fingerprint: NAME`. It gets the contents of a file and returns a
+///   fingerprint, which only changes when the file changes in a way that
+///   matters.
+/// - `mk_run` adds a command runner, picked with `runner: NAME`. It gets a
+///   command of the rule and returns the shell command that runs it.
+///
+/// Hooks take the pointer and length of their input, which is UTF-8 text
+/// except for file contents, and return the pointer to their output in the
+/// high 32 bits of an `i64` and its length in the low ones. Plugins have no
+/// imports, so they can only compute, and leave anything else to the
+/// commands they return.
+pub struct Plugin {
+    name: String,
+    #[cfg(feature = "plugins")]
+    module: wasm::Module,
+}
+
+impl Plugin {
+    #[cfg(feature = "plugins")]
+    fn load(path: &Path) -> Result<Self, MkError> {
+        let module = wasm::Module::load(path).map_err(|err| {
+            plugin_error(format!("Can't load plugin '{}': {err}", path.display()))
+        })?;
+        Ok(Plugin {
+            name: path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            module,
+        })
+    }
+
+    #[cfg(not(feature = "plugins"))]
+    fn load(path: &Path) -> Result<Self, MkError> {
+        Err(plugin_error(format!(
+            "Can't load plugin '{}': mk was built without the plugins feature",
+            path.display()
+        )))
+    }
+
+    /// Returns the shell command that succeeds if a target of the plugin's
+    /// kind is up to date.
+    pub fn check_command(&self, target: &str) -> Result<String, MkError> {
+        self.call_text("mk_check", target.as_bytes())
+    }
+
+    /// Returns the fingerprint of a file's contents.
+    pub fn fingerprint(&self, contents: &[u8]) -> Result<String, MkError> {
+        self.call_text("mk_fingerprint", contents)
+    }
+
+    /// Returns the shell command that runs a command of a rule.
+    pub fn command(&self, command: &str) -> Result<String, MkError> {
+        self.call_text("mk_run", command.as_bytes())
+    }
+
+    /// Returns a freshness checker that compares the plugin's fingerprints.
+    pub fn checker(&self) -> FingerprintChecker<'_> {
+        FingerprintChecker { plugin: self }
+    }
+
+    fn call_text(&self, hook: &str, input: &[u8]) -> Result<String, MkError> {
+        let output = self.call(hook, input)?;
+        String::from_utf8(output).map_err(|_| {
+            plugin_error(format!(
+                "Plugin '{}' returned invalid UTF-8 from '{hook}'",
+                self.name
+            ))
+        })
+    }
+
+    #[cfg(feature = "plugins")]
+    fn call(&self, hook: &str, input: &[u8]) -> Result<Vec<u8>, MkError> {
+        self.module.call(hook, input).map_err(|err| {
+            plugin_error(format!("Plugin '{}' failed in '{hook}': {err}", self.name))
+        })
+    }
+
+    #[cfg(not(feature = "plugins"))]
+    fn call(&self, _hook: &str, _input: &[u8]) -> Result<Vec<u8>, MkError> {
+        unreachable!("plugins can't be loaded without the plugins feature")
+    }
+}
+
+fn plugin_error(message: String) -> MkError {
+    MkError::Io(std::io::Error::other(message))
+}
+
+/// The plugins an mkfile loaded, by name.
+#[derive(Default)]
+pub struct Plugins {
+    plugins: BTreeMap<String, Plugin>,
+}
+
+impl Plugins {
+    /// Loads the plugins at the given paths.
+    pub fn load(paths: &[PathBuf]) -> Result<Self, MkError> {
+        let mut plugins = BTreeMap::new();
+        for path in paths {
+            let plugin = Plugin::load(path)?;
+            plugins.insert(plugin.name.clone(), plugin);
+        }
+        Ok(Plugins { plugins })
+    }
+
+    /// Returns the plugin with the given name.
+    pub fn get(&self, name: &str) -> Result<&Plugin, MkError> {
+        self.plugins
+            .get(name)
+            .ok_or_else(|| MkError::Parse(format!("No plugin named '{name}'")))
+    }
+}
+
+/// Compares files by the fingerprints a plugin gives their contents.
+pub struct FingerprintChecker<'a> {
+    plugin: &'a Plugin,
+}
+
+impl FingerprintChecker<'_> {
+    fn fingerprint(&self, vfs: &dyn Vfs, path: &ConcreteTarget) -> Result<String, MkError> {
+        self.plugin.fingerprint(&vfs.read(path.pathbuf())?)
+    }
+}
+
+impl FreshnessChecker for FingerprintChecker<'_> {
+    fn check(
+        &self,
+        state: &UpdateState,
+        vfs: &dyn Vfs,
+        path: &ConcreteTarget,
+    ) -> Result<Result<(), RebuildReason>, MkError> {
+        let Some(recorded) = state.recorded_hash(path) else {
+            return Ok(Err(RebuildReason::NotRecorded));
+        };
+        if self.fingerprint(vfs, path)? == recorded {
+            Ok(Ok(()))
+        } else {
+            Ok(Err(RebuildReason::Modified))
+        }
+    }
+
+    fn record(
+        &self,
+        state: &mut UpdateState,
+        vfs: &dyn Vfs,
+        path: &ConcreteTarget,
+    ) -> Result<bool, MkError> {
+        state.update_state(vfs, path)?;
+        let fingerprint = self.fingerprint(vfs, path)?;
+        Ok(state.record_hash(path, fingerprint))
+    }
+}
+
+#[cfg(feature = "plugins")]
+mod wasm {
+    use std::{path::Path, sync::Mutex};
+
+    use wasmtime::{Engine, Instance, Memory, Store};
+
+    /// An instance of a plugin's module. Calls to it take turns.
+    pub struct Module {
+        instance: Mutex<(Store<()>, Instance)>,
+    }
+
+    impl Module {
+        pub fn load(path: &Path) -> wasmtime::Result<Self> {
+            let engine = Engine::default();
+            let module = wasmtime::Module::from_file(&engine, path)?;
+            let mut store = Store::new(&engine, ());
+            let instance = Instance::new(&mut store, &module, &[])?;
+            Ok(Module {
+                instance: Mutex::new((store, instance)),
+            })
+        }
+
+        pub fn call(&self, hook: &str, input: &[u8]) -> wasmtime::Result<Vec<u8>> {
+            let mut guard = self.instance.lock().unwrap();
+            let (store, instance) = &mut *guard;
+            let memory: Memory = instance
+                .get_memory(&mut *store, "memory")
+                .ok_or_else(|| wasmtime::Error::msg("no exported memory"))?;
+            let alloc = instance.get_typed_func::<i32, i32>(&mut *store, "mk_alloc")?;
+            let hook = instance.get_typed_func::<(i32, i32), i64>(&mut *store, hook)?;
+
+            let length = i32::try_from(input.len())?;
+            let pointer = alloc.call(&mut *store, length)?;
+            memory.write(&mut *store, pointer as u32 as usize, input)?;
+            let output = hook.call(&mut *store, (pointer, length))? as u64;
+            let (pointer, length) = ((output >> 32) as usize, (output & 0xffff_ffff) as usize);
+            let mut bytes = vec![0; length];
+            memory.read(&*store, pointer, &mut bytes)?;
+            Ok(bytes)
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    fmt::Write as _,
+    io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    making::{content_hash, implicit_dependencies, with_suffix},
+    mkfile::{MkFile, Target},
+    vfs::Vfs,
+};
+
+/// What went into making an output: every target it was made from, with
+/// the commands of their rules and a hash of their contents at the time.
+/// It is written next to the output, so that shipped files can be traced
+/// back to their inputs later.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Provenance {
+    pub target: String,
+    /// Seconds since the Unix epoch.
+    pub built_at: u64,
+    /// The target itself first, then what it was made from, closest first.
+    pub nodes: Vec<Node>,
+}
+
+/// A target in the graph that made an output.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Node {
+    pub target: String,
+    /// Hash of the contents, for files and folders.
+    pub hash: Option<String>,
+    /// The commands of the target's rule, or none for sources.
+    pub commands: Vec<String>,
+    /// The targets it was made from, including those found by scanners.
+    pub dependencies: Vec<String>,
+}
+
+/// Returns where the provenance of an output is written.
+pub fn sidecar_path(output: &Path) -> PathBuf {
+    with_suffix(output, ".mk.json")
+}
+
+/// Takes a snapshot of the graph that made the target, as it is now.
+pub fn snapshot(mkfile: &MkFile, vfs: &dyn Vfs, target: &Target) -> io::Result<Provenance> {
+    let mut nodes = Vec::new();
+    let mut seen = HashSet::new();
+    let mut pending = VecDeque::from([target.clone()]);
+    while let Some(target) = pending.pop_front() {
+        if !seen.insert(target.clone()) {
+            continue;
+        }
+        let (commands, dependencies) = if mkfile.has_target(&target) {
+            let mut dependencies = mkfile.dependencies(&target).clone();
+            dependencies.extend(implicit_dependencies(mkfile, vfs, &target));
+            (mkfile.commands(&target).clone(), dependencies)
+        } else {
+            (Vec::new(), Vec::new())
+        };
+        let hash = match &target {
+            Target::Concrete(path) if vfs.exists(path.pathbuf()) => Some(content_hash(vfs, path)?),
+            _ => None,
+        };
+        nodes.push(Node {
+            target: target.to_string(),
+            hash,
+            commands,
+            dependencies: dependencies.iter().map(Target::to_string).collect(),
+        });
+        pending.extend(dependencies);
+    }
+    Ok(Provenance {
+        target: target.to_string(),
+        built_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs()),
+        nodes,
+    })
+}
+
+/// Writes the provenance of a target that was just made next to its output.
+pub fn record(mkfile: &MkFile, vfs: &dyn Vfs, target: &Target, output: &Path) -> io::Result<()> {
+    let provenance = snapshot(mkfile, vfs, target)?;
+    let text = serde_json::to_string_pretty(&provenance)?;
+    vfs.write(&sidecar_path(output), text.as_bytes())
+}
+
+/// Reads the provenance written next to an output.
+pub fn load(vfs: &dyn Vfs, output: &Path) -> io::Result<Provenance> {
+    let bytes = vfs.read(&sidecar_path(output))?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+/// Explains how an output was made: its recipe, then the recipe of each
+/// target it was made from and the sources they read, noting files that
+/// changed since.
+pub fn explain(provenance: &Provenance, vfs: &dyn Vfs) -> String {
+    let current = |node: &Node| {
+        let path = Target::parse(&node.target);
+        match path {
+            Target::Concrete(path) if vfs.exists(path.pathbuf()) => content_hash(vfs, &path).ok(),
+            _ => None,
+        }
+    };
+    let short = |hash: &str| hash.chars().take(12).collect::<String>();
+
+    let mut text = String::new();
+    let mut sources = BTreeMap::new();
+    for (index, node) in provenance.nodes.iter().enumerate() {
+        if node.commands.is_empty() && node.dependencies.is_empty() && index > 0 {
+            sources.insert(&node.target, node);
+            continue;
+        }
+        if index == 0 {
+            write!(text, "'{}'", node.target).unwrap();
+        } else {
+            write!(text, "\nfrom '{}'", node.target).unwrap();
+        }
+        if let Some(hash) = &node.hash {
+            write!(text, " ({})", short(hash)).unwrap();
+        }
+        if node.commands.is_empty() {
+            writeln!(text, " only groups {}", node.dependencies.join(", ")).unwrap();
+            continue;
+        }
+        text.push_str(" was made by running:\n");
+        for command in &node.commands {
+            writeln!(text, "    {command}").unwrap();
+        }
+        if !node.dependencies.is_empty() {
+            writeln!(text, "  with {}", node.dependencies.join(", ")).unwrap();
+        }
+        if node.hash.is_some() && current(node) != node.hash {
+            text.push_str("  and has changed since\n");
+        }
+    }
+    if !sources.is_empty() {
+        text.push_str("\nfrom the sources:\n");
+        for node in sources.values() {
+            match &node.hash {
+                Some(hash) if current(node).as_ref() == Some(hash) => {
+                    writeln!(text, "    {} ({})", node.target, short(hash)).unwrap()
+                }
+                Some(hash) => writeln!(
+                    text,
+                    "    {} ({}), which changed since",
+                    node.target,
+                    short(hash)
+                )
+                .unwrap(),
+                None => writeln!(text, "    {}", node.target).unwrap(),
+            }
+        }
+    }
+    text
+}
@@ -0,0 +1,145 @@
+use std::io::{self, Write};
+
+use serde_json::json;
+
+use crate::{
+    error::MkError,
+    making::UpdateState,
+    mkfile::{MkFile, Target},
+};
+
+/// Picks targets by name and tags.
+#[derive(Debug, Default)]
+pub struct TargetFilter {
+    pattern: Option<glob::Pattern>,
+    tags: Vec<String>,
+}
+
+impl TargetFilter {
+    /// Creates a filter for targets whose name matches the glob pattern, if
+    /// any, and whose rule has every one of the tags.
+    pub fn new(pattern: Option<&str>, tags: Vec<String>) -> Result<Self, MkError> {
+        let pattern = pattern
+            .map(glob::Pattern::new)
+            .transpose()
+            .map_err(|err| MkError::Parse(format!("Invalid pattern: {err}")))?;
+        Ok(TargetFilter { pattern, tags })
+    }
+
+    /// Returns true if the target passes the filter. Patterns match virtual
+    /// targets with or without their `$`.
+    pub fn matches(&self, mkfile: &MkFile, target: &Target) -> bool {
+        let name = target.to_string();
+        let name_matches = self.pattern.as_ref().is_none_or(|pattern| {
+            pattern.matches(&name) || pattern.matches(name.trim_start_matches('$'))
+        });
+        let tags = &mkfile.options(target).tags;
+        name_matches && self.tags.iter().all(|tag| tags.contains(tag))
+    }
+}
+
+/// Writes every target that passes the filter as soon as it is found, one
+/// per line, either as its name and description or as a JSON object with
+/// its stable ID from the state. Returns how many targets were written.
+pub fn list(
+    mkfile: &MkFile,
+    state: &UpdateState,
+    filter: &TargetFilter,
+    as_json: bool,
+    out: &mut dyn Write,
+) -> io::Result<usize> {
+    let mut count = 0;
+    for target in mkfile
+        .targets()
+        .filter(|target| filter.matches(mkfile, target))
+    {
+        let description = mkfile.description(target);
+        if as_json {
+            let value = json!({
+                "target": target.to_string(),
+                "id": state.target_id(target),
+                "description": description,
+                "dependencies": mkfile
+                    .dependencies(target)
+                    .iter()
+                    .map(Target::to_string)
+                    .collect::<Vec<_>>(),
+                "tags": mkfile.options(target).tags,
+            });
+            writeln!(out, "{value}")?;
+        } else {
+            match description.and_then(|description| description.lines().next()) {
+                Some(summary) => writeln!(out, "{target}\t{summary}")?,
+                None => writeln!(out, "{target}")?,
+            }
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MKFILE: &str = "# Builds everything\n$all: app docs\n\n\
+                          # Compiles the program.\n# Links it too.\napp: app.c\n    tags: build c\n    cc app.c\n\n\
+                          docs:\n    tags: docs\n    mkdocs build\n\n\
+                          $bench: app\n    tags: build\n    ./app --bench\n";
+
+    fn listed(filter: &TargetFilter, as_json: bool) -> (String, usize) {
+        let mkfile = MkFile::parse(MKFILE).unwrap();
+        let mut out = Vec::new();
+        let count = list(&mkfile, &UpdateState::default(), filter, as_json, &mut out).unwrap();
+        (String::from_utf8(out).unwrap(), count)
+    }
+
+    #[test]
+    fn test_list_names_and_summaries() {
+        let (text, count) = listed(&TargetFilter::default(), false);
+
+        assert_eq!(
+            text,
+            "$all\tBuilds everything\napp\tCompiles the program.\ndocs\n$bench\n"
+        );
+        assert_eq!(count, 4);
+    }
+
+    #[test]
+    fn test_list_filters_by_pattern_and_tags() {
+        let names = |pattern: Option<&str>, tags: &[&str]| {
+            let tags = tags.iter().map(|tag| tag.to_string()).collect();
+            listed(&TargetFilter::new(pattern, tags).unwrap(), false)
+                .0
+                .lines()
+                .map(|line| line.split('\t').next().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names(Some("a*"), &[]), ["$all", "app"]);
+        assert_eq!(names(Some("$b*"), &[]), ["$bench"]);
+        assert_eq!(names(None, &["build"]), ["app", "$bench"]);
+        assert_eq!(names(None, &["build", "c"]), ["app"]);
+        assert_eq!(names(Some("d*"), &["build"]), Vec::<String>::new());
+        assert!(TargetFilter::new(Some("[a"), Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_list_as_json() {
+        let filter = TargetFilter::new(Some("app"), Vec::new()).unwrap();
+        let (text, count) = listed(&filter, true);
+
+        let value: serde_json::Value = serde_json::from_str(text.trim_end()).unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(
+            value,
+            json!({
+                "target": "app",
+                "id": UpdateState::default().target_id(&Target::parse("app")),
+                "description": "Compiles the program.\nLinks it too.",
+                "dependencies": ["app.c"],
+                "tags": ["build", "c"],
+            })
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{making::format_size, mkfile::Target, vfs::Vfs};
+
+/// What a release rule made, as one line of the release manifest.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReleaseEntry {
+    /// Name of the artifact, from the rule's `release:` option.
+    pub artifact: String,
+    /// From the rule's `version:` option, usually a variable.
+    pub version: Option<String>,
+    pub target: String,
+    /// Hash of the output, for targets that are a single file.
+    pub sha256: Option<String>,
+    pub size: Option<u64>,
+    /// The commit checked out when the artifact was made, if in a git
+    /// repository.
+    pub commit: Option<String>,
+    /// Whether files tracked by git had uncommitted changes.
+    #[serde(default)]
+    pub dirty: bool,
+    /// Seconds since the Unix epoch.
+    pub built_at: u64,
+}
+
+/// A file that release rules add an entry to whenever they are made, one
+/// JSON object per line, so that release notes are written from what was
+/// actually built.
+pub struct ReleaseManifest {
+    path: PathBuf,
+    /// Held while the file is rewritten, as rules can finish together.
+    writing: Mutex<()>,
+    /// The commit and whether there are uncommitted changes, looked up once.
+    checkout: OnceLock<(Option<String>, bool)>,
+}
+
+impl ReleaseManifest {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        ReleaseManifest {
+            path: path.into(),
+            writing: Mutex::new(()),
+            checkout: OnceLock::new(),
+        }
+    }
+
+    /// Adds an entry for a target whose rule has a `release:` option, just
+    /// after it was made.
+    pub fn record(
+        &self,
+        vfs: &dyn Vfs,
+        target: &Target,
+        artifact: &str,
+        version: Option<&str>,
+    ) -> io::Result<()> {
+        let (sha256, size) = match target {
+            Target::Concrete(path) if !path.is_deep() => {
+                let path = path.pathbuf();
+                (Some(vfs.file_hash(path)?), Some(vfs.metadata(path)?.len))
+            }
+            _ => (None, None),
+        };
+        let (commit, dirty) = self.checkout.get_or_init(checkout).clone();
+        let entry = ReleaseEntry {
+            artifact: artifact.to_string(),
+            version: version.map(String::from),
+            target: target.to_string(),
+            sha256,
+            size,
+            commit,
+            dirty,
+            built_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+        };
+
+        let _writing = self.writing.lock().unwrap();
+        let mut text = vfs.read(&self.path).unwrap_or_default();
+        if !text.is_empty() && !text.ends_with(b"\n") {
+            text.push(b'\n');
+        }
+        text.extend(serde_json::to_string(&entry)?.into_bytes());
+        text.push(b'\n');
+        vfs.write(&self.path, &text)
+    }
+}
+
+/// Returns the commit checked out in the current folder and whether tracked
+/// files have uncommitted changes.
+fn checkout() -> (Option<String>, bool) {
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    let commit = git(&["rev-parse", "HEAD"]);
+    let dirty = commit.is_some()
+        && git(&["status", "--porcelain", "--untracked-files=no"])
+            .is_some_and(|status| !status.is_empty());
+    (commit, dirty)
+}
+
+/// Reads every entry of a release manifest, oldest first.
+pub fn load(vfs: &dyn Vfs, path: &Path) -> io::Result<Vec<ReleaseEntry>> {
+    let text = String::from_utf8(vfs.read(path)?).map_err(io::Error::other)?;
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(number, line)| {
+            serde_json::from_str(line).map_err(|err| {
+                io::Error::other(format!("Invalid entry on line {}: {err}", number + 1))
+            })
+        })
+        .collect()
+}
+
+/// Renders release notes in Markdown, with the most recent build of each
+/// artifact and version. With `all`, lists every build instead.
+pub fn notes(entries: &[ReleaseEntry], all: bool) -> String {
+    let mut releases: BTreeMap<(&str, Option<&str>), Vec<&ReleaseEntry>> = BTreeMap::new();
+    for entry in entries {
+        let builds = releases
+            .entry((&entry.artifact, entry.version.as_deref()))
+            .or_default();
+        if !all {
+            builds.clear();
+        }
+        builds.push(entry);
+    }
+
+    let mut page = String::from("# Release notes\n");
+    for ((artifact, version), builds) in releases {
+        match version {
+            Some(version) => write!(page, "\n## {artifact} {version}\n\n").unwrap(),
+            None => write!(page, "\n## {artifact}\n\n").unwrap(),
+        }
+        for build in builds {
+            write!(page, "- `{}`", build.target).unwrap();
+            if let Some(size) = build.size {
+                write!(page, ", {}", format_size(size)).unwrap();
+            }
+            if let Some(sha256) = &build.sha256 {
+                write!(page, ", sha256 `{sha256}`").unwrap();
+            }
+            match &build.commit {
+                Some(commit) if build.dirty => write!(
+                    page,
+                    ", built from {} with uncommitted changes",
+                    &commit[..commit.len().min(12)]
+                )
+                .unwrap(),
+                Some(commit) => {
+                    write!(page, ", built from {}", &commit[..commit.len().min(12)]).unwrap()
+                }
+                None => {}
+            }
+            page.push('\n');
+        }
+    }
+    page
+}
@@ -0,0 +1,166 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+
+use crate::error::MkError;
+
+/// Folder that fetched mkfiles are kept in, for when mk is offline or they
+/// can't be fetched.
+pub const CACHE_DIR: &str = ".mk-includes";
+
+/// Returns true if an include names a file elsewhere rather than a path.
+pub fn is_remote(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://") || source.starts_with("git:")
+}
+
+/// Returns where a copy of the included mkfile is kept, fetching it first.
+/// Sources are `http://` or `https://` URLs, which are downloaded with curl,
+/// or `git:REPOSITORY//PATH?ref=REF`, which takes the file at `PATH` from a
+/// shallow clone of the repository, at the branch or tag `REF` if given.
+///
+/// Sources are fetched again every time, so that branches and URLs whose
+/// contents change are followed, and the copy fetched before is only used
+/// if that fails. Offline, only copies fetched before are used, however
+/// old they are.
+pub fn fetch(source: &str, offline: bool) -> Result<PathBuf, MkError> {
+    fetch_in(Path::new(CACHE_DIR), source, offline)
+}
+
+/// Fetches the source into a folder of the cache, as [`fetch`] does.
+fn fetch_in(cache: &Path, source: &str, offline: bool) -> Result<PathBuf, MkError> {
+    let key = format!("{:x}", Sha256::digest(source.as_bytes()));
+    let dir = cache.join(&key[..16]);
+    let (repository, path, reference) = match source.strip_prefix("git:") {
+        Some(rest) => {
+            let (rest, reference) = match rest.rsplit_once("?ref=") {
+                Some((rest, reference)) => (rest, Some(reference)),
+                None => (rest, None),
+            };
+            // The `//` of the URL's scheme isn't the one before the path
+            let after_scheme = rest.find("://").map_or(0, |scheme| scheme + 3);
+            let split = rest[after_scheme..]
+                .find("//")
+                .map(|split| after_scheme + split)
+                .ok_or_else(|| {
+                    MkError::Parse(format!(
+                        "Include '{source}' needs the path in the repository after '//'"
+                    ))
+                })?;
+            (Some(&rest[..split]), &rest[split + 2..], reference)
+        }
+        None => (None, "mkfile", None),
+    };
+    let file = dir.join(path);
+    if offline {
+        if file.exists() {
+            return Ok(file);
+        }
+        return Err(MkError::Parse(format!(
+            "Include '{source}' wasn't fetched before, and mk is offline"
+        )));
+    }
+
+    // Fetched next to the copy from before, which is kept if this fails
+    info!("Fetching '{source}'");
+    let fetching = cache.join(format!("{}.tmp", &key[..16]));
+    let _ = fs::remove_dir_all(&fetching);
+    let mut command = match repository {
+        Some(repository) => {
+            let mut command = Command::new("git");
+            command.args(["clone", "--quiet", "--depth", "1"]);
+            if let Some(reference) = reference {
+                command.args(["--branch", reference]);
+            }
+            command.arg(repository).arg(&fetching);
+            command
+        }
+        None => {
+            fs::create_dir_all(&fetching)?;
+            let mut command = Command::new("curl");
+            command.args([
+                "--fail",
+                "--silent",
+                "--show-error",
+                "--location",
+                "--output",
+            ]);
+            command.arg(fetching.join(path)).arg(source);
+            command
+        }
+    };
+    let fetched = match command.output() {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(output) => Err(String::from_utf8_lossy(&output.stderr).trim().to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+    if let Err(err) = fetched {
+        let _ = fs::remove_dir_all(&fetching);
+        if file.exists() {
+            warn!("Failed to fetch '{source}', using the copy from before: {err}");
+            return Ok(file);
+        }
+        return Err(MkError::Parse(format!("Failed to fetch '{source}': {err}")));
+    }
+    if !fetching.join(path).exists() {
+        let _ = fs::remove_dir_all(&fetching);
+        return Err(MkError::Parse(format!(
+            "Include '{source}' has no file '{path}'"
+        )));
+    }
+    let _ = fs::remove_dir_all(&dir);
+    fs::rename(&fetching, &dir)?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Runs git in the folder, failing the test if it fails.
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .args(["-c", "user.name=mk", "-c", "user.email=mk@example.com"])
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap()
+            .status;
+        assert!(status.success(), "git {args:?} failed");
+    }
+
+    #[test]
+    fn test_fetch_follows_branches_unless_offline() {
+        let dir = std::env::temp_dir().join(format!("mk-test-{}-remote", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (repository, cache) = (dir.join("repository"), dir.join("cache"));
+        fs::create_dir_all(&repository).unwrap();
+        git(&repository, &["init", "--quiet"]);
+        let commit = |text: &str| {
+            fs::write(repository.join("common.mk"), text).unwrap();
+            git(&repository, &["add", "common.mk"]);
+            git(&repository, &["commit", "--quiet", "-m", text]);
+        };
+        let source = format!("git:file://{}//common.mk", repository.display());
+        let read = |offline| fs::read_to_string(fetch_in(&cache, &source, offline).unwrap());
+
+        assert!(fetch_in(&cache, &source, true).is_err());
+        commit("A = 1\n");
+        assert_eq!(read(false).unwrap(), "A = 1\n");
+        commit("A = 2\n");
+        assert_eq!(read(true).unwrap(), "A = 1\n");
+        assert_eq!(read(false).unwrap(), "A = 2\n");
+
+        // The copy from before is used when the source is gone
+        fs::remove_dir_all(&repository).unwrap();
+        assert_eq!(read(false).unwrap(), "A = 2\n");
+        let other = format!("git:file://{}//other.mk", repository.display());
+        assert!(fetch_in(&cache, &other, false).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
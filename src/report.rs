@@ -0,0 +1,200 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::Duration,
+};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use log::{info, warn};
+
+use crate::{making::RebuildReason, mkfile::Target};
+
+/// Something that happened while making targets.
+#[derive(Debug)]
+pub enum Event<'a> {
+    TargetStarted(&'a Target),
+    /// The target's rule doesn't apply here, so it is left alone.
+    Skipped(&'a Target, &'a str),
+    Outdated(&'a Target, &'a RebuildReason),
+    /// The target changed on disk, or was made, but its contents are the same.
+    Unchanged(&'a Target),
+    /// The target's output was copied from the cache instead of made.
+    Restored(&'a Target),
+    CommandStarted(&'a Target, &'a str),
+    CommandFinished(&'a Target, &'a str),
+    /// The target's commands failed and are run again, as the given attempt,
+    /// counting from 1.
+    Retrying(&'a Target, usize, &'a str),
+    TargetFinished(&'a Target, bool),
+    TargetFailed(&'a Target, &'a str),
+}
+
+/// Receives events as targets are made.
+pub trait Reporter: Send + Sync {
+    fn report(&self, event: &Event);
+
+    /// Called once after the build is over.
+    fn finish(&self) {}
+}
+
+impl<T: Reporter> Reporter for std::sync::Arc<T> {
+    fn report(&self, event: &Event) {
+        self.as_ref().report(event);
+    }
+
+    fn finish(&self) {
+        self.as_ref().finish();
+    }
+}
+
+/// Forwards every event to several reporters.
+pub struct Reporters(pub Vec<Box<dyn Reporter>>);
+
+impl Reporter for Reporters {
+    fn report(&self, event: &Event) {
+        for reporter in &self.0 {
+            reporter.report(event);
+        }
+    }
+
+    fn finish(&self) {
+        for reporter in &self.0 {
+            reporter.finish();
+        }
+    }
+}
+
+/// Reports events as plain log lines.
+#[derive(Default)]
+pub struct LogReporter {
+    /// Also log why each target is being made.
+    pub explain: bool,
+}
+
+impl Reporter for LogReporter {
+    fn report(&self, event: &Event) {
+        match event {
+            Event::TargetStarted(target) => info!("Making target '{:?}'", target),
+            Event::Skipped(target, reason) => info!("Skipping target '{}': {}", target, reason),
+            Event::Outdated(target, reason) => {
+                if self.explain {
+                    info!("Target '{}' is out of date: {}", target, reason)
+                }
+            }
+            Event::Unchanged(target) => {
+                if self.explain {
+                    info!("Target '{}' has the same contents as before", target)
+                }
+            }
+            Event::Restored(target) => info!("Restored target '{}' from the cache", target),
+            Event::CommandStarted(_, command) => info!("Executing command '{}'", command),
+            Event::Retrying(target, attempt, err) => {
+                warn!(
+                    "Target '{}' failed, starting attempt {}: {}",
+                    target, attempt, err
+                )
+            }
+            // Failures are reported by the caller once the error reaches it
+            Event::CommandFinished(..) | Event::TargetFinished(..) | Event::TargetFailed(..) => {}
+        }
+    }
+}
+
+struct ProgressState {
+    finished: HashSet<Target>,
+    running: HashMap<Target, ProgressBar>,
+}
+
+/// Shows an overall progress bar plus a spinner for every target whose
+/// commands are currently running.
+pub struct ProgressReporter {
+    bars: MultiProgress,
+    overall: ProgressBar,
+    state: Mutex<ProgressState>,
+    explain: bool,
+}
+
+impl ProgressReporter {
+    /// Creates a progress display for a build of `total` targets.
+    pub fn new(total: usize, explain: bool) -> Self {
+        let bars = MultiProgress::new();
+        let overall = bars.add(ProgressBar::new(total as u64));
+        overall.set_style(
+            ProgressStyle::with_template("[{elapsed_precise}] {bar:40} {pos}/{len} targets")
+                .unwrap(),
+        );
+        ProgressReporter {
+            bars,
+            overall,
+            state: Mutex::new(ProgressState {
+                finished: HashSet::new(),
+                running: HashMap::new(),
+            }),
+            explain,
+        }
+    }
+
+    /// Marks the target as finished. Returns true if its commands were running.
+    fn finish(&self, target: &Target, status: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let running = state.running.remove(target);
+        if let Some(bar) = &running {
+            let elapsed = bar.elapsed();
+            bar.finish_and_clear();
+            self.bars.remove(bar);
+            let _ = self
+                .bars
+                .println(format!("{status} {target} ({:.1}s)", elapsed.as_secs_f64()));
+        }
+        if state.finished.insert(target.clone()) {
+            self.overall.inc(1);
+        }
+        running.is_some()
+    }
+}
+
+impl Reporter for ProgressReporter {
+    fn report(&self, event: &Event) {
+        match event {
+            Event::TargetStarted(_)
+            | Event::Skipped(..)
+            | Event::Unchanged(_)
+            | Event::Restored(_)
+            | Event::CommandFinished(..) => {}
+            Event::Retrying(target, attempt, err) => {
+                let _ = self.bars.println(format!(
+                    "{target}: failed, starting attempt {attempt}: {err}"
+                ));
+            }
+            Event::Outdated(target, reason) => {
+                if self.explain {
+                    let _ = self.bars.println(format!("{target}: {reason}"));
+                }
+            }
+            Event::CommandStarted(target, _) => {
+                let mut state = self.state.lock().unwrap();
+                if !state.running.contains_key(*target) {
+                    let bar = self.bars.add(ProgressBar::new_spinner());
+                    bar.set_style(
+                        ProgressStyle::with_template("{spinner} {msg} ({elapsed})").unwrap(),
+                    );
+                    bar.set_message(target.to_string());
+                    bar.enable_steady_tick(Duration::from_millis(100));
+                    state.running.insert((*target).clone(), bar);
+                }
+            }
+            Event::TargetFinished(target, made) => {
+                self.finish(target, if *made { "done" } else { "up to date" });
+            }
+            Event::TargetFailed(target, err) => {
+                if self.finish(target, "FAILED") {
+                    let _ = self.bars.println(format!("  {err}"));
+                }
+            }
+        }
+    }
+
+    fn finish(&self) {
+        self.overall.finish();
+    }
+}
@@ -0,0 +1,284 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    making::{make, MakeOptions, UpdateState},
+    mkfile::{MkFile, ParseOptions},
+    report::{Event, LogReporter, Reporter, Reporters},
+    store,
+    vfs::{Metadata, RealFs, Vfs},
+};
+
+/// What planning saw of a single path. Fields are `None` when planning never
+/// asked, or when the path did not exist.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct Fingerprint {
+    metadata: Option<Metadata>,
+    entries: Option<Vec<PathBuf>>,
+    hash: Option<String>,
+}
+
+/// Everything needed to replay the planning of a build on another machine.
+/// File contents are not included, only their fingerprints.
+#[derive(Debug, Serialize, Deserialize)]
+struct Bundle {
+    mk_version: String,
+    os: String,
+    target: String,
+    #[serde(default)]
+    profile: Option<String>,
+    mkfile: String,
+    /// The update state, in the same format as the state file.
+    state: String,
+    fingerprints: BTreeMap<PathBuf, Fingerprint>,
+    /// Targets that would be made, with the reason, in order.
+    plan: Vec<(String, String)>,
+}
+
+/// Wraps the real filesystem and remembers everything that was looked at.
+#[derive(Default)]
+struct RecordingFs {
+    seen: Mutex<BTreeMap<PathBuf, Fingerprint>>,
+}
+
+impl RecordingFs {
+    fn record(&self, path: &Path, update: impl FnOnce(&mut Fingerprint)) {
+        let mut seen = self.seen.lock().unwrap();
+        update(seen.entry(path.to_path_buf()).or_default());
+    }
+}
+
+impl Vfs for RecordingFs {
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let metadata = RealFs.metadata(path);
+        self.record(path, |f| f.metadata = metadata.as_ref().ok().copied());
+        metadata
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let entries = RealFs.read_dir(path)?;
+        self.record(path, |f| f.entries = Some(entries.clone()));
+        Ok(entries)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        RealFs.read(path)
+    }
+
+    fn write(&self, _path: &Path, _contents: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn rename(&self, _from: &Path, _to: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn remove_all(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn create_dir_all(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn available_space(&self, path: &Path) -> io::Result<u64> {
+        RealFs.available_space(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        RealFs.canonicalize(path)
+    }
+
+    fn file_hash(&self, path: &Path) -> io::Result<String> {
+        let hash = RealFs.file_hash(path)?;
+        self.record(path, |f| f.hash = Some(hash.clone()));
+        Ok(hash)
+    }
+}
+
+/// A read-only filesystem that answers from recorded fingerprints.
+struct SnapshotFs {
+    fingerprints: BTreeMap<PathBuf, Fingerprint>,
+}
+
+impl SnapshotFs {
+    fn get(&self, path: &Path) -> Option<&Fingerprint> {
+        self.fingerprints.get(path)
+    }
+}
+
+fn not_recorded(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("'{}' is not in the bundle", path.display()),
+    )
+}
+
+impl Vfs for SnapshotFs {
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.get(path)
+            .and_then(|f| f.metadata)
+            .ok_or_else(|| not_recorded(path))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        self.get(path)
+            .and_then(|f| f.entries.clone())
+            .ok_or_else(|| not_recorded(path))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("contents of '{}' are not in the bundle", path.display()),
+        ))
+    }
+
+    fn write(&self, _path: &Path, _contents: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn rename(&self, _from: &Path, _to: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn remove_all(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn create_dir_all(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn available_space(&self, _path: &Path) -> io::Result<u64> {
+        Ok(u64::MAX)
+    }
+
+    fn file_hash(&self, path: &Path) -> io::Result<String> {
+        self.get(path)
+            .and_then(|f| f.hash.clone())
+            .ok_or_else(|| not_recorded(path))
+    }
+}
+
+/// Collects the targets a dry run decides to make.
+#[derive(Default)]
+struct PlanRecorder {
+    plan: Mutex<Vec<(String, String)>>,
+}
+
+impl Reporter for PlanRecorder {
+    fn report(&self, event: &Event) {
+        if let Event::Outdated(target, reason) = event {
+            let mut plan = self.plan.lock().unwrap();
+            plan.push((target.to_string(), reason.to_string()));
+        }
+    }
+}
+
+/// Works out what making the target would do, without running anything.
+/// Returns the plan.
+fn plan(
+    mkfile: &MkFile,
+    target: &str,
+    state: &UpdateState,
+    vfs: Box<dyn Vfs>,
+    explain: bool,
+) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let recorder = Arc::new(PlanRecorder::default());
+    let options = MakeOptions {
+        dry_run: true,
+        reporter: Box::new(Reporters(vec![
+            Box::new(recorder.clone()),
+            Box::new(LogReporter { explain }),
+        ])),
+        vfs,
+        ..MakeOptions::default()
+    };
+    let target = mkfile.resolve(target);
+    make(mkfile, &target, &mut state.clone(), &options)?;
+    let plan = recorder.plan.lock().unwrap().clone();
+    Ok(plan)
+}
+
+/// Records how the target would be planned on this machine into a bundle.
+pub fn create(
+    mkfile_path: &Path,
+    state_path: &Path,
+    target: &str,
+    profile: Option<&str>,
+    output: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let text = std::fs::read_to_string(mkfile_path)?;
+    let parse_options = ParseOptions {
+        profile: profile.map(String::from),
+        ..ParseOptions::default()
+    };
+    let mkfile = MkFile::parse_with(&text, &parse_options)?;
+    let state = store::open(state_path)?.load();
+
+    let recording = Arc::new(RecordingFs::default());
+    let plan = plan(&mkfile, target, &state, Box::new(recording.clone()), false)?;
+
+    let bundle = Bundle {
+        mk_version: env!("CARGO_PKG_VERSION").to_string(),
+        os: std::env::consts::OS.to_string(),
+        target: target.to_string(),
+        profile: parse_options.profile,
+        mkfile: text,
+        state: serde_sexpr::to_string(&state)?,
+        fingerprints: recording.seen.lock().unwrap().clone(),
+        plan,
+    };
+    std::fs::write(output, serde_json::to_string_pretty(&bundle)?)?;
+    info!("Wrote reproduction bundle to '{}'", output.display());
+    Ok(())
+}
+
+/// Replays the planning recorded in a bundle, explaining every decision and
+/// pointing out where it differs from what happened on the original machine.
+pub fn replay(bundle_path: &Path) -> Result<(), Box<dyn Error>> {
+    let bundle: Bundle = serde_json::from_str(&std::fs::read_to_string(bundle_path)?)?;
+    info!(
+        "Replaying '{}' as planned by mk {} on {}",
+        bundle.target, bundle.mk_version, bundle.os
+    );
+    if bundle.mk_version != env!("CARGO_PKG_VERSION") {
+        warn!(
+            "This is mk {}, planning may differ",
+            env!("CARGO_PKG_VERSION")
+        );
+    }
+
+    let parse_options = ParseOptions {
+        profile: bundle.profile,
+        ..ParseOptions::default()
+    };
+    let mkfile = MkFile::parse_with(&bundle.mkfile, &parse_options)?;
+    let state: UpdateState = serde_sexpr::from_str(&bundle.state)?;
+    let vfs = SnapshotFs {
+        fingerprints: bundle.fingerprints,
+    };
+    let plan = plan(&mkfile, &bundle.target, &state, Box::new(vfs), true)?;
+
+    if plan == bundle.plan {
+        info!("Replayed plan matches the recorded one");
+    } else {
+        warn!("Replayed plan differs from the recorded one:");
+        for (target, reason) in &bundle.plan {
+            warn!("  recorded: {} ({})", target, reason);
+        }
+        for (target, reason) in &plan {
+            warn!("  replayed: {} ({})", target, reason);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::vfs::Vfs;
+
+/// Finds the files a source file pulls in, so they can count as
+/// dependencies without being listed in the mkfile.
+pub trait Scanner: Send + Sync {
+    /// Returns the paths of the files the source at `path` refers to. They
+    /// don't need to exist.
+    fn scan(&self, path: &Path, contents: &str) -> Vec<PathBuf>;
+}
+
+/// Finds `#include "file"` lines in C and C++ sources, relative to the
+/// including file. System headers (`#include <file>`) are left out.
+pub struct CScanner;
+
+impl Scanner for CScanner {
+    fn scan(&self, path: &Path, contents: &str) -> Vec<PathBuf> {
+        lazy_static! {
+            static ref INCLUDE_RE: Regex =
+                Regex::new(r#"(?m)^\s*#\s*include\s*"([^"]+)""#).unwrap();
+        }
+        let dir = path.parent().unwrap_or(Path::new(""));
+        INCLUDE_RE
+            .captures_iter(contents)
+            .map(|cap| dir.join(&cap[1]))
+            .collect()
+    }
+}
+
+/// Finds relative imports (`from .module import name`) in Python sources.
+pub struct PythonScanner;
+
+impl Scanner for PythonScanner {
+    fn scan(&self, path: &Path, contents: &str) -> Vec<PathBuf> {
+        lazy_static! {
+            static ref IMPORT_RE: Regex =
+                Regex::new(r"(?m)^\s*from\s+\.([A-Za-z_][A-Za-z0-9_.]*)\s+import\b").unwrap();
+        }
+        let dir = path.parent().unwrap_or(Path::new(""));
+        IMPORT_RE
+            .captures_iter(contents)
+            .map(|cap| dir.join(format!("{}.py", cap[1].replace('.', "/"))))
+            .collect()
+    }
+}
+
+/// Returns the built-in scanner with the given name, as used by the
+/// `scan:` rule option.
+pub fn scanner(name: &str) -> Option<&'static dyn Scanner> {
+    match name {
+        "c" => Some(&CScanner),
+        "python" => Some(&PythonScanner),
+        _ => None,
+    }
+}
+
+/// Scans the sources, and everything they refer to in turn, returning the
+/// existing files found along the way. The sources themselves are not
+/// included.
+pub fn scan_all(scanner: &dyn Scanner, vfs: &dyn Vfs, sources: &[PathBuf]) -> Vec<PathBuf> {
+    let mut found = BTreeSet::new();
+    let mut pending = sources.to_vec();
+    while let Some(path) = pending.pop() {
+        let Ok(contents) = vfs.read(&path) else {
+            continue;
+        };
+        let contents = String::from_utf8_lossy(&contents);
+        for reference in scanner.scan(&path, &contents) {
+            if !sources.contains(&reference)
+                && vfs.exists(&reference)
+                && found.insert(reference.clone())
+            {
+                pending.push(reference);
+            }
+        }
+    }
+    found.into_iter().collect()
+}
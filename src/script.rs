@@ -0,0 +1,116 @@
+use crate::error::MkError;
+
+/// What a `script` block computed.
+#[derive(Debug, Default)]
+pub(crate) struct Output {
+    /// The variables the script set, in the order it set them.
+    pub variables: Vec<(String, String)>,
+    /// The rules the script emitted, written as in an mkfile.
+    pub rules: String,
+}
+
+/// Returns the expanded value of a variable of the mkfile, if it is set.
+pub(crate) type Lookup = dyn Fn(&str) -> Result<Option<String>, MkError>;
+
+/// Runs the source of a `script` block, written in
+/// [Rhai](https://rhai.rs). Besides the language's own arrays, with
+/// `map` and `filter`, scripts have these functions:
+///
+/// - `get(name)` returns the value of a variable, or `()` if it isn't set.
+/// - `set(name, value)` sets a variable, as `NAME := value` would. Arrays
+///   are joined with spaces.
+/// - `glob(pattern)` returns the sorted paths that match a pattern.
+/// - `rule(target, dependencies, commands)` adds a rule, where commands can
+///   also be option lines such as `"tags: build"`. `rule(target,
+///   dependencies)` adds one without commands.
+///
+/// `print` logs its text.
+#[cfg(feature = "scripting")]
+pub(crate) fn run(source: &str, lookup: Box<Lookup>) -> Result<Output, MkError> {
+    use std::{cell::RefCell, rc::Rc};
+
+    use log::info;
+    use rhai::{Array, Dynamic, Engine, EvalAltResult};
+
+    fn words(array: &Array) -> Vec<String> {
+        array.iter().map(|item| item.to_string()).collect()
+    }
+
+    let output = Rc::new(RefCell::new(Output::default()));
+    let mut engine = Engine::new();
+    engine.on_print(|text| info!("{text}"));
+
+    let set = output.clone();
+    engine.register_fn(
+        "get",
+        move |name: &str| -> Result<Dynamic, Box<EvalAltResult>> {
+            // Variables the script set are seen before those of the mkfile
+            let assigned = set
+                .borrow()
+                .variables
+                .iter()
+                .rev()
+                .find_map(|(set_name, value)| (set_name == name).then(|| value.clone()));
+            let value = match assigned {
+                Some(value) => Some(value),
+                None => lookup(name).map_err(|err| err.to_string())?,
+            };
+            Ok(value.map_or(Dynamic::UNIT, Dynamic::from))
+        },
+    );
+    let set = output.clone();
+    engine.register_fn("set", move |name: &str, value: Dynamic| {
+        let value = if value.is_array() {
+            words(&value.cast::<Array>()).join(" ")
+        } else {
+            value.to_string()
+        };
+        set.borrow_mut().variables.push((name.to_string(), value));
+    });
+    engine.register_fn(
+        "glob",
+        |pattern: &str| -> Result<Array, Box<EvalAltResult>> {
+            let matches =
+                glob::glob(pattern).map_err(|err| format!("Invalid glob '{pattern}': {err}"))?;
+            let mut paths: Vec<String> = matches
+                .flatten()
+                .map(|path| path.display().to_string())
+                .collect();
+            paths.sort_unstable();
+            Ok(paths.into_iter().map(Dynamic::from).collect())
+        },
+    );
+    let emit = output.clone();
+    let add_rule = move |target: &str, dependencies: &Array, commands: &Array| {
+        let rules = &mut emit.borrow_mut().rules;
+        rules.push_str(&format!("{target}: {}\n", words(dependencies).join(" ")));
+        for command in words(commands) {
+            for line in command.lines().filter(|line| !line.trim().is_empty()) {
+                rules.push_str(&format!("    {line}\n"));
+            }
+        }
+    };
+    let add_bare_rule = add_rule.clone();
+    engine.register_fn(
+        "rule",
+        move |target: &str, dependencies: Array, commands: Array| {
+            add_rule(target, &dependencies, &commands)
+        },
+    );
+    engine.register_fn("rule", move |target: &str, dependencies: Array| {
+        add_bare_rule(target, &dependencies, &Array::new())
+    });
+
+    engine
+        .run(source)
+        .map_err(|err| MkError::Parse(format!("In script: {err}")))?;
+    drop(engine);
+    Ok(output.take())
+}
+
+#[cfg(not(feature = "scripting"))]
+pub(crate) fn run(_source: &str, _lookup: Box<Lookup>) -> Result<Output, MkError> {
+    Err(MkError::Parse(
+        "The mkfile has a script block, but mk was built without the scripting feature".to_string(),
+    ))
+}
@@ -0,0 +1,204 @@
+use std::{
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use log::{error, info};
+use tiny_http::{Header, Response, Server};
+
+use crate::{
+    error::MkError,
+    making::{make, MakeOptions, UpdateState},
+    mkfile::{MkFile, Target},
+    report::{Event, Reporter},
+    store::StateStore,
+};
+
+/// How often sources are checked for changes while serving.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Asks the server for the build version, and reloads the page when it
+/// changes. Added to every HTML page served.
+const RELOAD_SCRIPT: &str = "<script>
+(function () {
+    let version = null;
+    setInterval(async function () {
+        try {
+            const current = await (await fetch('/__mk/version')).text();
+            if (version !== null && current !== version) location.reload();
+            version = current;
+        } catch (e) {}
+    }, 500);
+})();
+</script>
+";
+
+/// What a service target serves, from its `serve:` option, written as
+/// `dist/ --port 8080`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServeConfig {
+    pub dir: PathBuf,
+    pub port: u16,
+}
+
+impl FromStr for ServeConfig {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut words = text.split_whitespace();
+        let dir = words
+            .next()
+            .ok_or_else(|| "serve needs a folder".to_string())?;
+        let mut port = 8080;
+        while let Some(word) = words.next() {
+            match word {
+                "--port" => {
+                    port = words
+                        .next()
+                        .and_then(|port| port.parse().ok())
+                        .ok_or_else(|| "--port needs a port number".to_string())?;
+                }
+                _ => return Err(format!("Unknown serve argument '{word}'")),
+            }
+        }
+        Ok(ServeConfig {
+            dir: PathBuf::from(dir),
+            port,
+        })
+    }
+}
+
+/// Guesses the content type of a file from its extension.
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css",
+        Some("js" | "mjs") => "text/javascript",
+        Some("json") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("wasm") => "application/wasm",
+        Some("txt" | "md") => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Maps a request URL to a file in the served folder. Paths that would
+/// leave the folder are refused.
+fn resolve(dir: &Path, url: &str) -> Option<PathBuf> {
+    let url = url.split(['?', '#']).next().unwrap_or_default();
+    let relative = Path::new(url.trim_start_matches('/'));
+    if relative
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_)))
+    {
+        return None;
+    }
+    let path = dir.join(relative);
+    if path.is_dir() {
+        Some(path.join("index.html"))
+    } else {
+        Some(path)
+    }
+}
+
+fn respond(server: &Server, dir: &Path, version: &AtomicU64) {
+    for request in server.incoming_requests() {
+        let result = if request.url() == "/__mk/version" {
+            request.respond(Response::from_string(
+                version.load(Ordering::SeqCst).to_string(),
+            ))
+        } else {
+            match resolve(dir, request.url())
+                .and_then(|path| Some((std::fs::read(&path).ok()?, path)))
+            {
+                Some((mut contents, path)) => {
+                    let content_type = content_type(&path);
+                    if content_type.starts_with("text/html") {
+                        contents.extend_from_slice(RELOAD_SCRIPT.as_bytes());
+                    }
+                    let header = Header::from_bytes("Content-Type", content_type).unwrap();
+                    request.respond(Response::from_data(contents).with_header(header))
+                }
+                None => request.respond(Response::from_string("Not found").with_status_code(404)),
+            }
+        };
+        if let Err(err) = result {
+            error!("Failed to respond: {}", err);
+        }
+    }
+}
+
+/// Passes on events about targets that actually get made, so that checking
+/// sources over and over stays quiet.
+struct WorkOnly(Box<dyn Reporter>);
+
+impl Reporter for WorkOnly {
+    fn report(&self, event: &Event) {
+        match event {
+            Event::TargetStarted(_) | Event::TargetFinished(_, false) => {}
+            _ => self.0.report(event),
+        }
+    }
+
+    fn finish(&self) {
+        self.0.finish();
+    }
+}
+
+/// Serves the folder of a service target over HTTP, remaking its
+/// dependencies whenever their sources change and reloading open pages when that changes
+/// anything. Only returns if the server can't start.
+pub fn serve(
+    mkfile: &MkFile,
+    target: &Target,
+    config: &ServeConfig,
+    state: &mut UpdateState,
+    store: &dyn StateStore,
+    options: MakeOptions,
+) -> Result<(), MkError> {
+    let options = MakeOptions {
+        reporter: Box::new(WorkOnly(options.reporter)),
+        ..options
+    };
+    let server = Server::http(("0.0.0.0", config.port))
+        .map_err(|err| MkError::Io(std::io::Error::other(err.to_string())))?;
+    let version = Arc::new(AtomicU64::new(0));
+    info!(
+        "Serving '{}' on http://localhost:{}",
+        config.dir.display(),
+        config.port
+    );
+
+    let dir = config.dir.clone();
+    let serving = version.clone();
+    thread::spawn(move || respond(&server, &dir, &serving));
+
+    // Only the dependencies are remade, since the service itself would
+    // count as made every time if it's virtual
+    loop {
+        thread::sleep(POLL_INTERVAL);
+        let mut made = false;
+        for dependency in mkfile.dependencies(target) {
+            match make(mkfile, dependency, state, &options) {
+                Ok(dependency_made) => made |= dependency_made,
+                Err(err) => error!("Failed to remake '{}': {}", dependency, err),
+            }
+        }
+        if made {
+            if let Err(err) = store.save(state) {
+                error!("Failed to save state: {}", err);
+            }
+            version.fetch_add(1, Ordering::SeqCst);
+            info!("Sources of '{}' changed, reloading pages", target);
+        }
+    }
+}
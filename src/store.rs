@@ -0,0 +1,297 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+use fs2::FileExt;
+use log::info;
+
+use crate::{
+    error::MkError,
+    making::{with_suffix, UpdateState},
+    vfs::RealFs,
+};
+
+/// Where the update state is kept between runs.
+pub trait StateStore: Send + Sync {
+    /// Loads the state, or an empty one if nothing was stored yet.
+    fn load(&self) -> UpdateState;
+
+    /// Stores the state, replacing what was stored before.
+    fn save(&self, state: &UpdateState) -> Result<(), MkError>;
+
+    /// Loads the state as it was before it was last saved, if it was saved
+    /// before.
+    fn previous(&self) -> Option<UpdateState>;
+
+    /// Replaces the stored state with an empty one.
+    fn reset(&self) -> Result<(), MkError> {
+        self.save(&UpdateState::default())
+    }
+}
+
+/// Opens the store for a state path. Paths ending in `.sqlite` or `.db` are
+/// SQLite databases, anything else is an S-expression file.
+pub fn open(path: &Path) -> Result<Box<dyn StateStore>, MkError> {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        #[cfg(feature = "sqlite")]
+        Some("sqlite" | "db") => Ok(Box::new(sqlite::SqliteStore::open(path)?)),
+        #[cfg(not(feature = "sqlite"))]
+        Some("sqlite" | "db") => Err(MkError::Io(std::io::Error::other(format!(
+            "Can't open '{}': mk was built without the sqlite feature",
+            path.display()
+        )))),
+        _ => Ok(Box::new(FileStore {
+            path: path.to_path_buf(),
+        })),
+    }
+}
+
+/// An advisory lock on a state path, held by an mk process that will save
+/// the state, so that two of them don't overwrite each other's changes.
+/// The lock is on a `.lock` file next to the state, which is removed when
+/// the lock is dropped. The lock is released when the process exits too.
+pub struct StateLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl StateLock {
+    /// Takes the lock for the state path. If another process holds it,
+    /// waits for it to be released when `wait` is set, or fails with
+    /// `MkError::Locked` otherwise.
+    pub fn acquire(path: &Path, wait: bool) -> Result<StateLock, MkError> {
+        let lock_path = with_suffix(path, ".lock");
+        loop {
+            let mut file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&lock_path)?;
+            match file.try_lock_exclusive() {
+                Ok(()) => {}
+                Err(err) if err.kind() == fs2::lock_contended_error().kind() => {
+                    let mut holder = String::new();
+                    let _ = file.read_to_string(&mut holder);
+                    let holder = holder.trim().parse().ok();
+                    if !wait {
+                        return Err(MkError::Locked {
+                            path: lock_path,
+                            holder,
+                        });
+                    }
+                    info!("Waiting for another mk to finish");
+                    file.lock_exclusive()?;
+                }
+                Err(err) => return Err(err.into()),
+            }
+            // The holder removes the file as it lets go, so a lock taken
+            // after waiting may be on a file that is gone
+            if !is_same_file(&file, &lock_path) {
+                continue;
+            }
+            // Leave the process ID for whoever finds the lock taken
+            file.set_len(0)?;
+            file.rewind()?;
+            write!(file, "{}", std::process::id())?;
+            return Ok(StateLock {
+                file,
+                path: lock_path,
+            });
+        }
+    }
+}
+
+impl Drop for StateLock {
+    fn drop(&mut self) {
+        // Removed while still locked, so that nobody takes the lock on the
+        // file in between
+        let _ = std::fs::remove_file(&self.path);
+        let _ = self.file.unlock();
+    }
+}
+
+/// Returns true if the path still names the open file.
+#[cfg(unix)]
+fn is_same_file(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (file.metadata(), std::fs::metadata(path)) {
+        (Ok(open), Ok(named)) => open.dev() == named.dev() && open.ino() == named.ino(),
+        _ => false,
+    }
+}
+
+/// Returns true if the path still names the open file. Open files can't be
+/// removed on other platforms, so it always does.
+#[cfg(not(unix))]
+fn is_same_file(_file: &File, _path: &Path) -> bool {
+    true
+}
+
+/// The state in a single S-expression file, rewritten whole on every save.
+pub struct FileStore {
+    pub path: PathBuf,
+}
+
+impl StateStore for FileStore {
+    fn load(&self) -> UpdateState {
+        UpdateState::load(&RealFs, &self.path)
+    }
+
+    fn save(&self, state: &UpdateState) -> Result<(), MkError> {
+        state.save(&RealFs, &self.path)
+    }
+
+    fn previous(&self) -> Option<UpdateState> {
+        let path = UpdateState::previous_path(&self.path);
+        path.exists().then(|| UpdateState::load(&RealFs, &path))
+    }
+
+    fn reset(&self) -> Result<(), MkError> {
+        UpdateState::reset(&RealFs, &self.path)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use std::{collections::BTreeMap, path::Path, sync::Mutex};
+
+    use log::warn;
+    use rusqlite::{params, Connection};
+
+    use super::StateStore;
+    use crate::{error::MkError, making::UpdateState};
+
+    fn sqlite_error(err: rusqlite::Error) -> MkError {
+        MkError::Io(std::io::Error::other(err.to_string()))
+    }
+
+    /// The state in an SQLite database, with a row for every entry. Saving
+    /// only writes the rows that changed, and other processes can read the
+    /// state while a build writes it. The rows from before the last save are
+    /// kept in a table of their own.
+    pub struct SqliteStore {
+        connection: Mutex<Connection>,
+        /// The rows as they were last loaded or saved.
+        stored: Mutex<BTreeMap<(String, String), String>>,
+    }
+
+    impl SqliteStore {
+        pub fn open(path: &Path) -> Result<Self, MkError> {
+            let connection = Connection::open(path).map_err(sqlite_error)?;
+            connection
+                .execute_batch(
+                    "PRAGMA journal_mode = WAL;
+                     CREATE TABLE IF NOT EXISTS state (
+                         kind TEXT NOT NULL,
+                         key TEXT NOT NULL,
+                         value TEXT NOT NULL,
+                         PRIMARY KEY (kind, key)
+                     );
+                     CREATE TABLE IF NOT EXISTS previous (
+                         kind TEXT NOT NULL,
+                         key TEXT NOT NULL,
+                         value TEXT NOT NULL,
+                         PRIMARY KEY (kind, key)
+                     );",
+                )
+                .map_err(sqlite_error)?;
+            Ok(SqliteStore {
+                connection: Mutex::new(connection),
+                stored: Mutex::new(BTreeMap::new()),
+            })
+        }
+
+        /// Returns the rows of the `state` or `previous` table.
+        fn rows(&self, table: &str) -> rusqlite::Result<BTreeMap<(String, String), String>> {
+            let connection = self.connection.lock().unwrap();
+            let mut statement =
+                connection.prepare(&format!("SELECT kind, key, value FROM {table}"))?;
+            let rows = statement
+                .query_map([], |row| Ok(((row.get(0)?, row.get(1)?), row.get(2)?)))?
+                .collect();
+            rows
+        }
+    }
+
+    impl StateStore for SqliteStore {
+        fn load(&self) -> UpdateState {
+            let loaded = self
+                .rows("state")
+                .map_err(|err| err.to_string())
+                .and_then(|rows| {
+                    let state = UpdateState::from_rows(rows.clone()).map_err(|err| err.to_string());
+                    *self.stored.lock().unwrap() = rows;
+                    state
+                });
+            match loaded {
+                Ok(state) => state,
+                Err(err) => {
+                    warn!("State database is unreadable: {}", err);
+                    warn!("Starting from an empty state, so everything will be rebuilt");
+                    UpdateState::default()
+                }
+            }
+        }
+
+        fn save(&self, state: &UpdateState) -> Result<(), MkError> {
+            let rows = state.to_rows();
+            let mut stored = self.stored.lock().unwrap();
+            let mut connection = self.connection.lock().unwrap();
+            let transaction = connection.transaction().map_err(sqlite_error)?;
+            transaction
+                .execute_batch(
+                    "DELETE FROM previous;
+                     INSERT INTO previous SELECT kind, key, value FROM state;",
+                )
+                .map_err(sqlite_error)?;
+            {
+                let mut upsert = transaction
+                    .prepare(
+                        "INSERT INTO state (kind, key, value) VALUES (?1, ?2, ?3)
+                         ON CONFLICT (kind, key) DO UPDATE SET value = excluded.value",
+                    )
+                    .map_err(sqlite_error)?;
+                for (row, value) in &rows {
+                    if stored.get(row) != Some(value) {
+                        upsert
+                            .execute(params![row.0, row.1, value])
+                            .map_err(sqlite_error)?;
+                    }
+                }
+                let mut delete = transaction
+                    .prepare("DELETE FROM state WHERE kind = ?1 AND key = ?2")
+                    .map_err(sqlite_error)?;
+                for row in stored.keys() {
+                    if !rows.contains_key(row) {
+                        delete
+                            .execute(params![row.0, row.1])
+                            .map_err(sqlite_error)?;
+                    }
+                }
+            }
+            transaction.commit().map_err(sqlite_error)?;
+            *stored = rows;
+            Ok(())
+        }
+
+        fn previous(&self) -> Option<UpdateState> {
+            // Nothing was kept if the state was saved at most once
+            let loaded = self
+                .rows("previous")
+                .map_err(|err| err.to_string())
+                .and_then(|rows| {
+                    (!rows.is_empty())
+                        .then(|| UpdateState::from_rows(rows).map_err(|err| err.to_string()))
+                        .transpose()
+                });
+            loaded.unwrap_or_else(|err| {
+                warn!("Previous state is unreadable: {}", err);
+                None
+            })
+        }
+    }
+}
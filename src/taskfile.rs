@@ -0,0 +1,215 @@
+use std::collections::BTreeMap;
+
+use lazy_static::lazy_static;
+use log::warn;
+use regex::Regex;
+use serde::{de::IgnoredAny, Deserialize};
+use serde_yaml::Value;
+
+use crate::error::MkError;
+
+#[derive(Deserialize)]
+struct Taskfile {
+    #[serde(default)]
+    vars: BTreeMap<String, Value>,
+    #[serde(default)]
+    env: BTreeMap<String, Value>,
+    #[serde(default)]
+    tasks: BTreeMap<String, TaskDefinition>,
+}
+
+/// A task, which can also be written as just its commands.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TaskDefinition {
+    Command(String),
+    Commands(Vec<Step>),
+    Task(Task),
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Task {
+    desc: Option<String>,
+    deps: Vec<Call>,
+    cmds: Vec<Step>,
+    sources: Vec<String>,
+    generates: Vec<String>,
+    dir: Option<String>,
+    env: BTreeMap<String, Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Call {
+    Name(String),
+    Task { task: String },
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Step {
+    Line(String),
+    Shell { cmd: String },
+    Call { task: String },
+    Other(IgnoredAny),
+}
+
+/// Converts a go-task `Taskfile.yml` into the text of an mkfile, so that
+/// the tasks can be moved over one at a time.
+///
+/// Every task becomes a virtual target with the same name, with `:` in
+/// namespaced names replaced by `-`. Its `deps` become dependencies, as do
+/// tasks that its `cmds` call, which run before its commands instead of in
+/// between them. Its `sources` become dependencies too, through
+/// `$(wildcard ...)` if they are globs. A task that `generates` files gets
+/// a rule for the first of them, with the others as its `outputs:`, which
+/// its virtual target depends on. `vars` become variables, `{{.NAME}}`
+/// becomes `$(NAME)`, and `env` becomes the `env:` of every rule.
+pub fn convert(text: &str) -> Result<String, MkError> {
+    let taskfile: Taskfile = serde_yaml::from_str(text)
+        .map_err(|err| MkError::Parse(format!("Invalid Taskfile: {err}")))?;
+
+    let mut mkfile = String::from("# Converted from a Taskfile.\n");
+    if !taskfile.vars.is_empty() {
+        mkfile.push('\n');
+    }
+    for (name, value) in &taskfile.vars {
+        match value_text(value) {
+            Some(value) => mkfile.push_str(&format!("{name} = {value}\n")),
+            None => warn!("Variable '{name}' can't be converted, leaving it out"),
+        }
+    }
+
+    for (name, definition) in taskfile.tasks {
+        let task = match definition {
+            TaskDefinition::Command(command) => Task {
+                cmds: vec![Step::Line(command)],
+                ..Task::default()
+            },
+            TaskDefinition::Commands(cmds) => Task {
+                cmds,
+                ..Task::default()
+            },
+            TaskDefinition::Task(task) => task,
+        };
+        let name = name.as_str();
+
+        let mut dependencies: Vec<String> = task
+            .deps
+            .iter()
+            .map(|call| format!("${}", target_name(call.name())))
+            .collect();
+        let mut commands = Vec::new();
+        for step in &task.cmds {
+            match step {
+                Step::Line(line) | Step::Shell { cmd: line } => {
+                    let line = templates(line, name);
+                    commands.extend(line.lines().map(|line| match &task.dir {
+                        Some(dir) => format!("cd {} && {line}", templates(dir, name)),
+                        None => line.to_string(),
+                    }));
+                }
+                Step::Call { task } => dependencies.push(format!("${}", target_name(task))),
+                Step::Other(_) => {
+                    warn!("A command of task '{name}' can't be converted, leaving it out")
+                }
+            }
+        }
+        let sources = task.sources.iter().map(|source| {
+            let source = templates(source, name);
+            if source.contains(['*', '?', '[']) {
+                format!("$(wildcard {source})")
+            } else {
+                source
+            }
+        });
+
+        let env: Vec<String> = taskfile
+            .env
+            .iter()
+            .chain(&task.env)
+            .filter_map(|(name, value)| Some(format!("{name}={}", value_text(value)?)))
+            .collect();
+        let mut body: Vec<String> = Vec::new();
+        if !env.is_empty() {
+            body.push(format!("env: {}", env.join(" ")));
+        }
+
+        mkfile.push('\n');
+        for line in task.desc.iter().flat_map(|desc| desc.lines()) {
+            mkfile.push_str(&format!("# {line}\n"));
+        }
+        let target = format!("${}", target_name(name));
+        let generates: Vec<String> = task
+            .generates
+            .iter()
+            .map(|path| templates(path, name))
+            .collect();
+        match generates.split_first() {
+            Some((first, others)) => {
+                if generates.iter().any(|path| path.contains('*')) {
+                    warn!("Task '{name}' generates files by pattern, which mk takes literally");
+                }
+                mkfile.push_str(&format!("{target}: {first}\n\n"));
+                dependencies.extend(sources);
+                mkfile.push_str(&format!("{first}: {}\n", dependencies.join(" ")));
+                if !others.is_empty() {
+                    body.insert(0, format!("outputs: {}", others.join(" ")));
+                }
+            }
+            None => {
+                dependencies.extend(sources);
+                let line = format!("{target}: {}", dependencies.join(" "));
+                mkfile.push_str(&format!("{}\n", line.trim_end()));
+            }
+        }
+        for line in body.iter().chain(&commands) {
+            mkfile.push_str(&format!("    {line}\n"));
+        }
+    }
+    Ok(mkfile)
+}
+
+impl Call {
+    fn name(&self) -> &str {
+        match self {
+            Call::Name(name) | Call::Task { task: name } => name,
+        }
+    }
+}
+
+/// Names a task's target, which can't have `:` in it.
+fn target_name(task: &str) -> String {
+    task.replace(':', "-")
+}
+
+/// Writes a variable's value as in an mkfile. Values computed with `sh:`
+/// become `$(shell ...)`.
+fn value_text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(templates(text, "")),
+        Value::Number(number) => Some(number.to_string()),
+        Value::Bool(flag) => Some(flag.to_string()),
+        Value::Mapping(mapping) => match mapping.get("sh")? {
+            Value::String(command) => Some(format!("$(shell {})", templates(command, ""))),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Replaces `{{.NAME}}` with `$(NAME)`, and `{{.TASK}}` with the name of
+/// the task.
+fn templates(text: &str, task: &str) -> String {
+    lazy_static! {
+        static ref TEMPLATE_RE: Regex =
+            Regex::new(r"\{\{\s*\.([A-Za-z_][A-Za-z0-9_]*)\s*\}\}").unwrap();
+    }
+    TEMPLATE_RE
+        .replace_all(text, |cap: &regex::Captures| match &cap[1] {
+            "TASK" => task.to_string(),
+            name => format!("$({name})"),
+        })
+        .into_owned()
+}
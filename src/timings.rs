@@ -0,0 +1,66 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::info;
+
+use crate::{
+    mkfile::Target,
+    report::{Event, Reporter},
+};
+
+#[derive(Default)]
+struct TargetTimings {
+    commands: Vec<(String, Duration)>,
+    running: Option<Instant>,
+}
+
+impl TargetTimings {
+    fn total(&self) -> Duration {
+        self.commands.iter().map(|(_, duration)| *duration).sum()
+    }
+}
+
+/// Measures how long each command takes and prints a summary of the slowest
+/// targets once the build is over.
+#[derive(Default)]
+pub struct TimingsReporter {
+    targets: Mutex<HashMap<Target, TargetTimings>>,
+}
+
+impl Reporter for TimingsReporter {
+    fn report(&self, event: &Event) {
+        let mut targets = self.targets.lock().unwrap();
+        match event {
+            Event::CommandStarted(target, _) => {
+                let timings = targets.entry((*target).clone()).or_default();
+                timings.running = Some(Instant::now());
+            }
+            Event::CommandFinished(target, command) => {
+                let timings = targets.entry((*target).clone()).or_default();
+                if let Some(started) = timings.running.take() {
+                    timings
+                        .commands
+                        .push((command.to_string(), started.elapsed()));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn finish(&self) {
+        let targets = self.targets.lock().unwrap();
+        let mut sorted: Vec<_> = targets.iter().collect();
+        sorted.sort_by_key(|(_, timings)| std::cmp::Reverse(timings.total()));
+
+        info!("Timings (slowest first):");
+        for (target, timings) in sorted {
+            info!("{:>9.2}s  {}", timings.total().as_secs_f64(), target);
+            for (command, duration) in &timings.commands {
+                info!("{:>13.2}s  {}", duration.as_secs_f64(), command);
+            }
+        }
+    }
+}
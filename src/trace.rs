@@ -0,0 +1,115 @@
+use std::{collections::HashMap, path::PathBuf, sync::Mutex, thread::ThreadId, time::Instant};
+
+use log::error;
+use serde::Serialize;
+
+use crate::report::{Event, Reporter};
+
+/// One entry of Chrome's trace event format.
+#[derive(Serialize)]
+struct TraceEvent {
+    name: String,
+    cat: &'static str,
+    ph: &'static str,
+    ts: u128,
+    pid: u32,
+    tid: usize,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    args: HashMap<&'static str, String>,
+}
+
+#[derive(Default)]
+struct TraceState {
+    events: Vec<TraceEvent>,
+    threads: HashMap<ThreadId, usize>,
+}
+
+/// Records when targets and commands start and stop, and writes them as a
+/// Chrome trace that can be opened in `chrome://tracing` or Perfetto.
+pub struct TraceReporter {
+    path: PathBuf,
+    start: Instant,
+    state: Mutex<TraceState>,
+}
+
+impl TraceReporter {
+    pub fn new(path: PathBuf) -> Self {
+        TraceReporter {
+            path,
+            start: Instant::now(),
+            state: Mutex::new(TraceState::default()),
+        }
+    }
+
+    fn record(
+        &self,
+        name: String,
+        cat: &'static str,
+        ph: &'static str,
+        args: &[(&'static str, String)],
+    ) {
+        let ts = self.start.elapsed().as_micros();
+        let mut state = self.state.lock().unwrap();
+        // Give every thread a small, stable id so parallel work shows up as
+        // separate tracks
+        let next = state.threads.len();
+        let tid = *state
+            .threads
+            .entry(std::thread::current().id())
+            .or_insert(next);
+        state.events.push(TraceEvent {
+            name,
+            cat,
+            ph,
+            ts,
+            pid: std::process::id(),
+            tid,
+            args: args.iter().cloned().collect(),
+        });
+    }
+}
+
+impl Reporter for TraceReporter {
+    fn report(&self, event: &Event) {
+        match event {
+            Event::TargetStarted(target) => self.record(target.to_string(), "target", "B", &[]),
+            Event::TargetFinished(target, made) => self.record(
+                target.to_string(),
+                "target",
+                "E",
+                &[("made", made.to_string())],
+            ),
+            Event::TargetFailed(target, err) => self.record(
+                target.to_string(),
+                "target",
+                "E",
+                &[("error", err.to_string())],
+            ),
+            Event::CommandStarted(_, command) => {
+                self.record(command.to_string(), "command", "B", &[])
+            }
+            Event::CommandFinished(_, command) => {
+                self.record(command.to_string(), "command", "E", &[])
+            }
+            Event::Skipped(..)
+            | Event::Outdated(..)
+            | Event::Unchanged(_)
+            | Event::Restored(_)
+            | Event::Retrying(..) => {}
+        }
+    }
+
+    fn finish(&self) {
+        let state = self.state.lock().unwrap();
+        let written = serde_json::to_string(&state.events)
+            .map_err(|err| err.to_string())
+            .and_then(|json| std::fs::write(&self.path, json).map_err(|err| err.to_string()));
+        if let Err(err) = written {
+            error!(
+                "Failed to write trace file '{}': {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+}
@@ -0,0 +1,586 @@
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use log::{Level, Record};
+use ratatui::{
+    crossterm::event::{self, Event as TermEvent, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, List, ListItem, ListState, Paragraph},
+    DefaultTerminal, Frame,
+};
+use regex::Regex;
+
+use crate::{
+    executor::{self, CommandExecutor, Invocation, Redirect},
+    interrupt,
+    mkfile::{MkFile, Target},
+    report::{Event, Reporter},
+    warnings,
+};
+
+/// How often the screen is drawn again.
+const FRAME: Duration = Duration::from_millis(100);
+/// Shown in turn next to targets whose commands are running.
+const SPINNER: &[&str] = &["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
+/// How many lines of each target's log are kept.
+const LOG_LINES: usize = 10_000;
+/// How many lines a page up or down scrolls.
+const PAGE: usize = 10;
+/// The signal that keys which stop the build stand for.
+const SIGINT: i32 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Status {
+    Waiting,
+    Running,
+    Made,
+    UpToDate,
+    Skipped,
+    Failed(String),
+}
+
+/// A target, as a line in the tree.
+struct Row {
+    name: String,
+    depth: usize,
+    status: Status,
+    /// When its commands started running.
+    started: Option<Instant>,
+    /// How long it took to make, once it is made.
+    took: Option<Duration>,
+    log: Vec<String>,
+}
+
+impl Row {
+    fn log(&mut self, line: String) {
+        if self.log.len() == LOG_LINES {
+            self.log.remove(0);
+        }
+        self.log.push(line);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Focus {
+    Targets,
+    Failures,
+}
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    Nothing,
+    /// Stop the build, as an interrupt would.
+    Interrupt,
+}
+
+struct Model {
+    rows: Vec<Row>,
+    by_name: HashMap<String, usize>,
+    selected: usize,
+    /// How many lines the log is scrolled up from its end, where it
+    /// follows new lines.
+    scroll: usize,
+    focus: Focus,
+    /// The rows of the targets that failed, in the order they failed.
+    failures: Vec<usize>,
+    failure_selected: usize,
+    /// mk's own log lines, held until the terminal is given back.
+    messages: Vec<(Level, String, String)>,
+    /// The build is over, and what it left is shown until the user leaves.
+    over: bool,
+    leave: bool,
+    /// How many times the screen was drawn, which turns the spinners.
+    frames: usize,
+}
+
+impl Model {
+    /// Lays out the targets that making the goal reaches as a tree, each
+    /// under the first target it is reached from.
+    fn new(mkfile: &MkFile, goal: &Target) -> Self {
+        let mut model = Model {
+            rows: Vec::new(),
+            by_name: HashMap::new(),
+            selected: 0,
+            scroll: 0,
+            focus: Focus::Targets,
+            failures: Vec::new(),
+            failure_selected: 0,
+            messages: Vec::new(),
+            over: false,
+            leave: false,
+            frames: 0,
+        };
+        let mut pending = vec![(goal.clone(), 0)];
+        while let Some((target, depth)) = pending.pop() {
+            let name = target.to_string();
+            if model.by_name.contains_key(&name) {
+                continue;
+            }
+            model.add(name, depth);
+            let next: Vec<&Target> = if mkfile.has_target(&target) {
+                mkfile.dependencies(&target).iter().collect()
+            } else {
+                mkfile.producer(&target).into_iter().collect()
+            };
+            pending.extend(next.into_iter().rev().map(|next| (next.clone(), depth + 1)));
+        }
+        model
+    }
+
+    fn add(&mut self, name: String, depth: usize) -> usize {
+        self.by_name.insert(name.clone(), self.rows.len());
+        self.rows.push(Row {
+            name,
+            depth,
+            status: Status::Waiting,
+            started: None,
+            took: None,
+            log: Vec::new(),
+        });
+        self.rows.len() - 1
+    }
+
+    /// Returns the row of the target, added at the end if it wasn't known
+    /// to be reached.
+    fn index(&mut self, name: &str) -> usize {
+        match self.by_name.get(name) {
+            Some(&index) => index,
+            None => self.add(name.to_string(), 0),
+        }
+    }
+
+    fn row(&mut self, target: &Target) -> &mut Row {
+        let index = self.index(&target.to_string());
+        &mut self.rows[index]
+    }
+
+    fn key(&mut self, code: KeyCode, modifiers: KeyModifiers) -> Action {
+        let stop = matches!(code, KeyCode::Char('q') | KeyCode::Esc)
+            || (code == KeyCode::Char('c') && modifiers.contains(KeyModifiers::CONTROL));
+        if stop {
+            if self.over {
+                self.leave = true;
+                return Action::Nothing;
+            }
+            return Action::Interrupt;
+        }
+        let (selected, count) = match self.focus {
+            Focus::Targets => (&mut self.selected, self.rows.len()),
+            Focus::Failures => (&mut self.failure_selected, self.failures.len()),
+        };
+        match code {
+            KeyCode::Up | KeyCode::Char('k') => {
+                *selected = selected.saturating_sub(1);
+                self.scroll = 0;
+            }
+            KeyCode::Down | KeyCode::Char('j') => {
+                *selected = (*selected + 1).min(count.saturating_sub(1));
+                self.scroll = 0;
+            }
+            KeyCode::PageUp => self.scroll += PAGE,
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(PAGE),
+            KeyCode::Home => self.scroll = LOG_LINES,
+            KeyCode::End => self.scroll = 0,
+            KeyCode::Tab if !self.failures.is_empty() => {
+                self.focus = match self.focus {
+                    Focus::Targets => Focus::Failures,
+                    Focus::Failures => Focus::Targets,
+                };
+            }
+            KeyCode::Enter if self.focus == Focus::Failures => {
+                if let Some(&index) = self.failures.get(self.failure_selected) {
+                    self.selected = index;
+                    self.scroll = 0;
+                    self.focus = Focus::Targets;
+                }
+            }
+            _ => {}
+        }
+        Action::Nothing
+    }
+}
+
+/// Removes the escape sequences that color output, which would garble the
+/// screen.
+fn strip_escapes(line: &str) -> String {
+    lazy_static! {
+        static ref ESCAPE_RE: Regex = Regex::new(r"\x1b\[[0-9;?]*[ -/]*[@-~]").unwrap();
+    }
+    ESCAPE_RE.replace_all(line, "").into_owned()
+}
+
+fn draw(frame: &mut Frame, model: &Model) {
+    let failures_height = if model.failures.is_empty() {
+        0
+    } else {
+        (model.failures.len() as u16 + 2).min(8)
+    };
+    let [main, failures, status] = Layout::vertical([
+        Constraint::Min(3),
+        Constraint::Length(failures_height),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+    let [tree, log] =
+        Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(main);
+    let border = |focus: Focus| {
+        if model.focus == focus {
+            Style::new().fg(Color::Cyan)
+        } else {
+            Style::new()
+        }
+    };
+    let highlight = Style::new().add_modifier(Modifier::REVERSED);
+
+    let items: Vec<ListItem> = model
+        .rows
+        .iter()
+        .map(|row| {
+            let (symbol, style) = match &row.status {
+                Status::Waiting => (" ", Style::new().fg(Color::DarkGray)),
+                Status::Running => (
+                    SPINNER[model.frames % SPINNER.len()],
+                    Style::new().fg(Color::Yellow),
+                ),
+                Status::Made => ("✔", Style::new().fg(Color::Green)),
+                Status::UpToDate => ("·", Style::new()),
+                Status::Skipped => ("-", Style::new().fg(Color::DarkGray)),
+                Status::Failed(_) => ("✘", Style::new().fg(Color::Red)),
+            };
+            let took = row
+                .took
+                .or_else(|| row.started.map(|started| started.elapsed()))
+                .map(|took| format!(" {:.1}s", took.as_secs_f64()))
+                .unwrap_or_default();
+            ListItem::new(Line::from(vec![
+                Span::raw("  ".repeat(row.depth)),
+                Span::styled(symbol, style),
+                Span::raw(" "),
+                Span::styled(row.name.clone(), style),
+                Span::styled(took, Style::new().fg(Color::DarkGray)),
+            ]))
+        })
+        .collect();
+    let list = List::new(items)
+        .block(
+            Block::bordered()
+                .title(" Targets ")
+                .border_style(border(Focus::Targets)),
+        )
+        .highlight_style(highlight);
+    let mut state = ListState::default().with_selected(Some(model.selected));
+    frame.render_stateful_widget(list, tree, &mut state);
+
+    if let Some(row) = model.rows.get(model.selected) {
+        let height = log.height.saturating_sub(2) as usize;
+        let end = row
+            .log
+            .len()
+            .saturating_sub(model.scroll)
+            .max(height.min(row.log.len()));
+        let start = end.saturating_sub(height);
+        let lines: Vec<Line> = row.log[start..end]
+            .iter()
+            .map(|line| Line::raw(line.as_str()))
+            .collect();
+        let title = match &row.status {
+            Status::Failed(err) => format!(" {} failed: {} ", row.name, err),
+            _ => format!(" {} ", row.name),
+        };
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(title)),
+            log,
+        );
+    }
+
+    if !model.failures.is_empty() {
+        let items: Vec<ListItem> = model
+            .failures
+            .iter()
+            .map(|&index| {
+                let row = &model.rows[index];
+                let err = match &row.status {
+                    Status::Failed(err) => err.as_str(),
+                    _ => "",
+                };
+                ListItem::new(Line::from(vec![
+                    Span::styled(row.name.clone(), Style::new().fg(Color::Red)),
+                    Span::raw(format!(": {err}")),
+                ]))
+            })
+            .collect();
+        let list = List::new(items)
+            .block(
+                Block::bordered()
+                    .title(" Failures ")
+                    .border_style(border(Focus::Failures)),
+            )
+            .highlight_style(highlight);
+        let mut state = ListState::default()
+            .with_selected((model.focus == Focus::Failures).then_some(model.failure_selected));
+        frame.render_stateful_widget(list, failures, &mut state);
+    }
+
+    let count =
+        |wanted: fn(&Status) -> bool| model.rows.iter().filter(|row| wanted(&row.status)).count();
+    let keys = if model.over {
+        "build over, q to leave"
+    } else {
+        "↑↓ select, PgUp/PgDn scroll, Tab failures, q stop"
+    };
+    let text = format!(
+        " {} made, {} up to date, {} failed, {} running of {} · {}",
+        count(|status| *status == Status::Made),
+        count(|status| *status == Status::UpToDate),
+        count(|status| matches!(status, Status::Failed(_))),
+        count(|status| *status == Status::Running),
+        model.rows.len(),
+        keys,
+    );
+    frame.render_widget(
+        Paragraph::new(text).style(Style::new().fg(Color::DarkGray)),
+        status,
+    );
+}
+
+/// Draws the screen and answers keys until the build is over, and then
+/// until the user leaves if something failed.
+fn draw_until_done(mut terminal: DefaultTerminal, model: &Mutex<Model>, done: &AtomicBool) {
+    loop {
+        let drawn = {
+            let mut model = model.lock().unwrap();
+            model.over = done.load(Ordering::SeqCst);
+            if model.over && (model.failures.is_empty() || model.leave) {
+                return;
+            }
+            model.frames += 1;
+            terminal.draw(|frame| draw(frame, &model)).map(|_| ())
+        };
+        if drawn.is_err() {
+            return;
+        }
+        let action = match event::poll(FRAME) {
+            Ok(true) => match event::read() {
+                Ok(TermEvent::Key(key)) if key.kind == KeyEventKind::Press => {
+                    model.lock().unwrap().key(key.code, key.modifiers)
+                }
+                _ => Action::Nothing,
+            },
+            _ => Action::Nothing,
+        };
+        // Keys come instead of signals while the terminal is taken over.
+        // A second interrupt exits right away, so the terminal is given
+        // back first
+        if action == Action::Interrupt {
+            if interrupt::received().is_some() {
+                ratatui::restore();
+            }
+            interrupt::interrupted(SIGINT);
+        }
+    }
+}
+
+/// A terminal interface that shows the targets a build reaches as a tree,
+/// with how each is doing, the log of the selected one and the targets
+/// that failed. It receives events as a reporter and runs commands with an
+/// inner executor, so that their output goes to the log of their target.
+pub struct Ui {
+    model: Arc<Mutex<Model>>,
+    done: Arc<AtomicBool>,
+    drawing: Mutex<Option<JoinHandle<()>>>,
+    inner: Arc<dyn CommandExecutor>,
+}
+
+impl Ui {
+    /// Takes over the terminal to show the build of the goal. mk's own log
+    /// lines are held until the build is over and the terminal is given
+    /// back, along with the output of the targets that failed. Commands are
+    /// run with the inner executor.
+    pub fn start(
+        mkfile: &MkFile,
+        goal: &Target,
+        inner: Arc<dyn CommandExecutor>,
+    ) -> io::Result<Self> {
+        let terminal = ratatui::try_init()?;
+        let model = Arc::new(Mutex::new(Model::new(mkfile, goal)));
+        let held = model.clone();
+        warnings::redirect(Some(Box::new(move |record: &Record| {
+            held.lock().unwrap().messages.push((
+                record.level(),
+                record.target().to_string(),
+                record.args().to_string(),
+            ));
+        })));
+        let done = Arc::new(AtomicBool::new(false));
+        let drawing = {
+            let (model, done) = (model.clone(), done.clone());
+            thread::spawn(move || draw_until_done(terminal, &model, &done))
+        };
+        Ok(Ui {
+            model,
+            done,
+            drawing: Mutex::new(Some(drawing)),
+            inner,
+        })
+    }
+}
+
+impl Reporter for Ui {
+    fn report(&self, event: &Event) {
+        let mut model = self.model.lock().unwrap();
+        match event {
+            Event::TargetStarted(_) | Event::CommandFinished(..) => {}
+            Event::Skipped(target, reason) => {
+                let row = model.row(target);
+                row.status = Status::Skipped;
+                row.log(format!("Skipped: {reason}"));
+            }
+            Event::Outdated(target, reason) => {
+                model.row(target).log(format!("Out of date: {reason}"))
+            }
+            Event::Unchanged(target) => {
+                model.row(target).log("Same contents as before".to_string())
+            }
+            Event::Restored(target) => model.row(target).log("Restored from the cache".to_string()),
+            Event::CommandStarted(target, command) => {
+                let row = model.row(target);
+                if row.status != Status::Running {
+                    row.status = Status::Running;
+                    row.started = Some(Instant::now());
+                }
+                row.log(format!("$ {command}"));
+            }
+            Event::Retrying(target, attempt, err) => model
+                .row(target)
+                .log(format!("Failed, starting attempt {attempt}: {err}")),
+            Event::TargetFinished(target, made) => {
+                let row = model.row(target);
+                row.status = if *made {
+                    Status::Made
+                } else {
+                    Status::UpToDate
+                };
+                row.took = row.started.map(|started| started.elapsed());
+            }
+            Event::TargetFailed(target, err) => {
+                let index = model.index(&target.to_string());
+                let row = &mut model.rows[index];
+                row.status = Status::Failed(err.to_string());
+                row.took = row.started.map(|started| started.elapsed());
+                if !model.failures.contains(&index) {
+                    model.failures.push(index);
+                }
+            }
+        }
+    }
+
+    /// Gives the terminal back once the user is done looking, then logs the
+    /// lines that were held and writes the output of the targets that
+    /// failed.
+    fn finish(&self) {
+        let Some(drawing) = self.drawing.lock().unwrap().take() else {
+            return;
+        };
+        self.done.store(true, Ordering::SeqCst);
+        let _ = drawing.join();
+        ratatui::restore();
+        warnings::redirect(None);
+
+        let (messages, failed) = {
+            let mut model = self.model.lock().unwrap();
+            let messages = std::mem::take(&mut model.messages);
+            let failed: Vec<(String, Vec<String>)> = model
+                .failures
+                .iter()
+                .map(|&index| &model.rows[index])
+                .filter(|row| !row.log.is_empty())
+                .map(|row| (row.name.clone(), row.log.clone()))
+                .collect();
+            (messages, failed)
+        };
+        for (level, target, message) in messages {
+            log::logger().log(
+                &Record::builder()
+                    .level(level)
+                    .target(&target)
+                    .args(format_args!("{message}"))
+                    .build(),
+            );
+        }
+        let mut stderr = io::stderr().lock();
+        for (name, log) in failed {
+            let _ = writeln!(stderr, "--- {name} ---");
+            for line in log {
+                let _ = writeln!(stderr, "{line}");
+            }
+        }
+    }
+}
+
+/// Sends command output to the log of its target, or to mk's own log lines
+/// for commands that aren't run for one.
+#[derive(Clone)]
+struct TargetLog {
+    model: Arc<Mutex<Model>>,
+    target: Option<String>,
+}
+
+impl Write for TargetLog {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buffer);
+        let mut model = self.model.lock().unwrap();
+        for line in text.lines().map(strip_escapes) {
+            match &self.target {
+                Some(target) => {
+                    let index = model.index(target);
+                    model.rows[index].log(line);
+                }
+                None => model.messages.push((Level::Info, "mk".to_string(), line)),
+            }
+        }
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Ui {
+    /// Sends the output of a command to the log of its target, whose name
+    /// needn't be in front of every line, or to mk's own log lines for
+    /// commands that aren't run for one, which would draw over the
+    /// interface otherwise.
+    fn redirect(&self, invocation: &Invocation) -> Invocation {
+        let log = TargetLog {
+            model: self.model.clone(),
+            target: invocation.target.clone(),
+        };
+        Invocation {
+            prefix: None,
+            redirect: Some(Redirect::to(log.clone(), log)),
+            ..invocation.clone()
+        }
+    }
+}
+
+impl CommandExecutor for Ui {
+    fn run(&self, invocation: &Invocation) -> io::Result<executor::Status> {
+        self.inner.run(&self.redirect(invocation))
+    }
+
+    fn capture(&self, invocation: &Invocation) -> io::Result<(executor::Status, Vec<u8>)> {
+        self.inner.capture(&self.redirect(invocation))
+    }
+}
@@ -0,0 +1,472 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// The subset of file metadata the engine cares about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    pub is_dir: bool,
+    pub len: u64,
+    pub modified: SystemTime,
+}
+
+/// A symbolic link, as opposed to what it points to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Link {
+    /// The path the link points to, as written in it.
+    pub target: PathBuf,
+    /// When the link itself was last changed, which is when it was created
+    /// or pointed somewhere else.
+    pub modified: SystemTime,
+}
+
+/// All filesystem access made while building goes through this trait, so the
+/// engine can run against something other than the real disk.
+pub trait Vfs: Send + Sync {
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+    /// Returns the paths of the entries in the folder.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+    /// Moves a file, replacing whatever was at `to`. Readers see either the
+    /// old file or the new one, never a mix.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    /// Deletes a file, or a folder with everything inside it.
+    fn remove_all(&self, path: &Path) -> io::Result<()>;
+    /// Creates the folder, along with any missing folders above it.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+    /// Returns the free space in bytes on the filesystem holding the path.
+    fn available_space(&self, path: &Path) -> io::Result<u64>;
+
+    fn exists(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok()
+    }
+
+    /// Reads the file, which must be UTF-8 text.
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        String::from_utf8(self.read(path)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Returns the link at the path, or `None` if it isn't a link.
+    fn link(&self, _path: &Path) -> io::Result<Option<Link>> {
+        Ok(None)
+    }
+
+    /// Returns the path with every link along it resolved, which names the
+    /// same folder however it is reached.
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        Ok(path.to_path_buf())
+    }
+
+    /// Returns a hex-encoded hash of the file's contents.
+    fn file_hash(&self, path: &Path) -> io::Result<String> {
+        Ok(format!("{:x}", Sha256::digest(self.read(path)?)))
+    }
+}
+
+impl<T: Vfs + ?Sized> Vfs for Arc<T> {
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.as_ref().metadata(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        self.as_ref().read_dir(path)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.as_ref().read(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        self.as_ref().write(path, contents)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.as_ref().rename(from, to)
+    }
+
+    fn remove_all(&self, path: &Path) -> io::Result<()> {
+        self.as_ref().remove_all(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.as_ref().create_dir_all(path)
+    }
+
+    fn available_space(&self, path: &Path) -> io::Result<u64> {
+        self.as_ref().available_space(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.as_ref().exists(path)
+    }
+
+    fn link(&self, path: &Path) -> io::Result<Option<Link>> {
+        self.as_ref().link(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        self.as_ref().canonicalize(path)
+    }
+
+    fn file_hash(&self, path: &Path) -> io::Result<String> {
+        self.as_ref().file_hash(path)
+    }
+}
+
+lazy_static! {
+    /// Hashes of the files read so far, with the size and modification
+    /// time each file had, so that unchanged files aren't read again.
+    static ref FILE_HASHES: Mutex<HashMap<PathBuf, (u64, SystemTime, String)>> =
+        Mutex::new(HashMap::new());
+}
+
+/// The real filesystem, through `std::fs`. File hashes are remembered until
+/// the file's size or modification time changes.
+pub struct RealFs;
+
+impl Vfs for RealFs {
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let metadata = path.metadata()?;
+        Ok(Metadata {
+            is_dir: metadata.is_dir(),
+            len: metadata.len(),
+            modified: metadata.modified()?,
+        })
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        std::fs::read_dir(path)?
+            .map(|entry| Ok(entry?.path()))
+            .collect()
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        std::fs::write(path, contents)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn remove_all(&self, path: &Path) -> io::Result<()> {
+        if path.symlink_metadata()?.is_dir() {
+            std::fs::remove_dir_all(path)
+        } else {
+            std::fs::remove_file(path)
+        }
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(path)
+    }
+
+    fn available_space(&self, path: &Path) -> io::Result<u64> {
+        fs2::available_space(path)
+    }
+
+    fn link(&self, path: &Path) -> io::Result<Option<Link>> {
+        let metadata = path.symlink_metadata()?;
+        if !metadata.is_symlink() {
+            return Ok(None);
+        }
+        Ok(Some(Link {
+            target: std::fs::read_link(path)?,
+            modified: metadata.modified()?,
+        }))
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        std::fs::canonicalize(path)
+    }
+
+    fn file_hash(&self, path: &Path) -> io::Result<String> {
+        let metadata = path.metadata()?;
+        let (len, modified) = (metadata.len(), metadata.modified()?);
+        if let Some((known_len, known_modified, hash)) = FILE_HASHES.lock().unwrap().get(path) {
+            if (*known_len, *known_modified) == (len, modified) {
+                return Ok(hash.clone());
+            }
+        }
+        let hash = format!("{:x}", Sha256::digest(self.read(path)?));
+        FILE_HASHES
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), (len, modified, hash.clone()));
+        Ok(hash)
+    }
+}
+
+/// The real filesystem, with links taken as they are instead of followed:
+/// a link is a file whose contents are the path it points to, even if that
+/// is a folder, and whether or not it exists.
+pub struct NoFollowFs;
+
+impl Vfs for NoFollowFs {
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let metadata = path.symlink_metadata()?;
+        Ok(Metadata {
+            is_dir: metadata.is_dir(),
+            len: metadata.len(),
+            modified: metadata.modified()?,
+        })
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        RealFs.read_dir(path)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        RealFs.read(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        RealFs.write(path, contents)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        RealFs.rename(from, to)
+    }
+
+    fn remove_all(&self, path: &Path) -> io::Result<()> {
+        RealFs.remove_all(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        RealFs.create_dir_all(path)
+    }
+
+    fn available_space(&self, path: &Path) -> io::Result<u64> {
+        RealFs.available_space(path)
+    }
+
+    fn link(&self, path: &Path) -> io::Result<Option<Link>> {
+        RealFs.link(path)
+    }
+
+    /// Links hash only the path they point to.
+    fn file_hash(&self, path: &Path) -> io::Result<String> {
+        match self.link(path)? {
+            Some(link) => Ok(format!(
+                "{:x}",
+                Sha256::digest(link.target.as_os_str().as_encoded_bytes())
+            )),
+            None => RealFs.file_hash(path),
+        }
+    }
+}
+
+enum Entry {
+    File {
+        contents: Vec<u8>,
+        modified: SystemTime,
+    },
+    Dir {
+        modified: SystemTime,
+    },
+}
+
+/// A filesystem kept in memory, for tests. Time only moves when something is
+/// written, by one second each time, so modification times are predictable.
+/// There is plenty of free space, unless less is set.
+pub struct MemoryFs {
+    entries: Mutex<BTreeMap<PathBuf, Entry>>,
+    now: Mutex<SystemTime>,
+    /// How much free space it says there is, anywhere.
+    available: Mutex<u64>,
+}
+
+impl Default for MemoryFs {
+    fn default() -> Self {
+        MemoryFs {
+            entries: Mutex::new(BTreeMap::new()),
+            now: Mutex::new(UNIX_EPOCH),
+            available: Mutex::new(u64::MAX),
+        }
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("'{}' does not exist", path.display()),
+    )
+}
+
+impl MemoryFs {
+    /// Moves the clock forward and returns the new time.
+    fn tick(&self) -> SystemTime {
+        let mut now = self.now.lock().unwrap();
+        *now += Duration::from_secs(1);
+        *now
+    }
+
+    /// Sets the modification time of a file or folder.
+    pub fn set_modified(&self, path: &Path, time: SystemTime) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get_mut(path) {
+            Some(Entry::File { modified, .. } | Entry::Dir { modified }) => {
+                *modified = time;
+                Ok(())
+            }
+            None => Err(not_found(path)),
+        }
+    }
+
+    /// Sets how much free space there is, which is unlimited until then.
+    pub fn set_available_space(&self, bytes: u64) {
+        *self.available.lock().unwrap() = bytes;
+    }
+
+    /// Bumps the modification time of a file without changing it.
+    pub fn touch(&self, path: &Path) -> io::Result<()> {
+        self.set_modified(path, self.tick())
+    }
+
+    /// Removes a file or folder, with everything inside it.
+    pub fn remove(&self, path: &Path) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|entry, _| !entry.starts_with(path));
+    }
+}
+
+impl Vfs for MemoryFs {
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        match self.entries.lock().unwrap().get(path) {
+            Some(Entry::File { contents, modified }) => Ok(Metadata {
+                is_dir: false,
+                len: contents.len() as u64,
+                modified: *modified,
+            }),
+            Some(Entry::Dir { modified }) => Ok(Metadata {
+                is_dir: true,
+                len: 0,
+                modified: *modified,
+            }),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(path) {
+            Some(Entry::Dir { .. }) => Ok(entries
+                .keys()
+                .filter(|entry| entry.parent() == Some(path))
+                .cloned()
+                .collect()),
+            Some(Entry::File { .. }) => Err(io::Error::other(format!(
+                "'{}' is not a folder",
+                path.display()
+            ))),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self.entries.lock().unwrap().get(path) {
+            Some(Entry::File { contents, .. }) => Ok(contents.clone()),
+            Some(Entry::Dir { .. }) => Err(io::Error::other(format!(
+                "'{}' is a folder",
+                path.display()
+            ))),
+            None => Err(not_found(path)),
+        }
+    }
+
+    /// Writes the file, creating any missing folders above it.
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let now = self.tick();
+        let mut entries = self.entries.lock().unwrap();
+        for ancestor in path.ancestors().skip(1) {
+            if ancestor.as_os_str().is_empty() {
+                break;
+            }
+            entries
+                .entry(ancestor.to_path_buf())
+                .or_insert(Entry::Dir { modified: now });
+        }
+        // Like on a real disk, adding a file changes its folder
+        if !entries.contains_key(path) {
+            if let Some(Entry::Dir { modified }) = path.parent().and_then(|p| entries.get_mut(p)) {
+                *modified = now;
+            }
+        }
+        entries.insert(
+            path.to_path_buf(),
+            Entry::File {
+                contents: contents.to_vec(),
+                modified: now,
+            },
+        );
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.remove(from) {
+            Some(entry @ Entry::File { .. }) => {
+                entries.insert(to.to_path_buf(), entry);
+                Ok(())
+            }
+            Some(entry) => {
+                entries.insert(from.to_path_buf(), entry);
+                Err(io::Error::other(format!(
+                    "'{}' is a folder",
+                    from.display()
+                )))
+            }
+            None => Err(not_found(from)),
+        }
+    }
+
+    fn remove_all(&self, path: &Path) -> io::Result<()> {
+        if !self.exists(path) {
+            return Err(not_found(path));
+        }
+        self.remove(path);
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let now = self.tick();
+        let mut entries = self.entries.lock().unwrap();
+        for ancestor in path.ancestors() {
+            if ancestor.as_os_str().is_empty() {
+                break;
+            }
+            match entries.get(ancestor) {
+                Some(Entry::Dir { .. }) => {}
+                Some(Entry::File { .. }) => {
+                    return Err(io::Error::other(format!(
+                        "'{}' is not a folder",
+                        ancestor.display()
+                    )))
+                }
+                None => {
+                    entries.insert(ancestor.to_path_buf(), Entry::Dir { modified: now });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn available_space(&self, _path: &Path) -> io::Result<u64> {
+        Ok(*self.available.lock().unwrap())
+    }
+}
@@ -0,0 +1,117 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    thread,
+};
+
+use crate::{
+    mkfile::ConcreteTarget,
+    vfs::{Link, Metadata, Vfs},
+};
+
+/// A file or folder found while walking a target.
+pub struct Found {
+    pub path: PathBuf,
+    pub metadata: Metadata,
+    /// The link at the path, if it is one.
+    pub link: Option<Link>,
+}
+
+impl Found {
+    fn at(vfs: &dyn Vfs, path: PathBuf) -> io::Result<Self> {
+        Ok(Found {
+            metadata: vfs.metadata(&path)?,
+            link: vfs.link(&path)?,
+            path,
+        })
+    }
+}
+
+/// A folder left to read, with the folders it is in, as they are named once
+/// links are resolved.
+type Pending = (PathBuf, Vec<PathBuf>);
+
+/// Finds the target and, if it is a deep folder, everything in it that its
+/// filters let through, in no particular order. The folders at each depth
+/// are read on several threads at once, since most of the time goes into
+/// waiting for the disk.
+pub fn walk(vfs: &dyn Vfs, target: &ConcreteTarget) -> io::Result<Vec<Found>> {
+    let root = Found::at(vfs, target.pathbuf().clone())?;
+    if !root.metadata.is_dir || !target.is_deep() {
+        return Ok(vec![root]);
+    }
+    let mut pending: Vec<Pending> = vec![(root.path.clone(), Vec::new())];
+    let mut found = vec![root];
+    while !pending.is_empty() {
+        let mut next = Vec::new();
+        for read in in_parallel(&pending, |folders| read_folders(vfs, target, folders)) {
+            let (entries, folders) = read?;
+            found.extend(entries);
+            next.extend(folders);
+        }
+        pending = next;
+    }
+    Ok(found)
+}
+
+fn read_folders(
+    vfs: &dyn Vfs,
+    target: &ConcreteTarget,
+    folders: &[Pending],
+) -> io::Result<(Vec<Found>, Vec<Pending>)> {
+    let mut found = Vec::new();
+    let mut next = Vec::new();
+    for (folder, within) in folders {
+        let mut within = within.clone();
+        enter(vfs, folder, &mut within)?;
+        for entry in vfs.read_dir(folder)? {
+            if target.ignores(&entry) {
+                continue;
+            }
+            let entry = Found::at(vfs, entry)?;
+            if entry.metadata.is_dir {
+                next.push((entry.path.clone(), within.clone()));
+            } else if !target.includes_file(&entry.path) {
+                continue;
+            }
+            found.push(entry);
+        }
+    }
+    Ok((found, next))
+}
+
+/// Notes that a walk goes into the folder, which fails if the walk is
+/// inside it already because a link led back to it.
+fn enter(vfs: &dyn Vfs, path: &Path, within: &mut Vec<PathBuf>) -> io::Result<()> {
+    let canonical = vfs.canonicalize(path)?;
+    if within.contains(&canonical) {
+        return Err(io::Error::other(format!(
+            "'{}' links back to a folder it is in, so it never ends; \
+             pass --no-follow-symlinks to take links as they are",
+            path.display()
+        )));
+    }
+    within.push(canonical);
+    Ok(())
+}
+
+/// Splits the items between as many threads as there are cores and runs
+/// `work` on each share, returning the results in order. A single item is
+/// worked on right away.
+pub fn in_parallel<T: Sync, R: Send>(items: &[T], work: impl Fn(&[T]) -> R + Sync) -> Vec<R> {
+    let threads = thread::available_parallelism().map_or(1, usize::from);
+    if items.len() < 2 || threads < 2 {
+        return vec![work(items)];
+    }
+    let work = &work;
+    thread::scope(|scope| {
+        let handles: Vec<_> = items
+            .chunks(items.len().div_ceil(threads))
+            .map(|share| scope.spawn(move || work(share)))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect()
+    })
+}
@@ -0,0 +1,107 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, RwLock},
+};
+
+use lazy_static::lazy_static;
+use log::{Level, Log, Metadata, Record};
+use regex::Regex;
+
+/// How many warnings of the same kind are shown before the rest are only
+/// counted.
+const SHOWN_PER_KIND: usize = 3;
+
+/// Where log records go instead of the wrapped logger, see [`redirect`].
+type Sink = Box<dyn Fn(&Record) + Send + Sync>;
+
+lazy_static! {
+    static ref REDIRECTED: RwLock<Option<Sink>> = RwLock::new(None);
+}
+
+/// Sends every log record to `sink` instead of the wrapped logger, such as
+/// to the mk that asked the daemon for a build, or back to the logger with
+/// `None`. Records sent elsewhere aren't held back as repeats.
+pub fn redirect(sink: Option<Sink>) {
+    *REDIRECTED.write().unwrap() = sink;
+}
+
+/// Wraps a logger so that warnings that keep repeating don't flood the
+/// console. Warnings that only differ in the names they quote, like the
+/// same problem with thousands of files, are of the same kind. The first
+/// few of each kind are passed on, and the rest are counted for
+/// `summarize`.
+pub struct WarningLayer<L> {
+    inner: L,
+    /// How many warnings of each kind were logged, with the first one.
+    kinds: Mutex<BTreeMap<String, (usize, String)>>,
+}
+
+impl<L: Log> WarningLayer<L> {
+    pub fn new(inner: L) -> Self {
+        WarningLayer {
+            inner,
+            kinds: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Logs how many warnings of each kind were held back, if any.
+    pub fn summarize(&self) {
+        let kinds = std::mem::take(&mut *self.kinds.lock().unwrap());
+        for (count, first) in kinds.into_values() {
+            if count > SHOWN_PER_KIND {
+                self.inner.log(
+                    &Record::builder()
+                        .level(Level::Warn)
+                        .target("mk")
+                        .args(format_args!(
+                            "{} more warnings like: {}",
+                            count - SHOWN_PER_KIND,
+                            first
+                        ))
+                        .build(),
+                );
+            }
+        }
+        self.inner.flush();
+    }
+}
+
+/// Returns what warnings of the same kind have in common: the message with
+/// quoted names left out.
+fn kind(message: &str) -> String {
+    lazy_static! {
+        static ref QUOTED_RE: Regex = Regex::new(r"'[^']*'").unwrap();
+    }
+    QUOTED_RE.replace_all(message, "''").into_owned()
+}
+
+impl<L: Log> Log for WarningLayer<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if let Some(sink) = &*REDIRECTED.read().unwrap() {
+            if self.enabled(record.metadata()) {
+                sink(record);
+            }
+            return;
+        }
+        if record.level() == Level::Warn && self.enabled(record.metadata()) {
+            let message = record.args().to_string();
+            let mut kinds = self.kinds.lock().unwrap();
+            let (count, _) = kinds
+                .entry(kind(&message))
+                .or_insert_with(|| (0, message.clone()));
+            *count += 1;
+            if *count > SHOWN_PER_KIND {
+                return;
+            }
+        }
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
@@ -0,0 +1,2076 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use mk::{
+    cache::{backend, Cache, Compression},
+    clean,
+    daemon::{Changes, DaemonChecker},
+    doctor,
+    executor::{MockExecutor, ShellExecutor},
+    export,
+    freshness::{FreshnessChecker, MtimeChecker},
+    hooks,
+    init::{self, Project},
+    logs::{self, LogDir},
+    making::{make, BuildSettings, Jobs, MakeOptions, UpdateState},
+    metrics::Metrics,
+    mkfile::{ConcreteTarget, MkFile, ParseOptions, Target},
+    ndjson::NdjsonReporter,
+    output::HeldOutput,
+    provenance,
+    release::{self, ReleaseManifest},
+    report::{Reporter, Reporters},
+    repro,
+    store::{self, StateLock},
+    taskfile,
+    trace::TraceReporter,
+    vfs::{MemoryFs, Vfs},
+    MkError,
+};
+
+/// Creates an empty scratch folder for a test.
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mk-test-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn makes_target_then_reports_up_to_date() {
+    let dir = scratch_dir("up-to-date");
+    let input = dir.join("input.txt");
+    let output = dir.join("output.txt");
+    std::fs::write(&input, "hello").unwrap();
+
+    let text = format!(
+        "{output}: {input}\n    cp {input} {output}\n",
+        input = input.display(),
+        output = output.display()
+    );
+    let mkfile = MkFile::parse(&text).unwrap();
+    let target = mkfile.resolve(&output.display().to_string());
+    let mut state = UpdateState::default();
+    let options = MakeOptions::default();
+
+    assert!(make(&mkfile, &target, &mut state, &options).unwrap());
+    assert_eq!(std::fs::read_to_string(&output).unwrap(), "hello");
+    assert!(!make(&mkfile, &target, &mut state, &options).unwrap());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn leaves_command_substitution_to_the_shell() {
+    let dir = scratch_dir("substitution");
+    let output = dir.join("names.txt");
+    let text = format!(
+        "LIB = /usr/lib/libfoo.so\n\n\
+         {output}:\n    echo name=$(basename $(LIB) .so) $(printf '%s\\n' b a | sort -r) > {output}\n",
+        output = output.display()
+    );
+    let mkfile = MkFile::parse(&text).unwrap();
+    let target = mkfile.resolve(&output.display().to_string());
+    let mut state = UpdateState::default();
+
+    assert!(make(&mkfile, &target, &mut state, &MakeOptions::default()).unwrap());
+    assert_eq!(
+        std::fs::read_to_string(&output).unwrap(),
+        "name=libfoo b a\n"
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn runs_dependency_commands_first() {
+    let mkfile =
+        MkFile::parse("$all: $first\n    echo all\n\n$first:\n    echo one\n    echo two\n")
+            .unwrap();
+    let executor = Arc::new(MockExecutor::default());
+    let options = MakeOptions {
+        executor: Box::new(executor.clone()),
+        ..MakeOptions::default()
+    };
+
+    let target = mkfile.resolve("$all");
+    assert!(make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap());
+    assert_eq!(executor.ran(), ["echo one", "echo two", "echo all"]);
+}
+
+#[test]
+fn builds_with_more_jobs_than_limits_allow() {
+    let mkfile = MkFile::parse("$all: $a $b\n\n$a:\n    echo a\n\n$b:\n    echo b\n").unwrap();
+    let executor = Arc::new(MockExecutor::default());
+    let options = MakeOptions {
+        executor: Box::new(executor.clone()),
+        jobs: usize::MAX,
+        ..MakeOptions::default()
+    };
+
+    let target = mkfile.resolve("$all");
+    assert!(make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap());
+    assert_eq!(executor.ran().len(), 2);
+}
+
+#[test]
+fn builds_with_auto_jobs() {
+    let mkfile =
+        MkFile::parse("$all: $a $b\n\n$a:\n    echo a\n\n$b:\n    memory: 1T\n    echo b\n")
+            .unwrap();
+    let executor = Arc::new(MockExecutor::default());
+    let options = MakeOptions {
+        executor: Box::new(executor.clone()),
+        auto_jobs: true,
+        ..MakeOptions::default()
+    };
+
+    let target = mkfile.resolve("$all");
+    assert!(make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap());
+    assert_eq!(executor.ran().len(), 2);
+}
+
+#[test]
+fn builds_while_load_is_above_max() {
+    let mkfile =
+        MkFile::parse("$all: $a $b $c\n\n$a:\n    echo a\n\n$b:\n    echo b\n\n$c:\n    echo c\n")
+            .unwrap();
+    let executor = Arc::new(MockExecutor::default());
+    // A job is started whenever none are running, however high the load
+    let options = MakeOptions {
+        executor: Box::new(executor.clone()),
+        jobs: 3,
+        max_load: Some(0.0),
+        ..MakeOptions::default()
+    };
+
+    let target = mkfile.resolve("$all");
+    assert!(make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap());
+    assert_eq!(executor.ran().len(), 3);
+}
+
+#[test]
+fn makes_wide_graphs_with_few_jobs() {
+    let mut text = String::from("$all:");
+    for i in 0..500 {
+        text += &format!(" $part{i}");
+    }
+    text += "\n    echo all\n\n$base:\n    echo base\n";
+    for i in 0..500 {
+        text += &format!("\n$part{i}: $base\n    echo part{i}\n");
+    }
+    let mkfile = MkFile::parse(&text).unwrap();
+    let executor = Arc::new(MockExecutor::default());
+    let options = MakeOptions {
+        executor: Box::new(executor.clone()),
+        jobs: 4,
+        ..MakeOptions::default()
+    };
+
+    let target = mkfile.resolve("$all");
+    assert!(make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap());
+    let ran = executor.ran();
+    assert_eq!(ran.len(), 502);
+    assert_eq!(ran.first().map(String::as_str), Some("echo base"));
+    assert_eq!(ran.last().map(String::as_str), Some("echo all"));
+}
+
+#[test]
+fn builds_with_the_variables_env_and_jobs_of_the_profile() {
+    let dir = scratch_dir("profile");
+    let output = dir.join("out.txt");
+    let text = format!(
+        "MODE = debug\n\n[profile.ci]\nvars = {{ MODE = \"release\" }}\nenv = {{ RUNNER = \"ci\" }}\n\
+         jobs = 3\n\n{output}:\n    echo $(MODE) $RUNNER > {output}\n",
+        output = output.display()
+    );
+    let parse_options = ParseOptions {
+        profile: Some("ci".to_string()),
+        ..ParseOptions::default()
+    };
+    let mkfile = MkFile::parse_with(&text, &parse_options).unwrap();
+    let settings = BuildSettings {
+        mkfile: dir.join("mkfile"),
+        parse_options,
+        freshness: &MtimeChecker,
+        follow_symlinks: true,
+        hermetic: false,
+        timeout: None,
+        max_load: None,
+        cache_dir: None,
+        cache_url: None,
+    };
+
+    let mut options = settings
+        .make_options(&mkfile, None, BTreeMap::new())
+        .unwrap();
+    options.release_manifest = None;
+    assert_eq!(options.jobs, 3);
+    assert_eq!(options.env.get("RUNNER").map(String::as_str), Some("ci"));
+    let target = mkfile.resolve(&output.display().to_string());
+    assert!(make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap());
+    assert_eq!(std::fs::read_to_string(&output).unwrap(), "release ci\n");
+
+    // Jobs given on the command line win over the profile's
+    let options = settings
+        .make_options(&mkfile, Some(Jobs::Count(1)), BTreeMap::new())
+        .unwrap();
+    assert_eq!(options.jobs, 1);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn retries_failed_commands() {
+    let mkfile = MkFile::parse("$fetch:\n    retries: 2\n    backoff: 10ms\n    false\n").unwrap();
+    let executor = Arc::new(MockExecutor::default().fail("false"));
+    let options = MakeOptions {
+        executor: Box::new(executor.clone()),
+        ..MakeOptions::default()
+    };
+
+    let target = mkfile.resolve("$fetch");
+    let err = make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap_err();
+    assert!(matches!(err, MkError::CommandFailed { .. }));
+    assert_eq!(executor.ran(), ["false", "false", "false"]);
+}
+
+#[test]
+fn stops_at_failed_command() {
+    let mkfile = MkFile::parse("$test:\n    false\n    echo done\n").unwrap();
+    let executor = Arc::new(MockExecutor::default().fail("false"));
+    let options = MakeOptions {
+        executor: Box::new(executor.clone()),
+        ..MakeOptions::default()
+    };
+
+    let target = mkfile.resolve("$test");
+    let err = make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap_err();
+    assert!(matches!(err, MkError::CommandFailed { command, .. } if command == "false"));
+    assert_eq!(executor.ran(), ["false"]);
+}
+
+#[test]
+fn rejects_dependency_cycles_before_making_anything() {
+    let mkfile =
+        MkFile::parse("$all: $a\n    echo all\n\n$a: $b\n    echo a\n\n$b: $all\n    echo b\n")
+            .unwrap();
+    let executor = Arc::new(MockExecutor::default());
+    let options = MakeOptions {
+        executor: Box::new(executor.clone()),
+        ..MakeOptions::default()
+    };
+
+    let target = mkfile.resolve("$all");
+    let err = make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap_err();
+    assert!(matches!(&err, MkError::Cycle(cycle) if cycle.len() == 4));
+    assert_eq!(
+        err.to_string(),
+        "Dependency cycle: $all -> $a -> $b -> $all"
+    );
+    assert_eq!(err.exit_code(), 3);
+    assert!(executor.ran().is_empty());
+}
+
+#[test]
+fn quarantines_flaky_targets_until_they_settle() {
+    let mkfile = MkFile::parse("$test:\n    run-tests\n").unwrap();
+    let target = mkfile.resolve("$test");
+    let mut state = UpdateState::default();
+    for succeeded in [false, true, false] {
+        state.record_outcome(&target, succeeded);
+    }
+    assert!(!state.is_flaky(&target));
+    assert_eq!(state.streak(&target), -1);
+    state.record_outcome(&target, true);
+    assert!(state.is_flaky(&target));
+    assert_eq!(state.streak(&target), 1);
+
+    // A quarantined target gets another try when its commands fail
+    let run = |state: &mut UpdateState, quarantine_flaky| {
+        let executor = Arc::new(MockExecutor::default().fail("run-tests"));
+        let options = MakeOptions {
+            executor: Box::new(executor.clone()),
+            quarantine_flaky,
+            ..MakeOptions::default()
+        };
+        assert!(make(&mkfile, &target, state, &options).is_err());
+        executor.ran().len()
+    };
+    assert_eq!(run(&mut state.clone(), false), 1);
+    assert_eq!(run(&mut state, true), 2);
+    assert_eq!(state.streak(&target), -2);
+
+    // It is let go once the runs it remembers stop flipping as often
+    for _ in 0..6 {
+        state.record_outcome(&target, true);
+        assert!(state.is_flaky(&target));
+    }
+    state.record_outcome(&target, true);
+    assert!(!state.is_flaky(&target));
+    assert_eq!(state.streak(&target), 7);
+}
+
+#[test]
+fn fails_before_running_commands_without_disk_space() {
+    let fs = Arc::new(MemoryFs::default());
+    fs.set_available_space(1 << 20);
+    let mkfile = MkFile::parse("out/big.bin:\n    size: 10M\n    make-big out/big.bin\n").unwrap();
+    let target = mkfile.resolve("out/big.bin");
+    let (options, executor) = memory_options(&fs);
+
+    let err = make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap_err();
+    assert!(matches!(
+        &err,
+        MkError::NoSpace { needed, available, dir, .. }
+            if *needed == 10 << 20 && *available == 1 << 20 && dir == Path::new(".")
+    ));
+    assert_eq!(err.exit_code(), 5);
+    assert_eq!(
+        err.to_string(),
+        "Not enough disk space to make 'out/big.bin': needs about 10.0 MiB, only 1.0 MiB available in '.'"
+    );
+    assert!(executor.ran().is_empty());
+
+    // The output being replaced frees its space
+    fs.write(Path::new("out/big.bin"), &vec![0; 9 << 20])
+        .unwrap();
+    assert!(make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap());
+    assert_eq!(executor.ran(), ["make-big out/big.bin"]);
+}
+
+/// Options that build against the in-memory filesystem, where running
+/// `cp a b` copies file `a` to `b`.
+fn memory_options(fs: &Arc<MemoryFs>) -> (MakeOptions, Arc<MockExecutor>) {
+    let copying = fs.clone();
+    let executor = Arc::new(MockExecutor::default().on_run(move |command| {
+        if let ["cp", from, to] = command.split_whitespace().collect::<Vec<_>>()[..] {
+            let contents = copying.read(Path::new(from)).unwrap();
+            copying.write(Path::new(to), &contents).unwrap();
+        }
+    }));
+    let options = MakeOptions {
+        vfs: Box::new(fs.clone()),
+        executor: Box::new(executor.clone()),
+        ..MakeOptions::default()
+    };
+    (options, executor)
+}
+
+#[test]
+fn remakes_only_when_contents_change() {
+    let fs = Arc::new(MemoryFs::default());
+    fs.write(Path::new("src/a.txt"), b"one").unwrap();
+    let mkfile = MkFile::parse("out/a.txt: src/a.txt\n    cp src/a.txt out/a.txt\n").unwrap();
+    let target = mkfile.resolve("out/a.txt");
+    let mut state = UpdateState::default();
+    let (options, executor) = memory_options(&fs);
+
+    assert!(make(&mkfile, &target, &mut state, &options).unwrap());
+    assert_eq!(fs.read(Path::new("out/a.txt")).unwrap(), b"one");
+
+    // Touching the input without changing it is not a change
+    fs.touch(Path::new("src/a.txt")).unwrap();
+    assert!(!make(&mkfile, &target, &mut state, &options).unwrap());
+
+    fs.write(Path::new("src/a.txt"), b"two").unwrap();
+    assert!(make(&mkfile, &target, &mut state, &options).unwrap());
+    assert_eq!(fs.read(Path::new("out/a.txt")).unwrap(), b"two");
+    assert_eq!(executor.ran().len(), 2);
+}
+
+#[test]
+fn remakes_deep_target_when_nested_file_changes() {
+    let fs = Arc::new(MemoryFs::default());
+    fs.write(Path::new("src/lib/a.rs"), b"fn a() {}").unwrap();
+    fs.write(Path::new("src/notes.md"), b"notes").unwrap();
+    let mkfile = MkFile::parse("out.rs: ^src[*.rs]\n    cp src/lib/a.rs out.rs\n").unwrap();
+    let target = mkfile.resolve("out.rs");
+    let mut state = UpdateState::default();
+    let (options, _) = memory_options(&fs);
+
+    assert!(make(&mkfile, &target, &mut state, &options).unwrap());
+
+    // Files the filter leaves out don't count
+    fs.write(Path::new("src/notes.md"), b"more notes").unwrap();
+    assert!(!make(&mkfile, &target, &mut state, &options).unwrap());
+
+    fs.write(Path::new("src/lib/a.rs"), b"fn a() { b() }")
+        .unwrap();
+    assert!(make(&mkfile, &target, &mut state, &options).unwrap());
+}
+
+#[test]
+fn remakes_when_scanned_include_changes() {
+    let fs = Arc::new(MemoryFs::default());
+    fs.write(Path::new("main.c"), b"#include \"util.h\"\n")
+        .unwrap();
+    fs.write(Path::new("util.h"), b"#include \"inner/types.h\"\n")
+        .unwrap();
+    fs.write(Path::new("inner/types.h"), b"typedef int t;\n")
+        .unwrap();
+    let mkfile = MkFile::parse("main.o: main.c\n    scan: c\n    cp main.c main.o\n").unwrap();
+    let target = mkfile.resolve("main.o");
+    let mut state = UpdateState::default();
+    let (options, executor) = memory_options(&fs);
+
+    make(&mkfile, &target, &mut state, &options).unwrap();
+    make(&mkfile, &target, &mut state, &options).unwrap();
+    assert_eq!(executor.ran().len(), 1);
+
+    // Headers included by headers count too
+    fs.write(Path::new("inner/types.h"), b"typedef long t;\n")
+        .unwrap();
+    make(&mkfile, &target, &mut state, &options).unwrap();
+    assert_eq!(executor.ran().len(), 2);
+}
+
+#[test]
+fn restores_output_from_cache() {
+    let fs = Arc::new(MemoryFs::default());
+    fs.write(Path::new("src/a.txt"), b"one").unwrap();
+    let mkfile = MkFile::parse("out/a.txt: src/a.txt\n    cp src/a.txt out/a.txt\n").unwrap();
+    let target = mkfile.resolve("out/a.txt");
+    let (options, executor) = memory_options(&fs);
+    let options = MakeOptions {
+        cache: Some(Cache::new(".cache")),
+        ..options
+    };
+
+    assert!(make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap());
+
+    // Like after `git clean`: no output and no state
+    fs.remove(Path::new("out"));
+    assert!(make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap());
+    assert_eq!(fs.read(Path::new("out/a.txt")).unwrap(), b"one");
+    assert_eq!(executor.ran().len(), 1);
+
+    // Different inputs mean a different key
+    fs.write(Path::new("src/a.txt"), b"two").unwrap();
+    fs.remove(Path::new("out"));
+    assert!(make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap());
+    assert_eq!(executor.ran().len(), 2);
+}
+
+#[test]
+fn restores_compressed_output_from_cache() {
+    let fs = Arc::new(MemoryFs::default());
+    let contents = "compresses well ".repeat(1000);
+    fs.write(Path::new("a.txt"), contents.as_bytes()).unwrap();
+    fs.write(Path::new("b.txt"), contents.as_bytes()).unwrap();
+    let mkfile = MkFile::parse(
+        "$all: a.out b.out\n\na.out: a.txt\n    cp a.txt a.out\n\n\
+         b.out: b.txt\n    compression: lz4\n    cp b.txt b.out\n",
+    )
+    .unwrap();
+    let target = mkfile.resolve("$all");
+    let (options, executor) = memory_options(&fs);
+    let options = MakeOptions {
+        cache: Some(Cache::new(".cache").with_compression(Compression::Zstd)),
+        ..options
+    };
+    make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap();
+
+    let mut entries = vec![PathBuf::from(".cache")];
+    let mut sizes = Vec::new();
+    while let Some(path) = entries.pop() {
+        match fs.read_dir(&path) {
+            Ok(children) => entries.extend(children),
+            Err(_) => sizes.push(fs.read(&path).unwrap().len()),
+        }
+    }
+    assert_eq!(sizes.len(), 2);
+    assert!(sizes.iter().all(|size| *size < contents.len() / 10));
+
+    fs.remove(Path::new("a.out"));
+    fs.remove(Path::new("b.out"));
+    make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap();
+    assert_eq!(fs.read(Path::new("a.out")).unwrap(), contents.as_bytes());
+    assert_eq!(fs.read(Path::new("b.out")).unwrap(), contents.as_bytes());
+    assert_eq!(executor.ran().len(), 2);
+}
+
+/// Starts an HTTP cache server that keeps outputs in memory, and returns
+/// its URL.
+fn start_cache_server() -> String {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/cache", server.server_addr());
+    thread::spawn(move || {
+        let stored = Mutex::new(HashMap::new());
+        for mut request in server.incoming_requests() {
+            let key = request.url().to_string();
+            let response = if *request.method() == tiny_http::Method::Put {
+                let mut body = Vec::new();
+                request.as_reader().read_to_end(&mut body).unwrap();
+                stored.lock().unwrap().insert(key, body);
+                tiny_http::Response::from_data(Vec::new())
+            } else {
+                match stored.lock().unwrap().get(&key) {
+                    Some(body) => tiny_http::Response::from_data(body.clone()),
+                    None => tiny_http::Response::from_data(Vec::new()).with_status_code(404),
+                }
+            };
+            request.respond(response).unwrap();
+        }
+    });
+    url
+}
+
+#[test]
+fn shares_outputs_through_remote_cache() {
+    let url = start_cache_server();
+    let mkfile = MkFile::parse("out/a.txt: src/a.txt\n    cp src/a.txt out/a.txt\n").unwrap();
+    let target = mkfile.resolve("out/a.txt");
+
+    // One machine makes the output, another gets it from the shared cache
+    let mut executors = Vec::new();
+    for _ in 0..2 {
+        let fs = Arc::new(MemoryFs::default());
+        fs.write(Path::new("src/a.txt"), b"one").unwrap();
+        let (options, executor) = memory_options(&fs);
+        let options = MakeOptions {
+            cache: Some(Cache::new(".cache").with_remote(backend(&url).unwrap())),
+            ..options
+        };
+        assert!(make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap());
+        assert_eq!(fs.read(Path::new("out/a.txt")).unwrap(), b"one");
+        executors.push(executor);
+    }
+    assert_eq!(executors[0].ran().len(), 1);
+    assert_eq!(executors[1].ran().len(), 0);
+}
+
+#[test]
+fn shares_outputs_through_s3_bucket() {
+    let url = start_cache_server();
+    let endpoint = url.trim_end_matches("/cache");
+    let remote = format!("s3://cache/outputs?endpoint={endpoint}");
+    let mkfile = MkFile::parse("out/a.txt: src/a.txt\n    cp src/a.txt out/a.txt\n").unwrap();
+    let target = mkfile.resolve("out/a.txt");
+
+    let mut executors = Vec::new();
+    for _ in 0..2 {
+        let fs = Arc::new(MemoryFs::default());
+        fs.write(Path::new("src/a.txt"), b"one").unwrap();
+        let (options, executor) = memory_options(&fs);
+        let options = MakeOptions {
+            cache: Some(Cache::default().with_remote(backend(&remote).unwrap())),
+            ..options
+        };
+        assert!(make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap());
+        assert_eq!(fs.read(Path::new("out/a.txt")).unwrap(), b"one");
+        executors.push(executor);
+    }
+    assert_eq!(executors[1].ran().len(), 0);
+}
+
+#[test]
+fn corrupt_remote_entries_are_not_restored() {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/cache", server.server_addr());
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            // Entries claim a hash that their contents don't have
+            let body = format!("mk-sha256:{}\ntampered", "0".repeat(64));
+            request
+                .respond(tiny_http::Response::from_data(body.into_bytes()))
+                .unwrap();
+        }
+    });
+    let fs = Arc::new(MemoryFs::default());
+    fs.write(Path::new("src/a.txt"), b"one").unwrap();
+    let mkfile = MkFile::parse("out/a.txt: src/a.txt\n    cp src/a.txt out/a.txt\n").unwrap();
+    let target = mkfile.resolve("out/a.txt");
+    let (options, executor) = memory_options(&fs);
+    let options = MakeOptions {
+        cache: Some(Cache::default().with_remote(backend(&url).unwrap())),
+        ..options
+    };
+
+    assert!(make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap());
+    assert_eq!(fs.read(Path::new("out/a.txt")).unwrap(), b"one");
+    assert_eq!(executor.ran().len(), 1);
+}
+
+#[test]
+fn builds_when_remote_cache_is_unreachable() {
+    // Nothing listens on a port that was just freed
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let fs = Arc::new(MemoryFs::default());
+    fs.write(Path::new("src/a.txt"), b"one").unwrap();
+    let mkfile = MkFile::parse("out/a.txt: src/a.txt\n    cp src/a.txt out/a.txt\n").unwrap();
+    let target = mkfile.resolve("out/a.txt");
+    let (options, executor) = memory_options(&fs);
+    let remote = backend(&format!("http://127.0.0.1:{port}")).unwrap();
+    let options = MakeOptions {
+        cache: Some(Cache::default().with_remote(remote)),
+        ..options
+    };
+
+    assert!(make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap());
+    assert_eq!(executor.ran().len(), 1);
+}
+
+#[test]
+fn state_survives_rows_round_trip() {
+    let fs = Arc::new(MemoryFs::default());
+    fs.write(Path::new("src/a.txt"), b"one").unwrap();
+    let mkfile = MkFile::parse("out/a.txt: src/a.txt\n    cp src/a.txt out/a.txt\n").unwrap();
+    let target = mkfile.resolve("out/a.txt");
+    let mut state = UpdateState::default();
+    let (options, _) = memory_options(&fs);
+    make(&mkfile, &target, &mut state, &options).unwrap();
+
+    let rows = state.to_rows();
+    assert!(!rows.is_empty());
+    let loaded = UpdateState::from_rows(rows.clone()).unwrap();
+    assert_eq!(loaded.to_rows(), rows);
+    assert!(!make(&mkfile, &target, &mut loaded.clone(), &options).unwrap());
+}
+
+#[test]
+fn targets_keep_id_when_renamed() {
+    let mut state = UpdateState::default();
+    let before = MkFile::parse("$test:\n    echo test\n").unwrap();
+    state.assign_ids(&before);
+    let id = state.target_id(&before.resolve("$test"));
+
+    let after = MkFile::parse("$check:\n    was: $test\n    echo test\n").unwrap();
+    state.assign_ids(&after);
+    assert_eq!(state.target_id(&after.resolve("$check")), id);
+    assert_ne!(state.target_id(&after.resolve("$other")), id);
+}
+
+#[test]
+fn garbage_collection_forgets_removed_rules() {
+    let fs = Arc::new(MemoryFs::default());
+    fs.write(Path::new("a.txt"), b"a").unwrap();
+    fs.write(Path::new("b.txt"), b"b").unwrap();
+    let mkfile = MkFile::parse(
+        "$all: a.out b.out\n\na.out: a.txt\n    cp a.txt a.out\n\nb.out: b.txt\n    cp b.txt b.out\n",
+    )
+    .unwrap();
+    let mut state = UpdateState::default();
+    let (options, _) = memory_options(&fs);
+    make(&mkfile, &mkfile.resolve("$all"), &mut state, &options).unwrap();
+
+    let mkfile = MkFile::parse("a.out: a.txt\n    cp a.txt a.out\n").unwrap();
+    assert!(state.collect_garbage(&mkfile, fs.as_ref()) > 0);
+    assert_eq!(state.collect_garbage(&mkfile, fs.as_ref()), 0);
+
+    // What is left is enough to know a.out is up to date
+    let target = mkfile.resolve("a.out");
+    assert!(!make(&mkfile, &target, &mut state, &options).unwrap());
+}
+
+#[test]
+fn forgotten_target_is_made_again() {
+    let fs = Arc::new(MemoryFs::default());
+    fs.write(Path::new("a.txt"), b"a").unwrap();
+    let mkfile = MkFile::parse("a.out: a.txt\n    cp a.txt a.out\n").unwrap();
+    let target = mkfile.resolve("a.out");
+    let mut state = UpdateState::default();
+    state.assign_ids(&mkfile);
+    let (options, _) = memory_options(&fs);
+    make(&mkfile, &target, &mut state, &options).unwrap();
+    let id = state.target_id(&target);
+
+    assert!(state
+        .entries(Some(&target))
+        .iter()
+        .any(|(_, field, _)| *field == "hash"));
+    assert!(state.forget(&target) > 0);
+    let fields: Vec<_> = state
+        .entries(Some(&target))
+        .into_iter()
+        .map(|(_, field, _)| field)
+        .collect();
+    assert_eq!(fields, ["id", "produced by"]);
+    assert_eq!(state.target_id(&target), id);
+    assert!(make(&mkfile, &target, &mut state, &options).unwrap());
+}
+
+#[test]
+fn truncated_state_is_replaced_on_save() {
+    let fs = MemoryFs::default();
+    let path = Path::new(".mkstate.sexpr");
+    let mkfile = MkFile::parse("$test:\n    echo test\n").unwrap();
+    let mut state = UpdateState::default();
+    state.assign_ids(&mkfile);
+    state.save(&fs, path).unwrap();
+    let saved = fs.read(path).unwrap();
+
+    fs.write(path, &saved[..saved.len() / 2]).unwrap();
+    let mut loaded = UpdateState::load(&fs, path);
+    assert!(loaded.ids().is_empty());
+    loaded.assign_ids(&mkfile);
+    loaded.save(&fs, path).unwrap();
+    assert_eq!(fs.read(path).unwrap(), saved);
+    assert!(!fs.exists(Path::new(".mkstate.sexpr.tmp")));
+}
+
+#[test]
+fn release_rules_add_to_manifest() {
+    let fs = Arc::new(MemoryFs::default());
+    fs.write(Path::new("src.txt"), b"v1").unwrap();
+    let mkfile = MkFile::parse(
+        "VERSION = 1.0\n\napp.tar: src.txt\n    release: app\n    version: $(VERSION)\n    cp src.txt app.tar\n",
+    )
+    .unwrap();
+    let target = mkfile.resolve("app.tar");
+    let (options, _) = memory_options(&fs);
+    let options = MakeOptions {
+        release_manifest: Some(ReleaseManifest::new("release.jsonl")),
+        ..options
+    };
+    let mut state = UpdateState::default();
+    make(&mkfile, &target, &mut state, &options).unwrap();
+    // Up to date, so nothing new was built
+    make(&mkfile, &target, &mut state, &options).unwrap();
+    fs.write(Path::new("src.txt"), b"v2").unwrap();
+    make(&mkfile, &target, &mut state, &options).unwrap();
+
+    let entries = release::load(fs.as_ref(), Path::new("release.jsonl")).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[1].artifact, "app");
+    assert_eq!(entries[1].version.as_deref(), Some("1.0"));
+    assert_eq!(entries[1].size, Some(2));
+    assert_ne!(entries[0].sha256, entries[1].sha256);
+
+    let notes = release::notes(&entries, false);
+    assert!(notes.contains("## app 1.0\n"));
+    assert_eq!(notes.matches("`app.tar`").count(), 1);
+    assert_eq!(
+        release::notes(&entries, true).matches("`app.tar`").count(),
+        2
+    );
+}
+
+#[test]
+fn clean_deletes_produced_outputs() {
+    let fs = Arc::new(MemoryFs::default());
+    fs.write(Path::new("a.txt"), b"a").unwrap();
+    fs.write(Path::new("b.txt"), b"b").unwrap();
+    let mkfile = MkFile::parse(
+        "$all: a.out b.out\n\na.out: a.txt\n    cp a.txt a.out\n\nb.out: b.txt\n    cp b.txt b.out\n",
+    )
+    .unwrap();
+    let mut state = UpdateState::default();
+    let (options, _) = memory_options(&fs);
+    make(&mkfile, &mkfile.resolve("$all"), &mut state, &options).unwrap();
+
+    let only = Some((&mkfile, &mkfile.resolve("a.out")));
+    let deleted = clean::clean(&mut state, fs.as_ref(), only, true).unwrap();
+    assert_eq!(deleted.len(), 1);
+    assert!(fs.exists(Path::new("a.out")));
+
+    clean::clean(&mut state, fs.as_ref(), only, false).unwrap();
+    assert!(!fs.exists(Path::new("a.out")));
+    assert!(fs.exists(Path::new("b.out")));
+
+    let deleted = clean::clean(&mut state, fs.as_ref(), None, false).unwrap();
+    assert_eq!(deleted.len(), 1);
+    assert!(!fs.exists(Path::new("b.out")));
+    assert!(fs.exists(Path::new("b.txt")));
+    assert_eq!(state.produced().count(), 0);
+}
+
+#[test]
+fn provenance_explains_output() {
+    let fs = Arc::new(MemoryFs::default());
+    fs.write(Path::new("a.txt"), b"a").unwrap();
+    let mkfile = MkFile::parse(
+        "out.tar: mid.txt\n    cp mid.txt out.tar\n\nmid.txt: a.txt\n    cp a.txt mid.txt\n",
+    )
+    .unwrap();
+    let target = mkfile.resolve("out.tar");
+    let (options, _) = memory_options(&fs);
+    let options = MakeOptions {
+        provenance: true,
+        ..options
+    };
+    make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap();
+
+    let recorded = provenance::load(fs.as_ref(), Path::new("out.tar")).unwrap();
+    let targets: Vec<_> = recorded.nodes.iter().map(|node| &node.target).collect();
+    assert_eq!(targets, ["out.tar", "mid.txt", "a.txt"]);
+    let explanation = provenance::explain(&recorded, fs.as_ref());
+    assert!(explanation.contains("    cp a.txt mid.txt\n"));
+    assert!(!explanation.contains("changed since"));
+
+    fs.write(Path::new("a.txt"), b"b").unwrap();
+    let explanation = provenance::explain(&recorded, fs.as_ref());
+    assert!(explanation.contains("a.txt ("));
+    assert!(explanation.contains("changed since"));
+}
+
+#[test]
+fn declared_outputs_are_tracked_and_cleaned() {
+    let fs = Arc::new(MemoryFs::default());
+    fs.write(Path::new("a.c"), b"a").unwrap();
+    let mkfile = MkFile::parse(
+        "deps.txt: a.d\n    cp a.d deps.txt\n\n\
+         a.o: a.c\n    outputs: a.d\n    cp a.c a.o\n    cp a.c a.d\n",
+    )
+    .unwrap();
+    let mut state = UpdateState::default();
+    let (options, executor) = memory_options(&fs);
+
+    // Depending on a declared output makes the rule that declares it
+    assert!(make(&mkfile, &mkfile.resolve("deps.txt"), &mut state, &options).unwrap());
+    assert_eq!(executor.ran().len(), 3);
+    assert!(!make(&mkfile, &mkfile.resolve("a.o"), &mut state, &options).unwrap());
+
+    fs.remove(Path::new("a.d"));
+    make(&mkfile, &mkfile.resolve("a.o"), &mut state, &options).unwrap();
+    assert_eq!(executor.ran().len(), 5);
+    assert!(fs.exists(Path::new("a.d")));
+
+    let only = Some((&mkfile, &mkfile.resolve("a.o")));
+    let deleted = clean::clean(&mut state, fs.as_ref(), only, false).unwrap();
+    assert_eq!(deleted.len(), 2);
+    assert!(!fs.exists(Path::new("a.d")));
+}
+
+#[test]
+fn deletes_output_of_failed_rule() {
+    let fs = Arc::new(MemoryFs::default());
+    fs.write(Path::new("in.txt"), b"one").unwrap();
+    fs.write(Path::new("old.txt"), b"old").unwrap();
+    let copying = fs.clone();
+    let executor = MockExecutor::default()
+        .fail("false")
+        .on_run(move |command| {
+            if let ["cp", from, to] = command.split_whitespace().collect::<Vec<_>>()[..] {
+                let contents = copying.read(Path::new(from)).unwrap();
+                copying.write(Path::new(to), &contents).unwrap();
+            }
+        });
+    let options = MakeOptions {
+        vfs: Box::new(fs.clone()),
+        executor: Box::new(executor),
+        ..MakeOptions::default()
+    };
+    let mkfile = MkFile::parse(
+        "out.txt: in.txt\n    cp in.txt out.txt\n    false\n\n\
+         kept.txt: in.txt\n    keep_on_error: yes\n    cp in.txt kept.txt\n    false\n\n\
+         old.txt: in.txt\n    false\n",
+    )
+    .unwrap();
+
+    for name in ["out.txt", "kept.txt", "old.txt"] {
+        let target = mkfile.resolve(name);
+        assert!(make(&mkfile, &target, &mut UpdateState::default(), &options).is_err());
+    }
+    assert!(!fs.exists(Path::new("out.txt")));
+    assert!(fs.exists(Path::new("kept.txt")));
+    // Files the commands didn't touch aren't deleted
+    assert!(fs.exists(Path::new("old.txt")));
+}
+
+#[test]
+fn kills_commands_that_time_out() {
+    let mkfile = MkFile::parse("$slow:\n    timeout: 200ms\n    sleep 10\n").unwrap();
+    let options = MakeOptions::default();
+
+    let target = mkfile.resolve("$slow");
+    let started = std::time::Instant::now();
+    let err = make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap_err();
+    assert!(matches!(err, MkError::TimedOut { command, .. } if command == "sleep 10"));
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+}
+
+#[test]
+fn remakes_when_env_input_changes() {
+    let fs = Arc::new(MemoryFs::default());
+    fs.write(Path::new("in.txt"), b"one").unwrap();
+    let mkfile =
+        MkFile::parse("out.txt: in.txt\n    env_inputs: MK_TEST_MODE\n    cp in.txt out.txt\n")
+            .unwrap();
+    let target = mkfile.resolve("out.txt");
+    let mut state = UpdateState::default();
+    let (mut options, executor) = memory_options(&fs);
+
+    make(&mkfile, &target, &mut state, &options).unwrap();
+    make(&mkfile, &target, &mut state, &options).unwrap();
+    assert_eq!(executor.ran().len(), 1);
+
+    options.env.insert("MK_TEST_MODE".into(), "release".into());
+    make(&mkfile, &target, &mut state, &options).unwrap();
+    make(&mkfile, &target, &mut state, &options).unwrap();
+    assert_eq!(executor.ran().len(), 2);
+}
+
+#[test]
+fn double_colon_rules_run_on_their_own() {
+    let fs = Arc::new(MemoryFs::default());
+    fs.write(Path::new("a.txt"), b"a").unwrap();
+    fs.write(Path::new("b.txt"), b"b").unwrap();
+    let mkfile = MkFile::parse(
+        "out.txt:: a.txt\n    cp a.txt out.txt\n\nout.txt:: b.txt\n    cp b.txt out.txt\n",
+    )
+    .unwrap();
+    let target = mkfile.resolve("out.txt");
+    let mut state = UpdateState::default();
+    let (options, executor) = memory_options(&fs);
+
+    make(&mkfile, &target, &mut state, &options).unwrap();
+    make(&mkfile, &target, &mut state, &options).unwrap();
+    assert_eq!(executor.ran(), ["cp a.txt out.txt", "cp b.txt out.txt"]);
+
+    fs.write(Path::new("b.txt"), b"changed").unwrap();
+    make(&mkfile, &target, &mut state, &options).unwrap();
+    assert_eq!(executor.ran()[2..], ["cp b.txt out.txt"]);
+}
+
+#[test]
+fn grouped_targets_run_their_commands_once() {
+    let fs = Arc::new(MemoryFs::default());
+    fs.write(Path::new("in.txt"), b"in").unwrap();
+    let mkfile = MkFile::parse(
+        "$all: a.txt b.txt\n\na.txt b.txt &: in.txt\n    cp in.txt a.txt\n    cp in.txt b.txt\n",
+    )
+    .unwrap();
+    let target = mkfile.resolve("$all");
+    let mut state = UpdateState::default();
+    let (options, executor) = memory_options(&fs);
+
+    make(&mkfile, &target, &mut state, &options).unwrap();
+    assert_eq!(executor.ran(), ["cp in.txt a.txt", "cp in.txt b.txt"]);
+    make(&mkfile, &mkfile.resolve("b.txt"), &mut state, &options).unwrap();
+    assert_eq!(executor.ran().len(), 2);
+}
+
+#[test]
+fn creates_parent_dirs_of_targets() {
+    let fs = Arc::new(MemoryFs::default());
+    fs.write(Path::new("in.txt"), b"in").unwrap();
+    let text = "build/obj/out.txt: in.txt\n    cp in.txt build/obj/out.txt\n";
+    let (options, _) = memory_options(&fs);
+
+    let mkfile = MkFile::parse(text).unwrap();
+    let target = mkfile.resolve("build/obj/out.txt");
+    make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap();
+    assert!(fs.metadata(Path::new("build/obj")).unwrap().is_dir);
+
+    let mkfile = MkFile::parse(&format!(".parent_dirs: no\n\n{text}")).unwrap();
+    assert!(!mkfile.creates_parent_dirs());
+}
+
+#[test]
+fn finds_dependencies_in_vpath() {
+    let dir = scratch_dir("vpath");
+    std::fs::create_dir_all(dir.join("lib")).unwrap();
+    std::fs::write(dir.join("lib/util.c"), "int util;").unwrap();
+    let text = format!(
+        ".vpath: {src} {lib}\n\nout.o: util.c\n    cc -c util.c\n",
+        src = dir.join("src").display(),
+        lib = dir.join("lib").display()
+    );
+    let mkfile = MkFile::parse(&text).unwrap();
+
+    let target = mkfile.resolve("out.o");
+    assert_eq!(
+        mkfile.dependencies(&target),
+        &[Target::parse(&dir.join("lib/util.c").to_string_lossy())]
+    );
+}
+
+#[test]
+fn parses_with_the_given_filesystem() {
+    let fs = Arc::new(MemoryFs::default());
+    fs.write(Path::new("common.mk"), b"$lint:\n    echo lint\n")
+        .unwrap();
+    fs.write(Path::new(".env"), b"GREETING=hello\n").unwrap();
+    fs.write(Path::new(".gitignore"), b"/target/\n").unwrap();
+    fs.write(Path::new("main.c"), b"int main;").unwrap();
+    fs.write(Path::new("lib/util.h"), b"int util;").unwrap();
+    let options = ParseOptions {
+        vfs: fs.clone(),
+        ..ParseOptions::default()
+    };
+    let text = ".vpath: lib\n.builtins: yes\n.env_file: .env\n.use_gitignore: yes\n\
+                include common.mk\n\napp: main.o util.h\n    echo $(GREETING)\n";
+    let mkfile = MkFile::parse_with(text, &options).unwrap();
+
+    let app = mkfile.resolve("app");
+    assert!(mkfile.has_target(&mkfile.resolve("$lint")));
+    assert!(mkfile.has_target(&mkfile.resolve("main.o")));
+    assert_eq!(
+        mkfile.dependencies(&app),
+        &[Target::parse("main.o"), Target::parse("lib/util.h")]
+    );
+    assert_eq!(mkfile.commands(&app), &["echo hello"]);
+    assert!(mkfile.ignores(Path::new("target")));
+}
+
+#[test]
+fn builtin_rules_compile_and_link() {
+    let dir = scratch_dir("builtins");
+    std::fs::write(dir.join("main.c"), "int main(void) { return 0; }").unwrap();
+    std::fs::write(dir.join("util.cpp"), "int util;").unwrap();
+    let path = |name: &str| dir.join(name).display().to_string();
+    let text = format!(
+        ".builtins: yes\nCFLAGS = -O2\n\n{app}: {main} {util}\n\n{dist}: {app}\n",
+        app = path("app"),
+        main = path("main.o"),
+        util = path("util.o"),
+        dist = path("dist/app")
+    );
+    let mkfile = MkFile::parse(&text).unwrap();
+
+    let commands = |name: &str| mkfile.commands(&Target::parse(&path(name))).clone();
+    assert_eq!(
+        commands("main.o"),
+        [format!(
+            "cc -O2  -c {} -o {}",
+            path("main.c"),
+            path("main.o")
+        )]
+    );
+    assert_eq!(
+        commands("util.o"),
+        [format!(
+            "c++   -c {} -o {}",
+            path("util.cpp"),
+            path("util.o")
+        )]
+    );
+    assert_eq!(
+        commands("app"),
+        [format!(
+            "c++  -o {} {} {} ",
+            path("app"),
+            path("main.o"),
+            path("util.o")
+        )]
+    );
+    assert_eq!(
+        commands("dist/app"),
+        [format!("cp {} {}", path("app"), path("dist/app"))]
+    );
+}
+
+#[test]
+fn includes_other_mkfiles() {
+    let dir = scratch_dir("include");
+    let included = dir.join("rules.mk");
+    let missing = dir.join("deps.mk");
+    std::fs::write(
+        &included,
+        "VERSION = 2\n\nout.txt:\n    echo $(VERSION) > out.txt\n",
+    )
+    .unwrap();
+    let text = format!(
+        "include {included}\n-include {missing}\n\n{missing}: out.txt\n    touch {missing}\n",
+        included = included.display(),
+        missing = missing.display()
+    );
+    let mkfile = MkFile::parse(&text).unwrap();
+
+    assert_eq!(
+        mkfile.commands(&mkfile.resolve("out.txt")),
+        &["echo 2 > out.txt"]
+    );
+    assert_eq!(mkfile.includes(), &[(included, false), (missing, true)]);
+}
+
+#[cfg(feature = "plugins")]
+#[test]
+fn plugins_check_targets_and_run_commands() {
+    use mk::plugin::Plugins;
+
+    // Both hooks return their input with `echo ` in front, by having the
+    // host write it right after that text
+    let dir = scratch_dir("plugins");
+    let plugin = dir.join("db.wat");
+    std::fs::write(
+        &plugin,
+        r#"(module
+            (memory (export "memory") 1)
+            (data (i32.const 2048) "echo ")
+            (func (export "mk_alloc") (param i32) (result i32) (i32.const 2053))
+            (func $echo (param $ptr i32) (param $len i32) (result i64)
+                (i64.or
+                    (i64.shl (i64.const 2048) (i64.const 32))
+                    (i64.extend_i32_u (i32.add (local.get $len) (i32.const 5)))))
+            (export "mk_check" (func $echo))
+            (export "mk_run" (func $echo)))"#,
+    )
+    .unwrap();
+    let text = format!(
+        ".plugin: {}\n\n$migrate:\n    kind: db\n    runner: db\n    create table users\n",
+        plugin.display()
+    );
+    let mkfile = MkFile::parse(&text).unwrap();
+    let target = mkfile.resolve("migrate");
+
+    let make_with = |executor: MockExecutor| {
+        let executor = Arc::new(executor);
+        let options = MakeOptions {
+            executor: Box::new(executor.clone()),
+            plugins: Plugins::load(mkfile.plugins()).unwrap(),
+            ..MakeOptions::default()
+        };
+        let made = make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap();
+        (made, executor.ran())
+    };
+    assert_eq!(
+        make_with(MockExecutor::default()),
+        (false, vec!["echo migrate".to_string()])
+    );
+    assert_eq!(
+        make_with(MockExecutor::default().fail("echo migrate")),
+        (
+            true,
+            vec![
+                "echo migrate".to_string(),
+                "echo create table users".to_string()
+            ]
+        )
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn offline_remote_includes_use_fetched_copies_only() {
+    let options = ParseOptions {
+        offline: true,
+        ..ParseOptions::default()
+    };
+    let text = "include https://example.invalid/common.mk\n\n$all:\n    echo hi\n";
+    let err = MkFile::parse_with(text, &options).unwrap_err();
+    assert!(err.to_string().contains("offline"), "{err}");
+
+    let text = "-include git:https://example.invalid/rules.git//common.mk?ref=v1\n";
+    assert!(MkFile::parse_with(text, &options)
+        .unwrap()
+        .includes()
+        .is_empty());
+}
+
+#[test]
+fn remakes_when_tool_version_changes() {
+    let dir = scratch_dir("tools");
+    let version = dir.join("version.txt");
+    let output = dir.join("output.txt");
+    std::fs::write(&version, "cc 1.0").unwrap();
+
+    let text = format!(
+        "{output}:\n    tools: cat {version}\n    echo made >> {output}\n",
+        version = version.display(),
+        output = output.display()
+    );
+    let mkfile = MkFile::parse(&text).unwrap();
+    let target = mkfile.resolve(&output.to_string_lossy());
+    let mut state = UpdateState::default();
+    let options = MakeOptions::default();
+
+    assert!(make(&mkfile, &target, &mut state, &options).unwrap());
+    assert!(!make(&mkfile, &target, &mut state, &options).unwrap());
+
+    std::fs::write(&version, "cc 2.0").unwrap();
+    assert!(make(&mkfile, &target, &mut state, &options).unwrap());
+    assert_eq!(std::fs::read_to_string(&output).unwrap(), "made\nmade\n");
+}
+
+#[test]
+fn hermetic_rules_run_in_scrubbed_environment() {
+    let dir = scratch_dir("hermetic");
+    let output = dir.join("env.txt");
+    let text = format!(
+        "{output}:\n    hermetic: yes\n    env_inputs: MK_TEST_ALLOWED\n    env > {output}\n",
+        output = output.display()
+    );
+    let mkfile = MkFile::parse(&text).unwrap();
+    let target = mkfile.resolve(&output.to_string_lossy());
+    let mut options = MakeOptions::default();
+    options.env.insert("MK_TEST_SET".into(), "set".into());
+
+    make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap();
+    let env = std::fs::read_to_string(&output).unwrap();
+    assert!(env.lines().any(|line| line == "MK_TEST_SET=set"));
+    assert!(env
+        .lines()
+        .any(|line| line.starts_with("PATH=/usr/local/bin:")));
+    assert!(!env.lines().any(|line| line.starts_with("HOME=")));
+}
+
+#[test]
+fn sets_rule_env_for_its_commands() {
+    let dir = scratch_dir("rule-env");
+    let output = dir.join("env.txt");
+    let text = format!(
+        "DB = postgres://localhost\n\n\
+         {output}:\n    env: MK_TEST_DB=$(DB) MK_TEST_MODE=debug\n    env > {output}\n",
+        output = output.display()
+    );
+    let mkfile = MkFile::parse(&text).unwrap();
+    let target = mkfile.resolve(&output.to_string_lossy());
+    let mut options = MakeOptions::default();
+    options.env.insert("MK_TEST_MODE".into(), "release".into());
+
+    make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap();
+    let env = std::fs::read_to_string(&output).unwrap();
+    assert!(env
+        .lines()
+        .any(|line| line == "MK_TEST_DB=postgres://localhost"));
+    assert!(env.lines().any(|line| line == "MK_TEST_MODE=debug"));
+}
+
+#[test]
+fn scoped_variables_apply_to_dependency_subtree() {
+    let dir = scratch_dir("scoped-vars");
+    let output = dir.join("opt.txt");
+    let text = format!(
+        "OPT = -O0\n\n$release: OPT=-O3\n\n$release: $debug\n\n$debug: {output}\n\n\
+         {output}:\n    echo $(OPT) > {output}\n",
+        output = output.display()
+    );
+    let output_target = MkFile::parse(&text)
+        .unwrap()
+        .resolve(&output.to_string_lossy());
+
+    let mut mkfile = MkFile::parse(&text).unwrap();
+    let debug = mkfile.resolve("$debug");
+    mkfile.scope(&debug).unwrap();
+    assert_eq!(
+        mkfile.commands(&output_target)[0],
+        format!("echo -O0 > {}", output.display())
+    );
+
+    let mut mkfile = MkFile::parse(&text).unwrap();
+    let target = mkfile.resolve("$release");
+    mkfile.scope(&target).unwrap();
+    make(
+        &mkfile,
+        &target,
+        &mut UpdateState::default(),
+        &MakeOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(std::fs::read_to_string(&output).unwrap(), "-O3\n");
+}
+
+#[test]
+fn loads_env_file() {
+    let dir = scratch_dir("env-file");
+    let env_file = dir.join("test.env");
+    let output = dir.join("env.txt");
+    std::fs::write(
+        &env_file,
+        "# Settings\nexport MK_TEST_NAME=\"from env\"\nMK_TEST_OTHER=plain # comment\n",
+    )
+    .unwrap();
+    let text = format!(
+        ".env_file: {env_file}\n\n{output}:\n    echo '$(MK_TEST_NAME)' > {output}\n    env >> {output}\n",
+        env_file = env_file.display(),
+        output = output.display()
+    );
+    let mkfile = MkFile::parse(&text).unwrap();
+    assert_eq!(mkfile.variable("MK_TEST_OTHER"), Some("plain"));
+    let target = mkfile.resolve(&output.to_string_lossy());
+    let options = MakeOptions {
+        env: mkfile.dotenv().clone(),
+        ..MakeOptions::default()
+    };
+
+    make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap();
+    let made = std::fs::read_to_string(&output).unwrap();
+    assert!(made.starts_with("from env\n"));
+    assert!(made.lines().any(|line| line == "MK_TEST_OTHER=plain"));
+}
+
+#[test]
+fn runs_hooks_as_builds_start_and_end() {
+    let dir = scratch_dir("hooks");
+    let log = dir.join("hooks.log");
+    let text = format!(
+        "[hooks]\non_start = \"echo $MK_EVENT $MK_TARGET >> {log}\"\n\
+         on_success = [\"cat >> {log}\", \"exit 1\"]\non_failure = \"echo failed >> {log}\"\n\n\
+         $all:\n    true\n",
+        log = log.display()
+    );
+    let mkfile = MkFile::parse(&text).unwrap();
+    let target = mkfile.resolve("$all");
+
+    let options = MakeOptions::default();
+    hooks::started(mkfile.hooks(), &target, &options);
+    let made = make(&mkfile, &target, &mut UpdateState::default(), &options);
+    hooks::finished(
+        mkfile.hooks(),
+        &target,
+        &made,
+        Duration::from_secs(2),
+        &options,
+    );
+    let log = std::fs::read_to_string(&log).unwrap();
+    let mut lines = log.lines();
+    assert_eq!(lines.next(), Some("start $all"));
+    let build: serde_json::Value = serde_json::from_str(lines.next().unwrap()).unwrap();
+    assert_eq!(build["event"], "success");
+    assert_eq!(build["made"], true);
+    assert_eq!(build["duration"], 2.0);
+    assert_eq!(lines.next(), None);
+}
+
+#[test]
+fn runs_hooks_with_the_build_executor() {
+    let mkfile = MkFile::parse(
+        "[hooks]\non_start = \"notify start\"\non_failure = [\"notify failed\", \"page\"]\n\n\
+         $all:\n    false\n",
+    )
+    .unwrap();
+    let target = mkfile.resolve("$all");
+    let executor = Arc::new(MockExecutor::default().fail("false"));
+    let options = MakeOptions {
+        executor: Box::new(executor.clone()),
+        ..MakeOptions::default()
+    };
+
+    hooks::started(mkfile.hooks(), &target, &options);
+    let made = make(&mkfile, &target, &mut UpdateState::default(), &options);
+    hooks::finished(mkfile.hooks(), &target, &made, Duration::ZERO, &options);
+    assert_eq!(
+        executor.ran(),
+        ["notify start", "false", "notify failed", "page"]
+    );
+}
+
+#[test]
+fn runs_hooks_in_hermetic_builds_without_the_environment() {
+    let dir = scratch_dir("hermetic-hooks");
+    let log = dir.join("hooks.log");
+    let text = format!(
+        "[hooks]\non_start = \"echo ${{HOME:-none}} $MK_TARGET $SET >> {}\"\n\n$all:\n    true\n",
+        log.display()
+    );
+    let mkfile = MkFile::parse(&text).unwrap();
+    let options = MakeOptions {
+        hermetic: true,
+        env: BTreeMap::from([("SET".to_string(), "set".to_string())]),
+        ..MakeOptions::default()
+    };
+
+    hooks::started(mkfile.hooks(), &mkfile.resolve("$all"), &options);
+    assert_eq!(std::fs::read_to_string(&log).unwrap(), "none $all set\n");
+}
+
+#[test]
+fn counts_builds_as_metrics() {
+    let mkfile = MkFile::parse("$a:\n    echo a\n").unwrap();
+    let metrics = Arc::new(Metrics::default());
+    let options = MakeOptions {
+        executor: Box::new(MockExecutor::default()),
+        reporter: Box::new(metrics.clone()),
+        ..MakeOptions::default()
+    };
+
+    let target = mkfile.resolve("$a");
+    let made = make(&mkfile, &target, &mut UpdateState::default(), &options);
+    metrics.build_over(&made, Duration::from_secs(1));
+    let text = metrics.render();
+    assert!(text.contains("# TYPE mk_builds_total counter\nmk_builds_total{result=\"made\"} 1\n"));
+    assert!(text.contains("mk_target_made_total{target=\"$a\"} 1\n"));
+    assert!(text.contains("mk_target_duration_seconds_count{target=\"$a\"} 1\n"));
+    assert!(text.contains("mk_cache_hit_ratio 0\n"));
+}
+
+#[test]
+fn writes_target_output_to_log_dir() {
+    let dir = scratch_dir("log-dir");
+    let mkfile =
+        MkFile::parse("$all: $a\n    echo all\n\n$a:\n    echo out\n    echo err >&2\n").unwrap();
+    let logs = LogDir::new(&dir, Arc::new(ShellExecutor)).unwrap();
+    let options = MakeOptions {
+        executor: Box::new(logs.clone()),
+        reporter: Box::new(logs.clone()),
+        ..MakeOptions::default()
+    };
+
+    let target = mkfile.resolve("$all");
+    assert!(make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap());
+    assert_eq!(
+        std::fs::read_to_string(logs.path("$a")).unwrap(),
+        "$ echo out\nout\n$ echo err >&2\nerr\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(logs.path("$all")).unwrap(),
+        "$ echo all\nall\n"
+    );
+    let at = std::time::UNIX_EPOCH + Duration::from_secs(1_709_251_199);
+    assert_eq!(logs::stamp(at), "20240229-235959");
+}
+
+#[test]
+fn writes_target_output_to_log_dir_under_held_output() {
+    let dir = scratch_dir("log-dir-held");
+    let mkfile = MkFile::parse("$a:\n    echo out\n").unwrap();
+    let logs = LogDir::new(&dir, Arc::new(ShellExecutor)).unwrap();
+    let held = HeldOutput::new(Arc::new(logs.clone()));
+    let options = MakeOptions {
+        executor: Box::new(held.clone()),
+        reporter: Box::new(Reporters(vec![Box::new(logs.clone()), Box::new(held)])),
+        ..MakeOptions::default()
+    };
+
+    let target = mkfile.resolve("$a");
+    assert!(make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap());
+    assert_eq!(
+        std::fs::read_to_string(logs.path("$a")).unwrap(),
+        "$ echo out\nout\n"
+    );
+}
+
+#[test]
+fn converts_taskfiles_into_mkfiles() {
+    let taskfile = r#"
+version: '3'
+vars:
+  BINARY: app
+tasks:
+  generate:
+    cmds:
+      - go generate ./...
+  build:
+    desc: Builds the app.
+    deps: [generate]
+    sources: ["src/**/*.go", go.mod]
+    generates: ["bin/{{.BINARY}}"]
+    cmds:
+      - go build -o bin/{{.BINARY}} ./src
+  docker:push:
+    cmds:
+      - task: build
+      - docker push app
+"#;
+    let mkfile = MkFile::parse(&taskfile::convert(taskfile).unwrap()).unwrap();
+
+    let build = mkfile.resolve("build");
+    let binary = Target::parse("bin/app");
+    assert_eq!(mkfile.dependencies(&build), std::slice::from_ref(&binary));
+    assert_eq!(mkfile.description(&build), Some("Builds the app."));
+    assert_eq!(
+        mkfile.dependencies(&binary),
+        &[Target::parse("$generate"), Target::parse("go.mod")]
+    );
+    assert_eq!(mkfile.commands(&binary), &["go build -o bin/app ./src"]);
+    let push = mkfile.resolve("docker-push");
+    assert_eq!(mkfile.dependencies(&push), &[build]);
+    assert_eq!(mkfile.commands(&push), &["docker push app"]);
+}
+
+#[test]
+fn exports_rules_to_ninja() {
+    let mkfile = MkFile::parse(
+        "NAME = world\n\n$all: out/hello.txt\n\n\
+         out/hello.txt: in.txt\n    outputs: out/extra.txt\n    echo $(NAME) > $$1\n\n\
+         $unused:\n    echo unused\n",
+    )
+    .unwrap();
+    let ninja = export::ninja(&mkfile, &[mkfile.resolve("all")]);
+
+    assert!(
+        ninja.contains("build all: phony out/hello.txt\n"),
+        "{ninja}"
+    );
+    assert!(
+        ninja.contains("  command = echo world > $$$$1\n"),
+        "{ninja}"
+    );
+    assert!(
+        ninja.contains("build out/hello.txt | out/extra.txt: mk_1 in.txt\n"),
+        "{ninja}"
+    );
+    assert!(!ninja.contains("unused"), "{ninja}");
+    assert!(ninja.ends_with("default all\n"), "{ninja}");
+}
+
+#[test]
+fn exports_compile_commands() {
+    let mkfile = MkFile::parse(
+        "app: main.o util.o\n    gcc -o app main.o util.o\n\n\
+         main.o: main.c util.h\n    gcc -Wall -c main.c -o main.o\n\n\
+         util.o: util.c\n    cd . && CCACHE_DISABLE=1 clang-17 -DNAME='\"util\"' -c util.c -o util.o\n",
+    )
+    .unwrap();
+    let json = export::compdb(&mkfile, &[mkfile.resolve("app")], Path::new("/src/project"));
+    let entries: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+    assert_eq!(
+        entries,
+        serde_json::json!([
+            {
+                "directory": "/src/project",
+                "arguments": ["gcc", "-Wall", "-c", "main.c", "-o", "main.o"],
+                "file": "main.c",
+                "output": "main.o",
+            },
+            {
+                "directory": "/src/project",
+                "arguments": ["clang-17", "-DNAME=\"util\"", "-c", "util.c", "-o", "util.o"],
+                "file": "util.c",
+                "output": "util.o",
+            },
+        ])
+    );
+}
+
+#[test]
+fn exports_github_actions_workflows() {
+    let mkfile =
+        MkFile::parse("$test: app\n    ./app --test\n\napp: main.c\n    cc -o app main.c\n")
+            .unwrap();
+    let targets = [mkfile.resolve("test"), mkfile.resolve("app")];
+    let workflow = export::github_actions(
+        &targets,
+        "build/mkfile",
+        Path::new(".mkstate.sexpr"),
+        Some(Path::new(".mk-cache")),
+    );
+    let workflow: serde_yaml::Value = serde_yaml::from_str(&workflow).unwrap();
+    let steps = &workflow["jobs"]["build"]["steps"];
+
+    assert_eq!(
+        steps[2]["with"]["path"].as_str(),
+        Some(".mkstate.sexpr\n.mk-cache\n")
+    );
+    assert_eq!(
+        steps[3]["run"].as_str(),
+        Some("mk --mkfile build/mkfile test")
+    );
+    assert_eq!(
+        steps[4]["run"].as_str(),
+        Some("mk --mkfile build/mkfile app")
+    );
+}
+
+#[test]
+fn exports_hermetic_rules_to_nix() {
+    let mkfile = MkFile::parse(
+        "app: main.c gen.h\n    hermetic: true\n    env: CFLAGS=-O2\n    cc $CFLAGS ${X:-} -o app main.c\n\n\
+         gen.h: gen.sh\n    hermetic: true\n    sh gen.sh > gen.h\n\n\
+         main.c: template.c\n    cp template.c main.c\n",
+    )
+    .unwrap();
+    let nix = export::nix(&mkfile, &[mkfile.resolve("app")], false);
+
+    assert!(nix.contains("mk_0 = pkgs.runCommand \"app\" { \"CFLAGS\" = \"-O2\"; } ''"));
+    assert!(nix.contains("cp -r \"${mk_1}/gen.h\" 'gen.h'"));
+    assert!(nix.contains("cp -r \"${src + \"/main.c\"}\" 'main.c'"));
+    assert!(nix.contains("cc $CFLAGS ''${X:-} -o app main.c"));
+    assert!(nix.contains("cp -r 'gen.h' \"$out\"/'gen.h'"));
+    assert!(!nix.contains("template.c"));
+    assert!(nix.ends_with("in\n{\n  \"app\" = mk_0;\n  \"gen.h\" = mk_1;\n}\n"));
+}
+
+#[test]
+fn init_writes_starter_mkfiles() {
+    let dir = scratch_dir("init");
+    std::fs::write(dir.join("main.c"), "int main(void) { return 0; }\n").unwrap();
+    let program = dir.file_name().unwrap().to_string_lossy().into_owned();
+    let project = Project::detect(&dir);
+    assert_eq!(
+        project,
+        Project::C {
+            program: program.clone(),
+            cpp: false
+        }
+    );
+    let mkfile = MkFile::parse(&project.mkfile()).unwrap();
+    assert_eq!(mkfile.default_target(), Some(&Target::parse("$build")));
+    assert_eq!(
+        mkfile.dependencies(&Target::parse("$build")),
+        &[Target::parse(&program)]
+    );
+    for name in ["$test", "$clean"] {
+        assert!(mkfile.has_target(&Target::parse(name)));
+    }
+
+    std::fs::write(dir.join("package.json"), r#"{"scripts": {"test": "jest"}}"#).unwrap();
+    std::fs::create_dir(dir.join("src")).unwrap();
+    let project = Project::detect(&dir);
+    assert_eq!(
+        project,
+        Project::Node {
+            scripts: vec!["test".to_string()],
+            lockfile: false,
+            sources: Some("src".to_string()),
+        }
+    );
+    let mkfile = MkFile::parse(&project.mkfile()).unwrap();
+    let test = Target::parse("$test");
+    assert_eq!(mkfile.commands(&test), &["npm test"]);
+    assert_eq!(
+        mkfile.dependencies(&test),
+        &[Target::parse("node_modules"), Target::parse("^src")]
+    );
+
+    std::fs::write(dir.join("Cargo.toml"), "[package]\n").unwrap();
+    assert_eq!(Project::detect(&dir), Project::Rust);
+
+    assert_eq!(
+        init::ignore_state("target\n", ".mkstate.sexpr").as_deref(),
+        Some("target\n/.mkstate.sexpr\n")
+    );
+    assert_eq!(
+        init::ignore_state("target\n.mkstate.sexpr", ".mkstate.sexpr"),
+        None
+    );
+}
+
+#[test]
+fn duplicate_rules_fail_unless_merged() {
+    let dir = scratch_dir("duplicates");
+    let common = dir.join("common.mk");
+    std::fs::write(
+        &common,
+        "# Shared rules\n\napp: main.o\n    cc -o app main.o\n",
+    )
+    .unwrap();
+    let text = format!("include {}\n\n$all: app\n\napp: util.o\n", common.display());
+
+    let err = MkFile::parse(&text).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "'app' has more than one rule, at line 3 of '{}' and at line 5",
+            common.display()
+        )
+    );
+
+    let options = ParseOptions {
+        merge_duplicates: true,
+        ..ParseOptions::default()
+    };
+    let mkfile = MkFile::parse_with(&text, &options).unwrap();
+    let app = Target::parse("app");
+    assert_eq!(
+        mkfile.dependencies(&app),
+        &[Target::parse("main.o"), Target::parse("util.o")]
+    );
+    assert_eq!(mkfile.commands(&app), &["cc -o app main.o"]);
+    assert_eq!(mkfile.duplicates(), &[app]);
+
+    let both = "app: a\n    touch app\n\napp: b\n    touch app\n";
+    assert!(MkFile::parse_with(both, &options).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn state_keeps_paths_that_are_not_utf8() {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    use mk::{mkfile::ConcreteTarget, vfs::RealFs};
+
+    let dir = scratch_dir("not-utf8");
+    let file = ConcreteTarget::Shallow(dir.join(OsStr::from_bytes(b"caf\xe9.txt")));
+    std::fs::write(file.pathbuf(), "hello").unwrap();
+    let state_path = dir.join("state");
+
+    let mut state = UpdateState::default();
+    state.update_state(&RealFs, &file).unwrap();
+    state.update_hash(&RealFs, &file).unwrap();
+    state.save(&RealFs, &state_path).unwrap();
+
+    let mut loaded = UpdateState::load(&RealFs, &state_path);
+    assert!(loaded.is_up_to_date(&RealFs, &file).unwrap().is_ok());
+    assert!(!loaded.update_hash(&RealFs, &file).unwrap());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinks_are_followed_or_taken_as_they_are() {
+    use std::os::unix::fs::symlink;
+
+    use mk::{
+        making::{content_hash, update_time},
+        mkfile::ConcreteTarget,
+        vfs::{NoFollowFs, RealFs},
+    };
+
+    let dir = scratch_dir("symlinks");
+    let sources = dir.join("src");
+    std::fs::create_dir_all(&sources).unwrap();
+    std::fs::write(sources.join("a.txt"), "same").unwrap();
+    std::fs::write(sources.join("b.txt"), "same").unwrap();
+    symlink("..", sources.join("up")).unwrap();
+
+    let deep = ConcreteTarget::Deep(sources.clone());
+    let err = update_time(&RealFs, &deep).unwrap_err();
+    assert!(err.to_string().contains("links back"), "{err}");
+    assert!(content_hash(&RealFs, &deep).is_err());
+    assert!(update_time(&NoFollowFs, &deep).is_ok());
+
+    let current = ConcreteTarget::Shallow(dir.join("current"));
+    symlink("src/a.txt", current.pathbuf()).unwrap();
+    let mut state = UpdateState::default();
+    state.update_state(&RealFs, &current).unwrap();
+    state.update_hash(&RealFs, &current).unwrap();
+
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    std::fs::remove_file(current.pathbuf()).unwrap();
+    symlink("src/b.txt", current.pathbuf()).unwrap();
+    for vfs in [&RealFs as &dyn Vfs, &NoFollowFs] {
+        assert!(state.is_up_to_date(vfs, &current).unwrap().is_err());
+        assert!(state.clone().update_hash(vfs, &current).unwrap());
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn ignored_files_do_not_change_deep_targets() {
+    use mk::making::{content_hash, update_time};
+
+    let fs = MemoryFs::default();
+    fs.write(Path::new("src/main.rs"), b"fn main() {}").unwrap();
+    fs.write(Path::new("src/.git/HEAD"), b"main").unwrap();
+    let Target::Concrete(ignoring) = Target::parse("^src[!.git]") else {
+        unreachable!()
+    };
+    let Target::Concrete(everything) = Target::parse("^src") else {
+        unreachable!()
+    };
+    let before = (
+        update_time(&fs, &ignoring).unwrap(),
+        content_hash(&fs, &ignoring).unwrap(),
+    );
+    let all_before = update_time(&fs, &everything).unwrap();
+
+    fs.write(Path::new("src/.git/HEAD"), b"other").unwrap();
+    assert_eq!(
+        (
+            update_time(&fs, &ignoring).unwrap(),
+            content_hash(&fs, &ignoring).unwrap()
+        ),
+        before
+    );
+    assert!(update_time(&fs, &everything).unwrap() > all_before);
+}
+
+/// Returns the changes a daemon watching `/project` had seen, as it sends
+/// them.
+fn daemon_changes(daemon: &str, seen: u64, changed: serde_json::Value) -> Changes {
+    serde_json::from_value(serde_json::json!({
+        "daemon": daemon,
+        "root": "/project",
+        "seen": seen,
+        "changed": changed,
+    }))
+    .unwrap()
+}
+
+#[test]
+fn daemon_changes_cover_folders_and_what_is_in_them() {
+    let changes = daemon_changes("d1", 9, serde_json::json!({ "src/main.c": 3, "docs": 7 }));
+
+    assert!(changes.touched(Path::new("/project/src/main.c"), 2));
+    assert!(!changes.touched(Path::new("/project/src/main.c"), 3));
+    assert!(changes.touched(Path::new("src/main.c"), 2));
+    // Folders change with what is in them, and files with their folders
+    assert!(changes.touched(Path::new("/project/src"), 2));
+    assert!(changes.touched(Path::new("/project/docs/guide.md"), 5));
+    assert!(!changes.touched(Path::new("/project/docs/guide.md"), 7));
+    assert!(!changes.touched(Path::new("/project/srcs"), 0));
+    // Paths the daemon doesn't watch could have changed
+    assert!(changes.touched(Path::new("/elsewhere/main.c"), 9));
+    assert!(changes.touched(Path::new("src/../../main.c"), 9));
+}
+
+#[test]
+fn daemon_checker_only_checks_what_changed() {
+    let fs = MemoryFs::default();
+    let main = ConcreteTarget::Shallow(PathBuf::from("/project/src/main.c"));
+    let guide = ConcreteTarget::Shallow(PathBuf::from("/project/docs/guide.md"));
+    fs.write(main.pathbuf(), b"int main;").unwrap();
+    fs.write(guide.pathbuf(), b"# Guide").unwrap();
+    let mut state = UpdateState::default();
+    let checker = DaemonChecker::new(
+        daemon_changes("d1", 5, serde_json::json!({})),
+        &MtimeChecker,
+    );
+    checker.record(&mut state, &fs, &main).unwrap();
+    checker.record(&mut state, &fs, &guide).unwrap();
+    checker.remember(&mut state);
+
+    // Both are touched, but the daemon only saw the first change
+    let later = std::time::SystemTime::now() + Duration::from_secs(60);
+    fs.set_modified(main.pathbuf(), later).unwrap();
+    fs.set_modified(guide.pathbuf(), later).unwrap();
+    let changes = daemon_changes("d1", 8, serde_json::json!({ "src/main.c": 7 }));
+    let checker = DaemonChecker::new(changes, &MtimeChecker);
+    assert!(checker.check(&state, &fs, &main).unwrap().is_err());
+    assert!(checker.check(&state, &fs, &guide).unwrap().is_ok());
+
+    // Another daemon may have missed changes
+    let checker = DaemonChecker::new(
+        daemon_changes("d2", 0, serde_json::json!({})),
+        &MtimeChecker,
+    );
+    assert!(checker.check(&state, &fs, &guide).unwrap().is_err());
+}
+
+#[test]
+fn diffs_the_state_of_two_builds() {
+    let fs = Arc::new(MemoryFs::default());
+    fs.write(Path::new("src/a.txt"), b"one").unwrap();
+    let mkfile = MkFile::parse("out/a.txt: src/a.txt\n    cp src/a.txt out/a.txt\n").unwrap();
+    let target = mkfile.resolve("out/a.txt");
+    let (options, _) = memory_options(&fs);
+    let mut state = UpdateState::default();
+    assert!(make(&mkfile, &target, &mut state, &options).unwrap());
+    let before = state.clone();
+
+    fs.write(Path::new("src/a.txt"), b"two").unwrap();
+    assert!(make(&mkfile, &target, &mut state, &options).unwrap());
+    let changes = before.diff(&state);
+    let changed = |target: &str, field| {
+        changes
+            .iter()
+            .find(|change| change.target == target && change.field == field)
+    };
+    let mtime = changed("out/a.txt", "mtime").unwrap();
+    assert!(mtime.before.is_some() && mtime.after.is_some());
+    assert_ne!(mtime.before, mtime.after);
+    assert!(changed("src/a.txt", "mtime").is_some());
+    assert!(state.diff(&state).is_empty());
+
+    let added = UpdateState::default().diff(&before);
+    assert!(added.iter().all(|change| change.before.is_none()));
+    assert!(added.iter().any(|change| change.target == "out/a.txt"));
+}
+
+#[test]
+fn replays_reproduction_bundles_without_the_files() {
+    let dir = scratch_dir("repro");
+    let (source, output) = (dir.join("in.txt"), dir.join("out.txt"));
+    std::fs::write(&source, "one").unwrap();
+    let mkfile = dir.join("mkfile");
+    let text = format!(
+        "{0}: {1}\n    cp {1} {0}\n",
+        output.display(),
+        source.display()
+    );
+    std::fs::write(&mkfile, text).unwrap();
+    let bundle = dir.join("bundle.json");
+    let target = output.display().to_string();
+
+    repro::create(&mkfile, &dir.join("state"), &target, None, &bundle).unwrap();
+    let recorded: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&bundle).unwrap()).unwrap();
+    assert_eq!(
+        recorded["plan"],
+        serde_json::json!([
+            [source.display().to_string(), "no previous build recorded"],
+            [target, "output is missing"],
+        ])
+    );
+    assert!(recorded["fingerprints"]
+        .get(source.display().to_string())
+        .is_some());
+
+    // Replaying plans with what the bundle recorded, not the files
+    std::fs::remove_file(&source).unwrap();
+    std::fs::remove_file(&mkfile).unwrap();
+    repro::replay(&bundle).unwrap();
+}
+
+#[test]
+fn writes_targets_and_commands_to_trace_file() {
+    let path = scratch_dir("trace").join("trace.json");
+    let mkfile = MkFile::parse("$all: $a\n\n$a:\n    echo a\n").unwrap();
+    let reporter = Arc::new(TraceReporter::new(path.clone()));
+    let options = MakeOptions {
+        executor: Box::new(MockExecutor::default()),
+        reporter: Box::new(reporter.clone()),
+        ..MakeOptions::default()
+    };
+
+    let target = mkfile.resolve("$all");
+    assert!(make(&mkfile, &target, &mut UpdateState::default(), &options).unwrap());
+    reporter.finish();
+    let trace: Vec<serde_json::Value> =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    let spans: Vec<(&str, &str, &str)> = trace
+        .iter()
+        .map(|event| {
+            let field = |name| event[name].as_str().unwrap();
+            (field("name"), field("cat"), field("ph"))
+        })
+        .collect();
+    assert_eq!(
+        spans,
+        [
+            ("$all", "target", "B"),
+            ("$a", "target", "B"),
+            ("echo a", "command", "B"),
+            ("echo a", "command", "E"),
+            ("$a", "target", "E"),
+            ("$all", "target", "E"),
+        ]
+    );
+    assert_eq!(trace[4]["args"]["made"], "true");
+}
+
+/// A writer whose output can be read back by a test.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for SharedBuffer {
+    fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buffer);
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn streams_events_as_json_lines() {
+    let mkfile = MkFile::parse("$all: $a\n\n$a:\n    echo a\n").unwrap();
+    let buffer = SharedBuffer::default();
+    let ids = HashMap::from([("$a".to_string(), "t-1".to_string())]);
+    let reporter = NdjsonReporter::new(Box::new(buffer.clone()))
+        .with_ids(ids)
+        .with_field("build", serde_json::json!(7));
+    let options = MakeOptions {
+        executor: Box::new(MockExecutor::default().fail("echo a")),
+        reporter: Box::new(reporter),
+        ..MakeOptions::default()
+    };
+
+    let target = mkfile.resolve("$all");
+    assert!(make(&mkfile, &target, &mut UpdateState::default(), &options).is_err());
+    let text = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let events: Vec<serde_json::Value> = text
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let kinds: Vec<&str> = events
+        .iter()
+        .map(|event| event["event"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        kinds,
+        [
+            "target_started",
+            "target_started",
+            "target_outdated",
+            "command_started",
+            "command_finished",
+            "error",
+            "error",
+        ]
+    );
+    assert!(events.iter().all(|event| event["build"] == 7));
+    assert!(events.iter().all(|event| event["time"].is_f64()));
+    assert_eq!(events[1]["target"], "$a");
+    assert_eq!(events[1]["target_id"], "t-1");
+    assert!(events[0].get("target_id").is_none());
+}
+
+#[test]
+fn doctor_finds_missing_tools() {
+    let mkfile = MkFile::parse(
+        "$all: $a $b\n    echo all\n\n$a:\n    CC=cc mk-test-missing-tool -o a\n    sh -c true\n\n\
+         $b:\n    [platform: no-such-os]\n    mk-test-other-missing-tool\n",
+    )
+    .unwrap();
+
+    let problems = doctor::check_tools(&mkfile);
+    assert_eq!(problems.len(), 1);
+    assert_eq!(
+        problems[0].message,
+        "'mk-test-missing-tool' used by target '$a' was not found"
+    );
+    assert!(doctor::check_shell().is_ok());
+    let state = scratch_dir("doctor").join("state");
+    assert!(doctor::check_state_dir(&state).is_ok());
+    assert!(doctor::check_state_dir(&state.join("missing/state")).is_err());
+
+    let path = state.with_file_name("mkfile");
+    std::fs::write(&path, "$a:\n    mk-test-missing-tool\n").unwrap();
+    assert!(!doctor::doctor(&path, &state));
+}
+
+#[test]
+fn removes_the_state_lock_file_when_released() {
+    let state = scratch_dir("lock-file").join(".mkstate.sexpr");
+    let lock_path = state.with_file_name(".mkstate.sexpr.lock");
+
+    let lock = StateLock::acquire(&state, false).unwrap();
+    let holder = std::fs::read_to_string(&lock_path).unwrap();
+    assert_eq!(holder, std::process::id().to_string());
+    drop(lock);
+    assert!(!lock_path.exists());
+
+    // The lock can be taken again once the file is gone
+    let _lock = StateLock::acquire(&state, false).unwrap();
+    assert!(lock_path.exists());
+}
+
+#[test]
+fn refuses_or_waits_for_a_state_lock_that_is_held() {
+    let state = scratch_dir("lock-held").join(".mkstate.sexpr");
+    let lock = StateLock::acquire(&state, false).unwrap();
+
+    let err = StateLock::acquire(&state, false).err().unwrap();
+    assert!(matches!(
+        err,
+        MkError::Locked { holder: Some(holder), .. } if holder == std::process::id()
+    ));
+
+    let (taken, took) = std::sync::mpsc::channel();
+    let waiting = {
+        let state = state.clone();
+        std::thread::spawn(move || {
+            let lock = StateLock::acquire(&state, true).unwrap();
+            taken.send(()).unwrap();
+            lock
+        })
+    };
+    assert!(took.recv_timeout(Duration::from_millis(200)).is_err());
+    drop(lock);
+    took.recv_timeout(Duration::from_secs(5)).unwrap();
+    let _lock = waiting.join().unwrap();
+    assert!(StateLock::acquire(&state, false).is_err());
+}
+
+#[test]
+fn keeps_the_state_from_before_the_last_save() {
+    let dir = scratch_dir("previous-state");
+    let mkfile = MkFile::parse("a.txt:\n    touch a.txt\n\nb.txt:\n    touch b.txt\n").unwrap();
+    let mut paths = vec![dir.join(".mkstate.sexpr")];
+    if cfg!(feature = "sqlite") {
+        paths.push(dir.join("state.sqlite"));
+    }
+    for path in paths {
+        let store = store::open(&path).unwrap();
+        let mut state = UpdateState::default();
+        state.assign_ids(&mkfile);
+        store.save(&state).unwrap();
+        assert!(store.previous().is_none());
+
+        let mut grown = state.clone();
+        grown.record_outcome(&Target::parse("a.txt"), true);
+        store.save(&grown).unwrap();
+        let previous = store.previous().unwrap();
+        assert!(previous.diff(&state).is_empty());
+        assert!(!previous.diff(&store.load()).is_empty());
+    }
+}
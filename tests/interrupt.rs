@@ -0,0 +1,63 @@
+#![cfg(unix)]
+
+use std::{
+    path::Path,
+    process::Command,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Waits for the file to be written, and returns what is in it.
+fn wait_for(path: &Path) -> String {
+    let started = Instant::now();
+    loop {
+        if let Ok(text) = std::fs::read_to_string(path) {
+            if text.ends_with('\n') {
+                return text;
+            }
+        }
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "{path:?} wasn't written"
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn stops_commands_and_saves_the_state_when_interrupted() {
+    let dir = std::env::temp_dir().join(format!("mk-test-{}-interrupt", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("mkfile"),
+        "$all: done.txt $slow\n\n\
+         done.txt:\n    echo done > done.txt\n\n\
+         $slow:\n    echo $$ > slow.pid; exec sleep 30\n    touch after.txt\n",
+    )
+    .unwrap();
+
+    let mut mk = Command::new(env!("CARGO_BIN_EXE_mk"))
+        .arg("-C")
+        .arg(&dir)
+        .spawn()
+        .unwrap();
+    let pid: libc::pid_t = wait_for(&dir.join("slow.pid")).trim().parse().unwrap();
+    // SAFETY: kill has no memory safety requirements
+    assert_eq!(
+        unsafe { libc::kill(mk.id() as libc::pid_t, libc::SIGINT) },
+        0
+    );
+
+    let status = mk.wait().unwrap();
+    assert_eq!(status.code(), Some(128 + libc::SIGINT));
+    // The command was stopped, rather than left running or followed by the
+    // next one
+    assert_ne!(unsafe { libc::kill(pid, 0) }, 0);
+    assert!(!dir.join("after.txt").exists());
+    // What was made before is remembered
+    let state = std::fs::read_to_string(dir.join(".mkstate.sexpr")).unwrap();
+    assert!(state.contains("done.txt"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
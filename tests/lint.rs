@@ -0,0 +1,70 @@
+use std::collections::BTreeMap;
+
+use mk::{
+    lint::{lint, Severity},
+    mkfile::{MkFile, ParseOptions},
+};
+
+#[test]
+fn finds_problems_in_commands() {
+    let mkfile = MkFile::parse(
+        "$clean:\n    rm -rf $OUT/*\n\n$test:\n    [[ -f x ]] && ./run $@\n\n$fine:\n    rm -rf \"$OUT\"\n",
+    )
+    .unwrap();
+
+    let findings = lint(&mkfile, &BTreeMap::new());
+    let found: Vec<_> = findings
+        .iter()
+        .map(|finding| (finding.target.to_string(), finding.lint, finding.severity))
+        .collect();
+    assert_eq!(
+        found,
+        [
+            ("$clean".to_string(), "dangerous-rm", Severity::Error),
+            ("$test".to_string(), "bashism", Severity::Warning),
+            ("$test".to_string(), "unquoted-args", Severity::Warning),
+        ]
+    );
+
+    let levels = BTreeMap::from([("bashism".to_string(), Severity::Off)]);
+    assert_eq!(lint(&mkfile, &levels).len(), 2);
+}
+
+#[test]
+fn finds_problems_in_rules() {
+    let options = ParseOptions {
+        merge_duplicates: true,
+        ..ParseOptions::default()
+    };
+    let mkfile = MkFile::parse_with(
+        "$all: app $docs\n\n\
+         app: main.c missing.c\n    cc -o app main.c $(CFLAGS) $(LDFLAGS)\n\n\
+         main.c:\n    touch main.c\n\n\
+         old.o: old.c\n    cc -c old.c\n\n\
+         old.o: old.c\n",
+        &options,
+    )
+    .unwrap();
+
+    let findings = lint(&mkfile, &BTreeMap::new());
+    let found: Vec<_> = findings
+        .iter()
+        .map(|finding| (finding.target.to_string(), finding.lint, finding.severity))
+        .collect();
+    assert_eq!(
+        found,
+        [
+            ("$all".to_string(), "unreachable-virtual", Severity::Error),
+            ("app".to_string(), "missing-source", Severity::Error),
+            ("app".to_string(), "undefined-variable", Severity::Warning),
+            ("app".to_string(), "undefined-variable", Severity::Warning),
+            ("old.o".to_string(), "duplicate-rule", Severity::Warning),
+            ("old.o".to_string(), "missing-source", Severity::Error),
+            ("old.o".to_string(), "unused-rule", Severity::Warning),
+        ]
+    );
+    assert_eq!(
+        findings[1].message,
+        "depends on 'missing.c', which no rule makes and isn't on disk"
+    );
+}
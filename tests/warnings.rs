@@ -0,0 +1,47 @@
+use std::sync::{Arc, Mutex};
+
+use log::{Level, Log, Metadata, Record};
+use mk::warnings::WarningLayer;
+
+/// Keeps the messages it is asked to log.
+#[derive(Default, Clone)]
+struct Recorder(Arc<Mutex<Vec<String>>>);
+
+impl Log for Recorder {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        self.0.lock().unwrap().push(record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+fn warn(logger: &dyn Log, message: &str) {
+    logger.log(
+        &Record::builder()
+            .level(Level::Warn)
+            .args(format_args!("{message}"))
+            .build(),
+    );
+}
+
+#[test]
+fn holds_back_repeated_warnings() {
+    let recorder = Recorder::default();
+    let layer = WarningLayer::new(recorder.clone());
+    for i in 0..10 {
+        warn(&layer, &format!("File 'src/{i}.c' is ignored"));
+    }
+    warn(&layer, "Something 'else'");
+    assert_eq!(recorder.0.lock().unwrap().len(), 4);
+
+    layer.summarize();
+    let logged = recorder.0.lock().unwrap();
+    assert_eq!(
+        logged.last().unwrap(),
+        "7 more warnings like: File 'src/0.c' is ignored"
+    );
+}